@@ -1,15 +1,74 @@
 use anyhow::{Context, Result};
 use std::sync::OnceLock;
 
-fn language() -> tree_sitter::Language {
+pub(crate) fn language() -> tree_sitter::Language {
     static LANGUAGE: OnceLock<tree_sitter::Language> = OnceLock::new();
     *LANGUAGE.get_or_init(|| tree_sitter_protobuf::language())
 }
 
+// All tree-sitter queries, compiled once and shared by every file.
+pub(crate) struct Queries {
+    pub package: tree_sitter::Query,
+    pub syntax: tree_sitter::Query,
+    pub imports: tree_sitter::Query,
+    pub symbols: tree_sitter::Query,
+    pub type_refs: tree_sitter::Query,
+    pub field_names: tree_sitter::Query,
+    pub message_names: tree_sitter::Query,
+    pub enum_values: tree_sitter::Query,
+    pub enum_value_prefixes: tree_sitter::Query,
+    pub required_labels: tree_sitter::Query,
+    pub groups: tree_sitter::Query,
+    pub extends: tree_sitter::Query,
+}
+
+impl Queries {
+    fn new() -> Queries {
+        let query = |source: &str| tree_sitter::Query::new(language(), source).unwrap();
+        Queries {
+            package: query("(package (fullIdent (ident)) @id)"),
+            syntax: query("(syntax) @syntax"),
+            imports: query("(import (strLit) @path)"),
+            symbols: query(
+                "[
+                     (message (messageName (ident) @id))
+                     (enum (enumName (ident) @id))
+                     (service (serviceName (ident) @id))
+                     (rpc (rpcName (ident) @id))
+                     (oneof (oneofName (ident) @id))
+                     (oneofField (fieldName (ident) @id))
+                     (mapField (mapName (ident) @id))
+                 ] @def",
+            ),
+            type_refs: query("[(field (type) @name) (rpc (messageType) @name)]"),
+            field_names: query("(field (fieldName (ident) @name))"),
+            message_names: query("(message (messageName (ident) @name))"),
+            enum_values: query("(enumField (ident) @name)"),
+            enum_value_prefixes: query(
+                "(enum (enumName (ident) @enum) (enumBody (enumField (ident) @value)))",
+            ),
+            required_labels: query("(field \"required\" @label)"),
+            groups: query("(group) @group"),
+            extends: query("(extend) @extend"),
+        }
+    }
+}
+
+pub(crate) fn queries() -> &'static Queries {
+    static QUERIES: OnceLock<Queries> = OnceLock::new();
+    QUERIES.get_or_init(Queries::new)
+}
+
 #[derive(Debug, PartialEq)]
 pub enum SymbolKind {
     Message,
     Enum,
+    Service,
+    Rpc,
+    Oneof,
+    Field,
+    // A top-level extend block, named by the message it extends.
+    Extend,
 }
 
 #[derive(Debug, PartialEq)]
@@ -17,37 +76,123 @@ pub struct Symbol {
     pub kind: SymbolKind,
     pub name: String,
     pub range: tree_sitter::Range,
+    pub deprecated: bool,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Syntax {
+    Proto2,
+    Proto3,
+    Editions,
 }
 
 #[derive(Debug, PartialEq)]
 pub enum CompletionContext<'a> {
-    Message(&'a str),
+    // The fully qualified name of the enclosing message, e.g. Outer.Inner
+    Message(String),
     Enum(&'a str),
     Import,
     Keyword,
     Syntax,
     Option,
+    Package,
+    // After the returns clause of an rpc, where the body goes.
+    Rpc,
+    // The request or response type of an rpc, e.g. rpc Foo (|
+    // stream is set if the stream keyword could still be typed, e.g. rpc Foo (st|
+    RpcType {
+        stream: bool,
+    },
+    // The message being extended, e.g. extend |
+    Extend,
+    // The key type of a map field, e.g. map<|
+    MapKey,
+    // The number of an enum value, holding the next unused number.
+    EnumValueNumber(i64),
+    // The name of a new enum value, e.g. RED|, and the next unused number.
+    EnumValueName {
+        name: String,
+        number: i64,
+    },
+    // A name in the reserved statement of an enum, e.g. reserved "RED", |
+    ReservedName,
+    // The number of a field, holding the lowest number that is free to use.
+    FieldNumber(i64),
+    // The name of an option of a field, e.g. int32 foo = 1 [|
+    FieldOption,
+    // A comment just started above a definition, e.g. //|
+    // kind is the kind of node being documented, e.g. message or field.
+    DocComment {
+        kind: &'a str,
+        name: &'a str,
+        indent: String,
+    },
 }
 
 #[derive(Debug, PartialEq)]
 pub struct GotoTypeContext<'a> {
     pub name: &'a str,
     pub parent: Option<String>,
+    // Set for rpc request and response types, which must be messages.
+    pub message_only: bool,
 }
 
 #[derive(Debug, PartialEq)]
 pub enum GotoContext<'a> {
     Type(GotoTypeContext<'a>),
     Import(&'a str),
+    // A field name listed in a reserved statement, and the message that reserves it.
+    Reserved {
+        message: String,
+        name: &'a str,
+    },
+    // A key in an aggregate option value, e.g. option (foo) = { bar: 1 };
+    OptionField {
+        option: &'a str,
+        field: &'a str,
+    },
+    // An enum value set as the default of a field of that enum type, e.g.
+    // Color c = 1 [default = RED];
+    EnumValue {
+        typ: GotoTypeContext<'a>,
+        value: &'a str,
+    },
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Field<'a> {
+    pub label: Option<&'a str>,
+    pub label_range: Option<tree_sitter::Range>,
+    pub typ: &'a str,
+    pub type_range: tree_sitter::Range,
+}
+
+// The name of a field, and the name it has in the JSON encoding.
+#[derive(Debug, PartialEq)]
+pub struct JsonName<'a> {
+    pub name: &'a str,
+    pub name_range: tree_sitter::Range,
+    pub json_name: String,
+}
+
+// The extension ranges declared by a message, and the numbers of its fields.
+#[derive(Debug, PartialEq)]
+pub struct MessageNumbers {
+    // The first and last number of each range, and the extensions statement declaring it.
+    pub extensions: Vec<(i64, i64, tree_sitter::Range)>,
+    pub fields: Vec<(i64, tree_sitter::Range)>,
 }
 
 pub struct File {
     tree: tree_sitter::Tree,
     text: String,
+    // The byte offset at which each line starts, kept up to date on edit.
+    line_starts: Vec<usize>,
 }
 
 impl File {
     pub fn new(text: String) -> Result<File> {
+        let text = strip_bom(text);
         let mut parser = tree_sitter::Parser::new();
         parser
             .set_language(language())
@@ -55,40 +200,29 @@ impl File {
 
         let tree = parser.parse(&text, None).context("Parse failed")?;
         log::trace!("Parsed: {}", tree.root_node().to_sexp());
-        Ok(File { tree, text })
+        let line_starts = line_starts(&text);
+        Ok(File {
+            tree,
+            text,
+            line_starts,
+        })
     }
 
     pub fn edit(&mut self, changes: Vec<lsp_types::TextDocumentContentChangeEvent>) -> Result<()> {
+        // Once the whole document is replaced, the old tree cannot be reused.
+        let mut reparse = false;
         for change in changes {
-            let range = change
-                .range
-                .with_context(|| format!("No range in change notification {change:?}"))?;
-            let mut lines = self.text.split_inclusive("\n").peekable();
-            // First count bytes in all lines preceding the edit.
-            let start_byte = lines
-                .by_ref()
-                .take(range.start.line.try_into()?)
-                .map(str::len)
-                .sum::<usize>();
-            // Now add bytes up to the character within the start line.
-            let start_offset = lines
-                .peek()
-                .map(|line| char_to_byte(&line, range.start.character))
-                .unwrap_or(0);
-            let start_byte = start_byte + start_offset;
-            // Now count bytes in all lines following the edit.
-            let end_byte = start_byte
-                + lines
-                    .by_ref()
-                    .take((range.end.line - range.start.line).try_into()?)
-                    .map(str::len)
-                    .sum::<usize>();
-            // Now add bytes up to the character within the end line.
-            let end_offset = lines
-                .peek()
-                .map(|line| char_to_byte(&line, range.end.character))
-                .unwrap_or(0);
-            let end_byte = end_byte + end_offset - start_offset;
+            let Some(range) = change.range else {
+                // No range means the change replaces the whole document.
+                self.text = strip_bom(change.text);
+                self.line_starts = line_starts(&self.text);
+                reparse = true;
+                continue;
+            };
+            let start_byte = self.offset(range.start);
+            let end_byte = self.offset(range.end);
+            let start_position = self.point(start_byte);
+            let old_end_position = self.point(end_byte);
 
             log::trace!(
                 "Computing change {start_byte}..{end_byte} with text {}",
@@ -96,6 +230,30 @@ impl File {
             );
 
             self.text.replace_range(start_byte..end_byte, &change.text);
+
+            // Lines starting within the replaced range are replaced by those in the new text,
+            // and lines after it are shifted by the change in length.
+            let new_end_byte = start_byte + change.text.len();
+            let first = self.line_starts.partition_point(|&s| s <= start_byte);
+            let last = self.line_starts.partition_point(|&s| s <= end_byte);
+            let inserted = change
+                .text
+                .match_indices('\n')
+                .map(|(i, _)| start_byte + i + 1);
+            let shifted = self.line_starts[last..]
+                .iter()
+                .map(|s| s - end_byte + new_end_byte);
+            let tail: Vec<_> = inserted.chain(shifted).collect();
+            self.line_starts.splice(first.., tail);
+
+            self.tree.edit(&tree_sitter::InputEdit {
+                start_byte,
+                old_end_byte: end_byte,
+                new_end_byte,
+                start_position,
+                old_end_position,
+                new_end_position: self.point(new_end_byte),
+            });
         }
         log::trace!("Edited text to: {}", self.text);
 
@@ -103,13 +261,39 @@ impl File {
         parser
             .set_language(language())
             .expect("Error loading proto language");
-        self.tree = parser.parse(&self.text, None).context("Parse failed")?;
+        let old_tree = (!reparse).then_some(&self.tree);
+        self.tree = parser.parse(&self.text, old_tree).context("Parse failed")?;
         log::trace!("Edited tree to: {}", self.tree.root_node().to_sexp());
 
         Ok(())
     }
 
-    fn get_text(&self, node: tree_sitter::Node) -> &str {
+    // Return the byte offset of an LSP position.
+    // Positions past the end of the document are clamped to the end.
+    fn offset(&self, pos: lsp_types::Position) -> usize {
+        let row = pos.line as usize;
+        let Some(&start) = self.line_starts.get(row) else {
+            return self.text.len();
+        };
+        let end = self
+            .line_starts
+            .get(row + 1)
+            .copied()
+            .unwrap_or(self.text.len());
+        start + char_to_byte(&self.text[start..end], pos.character)
+    }
+
+    // Return the tree-sitter point of a byte offset.
+    fn point(&self, byte: usize) -> tree_sitter::Point {
+        // The first line always starts at 0, so there is at least one line start <= byte.
+        let row = self.line_starts.partition_point(|&s| s <= byte) - 1;
+        tree_sitter::Point {
+            row,
+            column: byte - self.line_starts[row],
+        }
+    }
+
+    pub fn get_text(&self, node: tree_sitter::Node) -> &str {
         node.utf8_text(self.text.as_bytes()).unwrap()
     }
 
@@ -117,30 +301,122 @@ impl File {
         self.text.as_str()
     }
 
+    pub(crate) fn tree(&self) -> &tree_sitter::Tree {
+        &self.tree
+    }
+
     pub fn package(&self) -> Option<&str> {
-        static QUERY: OnceLock<tree_sitter::Query> = OnceLock::new();
-        let query = QUERY.get_or_init(|| {
-            tree_sitter::Query::new(language(), "(package (fullIdent (ident)) @id)").unwrap()
-        });
+        self.package_node()
+            .map(|n| self.get_text(n))
+            .map(|s| s.trim_matches('"'))
+    }
+
+    // Return the range of the package name in the package declaration.
+    pub fn package_range(&self) -> Option<tree_sitter::Range> {
+        self.package_node().map(|n| n.range())
+    }
+
+    // Return the range of every package statement in the file.
+    // Only one is valid, but an invalid file may declare several.
+    pub fn package_statements(&self) -> Vec<tree_sitter::Range> {
+        let query = &queries().package;
+
+        let mut qc = tree_sitter::QueryCursor::new();
+        qc.matches(&query, self.tree.root_node(), self.text.as_bytes())
+            .filter_map(|m| m.captures[0].node.parent())
+            .map(|n| n.range())
+            .collect()
+    }
+
+    fn package_node(&self) -> Option<tree_sitter::Node> {
+        let query = &queries().package;
 
         let mut qc = tree_sitter::QueryCursor::new();
         let res = qc
             .matches(&query, self.tree.root_node(), self.text.as_bytes())
             .next()
-            .map(|m| m.captures[0].node)
-            .map(|n| self.get_text(n))
-            .map(|s| s.trim_matches('"'));
+            .map(|m| m.captures[0].node);
+        res
+    }
+
+    // Files without a syntax or edition statement are treated as proto2, as protoc does.
+    pub fn syntax(&self) -> Syntax {
+        let query = &queries().syntax;
+
+        let mut qc = tree_sitter::QueryCursor::new();
+        let res = qc
+            .matches(&query, self.tree.root_node(), self.text.as_bytes())
+            .next()
+            .map(|m| self.get_text(m.captures[0].node));
+        match res {
+            Some(text) if text.contains("proto3") => Syntax::Proto3,
+            Some(_) => Syntax::Proto2,
+            // The edition statement replaces syntax, e.g. edition = "2023";
+            None if self.text.lines().any(is_edition_line) => Syntax::Editions,
+            None => Syntax::Proto2,
+        }
+    }
+
+    // Return the key type of every map field with its range, e.g. Foo in map<Foo, Bar>.
+    // This scans the text, as the grammar does not parse a map field with an invalid key type,
+    // but skips comments and strings.
+    pub fn map_key_types(&self) -> Vec<(&str, tree_sitter::Range)> {
+        let mut res = vec![];
+        let mut offset = 0;
+        for (row, line) in self.text.split('\n').enumerate() {
+            for (i, _) in line.match_indices("map<") {
+                let pos = tree_sitter::Point { row, column: i };
+                let in_string = self
+                    .tree
+                    .root_node()
+                    .descendant_for_point_range(pos, pos)
+                    .is_some_and(|n| find_ancestor(n, "strLit").is_some());
+                if line[..i].ends_with(|c: char| c.is_alphanumeric() || c == '_')
+                    || in_string
+                    || self.in_comment(row, i + 1)
+                {
+                    continue;
+                }
+                let start = i + "map<".len();
+                // Wait for the key to be complete before reporting it.
+                let Some((key, _)) = line[start..].split_once(',') else {
+                    continue;
+                };
+                let name = key.trim();
+                let col = start + key.len() - key.trim_start().len();
+                res.push((
+                    name,
+                    tree_sitter::Range {
+                        start_byte: offset + col,
+                        end_byte: offset + col + name.len(),
+                        start_point: tree_sitter::Point { row, column: col },
+                        end_point: tree_sitter::Point {
+                            row,
+                            column: col + name.len(),
+                        },
+                    },
+                ));
+            }
+            offset += line.len() + 1;
+        }
         res
     }
 
+    // Return the range of every `required` field label.
+    pub fn required_labels(&self) -> Vec<tree_sitter::Range> {
+        let query = &queries().required_labels;
+
+        let mut qc = tree_sitter::QueryCursor::new();
+        qc.matches(&query, self.tree.root_node(), self.text.as_bytes())
+            .map(|m| m.captures[0].node.range())
+            .collect()
+    }
+
     pub fn imports<'this: 'cursor, 'cursor>(
         &'this self,
         qc: &'cursor mut tree_sitter::QueryCursor,
     ) -> impl Iterator<Item = &'this str> + 'cursor {
-        static QUERY: OnceLock<tree_sitter::Query> = OnceLock::new();
-        let query = QUERY.get_or_init(|| {
-            tree_sitter::Query::new(language(), "(import (strLit) @path)").unwrap()
-        });
+        let query = &queries().imports;
 
         qc.matches(&query, self.tree.root_node(), self.text.as_bytes())
             .map(|m| m.captures[0].node)
@@ -148,27 +424,117 @@ impl File {
             .map(|s| s.trim_matches('"'))
     }
 
+    // Return the paths of the imports that re-export their contents,
+    // e.g. foo.proto for import public "foo.proto";
+    pub fn public_imports(&self) -> Vec<&str> {
+        let root = self.tree.root_node();
+        let mut cursor = root.walk();
+        root.named_children(&mut cursor)
+            .filter(|n| n.kind() == "import")
+            .filter(|n| n.child(1).is_some_and(|c| c.kind() == "public"))
+            .filter_map(|n| {
+                let mut cursor = n.walk();
+                let path = n
+                    .named_children(&mut cursor)
+                    .find(|c| c.kind() == "strLit")?;
+                Some(self.get_text(path).trim_matches('"'))
+            })
+            .collect()
+    }
+
+    // Return the rows spanned by the first contiguous block of imports, and the text of
+    // that block with the imports sorted by path. Regular imports come first, followed by
+    // public and then weak imports. Comments directly above an import move with it, while
+    // blank lines and other comments stay where they are.
+    pub fn sorted_imports(&self) -> Option<(std::ops::Range<usize>, String)> {
+        let imports = self.import_block();
+        let rows = imports.first()?.0..imports.last()?.1 + 1;
+        let lines = self.text.lines().collect::<Vec<_>>();
+        let line = |row: usize| lines.get(row).copied().unwrap_or_default().to_string() + "\n";
+        // The rows between imports that are not attached to any of them.
+        let mut between = vec![];
+        let mut next = rows.start;
+        let mut sorted = vec![];
+        for (first, end, node) in imports {
+            between.push((next..first).map(line).collect::<String>());
+            next = end + 1;
+            let group = match node.child(1).map(|n| n.kind()) {
+                Some("public") => 1,
+                Some("weak") => 2,
+                _ => 0,
+            };
+            let mut cursor = node.walk();
+            let path = node
+                .named_children(&mut cursor)
+                .find(|n| n.kind() == "strLit")
+                .map(|n| self.get_text(n).trim_matches('"'))
+                .unwrap_or("");
+            let text = (first..=end).map(line).collect::<String>();
+            sorted.push((group, path, text));
+        }
+        sorted.sort();
+
+        let text = between
+            .into_iter()
+            .zip(sorted)
+            .map(|(between, (_, _, text))| between + &text)
+            .collect();
+        Some((rows, text))
+    }
+
+    // Return the rows of the first and last import in the first contiguous block of imports.
+    pub fn import_lines(&self) -> Option<(usize, usize)> {
+        let imports = self.import_block();
+        let (_, _, first) = imports.first()?;
+        let (_, last, _) = imports.last()?;
+        Some((first.start_position().row, *last))
+    }
+
+    // Return each import in the first contiguous block of imports, along with the first row
+    // of the comments directly above it (or its own row if there are none) and its last row.
+    fn import_block(&self) -> Vec<(usize, usize, tree_sitter::Node)> {
+        let root = self.tree.root_node();
+        let mut cursor = root.walk();
+        let mut imports = vec![];
+        // The rows of the comments directly above the next node.
+        let mut comments: Option<(usize, usize)> = None;
+        for node in root.named_children(&mut cursor) {
+            let (start, end) = (node.start_position().row, node.end_position().row);
+            let last = imports.last().map(|(_, end, _)| *end);
+            match node.kind() {
+                // A comment trailing an import is on the same line.
+                "comment" if last == Some(start) => {}
+                "comment" => {
+                    comments = match comments {
+                        Some((first, prev)) if prev + 1 == start => Some((first, end)),
+                        _ => Some((start, end)),
+                    };
+                }
+                "import" => {
+                    let first = match comments.take() {
+                        Some((first, prev)) if prev + 1 == start => first,
+                        _ => start,
+                    };
+                    imports.push((first, end, node));
+                }
+                _ if imports.is_empty() => comments = None,
+                _ => break,
+            }
+        }
+        imports
+    }
+
     pub fn symbols<'this: 'cursor, 'cursor>(
         &'this self,
         qc: &'cursor mut tree_sitter::QueryCursor,
     ) -> impl Iterator<Item = Symbol> + 'cursor {
-        static QUERY: OnceLock<tree_sitter::Query> = OnceLock::new();
-        let query = QUERY.get_or_init(|| {
-            tree_sitter::Query::new(
-                language(),
-                "[
-                     (message (messageName (ident) @id))
-                     (enum (enumName (ident) @id))
-                 ] @def",
-            )
-            .unwrap()
-        });
+        let query = &queries().symbols;
 
         qc.matches(&query, self.tree.root_node(), self.text.as_bytes())
             .map(|m| (m.captures[0].node, m.captures[1].node))
             .map(|(def, id)| {
                 let name = self.get_text(id);
-                let name = if let Some(p) = self.parent_name(def) {
+                let name = if let Some(p) = self.symbol_parent(def) {
                     p + "." + name
                 } else {
                     name.to_string()
@@ -176,15 +542,74 @@ impl File {
                 Symbol {
                     kind: match def.kind() {
                         "message" => SymbolKind::Message,
+                        "service" => SymbolKind::Service,
+                        "rpc" => SymbolKind::Rpc,
+                        "oneof" => SymbolKind::Oneof,
+                        "oneofField" | "mapField" => SymbolKind::Field,
                         _ => SymbolKind::Enum,
                     },
                     name,
                     range: def.range(),
+                    deprecated: self.is_deprecated(def),
                 }
             })
     }
 
-    // Return all symbols adjusted relative to a message.
+    // Return a symbol for each top-level extend block, named by its extendee,
+    // along with the symbols of the fields it declares.
+    // These are not types that can be referenced, so they are not part of symbols().
+    pub fn extend_symbols(&self) -> Vec<(Symbol, Vec<Symbol>)> {
+        let mut res = vec![];
+        let root = self.tree.root_node();
+        let mut cursor = root.walk();
+        for node in root
+            .named_children(&mut cursor)
+            .filter(|n| n.kind() == "extend")
+        {
+            let mut cursor = node.walk();
+            let children = node.named_children(&mut cursor).collect::<Vec<_>>();
+            let Some(extendee) = children
+                .iter()
+                .find(|n| n.kind() != "field" && n.kind() != "comment")
+                .map(|n| self.get_text(*n))
+            else {
+                continue;
+            };
+            let block = Symbol {
+                kind: SymbolKind::Extend,
+                name: extendee.to_string(),
+                range: node.range(),
+                deprecated: false,
+            };
+            let fields = children
+                .iter()
+                .filter(|n| n.kind() == "field")
+                .filter_map(|field| {
+                    let mut cursor = field.walk();
+                    let name = field
+                        .named_children(&mut cursor)
+                        .find(|n| n.kind() == "fieldName")?;
+                    Some(Symbol {
+                        kind: SymbolKind::Field,
+                        name: self.get_text(name).to_string(),
+                        range: field.range(),
+                        deprecated: self.is_deprecated(*field),
+                    })
+                })
+                .collect();
+            res.push((block, fields));
+        }
+        res
+    }
+
+    // Return true if the symbol's definition is complete, rather than just a keyword and name.
+    pub fn has_body(&self, sym: &Symbol) -> bool {
+        self.text[sym.range.start_byte..sym.range.end_byte]
+            .trim_end()
+            .ends_with('}')
+    }
+
+    // Return all symbols along with their name relative to a message.
     // For example, given base_name=Foo.Bar:
     // symbols()          -> [Foo, Foo.Bar, Foo.Bar.Baz, Foo.Bar.Baz.Biz]
     // relative_symbols() -> [Foo, Bar    , Baz        , Baz.Biz]
@@ -192,10 +617,10 @@ impl File {
         &'this self,
         base_name: &'this str,
         qc: &'cursor mut tree_sitter::QueryCursor,
-    ) -> impl Iterator<Item = Symbol> + 'cursor {
-        self.symbols(qc).map(|s| Symbol {
-            name: relative_name(base_name, &s.name),
-            ..s
+    ) -> impl Iterator<Item = (Symbol, String)> + 'cursor {
+        self.symbols(qc).map(|s| {
+            let name = relative_name(base_name, &s.name);
+            (s, name)
         })
     }
 
@@ -225,8 +650,15 @@ impl File {
                 .and_then(|n| Some(CompletionContext::Enum(n))),
             Some(n) if n.kind() == "messageBody" => n
                 .parent() // message
-                .and_then(|p| self.type_name(p))
-                .and_then(|n| Some(CompletionContext::Message(n))),
+                .and_then(|p| self.qualified_name(p))
+                .map(CompletionContext::Message),
+            // Oneof fields are completed like fields of the enclosing message.
+            Some(n) if n.kind() == "oneof" => n
+                .parent() // messageBody
+                .and_then(|p| p.parent()) // message
+                .filter(|p| p.kind() == "message")
+                .and_then(|p| self.qualified_name(p))
+                .map(CompletionContext::Message),
             Some(n) => self.parent_context(n.parent()),
         }
     }
@@ -241,6 +673,53 @@ impl File {
             return Ok(Some(CompletionContext::Syntax));
         }
 
+        if let Some(ctx) = self.doc_comment_context(row, col) {
+            return Ok(Some(ctx));
+        }
+
+        if self.in_comment(row, col) {
+            return Ok(None);
+        }
+
+        if self
+            .line_prefix(row, col)
+            .is_ok_and(|l| l.trim_start().starts_with("package "))
+        {
+            return Ok(Some(CompletionContext::Package));
+        }
+
+        if self
+            .line_prefix(row, col)
+            .is_ok_and(|l| is_extend_prefix(&l))
+        {
+            // extend google.protobuf.Fi|
+            return Ok(Some(CompletionContext::Extend));
+        }
+
+        if self.line_prefix(row, col).is_ok_and(|l| is_rpc_prefix(&l)) {
+            // rpc Foo (Bar) returns (Baz) |
+            return Ok(Some(CompletionContext::Rpc));
+        }
+
+        if let Some(line) = self
+            .line_prefix(row, col)
+            .ok()
+            .filter(|l| is_rpc_type_prefix(l))
+        {
+            // rpc Foo (Bar) returns (B|
+            return Ok(Some(CompletionContext::RpcType {
+                stream: is_stream_prefix(&line),
+            }));
+        }
+
+        if self
+            .line_prefix(row, col)
+            .is_ok_and(|l| is_map_key_prefix(&l))
+        {
+            // map<str|
+            return Ok(Some(CompletionContext::MapKey));
+        }
+
         let pos = tree_sitter::Point {
             row: row.try_into().unwrap(),
             // Generally, the node before the cursor is more interesting for context.
@@ -262,6 +741,57 @@ impl File {
             self.get_text(node),
         );
 
+        if let Some(e) = find_ancestor(node, "enum") {
+            // Editions reserve bare identifiers rather than quoted names.
+            if self.syntax() != Syntax::Editions
+                && self
+                    .line_prefix(row, col)
+                    .is_ok_and(|l| is_reserved_name_prefix(&l))
+            {
+                // reserved "RED", |
+                return Ok(Some(CompletionContext::ReservedName));
+            }
+            if self
+                .line_prefix(row, col)
+                .is_ok_and(|l| is_enum_value_prefix(&l))
+            {
+                // RED = |
+                return Ok(Some(CompletionContext::EnumValueNumber(
+                    self.next_enum_value(e),
+                )));
+            }
+            if let Some(name) = self
+                .line_prefix(row, col)
+                .ok()
+                .filter(|l| is_enum_value_name_prefix(l))
+            {
+                // RED|
+                return Ok(Some(CompletionContext::EnumValueName {
+                    name: name.trim().to_string(),
+                    number: self.next_enum_value(e),
+                }));
+            }
+        }
+
+        if let Some(m) = find_ancestor(node, "message") {
+            if self
+                .line_prefix(row, col)
+                .is_ok_and(|l| is_field_number_prefix(&l))
+            {
+                // int32 foo = |
+                return Ok(Some(CompletionContext::FieldNumber(
+                    self.next_field_number(m),
+                )));
+            }
+            if self
+                .line_prefix(row, col)
+                .is_ok_and(|l| is_field_option_prefix(&l))
+            {
+                // int32 foo = 1 [|
+                return Ok(Some(CompletionContext::FieldOption));
+            }
+        }
+
         Ok(if node.kind() == "option" {
             // option | -> (option)
             Some(CompletionContext::Option)
@@ -295,15 +825,7 @@ impl File {
             Some(CompletionContext::Keyword)
         } else if node.kind() == "source_file" {
             // NOTE: Not very efficient, but we're in a difficult spot here.
-            let line: String = self
-                .text
-                .lines()
-                .skip(row)
-                .next()
-                .with_context(|| format!("Line {row} out of range"))?
-                .chars()
-                .take(col)
-                .collect();
+            let line = self.line_prefix(row, col)?;
             let line = line.trim_start();
 
             log::trace!("Checking keyword completion for line {line}");
@@ -321,16 +843,198 @@ impl File {
         })
     }
 
+    // Return the definition on the line below a comment that has only just been started, e.g.
+    // //|
+    // message Foo {}
+    fn doc_comment_context(&self, row: usize, col: usize) -> Option<CompletionContext> {
+        let line = self.line_prefix(row, col).ok()?;
+        if line.trim() != "//" {
+            return None;
+        }
+        let indent = &line[..line.len() - line.trim_start().len()];
+        let pos = tree_sitter::Point {
+            row,
+            column: indent.len(),
+        };
+        let comment = self
+            .tree
+            .root_node()
+            .descendant_for_point_range(pos, pos)
+            .filter(|n| n.kind() == "comment")?;
+        let def = comment
+            .next_named_sibling()
+            .filter(|n| n.start_position().row == row + 1)
+            .filter(|n| DOCUMENTED_KINDS.contains(&n.kind()))?;
+        let mut cursor = def.walk();
+        let name = def.named_children(&mut cursor).find(|n| {
+            [
+                "messageName",
+                "enumName",
+                "serviceName",
+                "rpcName",
+                "fieldName",
+                "mapName",
+                "oneofName",
+                "ident",
+            ]
+            .contains(&n.kind())
+        })?;
+        Some(CompletionContext::DocComment {
+            kind: def.kind(),
+            name: self.get_text(name),
+            indent: indent.to_string(),
+        })
+    }
+
+    // Whether the cursor is within a comment, where there is nothing to complete.
+    fn in_comment(&self, row: usize, col: usize) -> bool {
+        let cursor = tree_sitter::Point { row, column: col };
+        // The character before the cursor, as at the end of a line comment.
+        let pos = tree_sitter::Point {
+            row,
+            column: col.saturating_sub(1),
+        };
+        let Some(comment) = self
+            .tree
+            .root_node()
+            .descendant_for_point_range(pos, pos)
+            .filter(|n| n.kind() == "comment")
+        else {
+            return false;
+        };
+        // A line comment runs to the end of the line, but a block comment ends at */.
+        comment.start_position() < cursor
+            && (cursor < comment.end_position() || !self.get_text(comment).starts_with("/*"))
+    }
+
+    // Return one more than the largest value in the enum, or 0 if it has no values.
+    // Aliases may reuse numbers, but a new value should still get the next one.
+    fn next_enum_value(&self, node: tree_sitter::Node) -> i64 {
+        let mut cursor = node.walk();
+        let Some(body) = node
+            .named_children(&mut cursor)
+            .find(|n| n.kind() == "enumBody")
+        else {
+            return 0;
+        };
+        let mut cursor = body.walk();
+        let res = body
+            .named_children(&mut cursor)
+            .filter(|n| n.kind() == "enumField")
+            .filter_map(|n| parse_enum_value(self.get_text(n)))
+            .max()
+            .map_or(0, |n| n + 1);
+        res
+    }
+
+    // Return the lowest positive field number of the message that is not used by a field,
+    // reserved, set aside for extensions, or in the range reserved by protobuf itself.
+    fn next_field_number(&self, node: tree_sitter::Node) -> i64 {
+        let mut excluded = vec![IMPLEMENTATION_RESERVED_NUMBERS];
+        let mut cursor = node.walk();
+        if let Some(body) = node
+            .named_children(&mut cursor)
+            .find(|n| n.kind() == "messageBody")
+        {
+            let mut cursor = body.walk();
+            for node in body.named_children(&mut cursor) {
+                let text = self.get_text(node);
+                match node.kind() {
+                    "reserved" => excluded.extend(parse_reserved_ranges(text)),
+                    "extensions" => excluded.extend(parse_extension_ranges(text)),
+                    "field" | "mapField" => excluded.extend(parse_enum_value(text).map(|n| (n, n))),
+                    "oneof" => {
+                        let mut cursor = node.walk();
+                        excluded.extend(
+                            node.named_children(&mut cursor)
+                                .filter(|n| n.kind() == "oneofField")
+                                .filter_map(|n| parse_enum_value(self.get_text(n)))
+                                .map(|n| (n, n)),
+                        );
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        // Skip past each range containing the candidate until none do.
+        let mut n = 1;
+        while let Some((_, end)) = excluded.iter().find(|(s, e)| (*s..=*e).contains(&n)) {
+            n = end + 1;
+        }
+        n
+    }
+
+    // Return the text of the given line up to the given column.
+    fn line_prefix(&self, row: usize, col: usize) -> Result<String> {
+        Ok(self
+            .text
+            .lines()
+            .skip(row)
+            .next()
+            .with_context(|| format!("Line {row} out of range"))?
+            .chars()
+            .take(col)
+            .collect())
+    }
+
+    // Return the keyword or scalar type under the cursor, e.g. message or string.
+    // These are tokens spelled like their own kind, so an identifier that happens to be
+    // spelled like a keyword (e.g. package rpc;) is not one.
+    pub fn keyword_at(&self, row: usize, col: usize) -> Option<&'static str> {
+        let pos = tree_sitter::Point { row, column: col };
+        let node = self.tree.root_node().descendant_for_point_range(pos, pos)?;
+        (node.child_count() == 0 && node.kind() == self.get_text(node)).then(|| node.kind())
+    }
+
+    // Return the // comment lines directly above a definition, without the comment markers.
+    pub fn leading_comment(&self, def: &Symbol) -> Option<String> {
+        let row = def.range.start_point.row;
+        let mut lines: Vec<_> = self
+            .text
+            .lines()
+            .take(row)
+            .collect::<Vec<_>>()
+            .into_iter()
+            .rev()
+            .map_while(|l| l.trim_start().strip_prefix("//"))
+            .map(|l| l.strip_prefix(' ').unwrap_or(l))
+            .collect();
+        if lines.is_empty() {
+            return None;
+        }
+        lines.reverse();
+        Some(lines.join("\n"))
+    }
+
+    // Return the first row of the // comment lines directly above a definition,
+    // or the row of the definition itself if there are none.
+    pub fn leading_comment_row(&self, def: &Symbol) -> usize {
+        let row = def.range.start_point.row;
+        let lines = self.text.lines().take(row).collect::<Vec<_>>();
+        row - lines
+            .iter()
+            .rev()
+            .take_while(|l| l.trim_start().starts_with("//"))
+            .count()
+    }
+
     pub fn type_references(
         self: &Self,
         pkg: Option<&str>,
         typ: &GotoTypeContext,
     ) -> Vec<tree_sitter::Range> {
-        static QUERY: OnceLock<tree_sitter::Query> = OnceLock::new();
-        let query = QUERY
-            .get_or_init(|| tree_sitter::Query::new(language(), "(field (type) @name)").unwrap());
+        let query = &queries().type_refs;
+        // Names may be relative to the scope they are used in, e.g. Inner within Outer.
+        // Resolve them against the types declared in this file, if it is in the same package.
+        let local = match self.package() == pkg {
+            true => self.type_names(),
+            false => vec![],
+        };
+        let target = self.resolve_local(&local, typ.name, typ.parent.as_deref());
         let typ = typ.name;
-        log::trace!("Searching for references to {typ} in package {pkg:?}");
+        let names = qualified_names(pkg, self.package(), typ);
+        log::trace!("Searching for references to {typ} ({target:?}) in package {pkg:?}");
 
         let mut qc = tree_sitter::QueryCursor::new();
         qc.matches(&query, self.tree.root_node(), self.text.as_bytes())
@@ -338,31 +1042,96 @@ impl File {
             .inspect(|x| log::trace!("Check {x:?}: {} == {typ}", self.get_text(*x)))
             .filter(|node| {
                 let text = self.get_text(*node);
-                // first check the fully qualified name
-                text == typ
-                    || match pkg {
-                        // If we have a package, try stripping that, in case we're in the same package
-                        Some(pkg) => {
-                            log::trace!(
-                                "Trying to match '{typ}' to '{text}' without package {pkg}"
-                            );
-                            text.strip_prefix(pkg)
-                                .and_then(|text| text.strip_prefix("."))
-                                .map(|text| typ == text)
-                                .unwrap_or(false)
-                        }
-                        None => false,
-                    }
+                let scope = self.parent_name(*node);
+                if let (Some(target), Some(found)) =
+                    (&target, self.resolve_local(&local, text, scope.as_deref()))
+                {
+                    return *target == found;
+                }
+                // Otherwise match the name as given, or any way this file could qualify it.
+                text == typ || names.iter().any(|n| n == text)
             })
             .map(|node| node.range())
             .collect()
     }
 
+    // Return the qualified name of every message and enum declared in the file.
+    pub fn type_names(&self) -> Vec<String> {
+        let mut qc = tree_sitter::QueryCursor::new();
+        self.symbols(&mut qc)
+            .filter(|s| s.kind == SymbolKind::Message || s.kind == SymbolKind::Enum)
+            .map(|s| s.name)
+            .collect()
+    }
+
+    // Resolve a type name used in the given scope to one of the given types declared in this
+    // file, searching the scope and then each enclosing scope, e.g. Inner in Outer.Other is
+    // Outer.Inner if there is no Outer.Other.Inner.
+    pub fn resolve_local(
+        &self,
+        types: &[String],
+        name: &str,
+        scope: Option<&str>,
+    ) -> Option<String> {
+        let find = |name: &str| types.iter().find(|t| t.as_str() == name).cloned();
+
+        // A leading dot means the name is fully qualified.
+        if let Some(name) = name.strip_prefix('.') {
+            let name = match self.package() {
+                Some(pkg) => name.strip_prefix(pkg)?.strip_prefix('.')?,
+                None => name,
+            };
+            return find(name);
+        }
+
+        let mut scope = scope;
+        loop {
+            let res = match scope {
+                Some(scope) => find(&format!("{scope}.{name}")),
+                None => find(name),
+            };
+            if res.is_some() {
+                return res;
+            }
+            match scope {
+                Some(s) => scope = s.rsplit_once('.').map(|(parent, _)| parent),
+                None => break,
+            }
+        }
+        find(name.strip_prefix(self.package()?)?.strip_prefix('.')?)
+    }
+
+    // Return the text and range of every type used by a field or rpc in the file.
+    pub fn type_refs(self: &Self) -> Vec<(&str, tree_sitter::Range)> {
+        let query = &queries().type_refs;
+
+        let mut qc = tree_sitter::QueryCursor::new();
+        qc.matches(&query, self.tree.root_node(), self.text.as_bytes())
+            .map(|m| m.captures[0].node)
+            .map(|n| (self.get_text(n), n.range()))
+            .collect()
+    }
+
+    // Return the messages named by extend blocks, e.g. foo.Bar in extend foo.Bar {
+    pub fn extendee_refs(&self) -> Vec<(&str, tree_sitter::Range)> {
+        let query = &queries().extends;
+
+        let mut qc = tree_sitter::QueryCursor::new();
+        qc.matches(&query, self.tree.root_node(), self.text.as_bytes())
+            .map(|m| m.captures[0].node)
+            .filter_map(|n| {
+                let mut cursor = n.walk();
+                let extendee = n
+                    .named_children(&mut cursor)
+                    .find(|c| c.kind() != "field" && c.kind() != "comment");
+                extendee
+            })
+            .map(|n| (self.get_text(n), n.range()))
+            .collect()
+    }
+
     pub fn import_references(self: &Self, file: &str) -> Vec<tree_sitter::Range> {
-        static QUERY: OnceLock<tree_sitter::Query> = OnceLock::new();
-        let query = QUERY.get_or_init(|| {
-            tree_sitter::Query::new(language(), "(import (strLit) @name)").unwrap()
-        });
+        let query = &queries().imports;
 
         let mut qc = tree_sitter::QueryCursor::new();
         qc.matches(&query, self.tree.root_node(), self.text.as_bytes())
@@ -372,6 +1141,19 @@ impl File {
             .collect()
     }
 
+    // Return the ranges of the fields declared with the given name in a message, e.g. Outer.Inner.
+    pub fn field_references(self: &Self, message: &str, name: &str) -> Vec<tree_sitter::Range> {
+        let query = &queries().field_names;
+
+        let mut qc = tree_sitter::QueryCursor::new();
+        qc.matches(&query, self.tree.root_node(), self.text.as_bytes())
+            .map(|m| m.captures[0].node)
+            .filter(|n| self.get_text(*n) == name)
+            .filter(|n| self.parent_name(*n).is_some_and(|p| p == message))
+            .map(|n| n.range())
+            .collect()
+    }
+
     pub fn type_at(self: &Self, row: usize, col: usize) -> Option<GotoContext> {
         log::trace!("Getting type at row: {row} col: {col}");
 
@@ -390,6 +1172,21 @@ impl File {
             return Some(GotoContext::Import(self.get_text(node).trim_matches('"')));
         }
 
+        if node.kind() == "strLit" {
+            // Enums reserve value names, which are not tracked.
+            let body = find_ancestor(node, "reserved").and_then(|r| r.parent());
+            if let Some(message) = body
+                .filter(|b| b.kind() == "messageBody")
+                .and_then(|b| b.parent())
+                .and_then(|m| self.qualified_name(m))
+            {
+                return Some(GotoContext::Reserved {
+                    message,
+                    name: self.get_text(node).trim_matches('"'),
+                });
+            }
+        }
+
         // Cursor is over a message or enum name.
         if is_sexp(node, &["enum", "enumName", "ident"])
             || is_sexp(node, &["message", "messageName", "ident"])
@@ -397,29 +1194,367 @@ impl File {
             return Some(GotoContext::Type(GotoTypeContext {
                 name: self.get_text(node.parent().unwrap()),
                 parent: None,
+                message_only: false,
+            }));
+        }
+
+        // Cursor is over an rpc name, which is qualified by the service.
+        if is_sexp(node, &["rpc", "rpcName", "ident"]) {
+            return Some(GotoContext::Type(GotoTypeContext {
+                name: self.get_text(node),
+                parent: self.parent_name(node),
+                message_only: false,
+            }));
+        }
+
+        // Cursor is over an rpc request or response type.
+        let rpc_type = find_ancestor(node, "messageType")
+            .filter(|t| t.parent().is_some_and(|p| p.kind() == "rpc"));
+        if let Some(typ) = rpc_type {
+            return Some(GotoContext::Type(GotoTypeContext {
+                name: self.get_text(typ),
+                parent: None,
+                message_only: true,
             }));
         }
 
+        // Cursor is over an enum value in the default option of a field.
+        if let Some(ctx) = self.default_enum_value(node) {
+            return Some(ctx);
+        }
+
         // Cursor is over a field type.
         if node.kind() == "ident" || node.kind() == "enumMessageType" {
             if let Some(name) = self.field_type(Some(node)) {
                 return Some(GotoContext::Type(GotoTypeContext {
                     name,
                     parent: self.parent_name(node),
+                    message_only: false,
                 }));
             }
         }
 
+        // Cursor is over a key in an aggregate option value.
+        if node.kind() == "ident" && node.next_sibling().is_some_and(|n| n.kind() == ":") {
+            if let Some(option) = find_ancestor(node, "option").and_then(|n| self.option_name(n)) {
+                return Some(GotoContext::OptionField {
+                    option,
+                    field: self.get_text(node),
+                });
+            }
+        }
+
+        // Cursor is over a type named in a service or rpc option value.
+        if node.kind() == "ident" && is_service_option_value(node) {
+            let name = node
+                .parent()
+                .filter(|p| p.kind() == "fullIdent")
+                .unwrap_or(node);
+            return Some(GotoContext::Type(GotoTypeContext {
+                name: self.get_text(name),
+                parent: None,
+                message_only: false,
+            }));
+        }
+
         None
     }
 
+    // Return the enum value named by a default option, along with the type of its field.
+    fn default_enum_value(&self, node: tree_sitter::Node) -> Option<GotoContext> {
+        let option = find_ancestor(node, "fieldOption")?;
+        let (key, default) = self.get_text(option).split_once('=')?;
+        let value = self.get_text(node);
+        if key.trim() != "default"
+            || default.trim() != value
+            || !value.starts_with(|c: char| c.is_alphabetic() || c == '_')
+        {
+            return None;
+        }
+        let field = find_ancestor(option, "field")?;
+        let mut cursor = field.walk();
+        let typ = field
+            .named_children(&mut cursor)
+            .find(|n| n.kind() == "type")?;
+        Some(GotoContext::EnumValue {
+            typ: GotoTypeContext {
+                name: self.get_text(typ),
+                parent: self.parent_name(field),
+                message_only: false,
+            },
+            value,
+        })
+    }
+
+    // Return the name of the extension set by an option, e.g. foo.bar for option (foo.bar) = 1;
+    fn option_name(&self, node: tree_sitter::Node) -> Option<&str> {
+        let mut cursor = node.walk();
+        let name = node
+            .named_children(&mut cursor)
+            .find(|n| n.kind() == "optionName")?;
+        let name = self.get_text(name).trim_start_matches('(');
+        name.split(')').next()
+    }
+
+    // Return each extension field declared in an extend block along with its type.
+    // Extensions are named relative to the package, e.g. Foo.bar for an extend nested in Foo.
+    pub fn extensions(&self) -> Vec<(Symbol, &str)> {
+        let query = &queries().field_names;
+
+        let mut qc = tree_sitter::QueryCursor::new();
+        qc.matches(&query, self.tree.root_node(), self.text.as_bytes())
+            .map(|m| m.captures[0].node)
+            .filter_map(|n| {
+                let field = find_ancestor(n, "field")?;
+                let extend = find_ancestor(field, "extend")?;
+                let mut cursor = field.walk();
+                let typ = field
+                    .named_children(&mut cursor)
+                    .find(|n| n.kind() == "type")?;
+                let name = self.get_text(n);
+                let name = match self.parent_name(extend) {
+                    Some(p) => p + "." + name,
+                    None => name.to_string(),
+                };
+                let sym = Symbol {
+                    kind: SymbolKind::Field,
+                    name,
+                    range: field.range(),
+                    deprecated: self.is_deprecated(field),
+                };
+                Some((sym, self.get_text(typ)))
+            })
+            .collect()
+    }
+
+    // Return the range of the field with the given name in the given message, e.g. Outer.Inner.
+    pub fn field_range(&self, message: &str, field: &str) -> Option<tree_sitter::Range> {
+        let query = &queries().field_names;
+
+        let mut qc = tree_sitter::QueryCursor::new();
+        let res = qc
+            .matches(&query, self.tree.root_node(), self.text.as_bytes())
+            .map(|m| m.captures[0].node)
+            .filter(|n| self.get_text(*n) == field)
+            .filter_map(|n| find_ancestor(n, "field"))
+            .find(|n| self.parent_name(*n).is_some_and(|p| p == message))
+            .map(|n| n.range());
+        res
+    }
+
+    // Return the range of the value with the given name in an enum, e.g. RED in Color.
+    pub fn enum_value_range(&self, name: &str, value: &str) -> Option<tree_sitter::Range> {
+        let query = &queries().enum_values;
+
+        let mut qc = tree_sitter::QueryCursor::new();
+        let res = qc
+            .matches(&query, self.tree.root_node(), self.text.as_bytes())
+            .map(|m| m.captures[0].node)
+            .filter(|n| self.get_text(*n) == value)
+            .find(|n| {
+                find_ancestor(*n, "enum")
+                    .and_then(|e| self.qualified_name(e))
+                    .is_some_and(|e| e == name)
+            })
+            .and_then(|n| n.parent())
+            .map(|n| n.range());
+        res
+    }
+
+    // Return the JSON name of the field whose name is at the given position.
+    // This is the json_name option if set, otherwise the name in lowerCamelCase.
+    pub fn json_name_at(&self, row: usize, col: usize) -> Option<JsonName> {
+        let pos = tree_sitter::Point { row, column: col };
+        let node = self
+            .tree
+            .root_node()
+            .named_descendant_for_point_range(pos, pos)?;
+        let name = node.parent().filter(|p| p.kind() == "fieldName")?;
+        let field = name
+            .parent()
+            .filter(|p| p.kind() == "field" || p.kind() == "oneofField")?;
+
+        let mut cursor = field.walk();
+        let options = field
+            .named_children(&mut cursor)
+            .filter(|n| n.kind() == "fieldOptions")
+            .collect::<Vec<_>>();
+        let explicit = options.into_iter().find_map(|options| {
+            let mut cursor = options.walk();
+            let res = options
+                .named_children(&mut cursor)
+                .filter(|n| n.kind() == "fieldOption")
+                .find_map(|n| {
+                    let (key, value) = self.get_text(n).split_once('=')?;
+                    (key.trim() == "json_name").then(|| value.trim().trim_matches(['"', '\'']))
+                });
+            res
+        });
+
+        let text = self.get_text(name);
+        Some(JsonName {
+            name: text,
+            name_range: name.range(),
+            json_name: match explicit {
+                Some(json_name) => json_name.to_string(),
+                None => to_json_name(text),
+            },
+        })
+    }
+
+    // Return the numbers used by each message that declares extension ranges.
+    pub fn message_numbers(&self) -> Vec<MessageNumbers> {
+        let query = &queries().message_names;
+        let mut qc = tree_sitter::QueryCursor::new();
+        let bodies = qc
+            .matches(query, self.tree.root_node(), self.text.as_bytes())
+            .filter_map(|m| m.captures[0].node.parent()?.parent())
+            .filter_map(|message| {
+                let mut cursor = message.walk();
+                let res = message
+                    .named_children(&mut cursor)
+                    .find(|n| n.kind() == "messageBody");
+                res
+            })
+            .collect::<Vec<_>>();
+
+        let mut res = vec![];
+        for body in bodies {
+            let mut numbers = MessageNumbers {
+                extensions: vec![],
+                fields: vec![],
+            };
+            let mut cursor = body.walk();
+            for node in body.named_children(&mut cursor) {
+                match node.kind() {
+                    "extensions" => numbers.extensions.extend(
+                        parse_extension_ranges(self.get_text(node))
+                            .into_iter()
+                            .map(|(start, end)| (start, end, node.range())),
+                    ),
+                    "field" | "mapField" => numbers
+                        .fields
+                        .extend(parse_enum_value(self.get_text(node)).map(|n| (n, node.range()))),
+                    "oneof" => {
+                        let mut cursor = node.walk();
+                        numbers.fields.extend(
+                            node.named_children(&mut cursor)
+                                .filter(|n| n.kind() == "oneofField")
+                                .filter_map(|n| {
+                                    parse_enum_value(self.get_text(n)).map(|num| (num, n.range()))
+                                }),
+                        );
+                    }
+                    _ => {}
+                }
+            }
+            if !numbers.extensions.is_empty() {
+                res.push(numbers);
+            }
+        }
+        res
+    }
+
+    // Return true if the definition sets the deprecated option, either with
+    // `option deprecated = true;` in its body or `[deprecated = true]` on a field.
+    fn is_deprecated(&self, node: tree_sitter::Node) -> bool {
+        let is_deprecated = |n: tree_sitter::Node| {
+            let text = self.get_text(n).split_whitespace().collect::<String>();
+            text.trim_start_matches("option").trim_end_matches(';') == "deprecated=true"
+        };
+
+        let mut cursor = node.walk();
+        let parents = node
+            .named_children(&mut cursor)
+            .filter(|n| n.kind().ends_with("Body") || n.kind() == "fieldOptions")
+            .collect::<Vec<_>>();
+        parents.into_iter().any(|parent| {
+            let mut cursor = parent.walk();
+            let res = parent
+                .named_children(&mut cursor)
+                .filter(|n| n.kind() == "option" || n.kind() == "fieldOption")
+                .any(is_deprecated);
+            res
+        })
+    }
+
+    // Return the range of the name of the definition spanning the given points.
+    pub fn definition_name(
+        &self,
+        start: tree_sitter::Point,
+        end: tree_sitter::Point,
+    ) -> Option<tree_sitter::Range> {
+        let node = self
+            .tree
+            .root_node()
+            .named_descendant_for_point_range(start, end)?;
+        let mut cursor = node.walk();
+        let res = node
+            .named_children(&mut cursor)
+            .find(|n| n.kind().ends_with("Name"))
+            .map(|n| n.range());
+        res
+    }
+
+    // Expand the given rows to span every top-level definition they overlap.
+    pub fn definition_rows(&self, start: usize, end: usize) -> Option<(usize, usize)> {
+        let root = self.tree.root_node();
+        let mut cursor = root.walk();
+        let res = root
+            .named_children(&mut cursor)
+            .filter(|n| n.start_position().row <= end && n.end_position().row >= start)
+            .map(|n| (n.start_position().row, n.end_position().row))
+            .reduce(|(s1, e1), (s2, e2)| (s1.min(s2), e1.max(e2)));
+        res
+    }
+
+    // Return the field declaration containing the given position.
+    pub fn field_at(&self, row: usize, col: usize) -> Option<Field> {
+        let pos = tree_sitter::Point { row, column: col };
+        let mut node = self.tree.root_node().descendant_for_point_range(pos, pos)?;
+        while node.kind() != "field" {
+            node = node.parent()?;
+        }
+
+        let mut cursor = node.walk();
+        let children = node.children(&mut cursor).collect::<Vec<_>>();
+        let label = children
+            .iter()
+            .find(|c| ["optional", "repeated", "required"].contains(&c.kind()));
+        let typ = children.iter().find(|c| c.kind() == "type")?;
+        Some(Field {
+            label: label.map(|n| self.get_text(*n)),
+            label_range: label.map(|n| n.range()),
+            typ: self.get_text(*typ),
+            type_range: typ.range(),
+        })
+    }
+
+    // Like parent_name, but nests oneof fields under their oneof.
+    // This is only for display, oneofs do not introduce a scope.
+    fn symbol_parent(&self, node: tree_sitter::Node) -> Option<String> {
+        let parent = self.parent_name(node);
+        let Some(oneof) = node.parent().filter(|p| p.kind() == "oneof") else {
+            return parent;
+        };
+        let mut cursor = oneof.walk();
+        let name = oneof
+            .named_children(&mut cursor)
+            .find(|c| c.kind() == "oneofName")
+            .map(|c| self.get_text(c));
+        match (parent, name) {
+            (Some(parent), Some(name)) => Some(parent + "." + name),
+            (parent, _) => parent,
+        }
+    }
+
     fn parent_name(&self, node: tree_sitter::Node) -> Option<String> {
         log::trace!("Finding parent name for {node:?}");
         let mut node = node;
         let mut res = Vec::<&str>::new();
         loop {
             if let Some(parent) = node.parent() {
-                if parent.kind() == "message" {
+                if parent.kind() == "message" || parent.kind() == "service" {
                     let name = self.type_name(parent);
                     log::trace!("Appending parent name {name:?}");
                     name.map(|n| res.push(n));
@@ -437,20 +1572,56 @@ impl File {
         }
     }
 
-    // Get the name of a Enum or Message node.
+    // Get the name of a Enum or Message node, qualified by any parent messages.
+    fn qualified_name(&self, node: tree_sitter::Node) -> Option<String> {
+        let name = self.type_name(node)?;
+        Some(match self.parent_name(node) {
+            Some(parent) => parent + "." + name,
+            None => name.to_string(),
+        })
+    }
+
+    // Get the name of a Enum, Message, Service, or Rpc node.
     fn type_name(&self, node: tree_sitter::Node) -> Option<&str> {
         debug_assert!(
-            node.kind() == "enum" || node.kind() == "message",
+            ["enum", "message", "service", "rpc"].contains(&node.kind()),
             "{node:?}"
         );
         let mut cursor = node.walk();
         let child = node
             .named_children(&mut cursor)
-            .find(|c| c.kind() == "messageName" || c.kind() == "enumName");
+            .find(|c| ["messageName", "enumName", "serviceName", "rpcName"].contains(&c.kind()));
         child.and_then(|c| c.utf8_text(self.text.as_bytes()).ok())
     }
 }
 
+// Check if the node is part of an option value (not a key) in a service or rpc.
+fn is_service_option_value(node: tree_sitter::Node) -> bool {
+    let ident = node
+        .parent()
+        .filter(|p| p.kind() == "fullIdent")
+        .unwrap_or(node);
+    // In an aggregate value like { key: Value }, the key is followed by a colon.
+    if ident.next_sibling().is_some_and(|n| n.kind() == ":") {
+        return false;
+    }
+
+    let mut in_constant = false;
+    let mut in_option = false;
+    let mut parent = node.parent();
+    while let Some(p) = parent {
+        match p.kind() {
+            "constant" => in_constant = true,
+            "option" => in_option = true,
+            "service" | "rpc" => return in_constant && in_option,
+            "message" | "enum" => return false,
+            _ => {}
+        }
+        parent = p.parent();
+    }
+    false
+}
+
 fn is_top_level_error(node: tree_sitter::Node) -> bool {
     if node.is_error() || node.is_missing() {
         match node.parent() {
@@ -464,6 +1635,45 @@ fn is_top_level_error(node: tree_sitter::Node) -> bool {
     }
 }
 
+// Return the possible package qualifiers to_pkg could use for a type imported from from_pkg
+pub fn possible_qualifiers<'a>(from_pkg: &'a str, to_pkg: &'a str) -> Vec<&'a str> {
+    log::trace!("possible_qualifiers({from_pkg}, {to_pkg})");
+    if to_pkg == "" {
+        return vec![from_pkg];
+    }
+
+    let mut res = vec![];
+    // Only whole package components are relative, e.g. foo.bar is bar from foo, but foobar is not.
+    if let Some(pkg) = from_pkg.strip_prefix(to_pkg) {
+        if pkg.is_empty() || pkg.starts_with('.') {
+            res.push(pkg.trim_start_matches('.'));
+        }
+    }
+
+    if let Some((to_pkg, _)) = to_pkg.rsplit_once(".") {
+        res.append(&mut possible_qualifiers(from_pkg, to_pkg));
+    } else {
+        res.push(from_pkg);
+    }
+    return res;
+}
+
+// Return every name a file in to_pkg could use to refer to a type declared in from_pkg.
+fn qualified_names(from_pkg: Option<&str>, to_pkg: Option<&str>, name: &str) -> Vec<String> {
+    let Some(from_pkg) = from_pkg else {
+        return vec![name.to_string(), format!(".{name}")];
+    };
+    let mut res: Vec<_> = possible_qualifiers(from_pkg, to_pkg.unwrap_or(""))
+        .into_iter()
+        .map(|qual| match qual {
+            "" => name.to_string(),
+            qual => format!("{qual}.{name}"),
+        })
+        .collect();
+    res.push(format!(".{from_pkg}.{name}"));
+    res
+}
+
 // Find the shortest form of a type name relative to a message
 // relative_name("Foo", "Foo.Bar.Baz") -> "Bar.Baz"
 // relative_name("Foo.Bar", "Foo.Bar.Baz") -> "Baz"
@@ -492,6 +1702,241 @@ fn relative_name<'a>(message: &str, name: &'a str) -> String {
     }
 }
 
+// Return true if the line ends with a complete rpc signature, e.g.
+// rpc Foo (Bar) returns (Baz)
+fn is_rpc_prefix(line: &str) -> bool {
+    let line = line.trim();
+    line.starts_with("rpc ")
+        && line
+            .split_once(" returns")
+            .is_some_and(|(_, ret)| ret.trim_start().starts_with('(') && ret.ends_with(')'))
+}
+
+// The default JSON name of a field, as protoc derives it, e.g.
+// foo_bar_baz -> fooBarBaz
+fn to_json_name(name: &str) -> String {
+    let mut res = String::new();
+    let mut upper = false;
+    for c in name.chars() {
+        if c == '_' {
+            upper = true;
+        } else if upper {
+            res.push(c.to_ascii_uppercase());
+            upper = false;
+        } else {
+            res.push(c);
+        }
+    }
+    res
+}
+
+// Return true if the line ends at the name of an extended message, e.g.
+// extend google.protobuf.Fi
+fn is_extend_prefix(line: &str) -> bool {
+    let Some(name) = line.trim_start().strip_prefix("extend ") else {
+        return false;
+    };
+    name.trim_start()
+        .chars()
+        .all(|c| c.is_alphanumeric() || c == '_' || c == '.')
+}
+
+// Return true if the line ends inside the parens of an rpc request or response, e.g.
+// rpc Foo (Ba
+// rpc Foo (Bar) returns (stream Ba
+fn is_rpc_type_prefix(line: &str) -> bool {
+    let line = line.trim_start();
+    let Some((sig, typ)) = line.rsplit_once('(') else {
+        return false;
+    };
+    let typ = typ.trim_start();
+    let typ = typ.strip_prefix("stream ").unwrap_or(typ).trim_start();
+    line.starts_with("rpc ")
+        && !sig.contains('{')
+        && typ
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '_' || c == '.')
+}
+
+// Return true if the type in an rpc line could still be preceded by stream, e.g.
+// rpc Foo (Bar) returns (st
+fn is_stream_prefix(line: &str) -> bool {
+    line.rsplit_once('(')
+        .is_some_and(|(_, typ)| "stream".starts_with(typ.trim_start()))
+}
+
+// Return true if the line declares an edition, e.g. edition = "2023";
+fn is_edition_line(line: &str) -> bool {
+    line.trim_start()
+        .strip_prefix("edition")
+        .is_some_and(|rest| rest.trim_start().starts_with('='))
+}
+
+// Return true if the line is an enum value up to its number, e.g.
+// RED = 1
+fn is_enum_value_prefix(line: &str) -> bool {
+    let Some((name, value)) = line.split_once('=') else {
+        return false;
+    };
+    let name = name.trim();
+    name.starts_with(|c: char| c.is_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_alphanumeric() || c == '_')
+        && value.trim().chars().all(|c| c.is_ascii_digit())
+}
+
+// Return true if the line is only the start of an enum value name, e.g.
+// RED
+fn is_enum_value_name_prefix(line: &str) -> bool {
+    let name = line.trim_start();
+    name.starts_with(|c: char| c.is_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_alphanumeric() || c == '_')
+}
+
+// Return true if the line ends where a new reserved name could go, e.g.
+// reserved "RED",
+// Names and numbers cannot be mixed, so a list must be empty or already hold a name.
+fn is_reserved_name_prefix(line: &str) -> bool {
+    let Some(names) = line.trim_start().strip_prefix("reserved") else {
+        return false;
+    };
+    let names = names.trim();
+    line.ends_with(char::is_whitespace)
+        && (names.is_empty()
+            || (names.starts_with('"')
+                && names.ends_with(',')
+                && names.matches('"').count() % 2 == 0))
+}
+
+// Return true if the line ends in the number of a field, e.g.
+// repeated int32 foo = 1
+fn is_field_number_prefix(line: &str) -> bool {
+    let Some((decl, value)) = line.split_once('=') else {
+        return false;
+    };
+    let words = decl.split_whitespace().collect::<Vec<_>>();
+    let Some(name) = words.last() else {
+        return false;
+    };
+    words.len() >= 2
+        && words[0] != "option"
+        && name.starts_with(|c: char| c.is_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_alphanumeric() || c == '_')
+        && value.trim().chars().all(|c| c.is_ascii_digit())
+}
+
+// Return true if the line is a field up to the name of one of its options, e.g.
+// int32 foo = 1 [deprecated = true, json|
+fn is_field_option_prefix(line: &str) -> bool {
+    let Some((field, options)) = line.split_once('[') else {
+        return false;
+    };
+    let name = options.rsplit(',').next().unwrap_or_default().trim_start();
+    is_field_number_prefix(field)
+        && !options.contains(']')
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || matches!(c, '_' | '.' | '(' | ')'))
+}
+
+// Parse the number of an enum value declaration, e.g.
+// RED = 0x1 [deprecated = true];
+// This also parses the number of a field, e.g.
+// int32 foo = 1 [default = 2];
+fn parse_enum_value(field: &str) -> Option<i64> {
+    let (_, value) = field.split_once('=')?;
+    parse_int(value.split(|c| c == '[' || c == ';').next()?)
+}
+
+// Parse a decimal, hex, or octal integer literal.
+fn parse_int(value: &str) -> Option<i64> {
+    let value = value.trim();
+    let (sign, value) = match value.strip_prefix('-') {
+        Some(value) => (-1, value.trim_start()),
+        None => (1, value),
+    };
+    let n = if let Some(hex) = value.strip_prefix("0x").or(value.strip_prefix("0X")) {
+        i64::from_str_radix(hex, 16)
+    } else if value.len() > 1 && value.starts_with('0') {
+        i64::from_str_radix(&value[1..], 8)
+    } else {
+        value.parse()
+    };
+    n.ok().map(|n| sign * n)
+}
+
+// The largest field number, which may be written as max in a range.
+const MAX_FIELD_NUMBER: i64 = 536_870_911;
+
+// The kinds of nodes that a documentation comment template can be offered for.
+const DOCUMENTED_KINDS: &[&str] = &[
+    "message",
+    "enum",
+    "service",
+    "rpc",
+    "field",
+    "mapField",
+    "oneof",
+    "enumField",
+];
+
+// Field numbers that protobuf reserves for its own implementation.
+const IMPLEMENTATION_RESERVED_NUMBERS: (i64, i64) = (19_000, 19_999);
+
+// Parse the ranges of an extensions statement, e.g.
+// extensions 100 to 199, 300, 1000 to max;
+fn parse_extension_ranges(text: &str) -> Vec<(i64, i64)> {
+    parse_ranges("extensions", text)
+}
+
+// Parse the number ranges of a reserved statement, e.g.
+// reserved 2, 4 to 6;
+// Reserved names, e.g. reserved "foo"; have no ranges.
+fn parse_reserved_ranges(text: &str) -> Vec<(i64, i64)> {
+    parse_ranges("reserved", text)
+}
+
+// Parse the comma-separated ranges of a statement starting with the given keyword.
+fn parse_ranges(keyword: &str, text: &str) -> Vec<(i64, i64)> {
+    let Some(ranges) = text.trim_start().strip_prefix(keyword) else {
+        return vec![];
+    };
+    let ranges = ranges.split(|c| c == '[' || c == ';').next().unwrap_or("");
+    ranges
+        .split(',')
+        .filter_map(|range| match range.split_once(" to ") {
+            Some((start, end)) if end.trim() == "max" => {
+                Some((parse_int(start)?, MAX_FIELD_NUMBER))
+            }
+            Some((start, end)) => Some((parse_int(start)?, parse_int(end)?)),
+            None => parse_int(range).map(|n| (n, n)),
+        })
+        .collect()
+}
+
+// Return true if the line ends in the key type of a map, e.g.
+// map<str
+fn is_map_key_prefix(line: &str) -> bool {
+    line.rsplit_once("map<").is_some_and(|(before, key)| {
+        !before.ends_with(|c: char| c.is_alphanumeric() || c == '_')
+            && key
+                .trim_start()
+                .chars()
+                .all(|c| c.is_alphanumeric() || c == '_')
+    })
+}
+
+// Return the nearest ancestor of the node with the given kind, including the node itself.
+fn find_ancestor<'a>(node: tree_sitter::Node<'a>, kind: &str) -> Option<tree_sitter::Node<'a>> {
+    let mut node = Some(node);
+    while let Some(n) = node {
+        if n.kind() == kind {
+            return Some(n);
+        }
+        node = n.parent();
+    }
+    None
+}
+
 fn is_sexp(node: tree_sitter::Node, sexp: &[&str]) -> bool {
     let Some((kind, rest)) = sexp.split_last() else {
         return true; // got to end, whole sexp matched
@@ -508,6 +1953,22 @@ fn is_sexp(node: tree_sitter::Node, sexp: &[&str]) -> bool {
     }
 }
 
+// Remove a leading UTF-8 byte order mark. Editors do not count it in positions, and
+// leaving it in would shift every offset on the first line.
+fn strip_bom(text: String) -> String {
+    match text.strip_prefix('\u{feff}') {
+        Some(rest) => rest.to_string(),
+        None => text,
+    }
+}
+
+// Return the byte offset at which each line of the text starts.
+fn line_starts(text: &str) -> Vec<usize> {
+    std::iter::once(0)
+        .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+        .collect()
+}
+
 fn char_to_byte(line: &str, char: u32) -> usize {
     line.chars()
         .take(char.try_into().unwrap())
@@ -572,6 +2033,7 @@ mod tests {
         let text = r#"syntax="proto3"; package main; package other"#;
         let file = File::new(text.to_string()).unwrap();
         assert_eq!(file.package(), Some("main".into()));
+        assert_eq!(file.package_statements().len(), 2);
 
         let text = r#"syntax="proto3"; package foo.bar.baz;"#;
         let file = File::new(text.to_string()).unwrap();
@@ -582,6 +2044,22 @@ mod tests {
         assert_eq!(file.package(), None);
     }
 
+    #[test]
+    fn test_syntax() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let file = File::new(r#"syntax="proto3"; package main;"#.to_string()).unwrap();
+        assert_eq!(file.syntax(), Syntax::Proto3);
+
+        let file = File::new(r#"syntax = 'proto2'; package main;"#.to_string()).unwrap();
+        assert_eq!(file.syntax(), Syntax::Proto2);
+
+        let file = File::new(r#"package main;"#.to_string()).unwrap();
+        assert_eq!(file.syntax(), Syntax::Proto2);
+
+        let file = File::new("edition = \"2023\";\npackage main;".to_string()).unwrap();
+        assert_eq!(file.syntax(), Syntax::Editions);
+    }
+
     #[test]
     fn test_imports() {
         let _ = env_logger::builder().is_test(true).try_init();
@@ -628,6 +2106,7 @@ mod tests {
                         start_point: Point { row: 3, column: 12 },
                         end_point: Point { row: 3, column: 25 },
                     },
+                    deprecated: false,
                 },
                 Symbol {
                     kind: SymbolKind::Enum,
@@ -638,6 +2117,7 @@ mod tests {
                         start_point: Point { row: 4, column: 12 },
                         end_point: Point { row: 4, column: 22 },
                     },
+                    deprecated: false,
                 },
                 Symbol {
                     kind: SymbolKind::Message,
@@ -648,6 +2128,7 @@ mod tests {
                         start_point: Point { row: 5, column: 12 },
                         end_point: Point { row: 9, column: 13 },
                     },
+                    deprecated: false,
                 },
                 Symbol {
                     kind: SymbolKind::Message,
@@ -658,6 +2139,7 @@ mod tests {
                         start_point: Point { row: 6, column: 16 },
                         end_point: Point { row: 8, column: 17 },
                     },
+                    deprecated: false,
                 },
                 Symbol {
                     kind: SymbolKind::Message,
@@ -668,9 +2150,158 @@ mod tests {
                         start_point: Point { row: 7, column: 20 },
                         end_point: Point { row: 7, column: 33 },
                     },
+                    deprecated: false,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_symbols_with_bom() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let text = "\u{feff}syntax = \"proto2\";\nmessage Foo {}\n";
+        let mut file = File::new(text.to_string()).unwrap();
+        assert_eq!(file.text(), "syntax = \"proto2\";\nmessage Foo {}\n");
+        assert_eq!(file.syntax(), Syntax::Proto2);
+
+        let mut qc = tree_sitter::QueryCursor::new();
+        assert_eq!(
+            file.symbols(&mut qc).collect::<Vec<_>>(),
+            vec![Symbol {
+                kind: SymbolKind::Message,
+                name: "Foo".into(),
+                range: tree_sitter::Range {
+                    start_byte: 19,
+                    end_byte: 33,
+                    start_point: Point { row: 1, column: 0 },
+                    end_point: Point { row: 1, column: 14 },
                 },
+                deprecated: false,
+            }]
+        );
+
+        // Positions on the first line do not count the BOM.
+        file.edit(vec![lsp_types::TextDocumentContentChangeEvent {
+            range: Some(lsp_types::Range {
+                start: lsp_types::Position::new(0, 10),
+                end: lsp_types::Position::new(0, 16),
+            }),
+            range_length: None,
+            text: "proto3".into(),
+        }])
+        .unwrap();
+        assert_eq!(file.text(), "syntax = \"proto3\";\nmessage Foo {}\n");
+        assert_eq!(file.syntax(), Syntax::Proto3);
+    }
+
+    #[test]
+    fn test_symbols_after_error() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let text = r#"
+            syntax = "proto3";
+            package main;
+            import "foo.proto"
+            option = ;
+            message Foo {
+                int32 x = 1;
+            }
+            enum Bar {
+                BAR_UNSPECIFIED = 0;
+            }
+            service Baz {}
+        "#;
+        let file = File::new(text.to_string()).unwrap();
+        assert!(file.tree.root_node().has_error());
+
+        let mut qc = tree_sitter::QueryCursor::new();
+        assert_eq!(
+            file.symbols(&mut qc)
+                .map(|s| (s.kind, s.name))
+                .collect::<Vec<_>>(),
+            vec![
+                (SymbolKind::Message, String::from("Foo")),
+                (SymbolKind::Enum, String::from("Bar")),
+                (SymbolKind::Service, String::from("Baz")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_oneof_and_map_symbols() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let text = r#"syntax="proto3";
+message Foo {
+  oneof choice {
+    int32 a = 1;
+    string b = 2;
+  }
+  map<string, int32> m = 3;
+  int32 i = 4;
+}
+"#;
+        let file = File::new(text.to_string()).unwrap();
+        let mut qc = tree_sitter::QueryCursor::new();
+        assert_eq!(
+            file.symbols(&mut qc)
+                .map(|s| (s.kind, s.name))
+                .collect::<Vec<_>>(),
+            vec![
+                (SymbolKind::Message, "Foo".into()),
+                (SymbolKind::Oneof, "Foo.choice".into()),
+                (SymbolKind::Field, "Foo.choice.a".into()),
+                (SymbolKind::Field, "Foo.choice.b".into()),
+                (SymbolKind::Field, "Foo.m".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_extend_symbols() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let text = r#"syntax="proto3";
+import "google/protobuf/descriptor.proto";
+message Foo {}
+extend google.protobuf.FieldOptions {
+  string foo = 50000;
+  int32 bar = 50001 [deprecated = true];
+}
+enum Bar {}
+"#;
+        let file = File::new(text.to_string()).unwrap();
+        let mut qc = tree_sitter::QueryCursor::new();
+        // Extend blocks are not types, so they are not among the symbols.
+        assert_eq!(
+            file.symbols(&mut qc)
+                .map(|s| (s.kind, s.name))
+                .collect::<Vec<_>>(),
+            vec![
+                (SymbolKind::Message, String::from("Foo")),
+                (SymbolKind::Enum, String::from("Bar")),
             ]
         );
+
+        let names = |syms: Vec<Symbol>| {
+            syms.into_iter()
+                .map(|s| (s.kind, s.name, s.deprecated))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            file.extend_symbols()
+                .into_iter()
+                .map(|(block, fields)| (names(vec![block]), names(fields)))
+                .collect::<Vec<_>>(),
+            vec![(
+                vec![(
+                    SymbolKind::Extend,
+                    String::from("google.protobuf.FieldOptions"),
+                    false
+                )],
+                vec![
+                    (SymbolKind::Field, String::from("foo"), false),
+                    (SymbolKind::Field, String::from("bar"), true),
+                ]
+            )]
+        );
     }
 
     #[test]
@@ -688,7 +2319,9 @@ mod tests {
         let file = File::new(text.to_string()).unwrap();
         let mut qc = tree_sitter::QueryCursor::new();
         assert_eq!(
-            file.relative_symbols("Foo", &mut qc).collect::<Vec<_>>(),
+            file.relative_symbols("Foo", &mut qc)
+                .map(|(s, name)| Symbol { name, ..s })
+                .collect::<Vec<_>>(),
             vec![
                 Symbol {
                     kind: SymbolKind::Message,
@@ -699,6 +2332,7 @@ mod tests {
                         start_point: Point { row: 3, column: 12 },
                         end_point: Point { row: 3, column: 25 },
                     },
+                    deprecated: false,
                 },
                 Symbol {
                     kind: SymbolKind::Enum,
@@ -709,6 +2343,7 @@ mod tests {
                         start_point: Point { row: 4, column: 12 },
                         end_point: Point { row: 4, column: 22 },
                     },
+                    deprecated: false,
                 },
                 Symbol {
                     kind: SymbolKind::Message,
@@ -719,6 +2354,7 @@ mod tests {
                         start_point: Point { row: 5, column: 12 },
                         end_point: Point { row: 7, column: 13 },
                     },
+                    deprecated: false,
                 },
                 Symbol {
                     kind: SymbolKind::Message,
@@ -729,6 +2365,7 @@ mod tests {
                         start_point: Point { row: 6, column: 16 },
                         end_point: Point { row: 6, column: 29 },
                     },
+                    deprecated: false,
                 }
             ]
         );
@@ -776,25 +2413,250 @@ mod tests {
                 Some(CompletionContext::Keyword),
                 None,
                 Some(CompletionContext::Import),
-                Some(CompletionContext::Message("Foo")),
-                Some(CompletionContext::Message("Buz")),
-                Some(CompletionContext::Message("Bar")),
+                Some(CompletionContext::Message("Foo".into())),
+                Some(CompletionContext::Message("Bar.Buz".into())),
+                Some(CompletionContext::Message("Bar".into())),
                 Some(CompletionContext::Keyword),
-                None,
-                Some(CompletionContext::Enum("Enum")),
+                Some(CompletionContext::EnumValueNumber(0)),
+                Some(CompletionContext::EnumValueName {
+                    name: "ENUM_TWO_".into(),
+                    number: 0,
+                }),
                 None,
             ]
         );
     }
 
     #[test]
-    fn test_completion_context_syntax() {
+    fn test_completion_context_enum_value_number() {
         let _ = env_logger::builder().is_test(true).try_init();
-        let (file, pos) = cursor("|");
-
+        let (file, pos) = cursor(
+            r#"
+            syntax = "proto3";
+            enum Color {
+                option allow_alias = true;
+                RED = 0;
+                CRIMSON = 0;
+                GREEN = 0x2 [deprecated = true];
+                BLUE = 1;
+                PURPLE = |
+            }
+            "#,
+        );
         assert_eq!(
             file.completion_context(pos.row, pos.column).unwrap(),
-            Some(CompletionContext::Syntax),
+            Some(CompletionContext::EnumValueNumber(3)),
+        );
+    }
+
+    #[test]
+    fn test_completion_context_reserved_name() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let (file, points) = cursors(
+            r#"
+            syntax = "proto3";
+            enum Color {
+                RED = 0;
+                reserved |
+            }
+            enum Shade {
+                DARK = 0;
+                reserved "LIGHT", |
+            }
+            enum Size {
+                SMALL = 0;
+                reserved 1, |
+            }
+            message Foo {
+                reserved |
+            }
+            "#,
+        );
+        assert_eq!(
+            points
+                .iter()
+                .map(|p| file.completion_context(p.row, p.column).unwrap())
+                .map(|c| c == Some(CompletionContext::ReservedName))
+                .collect::<Vec<_>>(),
+            vec![true, true, false, false]
+        );
+
+        let (file, pos) = cursor(
+            r#"
+            edition = "2023";
+            enum Color {
+                RED = 0;
+                reserved |
+            }
+            "#,
+        );
+        assert_ne!(
+            file.completion_context(pos.row, pos.column).unwrap(),
+            Some(CompletionContext::ReservedName)
+        );
+
+        assert!(is_reserved_name_prefix("  reserved "));
+        assert!(is_reserved_name_prefix("reserved \"A\", "));
+        assert!(!is_reserved_name_prefix("reserved"));
+        assert!(!is_reserved_name_prefix("reserved \"A"));
+        assert!(!is_reserved_name_prefix("reserved \"A, "));
+        assert!(!is_reserved_name_prefix("reserved_name "));
+        assert!(!is_reserved_name_prefix("reserved 1, "));
+        assert!(!is_reserved_name_prefix("reserved 1 to 2, "));
+    }
+
+    #[test]
+    fn test_completion_context_field_number() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let (file, pos) = cursor(
+            r#"
+            syntax = "proto3";
+            message Foo {
+                reserved 2, 4 to 6;
+                reserved "bar";
+                extensions 8;
+                int32 a = 1;
+                oneof o {
+                    int32 b = 3;
+                }
+                map<string, int32> c = 7;
+                repeated string d = |
+            }
+            "#,
+        );
+        assert_eq!(
+            file.completion_context(pos.row, pos.column).unwrap(),
+            Some(CompletionContext::FieldNumber(9)),
+        );
+    }
+
+    #[test]
+    fn test_completion_context_field_option() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let (file, points) = cursors(
+            r#"
+            syntax = "proto3";
+            message Foo {
+                int32 a = 1 [d|eprecated = true];
+                int32 b = 2 [deprecated = true, j|son_name = "b"];
+            }
+            "#,
+        );
+        assert_eq!(
+            points
+                .iter()
+                .map(|p| file.completion_context(p.row, p.column).unwrap())
+                .collect::<Vec<_>>(),
+            vec![
+                Some(CompletionContext::FieldOption),
+                Some(CompletionContext::FieldOption),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_is_field_option_prefix() {
+        assert!(is_field_option_prefix("int32 foo = 1 ["));
+        assert!(is_field_option_prefix(
+            "  int32 foo = 1 [json_name = \"f\", feat"
+        ));
+        assert!(is_field_option_prefix("Bar bar = 2 [(my.opt"));
+        assert!(!is_field_option_prefix("int32 foo = 1 [deprecated = "));
+        assert!(!is_field_option_prefix(
+            "int32 foo = 1 [deprecated = true] "
+        ));
+        assert!(!is_field_option_prefix("option (foo) = ["));
+        assert!(!is_field_option_prefix("int32 foo = "));
+    }
+
+    #[test]
+    fn test_next_field_number_skips_implementation_range() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let (file, pos) = cursor(
+            r#"
+            syntax = "proto3";
+            message Foo {
+                reserved 1 to 18999;
+                int32 a = 20000;
+                int32 b = |
+            }
+            "#,
+        );
+        assert_eq!(
+            file.completion_context(pos.row, pos.column).unwrap(),
+            Some(CompletionContext::FieldNumber(20001)),
+        );
+    }
+
+    #[test]
+    fn test_json_name_at() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let (file, points) = cursors(
+            r#"
+            syntax = "proto3";
+            message Foo {
+                string foo_|bar_baz = 1;
+                int32 i|d = 2 [json_name = "ID"];
+                oneof thing {
+                    string other_|thing = 3 [deprecated = true];
+                }
+                st|ring s = 4;
+            }
+            "#,
+        );
+
+        assert_eq!(
+            points
+                .iter()
+                .map(|p| file.json_name_at(p.row, p.column))
+                .map(|j| j.map(|j| (j.name, j.json_name)))
+                .collect::<Vec<_>>(),
+            vec![
+                Some(("foo_bar_baz", String::from("fooBarBaz"))),
+                Some(("id", String::from("ID"))),
+                Some(("other_thing", String::from("otherThing"))),
+                None,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_enum_value() {
+        assert_eq!(parse_enum_value("RED = 0;"), Some(0));
+        assert_eq!(parse_enum_value("RED=12;"), Some(12));
+        assert_eq!(parse_enum_value("RED = -1;"), Some(-1));
+        assert_eq!(parse_enum_value("RED = 0x1F;"), Some(31));
+        assert_eq!(parse_enum_value("RED = 010;"), Some(8));
+        assert_eq!(parse_enum_value("RED = 4 [deprecated = true];"), Some(4));
+        assert_eq!(parse_enum_value("RED = ;"), None);
+    }
+
+    #[test]
+    fn test_parse_extension_ranges() {
+        assert_eq!(
+            parse_extension_ranges("extensions 100 to 199;"),
+            vec![(100, 199)]
+        );
+        assert_eq!(
+            parse_extension_ranges("extensions 4, 20 to max [declaration = {}];"),
+            vec![(4, 4), (20, MAX_FIELD_NUMBER)]
+        );
+        assert_eq!(parse_extension_ranges("reserved 1 to 2;"), vec![]);
+        assert_eq!(
+            parse_reserved_ranges("reserved 2, 4 to 6;"),
+            vec![(2, 2), (4, 6)]
+        );
+        assert_eq!(parse_reserved_ranges("reserved \"foo\", \"bar\";"), vec![]);
+    }
+
+    #[test]
+    fn test_completion_context_syntax() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let (file, pos) = cursor("|");
+
+        assert_eq!(
+            file.completion_context(pos.row, pos.column).unwrap(),
+            Some(CompletionContext::Syntax),
         );
     }
 
@@ -875,87 +2737,500 @@ mod tests {
     }
 
     #[test]
-    fn test_completion_context_message() {
+    fn test_completion_context_comment() {
+        let _ = env_logger::builder().is_test(true).try_init();
+
+        fn test(lines: &[&str], expected: Option<CompletionContext>) {
+            let text = lines.join("\n");
+            let (file, point) = cursor(text.as_str());
+            assert_eq!(
+                file.completion_context(point.row, point.column).unwrap(),
+                expected,
+                "text:\n{}",
+                text
+            );
+        }
+
+        test(&[r#"syntax = "proto3";"#, "// mes|", ""], None);
+        test(&[r#"syntax = "proto3";"#, "// package |", ""], None);
+        test(&[r#"syntax = "proto3";"#, "/* mes|sage */", ""], None);
+        test(
+            &[r#"syntax = "proto3";"#, "/*", "  option |", "*/", ""],
+            None,
+        );
+        test(
+            &[r#"syntax = "proto3";"#, "message Foo {", "  // int|", "}"],
+            None,
+        );
+
+        // Before or after the comment.
+        for text in [
+            "/* comment */|",
+            "|/* comment */",
+            "|// comment",
+            "mes| // comment",
+        ] {
+            let (file, point) = cursor(text);
+            assert!(!file.in_comment(point.row, point.column), "{text}");
+        }
+    }
+
+    #[test]
+    fn test_completion_context_message() {
+        let _ = env_logger::builder().is_test(true).try_init();
+
+        fn test(lines: &[&str], expected: Option<CompletionContext>) {
+            let text = format!("syntax = \"proto3\";\n{}\n", lines.join("\n"));
+            let (file, point) = cursor(text.as_str());
+            assert_eq!(
+                file.completion_context(point.row, point.column).unwrap(),
+                expected,
+                "text:\n{}",
+                text
+            );
+        }
+
+        test(&["message Foo{ | }", ""], None);
+        test(
+            &["message Foo{ B| }", ""],
+            Some(CompletionContext::Message("Foo".into())),
+        );
+        test(
+            &["message Foo{ B|ar }", ""],
+            Some(CompletionContext::Message("Foo".into())),
+        );
+        test(
+            &["message Foo{ s|tring }", ""],
+            Some(CompletionContext::Message("Foo".into())),
+        );
+        test(&["message Foo{ Bar | }"], None);
+        test(&["message Foo{ Bar b| }"], None);
+        test(&["message Foo{ Bar b|ar }"], None);
+        test(&["message Foo{ Bar bar |}"], None);
+        test(&["message Foo{ Bar bar | }"], None);
+        test(&["message Foo{ Bar bar |= }"], None);
+        test(&["message Foo{ Bar bar =| }"], None);
+        test(&["message Foo{ Bar bar = | }"], None);
+        test(&["message Foo{ Bar bar = |1 }"], None);
+        test(&["message Foo{ Bar bar = 1| }"], None);
+        test(&["message Foo{ Bar bar = 1|; }"], None);
+        test(&["message Foo{ Bar bar = 1;| }"], None);
+        test(&["message Foo{ oneof th| }"], None);
+        test(
+            &["message Foo{ oneof thing { B| } }", ""],
+            Some(CompletionContext::Message("Foo".into())),
+        );
+        test(
+            &["message Foo{ oneof thing { string s = 1; B| } }", ""],
+            Some(CompletionContext::Message("Foo".into())),
+        );
+        test(
+            &[
+                "message Foo{ message Bar { oneof thing { s|tring s = 1; } } }",
+                "",
+            ],
+            Some(CompletionContext::Message("Foo.Bar".into())),
+        );
+    }
+
+    #[test]
+    fn test_completion_context_package() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let (file, pos) = cursor("syntax = \"proto3\";\npackage |");
+        assert_eq!(
+            file.completion_context(pos.row, pos.column).unwrap(),
+            Some(CompletionContext::Package),
+        );
+
+        let (file, pos) = cursor("syntax = \"proto3\";\npackage fo|");
+        assert_eq!(
+            file.completion_context(pos.row, pos.column).unwrap(),
+            Some(CompletionContext::Package),
+        );
+    }
+
+    #[test]
+    fn test_completion_context_rpc() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let (file, pos) = cursor(
+            r#"
+            syntax = "proto3";
+            service Foo {
+                rpc Bar (Req) returns (stream Resp) |
+            }
+            "#,
+        );
+        assert_eq!(
+            file.completion_context(pos.row, pos.column).unwrap(),
+            Some(CompletionContext::Rpc),
+        );
+
+        let (file, pos) = cursor(
+            r#"
+            syntax = "proto3";
+            service Foo {
+                rpc Bar (Req) returns (Re|
+            }
+            "#,
+        );
+        assert_ne!(
+            file.completion_context(pos.row, pos.column).unwrap(),
+            Some(CompletionContext::Rpc),
+        );
+
+        for (line, stream) in [
+            ("rpc Bar (|", true),
+            ("rpc Bar (Re|", false),
+            ("rpc Bar (stream Re|", false),
+            ("rpc Bar (Req) returns (|", true),
+            ("rpc Bar (Req) returns (str|", true),
+            ("rpc Bar (Req) returns (stream foo.Re|", false),
+        ] {
+            let text = format!("syntax = \"proto3\";\nservice Foo {{\n{line}\n}}\n");
+            let (file, pos) = cursor(&text);
+            assert_eq!(
+                file.completion_context(pos.row, pos.column).unwrap(),
+                Some(CompletionContext::RpcType { stream }),
+                "{line}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_completion_context_extend() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        for line in ["extend |", "extend google.protobuf.Fi|", "  extend  Foo|"] {
+            let text = format!("syntax = \"proto3\";\n{line}\n");
+            let (file, pos) = cursor(&text);
+            assert_eq!(
+                file.completion_context(pos.row, pos.column).unwrap(),
+                Some(CompletionContext::Extend),
+                "{line}"
+            );
+        }
+
+        let (file, pos) = cursor("syntax = \"proto3\";\nextend Foo { |\n");
+        assert_ne!(
+            file.completion_context(pos.row, pos.column).unwrap(),
+            Some(CompletionContext::Extend),
+        );
+    }
+
+    #[test]
+    fn test_completion_context_map() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let (file, pos) = cursor(
+            r#"
+            syntax = "proto3";
+            message Foo {
+                map<|
+            }
+            "#,
+        );
+        assert_eq!(
+            file.completion_context(pos.row, pos.column).unwrap(),
+            Some(CompletionContext::MapKey),
+        );
+
+        let (file, pos) = cursor(
+            r#"
+            syntax = "proto3";
+            message Foo {
+                map<st|, Bar> m = 1;
+            }
+            "#,
+        );
+        assert_eq!(
+            file.completion_context(pos.row, pos.column).unwrap(),
+            Some(CompletionContext::MapKey),
+        );
+
+        let (file, pos) = cursor(
+            r#"
+            syntax = "proto3";
+            message Foo {
+                map<string, B|> m = 1;
+            }
+            "#,
+        );
+        assert_eq!(
+            file.completion_context(pos.row, pos.column).unwrap(),
+            Some(CompletionContext::Message("Foo".into())),
+        );
+    }
+
+    #[test]
+    fn test_completion_context_nested_message() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let (file, pos) = cursor(
+            r#"
+            syntax = "proto3";
+            message Outer {
+                message Middle {
+                    message Inner {
+                        B|
+                    }
+                }
+            }
+            "#,
+        );
+        assert_eq!(
+            file.completion_context(pos.row, pos.column).unwrap(),
+            Some(CompletionContext::Message("Outer.Middle.Inner".into())),
+        );
+    }
+
+    #[test]
+    fn test_completion_context_option() {
+        let _ = env_logger::builder().is_test(true).try_init();
+
+        fn test(lines: &[&str]) {
+            let text = format!("syntax = \"proto3\";\n{}\n", lines.join("\n"));
+            let (file, point) = cursor(text.as_str());
+            assert_eq!(
+                file.completion_context(point.row, point.column).unwrap(),
+                Some(CompletionContext::Option),
+                "text:\n{}",
+                text
+            );
+        }
+
+        test(&["option |"]);
+        test(&["option java|"]);
+        test(&["option |java"]);
+        test(&["import \"blah.proto\";", "option |java"]);
+        test(&["option |java", "import \"blah.proto\";"]);
+        test(&["message Foo{}", "option |java"]);
+        test(&["option |java", "message Foo{}"]);
+    }
+
+    #[test]
+    fn test_type_at() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let (file, points) = cursors(
+            r#"
+            synt|ax = "proto3";
+
+            import "other|.proto";
+
+            message F|oo {
+                st|ring s = 1;
+                int|32 i = 2;
+                B|ar |b = 3;
+                Baz.|Buz b = |4;
+                foo.bar|.|Buz.Boz g = 5|;
+            }
+            "#,
+        );
+
+        assert_eq!(
+            points
+                .iter()
+                .map(|p| file.type_at(p.row, p.column))
+                .collect::<Vec<Option<GotoContext>>>(),
+            vec![
+                None,
+                Some(GotoContext::Import("other.proto")),
+                Some(GotoContext::Type(GotoTypeContext {
+                    name: "Foo",
+                    parent: None,
+                    message_only: false,
+                })),
+                None,
+                None,
+                Some(GotoContext::Type(GotoTypeContext {
+                    name: "Bar",
+                    parent: Some("Foo".into()),
+                    message_only: false,
+                })),
+                None,
+                Some(GotoContext::Type(GotoTypeContext {
+                    name: "Baz.Buz",
+                    parent: Some("Foo".into()),
+                    message_only: false,
+                })),
+                None,
+                Some(GotoContext::Type(GotoTypeContext {
+                    name: "foo.bar.Buz.Boz",
+                    parent: Some("Foo".into()),
+                    message_only: false,
+                })),
+                Some(GotoContext::Type(GotoTypeContext {
+                    name: "foo.bar.Buz.Boz",
+                    parent: Some("Foo".into()),
+                    message_only: false,
+                })),
+                None,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_field_at() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let (file, points) = cursors(
+            r#"
+            syntax = "proto3";
+            message Foo {
+                uint32 |i = 1;
+                optional st|ring s = 2;
+                repeated Foo f = |3;
+            }|
+            "#,
+        );
+
+        assert_eq!(
+            points
+                .iter()
+                .map(|p| file.field_at(p.row, p.column).map(|f| (f.label, f.typ)))
+                .collect::<Vec<_>>(),
+            vec![
+                Some((None, "uint32")),
+                Some((Some("optional"), "string")),
+                Some((Some("repeated"), "Foo")),
+                None,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_type_at_rpc_types() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let (file, points) = cursors(
+            r#"
+            syntax = "proto3";
+            service Greeter {
+                rpc SayHello (Hello|Request) returns (stream foo.Hello|Reply) {}
+            }
+            "#,
+        );
+
+        assert_eq!(
+            points
+                .iter()
+                .map(|p| file.type_at(p.row, p.column))
+                .collect::<Vec<Option<GotoContext>>>(),
+            vec![
+                Some(GotoContext::Type(GotoTypeContext {
+                    name: "HelloRequest",
+                    parent: None,
+                    message_only: true,
+                })),
+                Some(GotoContext::Type(GotoTypeContext {
+                    name: "foo.HelloReply",
+                    parent: None,
+                    message_only: true,
+                })),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_type_at_service_option() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let (file, points) = cursors(
+            r#"
+            syntax = "proto3";
+            service Greeter {
+                option (default_reply) = Hello|Reply;
+                rpc SayHello (HelloRequest) returns (HelloReply) {
+                    option (fallback) = foo.Fall|back;
+                }
+            }
+            message Foo {
+                option (def|ault) = Foo|Reply;
+            }
+            "#,
+        );
+
+        assert_eq!(
+            points
+                .iter()
+                .map(|p| file.type_at(p.row, p.column))
+                .collect::<Vec<Option<GotoContext>>>(),
+            vec![
+                Some(GotoContext::Type(GotoTypeContext {
+                    name: "HelloReply",
+                    parent: None,
+                    message_only: false,
+                })),
+                Some(GotoContext::Type(GotoTypeContext {
+                    name: "foo.Fallback",
+                    parent: None,
+                    message_only: false,
+                })),
+                None,
+                None,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_is_deprecated() {
         let _ = env_logger::builder().is_test(true).try_init();
-
-        fn test(lines: &[&str], expected: Option<CompletionContext>) {
-            let text = format!("syntax = \"proto3\";\n{}\n", lines.join("\n"));
-            let (file, point) = cursor(text.as_str());
-            assert_eq!(
-                file.completion_context(point.row, point.column).unwrap(),
-                expected,
-                "text:\n{}",
-                text
-            );
-        }
-
-        test(&["message Foo{ | }", ""], None);
-        test(
-            &["message Foo{ B| }", ""],
-            Some(CompletionContext::Message("Foo")),
-        );
-        test(
-            &["message Foo{ B|ar }", ""],
-            Some(CompletionContext::Message("Foo")),
-        );
-        test(
-            &["message Foo{ s|tring }", ""],
-            Some(CompletionContext::Message("Foo")),
+        let text = r#"syntax="proto3";
+message Old {
+  option deprecated = true;
+  map<string, int32> m = 1 [deprecated = true];
+}
+message New {
+  option deprecated = false;
+  map<string, int32> m = 1;
+}
+enum Color {
+  option deprecated=true;
+  RED = 0;
+}
+"#;
+        let file = File::new(text.to_string()).unwrap();
+        let mut qc = tree_sitter::QueryCursor::new();
+        assert_eq!(
+            file.symbols(&mut qc)
+                .map(|s| (s.deprecated, s.name))
+                .collect::<Vec<_>>(),
+            vec![
+                (true, "Old".into()),
+                (true, "Old.m".into()),
+                (false, "New".into()),
+                (false, "New.m".into()),
+                (true, "Color".into()),
+            ]
         );
-        test(&["message Foo{ Bar | }"], None);
-        test(&["message Foo{ Bar b| }"], None);
-        test(&["message Foo{ Bar b|ar }"], None);
-        test(&["message Foo{ Bar bar |}"], None);
-        test(&["message Foo{ Bar bar | }"], None);
-        test(&["message Foo{ Bar bar |= }"], None);
-        test(&["message Foo{ Bar bar =| }"], None);
-        test(&["message Foo{ Bar bar = | }"], None);
-        test(&["message Foo{ Bar bar = |1 }"], None);
-        test(&["message Foo{ Bar bar = 1| }"], None);
-        test(&["message Foo{ Bar bar = 1|; }"], None);
-        test(&["message Foo{ Bar bar = 1;| }"], None);
-        test(&["message Foo{ oneof th| }"], None);
     }
 
     #[test]
-    fn test_completion_context_option() {
+    fn test_definition_rows() {
         let _ = env_logger::builder().is_test(true).try_init();
+        let file = File::new(
+            [
+                "syntax = \"proto3\";", // 0
+                "message Foo {",        // 1
+                "  int32 i = 1;",       // 2
+                "}",                    // 3
+                "",                     // 4
+                "message Bar {",        // 5
+                "  int32 i = 1;",       // 6
+                "}",                    // 7
+            ]
+            .join("\n"),
+        )
+        .unwrap();
 
-        fn test(lines: &[&str]) {
-            let text = format!("syntax = \"proto3\";\n{}\n", lines.join("\n"));
-            let (file, point) = cursor(text.as_str());
-            assert_eq!(
-                file.completion_context(point.row, point.column).unwrap(),
-                Some(CompletionContext::Option),
-                "text:\n{}",
-                text
-            );
-        }
-
-        test(&["option |"]);
-        test(&["option java|"]);
-        test(&["option |java"]);
-        test(&["import \"blah.proto\";", "option |java"]);
-        test(&["option |java", "import \"blah.proto\";"]);
-        test(&["message Foo{}", "option |java"]);
-        test(&["option |java", "message Foo{}"]);
+        assert_eq!(file.definition_rows(2, 2), Some((1, 3)));
+        assert_eq!(file.definition_rows(3, 6), Some((1, 7)));
+        assert_eq!(file.definition_rows(4, 4), None);
     }
 
     #[test]
-    fn test_type_at() {
+    fn test_type_at_map_value() {
         let _ = env_logger::builder().is_test(true).try_init();
         let (file, points) = cursors(
             r#"
-            synt|ax = "proto3";
-
-            import "other|.proto";
-
-            message F|oo {
-                st|ring s = 1;
-                int|32 i = 2;
-                B|ar |b = 3;
-                Baz.|Buz b = |4;
-                foo.bar|.|Buz.Boz g = 5|;
+            syntax = "proto3";
+            message Foo {
+                map<string, Col|or> m = 1;
+                map<string, paint.Co|lor> n = 2;
+                map<str|ing, Color> o = 3;
             }
             "#,
         );
@@ -966,32 +3241,128 @@ mod tests {
                 .map(|p| file.type_at(p.row, p.column))
                 .collect::<Vec<Option<GotoContext>>>(),
             vec![
-                None,
-                Some(GotoContext::Import("other.proto")),
-                Some(GotoContext::Type(GotoTypeContext {
-                    name: "Foo",
-                    parent: None
-                })),
-                None,
-                None,
                 Some(GotoContext::Type(GotoTypeContext {
-                    name: "Bar",
+                    name: "Color",
                     parent: Some("Foo".into()),
+                    message_only: false,
                 })),
-                None,
                 Some(GotoContext::Type(GotoTypeContext {
-                    name: "Baz.Buz",
+                    name: "paint.Color",
                     parent: Some("Foo".into()),
+                    message_only: false,
                 })),
                 None,
-                Some(GotoContext::Type(GotoTypeContext {
-                    name: "foo.bar.Buz.Boz",
-                    parent: Some("Foo".into()),
-                })),
-                Some(GotoContext::Type(GotoTypeContext {
-                    name: "foo.bar.Buz.Boz",
-                    parent: Some("Foo".into()),
-                })),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_type_at_reserved() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let (file, points) = cursors(
+            r#"
+            syntax = "proto3";
+            message Foo {
+                reserved 2, 1|5 to 20;
+                reserved "f|oo", "b|ar";
+                int32 foo = 1;
+                message Bar {
+                    reserved "b|az";
+                }
+                enum Baz {
+                    BAZ_UNSPECIFIED = 0;
+                    reserved "B|IZ";
+                }
+            }
+            "#,
+        );
+
+        assert_eq!(
+            points
+                .iter()
+                .map(|p| file.type_at(p.row, p.column))
+                .collect::<Vec<Option<GotoContext>>>(),
+            vec![
+                None,
+                Some(GotoContext::Reserved {
+                    message: "Foo".into(),
+                    name: "foo"
+                }),
+                Some(GotoContext::Reserved {
+                    message: "Foo".into(),
+                    name: "bar"
+                }),
+                Some(GotoContext::Reserved {
+                    message: "Foo.Bar".into(),
+                    name: "baz"
+                }),
+                None,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_type_at_option_field() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let (file, points) = cursors(
+            r#"
+            syntax = "proto3";
+            message Foo {
+                option (rule) = { m|ax: 1 };
+                option (foo.rule) = { le|n: 2 };
+                option deprecated = tr|ue;
+            }
+            "#,
+        );
+
+        assert_eq!(
+            points
+                .iter()
+                .map(|p| file.type_at(p.row, p.column))
+                .collect::<Vec<Option<GotoContext>>>(),
+            vec![
+                Some(GotoContext::OptionField {
+                    option: "rule",
+                    field: "max",
+                }),
+                Some(GotoContext::OptionField {
+                    option: "foo.rule",
+                    field: "len",
+                }),
+                None,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_type_at_default_enum_value() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let (file, points) = cursors(
+            r#"
+            syntax = "proto2";
+            message Foo {
+                optional Color c = 1 [default = R|ED];
+                optional int32 i = 2 [default = |5];
+                optional string s = 3 [json_name = "f|oo"];
+            }
+            "#,
+        );
+
+        assert_eq!(
+            points
+                .iter()
+                .map(|p| file.type_at(p.row, p.column))
+                .collect::<Vec<Option<GotoContext>>>(),
+            vec![
+                Some(GotoContext::EnumValue {
+                    typ: GotoTypeContext {
+                        name: "Color",
+                        parent: Some("Foo".into()),
+                        message_only: false,
+                    },
+                    value: "RED",
+                }),
+                None,
                 None,
             ]
         );
@@ -1040,6 +3411,105 @@ mod tests {
         assert_eq!(file.import_references("baz.proto"), vec![]);
     }
 
+    #[test]
+    fn test_public_imports() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let file = File::new(
+            [
+                "syntax = \"proto3\";",
+                "import \"foo.proto\";",
+                "import public \"bar.proto\";",
+                "import weak \"baz.proto\";",
+                "// import public \"biz.proto\";",
+                "import public \"buz.proto\";",
+                "",
+            ]
+            .join("\n"),
+        )
+        .unwrap();
+        assert_eq!(file.public_imports(), vec!["bar.proto", "buz.proto"]);
+    }
+
+    #[test]
+    fn test_sorted_imports() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let file = File::new(
+            [
+                "syntax = \"proto3\";",
+                "// The package.",
+                "package thing;",
+                "",
+                "import public \"pub.proto\";",
+                "import \"foo.proto\"; // foo",
+                "// about bar",
+                "// and more",
+                "import \"bar.proto\";",
+                "",
+                "// about nothing",
+                "",
+                "import weak \"a.proto\";",
+                "import \"baz.proto\";",
+                "// about Foo",
+                "message Foo {}",
+                "",
+            ]
+            .join("\n"),
+        )
+        .unwrap();
+
+        assert_eq!(
+            file.sorted_imports(),
+            Some((
+                4..14,
+                [
+                    "// about bar",
+                    "// and more",
+                    "import \"bar.proto\";",
+                    "import \"baz.proto\";",
+                    "import \"foo.proto\"; // foo",
+                    "",
+                    "// about nothing",
+                    "",
+                    "import public \"pub.proto\";",
+                    "import weak \"a.proto\";",
+                    "",
+                ]
+                .join("\n")
+            ))
+        );
+
+        let file = File::new("syntax = \"proto3\";\nmessage Foo {}\n".into()).unwrap();
+        assert_eq!(file.sorted_imports(), None);
+    }
+
+    #[test]
+    fn test_possible_qualifiers() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        assert_eq!(possible_qualifiers("", ""), vec![""]);
+        assert_eq!(possible_qualifiers("foo", ""), vec!["foo"]);
+        assert_eq!(possible_qualifiers("foo.bar", ""), vec!["foo.bar"]);
+        assert_eq!(possible_qualifiers("foo", "bar"), vec!["foo"]);
+        assert_eq!(possible_qualifiers("foo.bar", "bar"), vec!["foo.bar"]);
+        assert_eq!(possible_qualifiers("foo", "foo"), vec!["", "foo"]);
+        assert_eq!(
+            possible_qualifiers("foo.bar", "foo"),
+            vec!["bar", "foo.bar"]
+        );
+        assert_eq!(
+            possible_qualifiers("foo.bar.baz", "foo.bar"),
+            vec!["baz", "bar.baz", "foo.bar.baz",]
+        );
+        assert_eq!(
+            possible_qualifiers("foo.bar.baz", "foo.bar.baz"),
+            vec!["", "baz", "bar.baz", "foo.bar.baz",]
+        );
+        assert_eq!(
+            possible_qualifiers("folder.stuff", "folder.what"),
+            vec!["stuff", "folder.stuff"]
+        );
+        assert_eq!(possible_qualifiers("foobar", "foo"), vec!["foobar"]);
+    }
+
     #[test]
     fn test_type_references() {
         let _ = env_logger::builder().is_test(true).try_init();
@@ -1065,7 +3535,8 @@ mod tests {
                 None,
                 &GotoTypeContext {
                     name: "Bar",
-                    parent: None
+                    parent: None,
+                    message_only: false,
                 }
             ),
             vec![tree_sitter::Range {
@@ -1081,7 +3552,8 @@ mod tests {
                 Some("thing"),
                 &GotoTypeContext {
                     name: "Biz.Buz",
-                    parent: None
+                    parent: None,
+                    message_only: false,
                 }
             ),
             vec![tree_sitter::Range {
@@ -1097,7 +3569,8 @@ mod tests {
                 Some("buf"),
                 &GotoTypeContext {
                     name: "Buf",
-                    parent: None
+                    parent: None,
+                    message_only: false,
                 }
             ),
             vec![tree_sitter::Range {
@@ -1113,7 +3586,8 @@ mod tests {
                 Some("thing"),
                 &GotoTypeContext {
                     name: "Buf",
-                    parent: None
+                    parent: None,
+                    message_only: false,
                 }
             ),
             vec![]
@@ -1124,11 +3598,43 @@ mod tests {
                 Some("thing"),
                 &GotoTypeContext {
                     name: "buf",
-                    parent: None
+                    parent: None,
+                    message_only: false,
                 }
             ),
             vec![]
         );
+
+        // A type in another package may be referenced by any qualifier that reaches it.
+        let file = File::new(
+            [
+                "syntax = \"proto3\";",
+                "package foo.bar;",
+                "message Foo {",
+                "    baz.Baz a = 1;",
+                "    bar.baz.Baz b = 2;",
+                "    foo.bar.baz.Baz c = 3;",
+                "    .foo.bar.baz.Baz d = 4;",
+                "}",
+                "",
+            ]
+            .join("\n"),
+        )
+        .unwrap();
+        assert_eq!(
+            file.type_references(
+                Some("foo.bar.baz"),
+                &GotoTypeContext {
+                    name: "Baz",
+                    parent: None,
+                    message_only: false,
+                }
+            )
+            .iter()
+            .map(|r| r.start_point.row)
+            .collect::<Vec<_>>(),
+            vec![3, 4, 5, 6]
+        );
     }
 
     #[test]
@@ -1306,4 +3812,50 @@ mod tests {
             .join("\n")
         );
     }
+
+    #[test]
+    fn test_edit_large() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        const COUNT: usize = 10_000;
+        let mut text = String::from("syntax = \"proto3\";\n");
+        for i in 0..COUNT {
+            text += &format!("message Msg{i} {{\n  int32 i = 1;\n}}\n");
+        }
+        let mut file = File::new(text.clone()).unwrap();
+        assert_eq!(file.line_starts, line_starts(&text));
+
+        let change =
+            |line: u32, character: u32, text: &str| lsp_types::TextDocumentContentChangeEvent {
+                range: Some(lsp_types::Range {
+                    start: lsp_types::Position { line, character },
+                    end: lsp_types::Position { line, character },
+                }),
+                range_length: None,
+                text: text.into(),
+            };
+
+        // Type a new field into messages at the start, middle, and end, one character at a time.
+        let start = std::time::Instant::now();
+        for (added, msg) in [0, COUNT / 2, COUNT - 1].into_iter().enumerate() {
+            // Insert before the closing brace, after the fields added to earlier messages.
+            let line = u32::try_from(msg * 3 + 3 + added).unwrap();
+            for (i, c) in "  string s = 2;\n".chars().enumerate() {
+                let i = u32::try_from(i).unwrap();
+                file.edit(vec![change(line, i, &c.to_string())]).unwrap();
+            }
+            text = text.replacen(
+                &format!("message Msg{msg} {{\n  int32 i = 1;\n"),
+                &format!("message Msg{msg} {{\n  int32 i = 1;\n  string s = 2;\n"),
+                1,
+            );
+        }
+        log::info!("Edited large file in {:?}", start.elapsed());
+
+        assert_eq!(file.text, text);
+        assert_eq!(file.line_starts, line_starts(&text));
+        assert_eq!(
+            file.tree.root_node().to_sexp(),
+            File::new(text).unwrap().tree.root_node().to_sexp()
+        );
+    }
 }
@@ -0,0 +1,139 @@
+use anyhow::{bail, Context, Result};
+use std::io::Write;
+
+// Format the given text using clang-format.
+pub fn format(text: &str) -> Result<String> {
+    let mut cmd = std::process::Command::new("clang-format");
+    cmd
+        // Tell clang-format to treat stdin as protobuf
+        .arg("--assume-filename=pbls.proto")
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped());
+
+    log::debug!("Running clang-format: {cmd:?}");
+    let mut child = cmd.spawn().context("Failed to run clang-format")?;
+    child
+        .stdin
+        .take()
+        .context("Failed to open clang-format stdin")?
+        .write_all(text.as_bytes())?;
+    let output = child.wait_with_output()?;
+
+    log::debug!("clang-format exited: {:?}", output.status);
+    if !output.status.success() {
+        bail!(
+            "clang-format failed: {}",
+            String::from_utf8_lossy(output.stderr.as_slice())
+        );
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+// Compute the edits needed to transform old into new.
+// This simply replaces the whole document if anything changed.
+pub fn edits(old: &str, new: &str) -> Vec<lsp_types::TextEdit> {
+    if old == new {
+        return vec![];
+    }
+    vec![lsp_types::TextEdit {
+        range: lsp_types::Range {
+            start: lsp_types::Position::default(),
+            end: end_position(old),
+        },
+        new_text: new.into(),
+    }]
+}
+
+// Compute the edits needed to transform old into new, where old starts at the given line.
+pub fn range_edits(old: &str, new: &str, line: u32) -> Vec<lsp_types::TextEdit> {
+    edits(old, new)
+        .into_iter()
+        .map(|mut edit| {
+            edit.range.start.line += line;
+            edit.range.end.line += line;
+            edit
+        })
+        .collect()
+}
+
+// Return the position just past the last character of the text.
+// Positions are UTF-8 byte offsets, as advertised to the client.
+fn end_position(text: &str) -> lsp_types::Position {
+    let (line, last) = text.split('\n').enumerate().last().unwrap_or((0, ""));
+    lsp_types::Position {
+        line: line.try_into().unwrap_or(u32::MAX),
+        character: last.len().try_into().unwrap_or(u32::MAX),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_edits() {
+        assert_eq!(edits("message Foo {}\n", "message Foo {}\n"), vec![]);
+
+        assert_eq!(
+            edits("message Foo{}\n", "message Foo {}\n"),
+            vec![lsp_types::TextEdit {
+                range: lsp_types::Range {
+                    start: lsp_types::Position::default(),
+                    end: lsp_types::Position {
+                        line: 1,
+                        character: 0
+                    },
+                },
+                new_text: "message Foo {}\n".into(),
+            }]
+        );
+
+        assert_eq!(
+            edits("syntax=\"proto3\";\nmessage Foo{}", "message Foo {}\n"),
+            vec![lsp_types::TextEdit {
+                range: lsp_types::Range {
+                    start: lsp_types::Position::default(),
+                    end: lsp_types::Position {
+                        line: 1,
+                        character: 13
+                    },
+                },
+                new_text: "message Foo {}\n".into(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_end_position() {
+        assert_eq!(end_position(""), lsp_types::Position::new(0, 0));
+        assert_eq!(end_position("a\n// é"), lsp_types::Position::new(1, 5));
+    }
+
+    #[test]
+    fn test_range_edits() {
+        assert_eq!(range_edits("message Foo {}", "message Foo {}", 3), vec![]);
+
+        assert_eq!(
+            range_edits(
+                "message Foo{\nint32 i = 1;}",
+                "message Foo {\n  int32 i = 1;\n}",
+                3
+            ),
+            vec![lsp_types::TextEdit {
+                range: lsp_types::Range {
+                    start: lsp_types::Position {
+                        line: 3,
+                        character: 0
+                    },
+                    end: lsp_types::Position {
+                        line: 4,
+                        character: 13
+                    },
+                },
+                new_text: "message Foo {\n  int32 i = 1;\n}".into(),
+            }]
+        );
+    }
+}
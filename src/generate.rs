@@ -0,0 +1,167 @@
+use anyhow::{bail, Result};
+use serde_json::Value;
+
+// The workspace/executeCommand command that inserts a message generated from JSON.
+// Arguments are the document URI, the position to insert at, the JSON sample
+// (as an object or a string), and optionally the name of the message.
+pub const FROM_JSON_COMMAND: &str = "pbls/fromJson";
+
+// Generate a message definition from a sample JSON object.
+// Nested objects become nested messages, and arrays become repeated fields.
+pub fn message(name: &str, json: &Value) -> Result<String> {
+    let mut lines = vec![];
+    write_message(&mut lines, 0, name, json)?;
+    Ok(lines.join("\n") + "\n")
+}
+
+fn write_message(lines: &mut Vec<String>, depth: usize, name: &str, json: &Value) -> Result<()> {
+    let Value::Object(obj) = json else {
+        bail!("Expected a JSON object for {name}, got {json}");
+    };
+
+    let indent = "  ".repeat(depth);
+    lines.push(format!("{indent}message {name} {{"));
+
+    let mut fields = vec![];
+    for (key, value) in obj {
+        let (label, value) = match value {
+            Value::Array(items) => ("repeated ", items.first().unwrap_or(&Value::Null)),
+            value => ("", value),
+        };
+        let typ = match value {
+            Value::Null | Value::String(_) => String::from("string"),
+            Value::Bool(_) => String::from("bool"),
+            Value::Number(n) if n.is_f64() => String::from("double"),
+            Value::Number(_) => String::from("int64"),
+            Value::Array(_) => bail!("Nested arrays are not supported: {key}"),
+            Value::Object(_) => {
+                let typ = to_upper_camel(key);
+                write_message(lines, depth + 1, &typ, value)?;
+                lines.push(String::new());
+                typ
+            }
+        };
+        let number = fields.len() + 1;
+        let field = to_lower_snake(key);
+        fields.push(format!("{indent}  {label}{typ} {field} = {number};"));
+    }
+
+    lines.extend(fields);
+    lines.push(format!("{indent}}}"));
+    Ok(())
+}
+
+// to_lower_snake("fooBar") -> "foo_bar"
+fn to_lower_snake(name: &str) -> String {
+    let mut res = String::new();
+    let mut prev_lower = false;
+    for c in name.chars() {
+        if c.is_ascii_uppercase() && prev_lower {
+            res.push('_');
+        }
+        prev_lower = c.is_ascii_lowercase() || c.is_ascii_digit();
+        res.push(match c {
+            '-' | ' ' | '.' => '_',
+            c => c.to_ascii_lowercase(),
+        });
+    }
+    res
+}
+
+// to_upper_camel("foo_bar") -> "FooBar"
+fn to_upper_camel(name: &str) -> String {
+    name.split(['_', '-', ' ', '.'])
+        .flat_map(|word| {
+            let mut chars = word.chars();
+            chars
+                .next()
+                .map(|c| c.to_ascii_uppercase())
+                .into_iter()
+                .chain(chars)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_names() {
+        assert_eq!(to_lower_snake("fooBar"), "foo_bar");
+        assert_eq!(to_lower_snake("FooBar"), "foo_bar");
+        assert_eq!(to_lower_snake("foo_bar"), "foo_bar");
+        assert_eq!(to_lower_snake("foo-bar"), "foo_bar");
+        assert_eq!(to_upper_camel("foo_bar"), "FooBar");
+        assert_eq!(to_upper_camel("fooBar"), "FooBar");
+        assert_eq!(to_upper_camel("foo-bar"), "FooBar");
+    }
+
+    #[test]
+    fn test_scalars() {
+        let json = serde_json::json!({
+            "name": "foo",
+            "count": 3,
+            "ratio": 0.5,
+            "enabled": true,
+            "userId": null,
+        });
+        assert_eq!(
+            message("Foo", &json).unwrap(),
+            [
+                "message Foo {",
+                "  int64 count = 1;",
+                "  bool enabled = 2;",
+                "  string name = 3;",
+                "  double ratio = 4;",
+                "  string user_id = 5;",
+                "}",
+                "",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn test_nested() {
+        let json = serde_json::json!({
+            "id": 1,
+            "owner": {"name": "me", "address": {"city": "here"}},
+            "tags": ["a", "b"],
+            "items": [{"price": 1.5}],
+        });
+        assert_eq!(
+            message("Order", &json).unwrap(),
+            [
+                "message Order {",
+                "  message Items {",
+                "    double price = 1;",
+                "  }",
+                "",
+                "  message Owner {",
+                "    message Address {",
+                "      string city = 1;",
+                "    }",
+                "",
+                "    Address address = 1;",
+                "    string name = 2;",
+                "  }",
+                "",
+                "  int64 id = 1;",
+                "  repeated Items items = 2;",
+                "  Owner owner = 3;",
+                "  repeated string tags = 4;",
+                "}",
+                "",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(message("Foo", &serde_json::json!([1, 2])).is_err());
+        assert!(message("Foo", &serde_json::json!({"a": [[1]]})).is_err());
+    }
+}
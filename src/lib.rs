@@ -1,9 +1,31 @@
 mod file;
+mod format;
+mod generate;
+mod lint;
 mod protoc;
+mod wkt;
 mod workspace;
 
+use lsp_types::notification::Cancel;
 use lsp_types::notification::DidChangeTextDocument;
+use lsp_types::request::ApplyWorkspaceEdit;
+use lsp_types::request::CallHierarchyIncomingCalls;
+use lsp_types::request::CallHierarchyOutgoingCalls;
+use lsp_types::request::CallHierarchyPrepare;
+use lsp_types::request::CodeActionRequest;
+use lsp_types::request::CodeActionResolveRequest;
 use lsp_types::request::Completion;
+use lsp_types::request::DocumentDiagnosticRequest;
+use lsp_types::request::ExecuteCommand;
+use lsp_types::request::FoldingRangeRequest;
+use lsp_types::request::Formatting;
+use lsp_types::request::HoverRequest;
+use lsp_types::request::LinkedEditingRange;
+use lsp_types::request::PrepareRenameRequest;
+use lsp_types::request::RangeFormatting;
+use lsp_types::request::Rename;
+use lsp_types::request::WillSaveWaitUntil;
+use lsp_types::CodeActionParams;
 use lsp_types::CompletionParams;
 use lsp_types::CompletionResponse;
 use lsp_types::DidChangeTextDocumentParams;
@@ -13,6 +35,8 @@ use lsp_types::TextDocumentSyncKind;
 
 use lsp_server::{Connection, Message};
 use lsp_types::request::References;
+use lsp_types::request::ResolveCompletionItem;
+use lsp_types::request::Shutdown;
 use lsp_types::request::{DocumentSymbolRequest, GotoDefinition, Request, WorkspaceSymbolRequest};
 use lsp_types::{
     notification::{DidOpenTextDocument, DidSaveTextDocument, Notification, PublishDiagnostics},
@@ -27,12 +51,40 @@ use lsp_types::{
 use std::fs;
 
 pub use anyhow::Result;
+use anyhow::{bail, Context};
 
 #[derive(Debug, serde::Deserialize)]
 struct Config {
     proto_paths: Vec<std::path::PathBuf>,
 }
 
+// Return the imports reachable from a file, for tooling that needs the import graph.
+enum ImportGraph {}
+
+impl Request for ImportGraph {
+    type Params = lsp_types::TextDocumentIdentifier;
+    type Result = workspace::ImportGraph;
+    const METHOD: &'static str = "pbls/importGraph";
+}
+
+// Return a stable identifier for the symbol at a position, for cross-repository indexing.
+enum Moniker {}
+
+impl Request for Moniker {
+    type Params = lsp_types::TextDocumentPositionParams;
+    type Result = Option<lsp_types::Moniker>;
+    const METHOD: &'static str = "pbls/moniker";
+}
+
+// Return per-file parse metrics, for debugging a slow workspace.
+enum Status {}
+
+impl Request for Status {
+    type Params = ();
+    type Result = Vec<workspace::FileStatus>;
+    const METHOD: &'static str = "pbls/status";
+}
+
 // Handle a request, returning the response to send.
 fn handle<Req>(
     workspace: &mut workspace::Workspace,
@@ -42,7 +94,23 @@ fn handle<Req>(
 where
     Req: lsp_types::request::Request,
 {
-    let (id, params) = req.extract::<Req::Params>(Req::METHOD)?;
+    let id = req.id.clone();
+    let params = match req.extract::<Req::Params>(Req::METHOD) {
+        Ok((_, params)) => params,
+        Err(err) => {
+            // Malformed params should not bring down the server.
+            log::error!("Failed to extract params for {}: {err:?}", Req::METHOD);
+            return Ok(Message::Response(lsp_server::Response {
+                id,
+                result: None,
+                error: Some(lsp_server::ResponseError {
+                    code: lsp_server::ErrorCode::InvalidParams as i32,
+                    message: format!("Invalid params for {}", Req::METHOD),
+                    data: None,
+                }),
+            }));
+        }
+    };
     Ok(Message::Response(match handler(workspace, params) {
         Ok(resp) => lsp_server::Response {
             id,
@@ -111,6 +179,194 @@ fn handle_references(
     workspace.references(params)
 }
 
+fn handle_prepare_call_hierarchy(
+    workspace: &mut workspace::Workspace,
+    params: lsp_types::CallHierarchyPrepareParams,
+) -> Result<Option<Vec<lsp_types::CallHierarchyItem>>> {
+    let pos = params.text_document_position_params;
+    workspace.prepare_call_hierarchy(&pos.text_document.uri, pos.position)
+}
+
+fn handle_incoming_calls(
+    workspace: &mut workspace::Workspace,
+    params: lsp_types::CallHierarchyIncomingCallsParams,
+) -> Result<Option<Vec<lsp_types::CallHierarchyIncomingCall>>> {
+    workspace.incoming_calls(params.item)
+}
+
+fn handle_outgoing_calls(
+    workspace: &mut workspace::Workspace,
+    params: lsp_types::CallHierarchyOutgoingCallsParams,
+) -> Result<Option<Vec<lsp_types::CallHierarchyOutgoingCall>>> {
+    workspace.outgoing_calls(params.item)
+}
+
+fn handle_prepare_rename(
+    workspace: &mut workspace::Workspace,
+    params: lsp_types::TextDocumentPositionParams,
+) -> Result<Option<lsp_types::PrepareRenameResponse>> {
+    workspace.prepare_rename(params)
+}
+
+fn handle_rename(
+    workspace: &mut workspace::Workspace,
+    params: lsp_types::RenameParams,
+) -> Result<Option<lsp_types::WorkspaceEdit>> {
+    workspace.rename(params)
+}
+
+fn handle_folding_range(
+    workspace: &mut workspace::Workspace,
+    params: lsp_types::FoldingRangeParams,
+) -> Result<Option<Vec<lsp_types::FoldingRange>>> {
+    Ok(Some(workspace.folding_ranges(&params.text_document.uri)?))
+}
+
+fn handle_linked_editing_range(
+    workspace: &mut workspace::Workspace,
+    params: lsp_types::LinkedEditingRangeParams,
+) -> Result<Option<lsp_types::LinkedEditingRanges>> {
+    let pos = params.text_document_position_params;
+    workspace.linked_editing_ranges(&pos.text_document.uri, pos.position)
+}
+
+fn handle_hover(
+    workspace: &mut workspace::Workspace,
+    params: lsp_types::HoverParams,
+) -> Result<Option<lsp_types::Hover>> {
+    let pos = params.text_document_position_params;
+    workspace.hover(&pos.text_document.uri, pos.position)
+}
+
+fn handle_code_action(
+    workspace: &mut workspace::Workspace,
+    params: CodeActionParams,
+) -> Result<Option<lsp_types::CodeActionResponse>> {
+    Ok(Some(workspace.code_actions(params)?))
+}
+
+fn handle_code_action_resolve(
+    workspace: &mut workspace::Workspace,
+    params: lsp_types::CodeAction,
+) -> Result<lsp_types::CodeAction> {
+    workspace.resolve_code_action(params)
+}
+
+// Run a command, asking the client to apply the edit it produces.
+// The command itself has no result, as clients do not apply one.
+fn handle_execute_command(
+    connection: &Connection,
+    workspace: &mut workspace::Workspace,
+    params: lsp_types::ExecuteCommandParams,
+) -> Result<Option<serde_json::Value>> {
+    if params.command == workspace::USE_TYPE_COMMAND {
+        let name = params
+            .arguments
+            .into_iter()
+            .next()
+            .context("Missing type")?;
+        workspace.use_type(serde_json::from_value(name)?);
+        return Ok(None);
+    }
+    if params.command != generate::FROM_JSON_COMMAND {
+        bail!("Unknown command {}", params.command);
+    }
+
+    let mut args = params.arguments.into_iter();
+    let uri: lsp_types::Url = serde_json::from_value(args.next().context("Missing uri")?)?;
+    let pos: lsp_types::Position =
+        serde_json::from_value(args.next().context("Missing position")?)?;
+    let json = match args.next().context("Missing JSON")? {
+        serde_json::Value::String(s) => serde_json::from_str(&s)?,
+        value => value,
+    };
+    let name = match args.next() {
+        Some(name) => serde_json::from_value(name)?,
+        None => String::from("Message"),
+    };
+
+    let edit = workspace.message_from_json(&uri, pos, &name, &json)?;
+    apply_edit(connection, "Generate message from JSON", edit)?;
+    Ok(None)
+}
+
+// Send a workspace/applyEdit request. The client's response is not waited for.
+fn apply_edit(connection: &Connection, label: &str, edit: lsp_types::WorkspaceEdit) -> Result<()> {
+    static NEXT_ID: std::sync::atomic::AtomicI32 = std::sync::atomic::AtomicI32::new(0);
+    let id = NEXT_ID.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    connection
+        .sender
+        .send(Message::Request(lsp_server::Request {
+            id: format!("pbls/applyEdit/{id}").into(),
+            method: ApplyWorkspaceEdit::METHOD.into(),
+            params: serde_json::to_value(lsp_types::ApplyWorkspaceEditParams {
+                label: Some(label.into()),
+                edit,
+            })?,
+        }))?;
+    Ok(())
+}
+
+fn handle_import_graph(
+    workspace: &mut workspace::Workspace,
+    params: lsp_types::TextDocumentIdentifier,
+) -> Result<workspace::ImportGraph> {
+    workspace.import_graph(&params.uri)
+}
+
+fn handle_moniker(
+    workspace: &mut workspace::Workspace,
+    params: lsp_types::TextDocumentPositionParams,
+) -> Result<Option<lsp_types::Moniker>> {
+    workspace.moniker(&params.text_document.uri, params.position)
+}
+
+fn handle_status(
+    workspace: &mut workspace::Workspace,
+    _params: (),
+) -> Result<Vec<workspace::FileStatus>> {
+    Ok(workspace.status())
+}
+
+fn handle_formatting(
+    workspace: &mut workspace::Workspace,
+    params: lsp_types::DocumentFormattingParams,
+) -> Result<Option<Vec<lsp_types::TextEdit>>> {
+    Ok(Some(workspace.format(&params.text_document.uri)?))
+}
+
+fn handle_range_formatting(
+    workspace: &mut workspace::Workspace,
+    params: lsp_types::DocumentRangeFormattingParams,
+) -> Result<Option<Vec<lsp_types::TextEdit>>> {
+    Ok(Some(
+        workspace.format_range(&params.text_document.uri, params.range)?,
+    ))
+}
+
+fn handle_will_save_wait_until(
+    workspace: &mut workspace::Workspace,
+    params: lsp_types::WillSaveTextDocumentParams,
+) -> Result<Option<Vec<lsp_types::TextEdit>>> {
+    workspace.will_save(&params.text_document.uri)
+}
+
+fn handle_document_diagnostic(
+    workspace: &mut workspace::Workspace,
+    params: lsp_types::DocumentDiagnosticParams,
+) -> Result<lsp_types::DocumentDiagnosticReportResult> {
+    let items = workspace.diagnostics(&params.text_document.uri)?;
+    Ok(lsp_types::DocumentDiagnosticReportResult::Report(
+        lsp_types::DocumentDiagnosticReport::Full(lsp_types::RelatedFullDocumentDiagnosticReport {
+            related_documents: None,
+            full_document_diagnostic_report: lsp_types::FullDocumentDiagnosticReport {
+                result_id: None,
+                items,
+            },
+        }),
+    ))
+}
+
 fn handle_goto_definition(
     workspace: &mut workspace::Workspace,
     params: GotoDefinitionParams,
@@ -130,6 +386,13 @@ fn handle_completion(
     workspace.complete(&uri, pos.line.try_into()?, pos.character.try_into()?)
 }
 
+fn handle_completion_resolve(
+    workspace: &mut workspace::Workspace,
+    params: lsp_types::CompletionItem,
+) -> Result<lsp_types::CompletionItem> {
+    workspace.resolve_completion(params)
+}
+
 fn notify_did_open(
     workspace: &mut workspace::Workspace,
     params: DidOpenTextDocumentParams,
@@ -173,8 +436,21 @@ fn notify_did_change(
     params: DidChangeTextDocumentParams,
 ) -> Result<Option<lsp_server::Notification>> {
     let uri = params.text_document.uri;
-    workspace.edit(&uri, params.content_changes)?;
-    Ok(None)
+    if !workspace.edit(&uri, params.content_changes)? {
+        return Ok(None);
+    }
+
+    // The imports changed, which may define or remove types the file uses.
+    let params = lsp_types::PublishDiagnosticsParams {
+        uri: uri.clone(),
+        diagnostics: workspace.diagnostics(&uri)?,
+        version: None,
+    };
+
+    Ok(Some(lsp_server::Notification {
+        method: PublishDiagnostics::METHOD.into(),
+        params: serde_json::to_value(&params)?,
+    }))
 }
 
 fn has_proto_files(path: impl AsRef<std::path::Path>) -> Result<bool> {
@@ -212,14 +488,60 @@ fn find_import_paths(root: std::path::PathBuf) -> Result<Vec<std::path::PathBuf>
         .collect())
 }
 
-pub fn run(connection: Connection) -> Result<()> {
-    let server_capabilities = serde_json::to_value(&ServerCapabilities {
+// Find the workspace root from the initialize params.
+// Try the root URI, then the workspace folders, then the deprecated root path,
+// and finally fall back to the current directory.
+fn root_dir(params: &InitializeParams) -> Result<std::path::PathBuf> {
+    let file_path = |uri: &lsp_types::Url| match uri.to_file_path() {
+        Ok(path) => Some(path),
+        Err(_) => {
+            log::warn!("Ignoring non-file root {uri}");
+            None
+        }
+    };
+
+    if let Some(path) = params.root_uri.as_ref().and_then(file_path) {
+        return Ok(path);
+    }
+
+    if let Some(path) = params
+        .workspace_folders
+        .iter()
+        .flatten()
+        .find_map(|folder| file_path(&folder.uri))
+    {
+        log::info!("No root URI, using workspace folder {path:?}");
+        return Ok(path);
+    }
+
+    #[allow(deprecated)]
+    if let Some(path) = params.root_path.as_ref() {
+        log::info!("No root URI, using root path {path:?}");
+        return Ok(path.into());
+    }
+
+    log::info!("No root provided, using current directory");
+    Ok(std::env::current_dir()?)
+}
+
+fn capabilities(options: &workspace::Options) -> ServerCapabilities {
+    ServerCapabilities {
         // BUG: technically we are supposed to support UTF-16.
         // From what I've seen editors seem to be happy with UTF-8.
         position_encoding: Some(lsp_types::PositionEncodingKind::UTF8),
         document_symbol_provider: Some(OneOf::Left(true)),
         workspace_symbol_provider: Some(OneOf::Left(true)),
         references_provider: Some(OneOf::Left(true)),
+        // Only packages can be renamed.
+        rename_provider: Some(OneOf::Right(lsp_types::RenameOptions {
+            prepare_provider: Some(true),
+            work_done_progress_options: Default::default(),
+        })),
+        // Messages form a hierarchy of the messages they are composed of.
+        call_hierarchy_provider: Some(lsp_types::CallHierarchyServerCapability::Simple(true)),
+        linked_editing_range_provider: Some(
+            lsp_types::LinkedEditingRangeServerCapabilities::Simple(true),
+        ),
         text_document_sync: Some(TextDocumentSyncCapability::Options(
             TextDocumentSyncOptions {
                 open_close: Some(true),
@@ -227,32 +549,137 @@ pub fn run(connection: Connection) -> Result<()> {
                     include_text: Some(false),
                 })),
                 change: Some(TextDocumentSyncKind::INCREMENTAL),
+                // Only returns edits if format_on_save is enabled.
+                will_save_wait_until: Some(true),
                 ..Default::default()
             },
         )),
         definition_provider: Some(OneOf::Left(true)),
+        folding_range_provider: Some(lsp_types::FoldingRangeProviderCapability::Simple(true)),
+        hover_provider: Some(lsp_types::HoverProviderCapability::Simple(true)),
+        document_formatting_provider: Some(OneOf::Left(true)),
+        document_range_formatting_provider: Some(OneOf::Left(true)),
+        // Some code actions are expensive, so their edits are computed on resolve.
+        code_action_provider: Some(lsp_types::CodeActionProviderCapability::Options(
+            lsp_types::CodeActionOptions {
+                resolve_provider: Some(true),
+                ..Default::default()
+            },
+        )),
+        execute_command_provider: Some(lsp_types::ExecuteCommandOptions {
+            commands: vec![
+                generate::FROM_JSON_COMMAND.into(),
+                workspace::USE_TYPE_COMMAND.into(),
+            ],
+            ..Default::default()
+        }),
         completion_provider: Some(lsp_types::CompletionOptions {
             trigger_characters: Some(vec!["\"".into()]),
+            resolve_provider: Some(true),
             ..Default::default()
         }),
         diagnostic_provider: Some(DiagnosticServerCapabilities::Options(
             lsp_types::DiagnosticOptions {
-                identifier: Some(String::from("pbls")),
-                workspace_diagnostics: true,
+                identifier: Some(options.diagnostic_source.clone()),
+                // Only textDocument/diagnostic is handled, not workspace/diagnostic.
+                workspace_diagnostics: false,
                 ..Default::default()
             },
         )),
         ..Default::default()
-    })
-    .unwrap();
+    }
+}
+
+// Return the next message to handle, or None if the connection is closed.
+// Messages are read ahead into the queue, so a request that is cancelled before it
+// is handled can be answered with RequestCanceled instead.
+fn next_message(
+    connection: &Connection,
+    queue: &mut std::collections::VecDeque<Message>,
+) -> Result<Option<Message>> {
+    loop {
+        for msg in connection.receiver.try_iter() {
+            enqueue(connection, queue, msg)?;
+        }
+        if let Some(msg) = queue.pop_front() {
+            return Ok(Some(msg));
+        }
+        match connection.receiver.recv() {
+            Ok(msg) => enqueue(connection, queue, msg)?,
+            Err(_) => return Ok(None),
+        }
+    }
+}
+
+fn enqueue(
+    connection: &Connection,
+    queue: &mut std::collections::VecDeque<Message>,
+    msg: Message,
+) -> Result<()> {
+    let not = match msg {
+        Message::Notification(not) if not.method == Cancel::METHOD => not,
+        // Answer pending requests right away, so they do not delay shutting down.
+        Message::Request(req) if req.method == Shutdown::METHOD => {
+            for msg in std::mem::take(queue) {
+                match msg {
+                    Message::Request(pending) => cancel_request(connection, pending.id)?,
+                    msg => queue.push_back(msg),
+                }
+            }
+            queue.push_back(Message::Request(req));
+            return Ok(());
+        }
+        msg => {
+            queue.push_back(msg);
+            return Ok(());
+        }
+    };
+    let params = match not.extract::<lsp_types::CancelParams>(Cancel::METHOD) {
+        Ok(params) => params,
+        Err(err) => {
+            log::error!("Invalid cancel notification: {err:?}");
+            return Ok(());
+        }
+    };
+    let id: lsp_server::RequestId = match params.id {
+        lsp_types::NumberOrString::Number(id) => id.into(),
+        lsp_types::NumberOrString::String(id) => id.into(),
+    };
+    // If the request is not queued, it was already handled and there is nothing to cancel.
+    let Some(idx) = queue
+        .iter()
+        .position(|m| matches!(m, Message::Request(req) if req.id == id))
+    else {
+        log::debug!("Ignoring cancel for request {id:?}");
+        return Ok(());
+    };
+    queue.remove(idx);
+    cancel_request(connection, id)
+}
+
+// Answer a request that will not be handled.
+fn cancel_request(connection: &Connection, id: lsp_server::RequestId) -> Result<()> {
+    log::info!("Cancelling request {id:?}");
+    connection
+        .sender
+        .send(Message::Response(lsp_server::Response {
+            id,
+            result: None,
+            error: Some(lsp_server::ResponseError {
+                code: lsp_server::ErrorCode::RequestCanceled as i32,
+                message: "Request cancelled".into(),
+                data: None,
+            }),
+        }))?;
+    Ok(())
+}
 
+pub fn run(connection: Connection) -> Result<()> {
     log::info!("Initializing");
-    let init_params = connection.initialize(server_capabilities)?;
-    let params: InitializeParams = serde_json::from_value(init_params).unwrap();
-    let root = params
-        .root_uri
-        .map(|u| u.to_file_path().unwrap())
-        .unwrap_or(std::env::current_dir().unwrap());
+    let (init_id, init_params) = connection.initialize_start()?;
+    let params: InitializeParams = serde_json::from_value(init_params)?;
+    let root = root_dir(&params)?;
+    log::info!("Using root {root:?}");
 
     // TODO: merge config from init params
 
@@ -288,9 +715,48 @@ pub fn run(connection: Connection) -> Result<()> {
         .collect();
     log::debug!("Using proto_paths {:?}", proto_paths);
 
-    let mut workspace = workspace::Workspace::new(proto_paths);
+    let options = match params.initialization_options {
+        Some(value) => serde_json::from_value(value)?,
+        None => workspace::Options::default(),
+    };
+    log::info!("Using options {:?}", options);
+
+    // Capabilities depend on the options, so finish initializing after reading them.
+    connection.initialize_finish(
+        init_id,
+        serde_json::to_value(lsp_types::InitializeResult {
+            capabilities: capabilities(&options),
+            server_info: None,
+        })?,
+    )?;
+
+    let mut workspace = workspace::Workspace::with_options(proto_paths, options);
+
+    let queue = std::rc::Rc::new(std::cell::RefCell::new(std::collections::VecDeque::new()));
+
+    // Keep reading messages while handling a request, to abandon it if asked to shut down.
+    let pending = queue.clone();
+    let reader = Connection {
+        sender: connection.sender.clone(),
+        receiver: connection.receiver.clone(),
+    };
+    workspace.set_shutdown_check(move || {
+        let mut queue = pending.borrow_mut();
+        for msg in reader.receiver.try_iter() {
+            if let Err(err) = enqueue(&reader, &mut queue, msg) {
+                log::error!("Failed to read message: {err:?}");
+            }
+        }
+        queue
+            .iter()
+            .any(|m| matches!(m, Message::Request(req) if req.method == Shutdown::METHOD))
+    });
 
-    for msg in &connection.receiver {
+    loop {
+        let msg = next_message(&connection, &mut queue.borrow_mut())?;
+        let Some(msg) = msg else {
+            break;
+        };
         log::info!("Handling message {msg:?}");
         match msg {
             Message::Request(req) => {
@@ -312,6 +778,44 @@ pub fn run(connection: Connection) -> Result<()> {
                     References::METHOD => {
                         Some(handle::<References>(&mut workspace, req, handle_references))
                     }
+                    Rename::METHOD => Some(handle::<Rename>(&mut workspace, req, handle_rename)),
+                    PrepareRenameRequest::METHOD => Some(handle::<PrepareRenameRequest>(
+                        &mut workspace,
+                        req,
+                        handle_prepare_rename,
+                    )),
+                    CallHierarchyPrepare::METHOD => Some(handle::<CallHierarchyPrepare>(
+                        &mut workspace,
+                        req,
+                        handle_prepare_call_hierarchy,
+                    )),
+                    CallHierarchyIncomingCalls::METHOD => {
+                        Some(handle::<CallHierarchyIncomingCalls>(
+                            &mut workspace,
+                            req,
+                            handle_incoming_calls,
+                        ))
+                    }
+                    CallHierarchyOutgoingCalls::METHOD => {
+                        Some(handle::<CallHierarchyOutgoingCalls>(
+                            &mut workspace,
+                            req,
+                            handle_outgoing_calls,
+                        ))
+                    }
+                    LinkedEditingRange::METHOD => Some(handle::<LinkedEditingRange>(
+                        &mut workspace,
+                        req,
+                        handle_linked_editing_range,
+                    )),
+                    FoldingRangeRequest::METHOD => Some(handle::<FoldingRangeRequest>(
+                        &mut workspace,
+                        req,
+                        handle_folding_range,
+                    )),
+                    HoverRequest::METHOD => {
+                        Some(handle::<HoverRequest>(&mut workspace, req, handle_hover))
+                    }
                     GotoDefinition::METHOD => Some(handle::<GotoDefinition>(
                         &mut workspace,
                         req,
@@ -320,6 +824,51 @@ pub fn run(connection: Connection) -> Result<()> {
                     Completion::METHOD => {
                         Some(handle::<Completion>(&mut workspace, req, handle_completion))
                     }
+                    ResolveCompletionItem::METHOD => Some(handle::<ResolveCompletionItem>(
+                        &mut workspace,
+                        req,
+                        handle_completion_resolve,
+                    )),
+                    Formatting::METHOD => {
+                        Some(handle::<Formatting>(&mut workspace, req, handle_formatting))
+                    }
+                    RangeFormatting::METHOD => Some(handle::<RangeFormatting>(
+                        &mut workspace,
+                        req,
+                        handle_range_formatting,
+                    )),
+                    WillSaveWaitUntil::METHOD => Some(handle::<WillSaveWaitUntil>(
+                        &mut workspace,
+                        req,
+                        handle_will_save_wait_until,
+                    )),
+                    CodeActionRequest::METHOD => Some(handle::<CodeActionRequest>(
+                        &mut workspace,
+                        req,
+                        handle_code_action,
+                    )),
+                    CodeActionResolveRequest::METHOD => Some(handle::<CodeActionResolveRequest>(
+                        &mut workspace,
+                        req,
+                        handle_code_action_resolve,
+                    )),
+                    ExecuteCommand::METHOD => Some(handle::<ExecuteCommand>(
+                        &mut workspace,
+                        req,
+                        |workspace, params| handle_execute_command(&connection, workspace, params),
+                    )),
+                    DocumentDiagnosticRequest::METHOD => Some(handle::<DocumentDiagnosticRequest>(
+                        &mut workspace,
+                        req,
+                        handle_document_diagnostic,
+                    )),
+                    ImportGraph::METHOD => Some(handle::<ImportGraph>(
+                        &mut workspace,
+                        req,
+                        handle_import_graph,
+                    )),
+                    Moniker::METHOD => Some(handle::<Moniker>(&mut workspace, req, handle_moniker)),
+                    Status::METHOD => Some(handle::<Status>(&mut workspace, req, handle_status)),
                     _ => None,
                 };
                 if let Some(resp) = resp {
@@ -348,3 +897,116 @@ pub fn run(connection: Connection) -> Result<()> {
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn request(id: i32) -> Message {
+        Message::Request(lsp_server::Request {
+            id: id.into(),
+            method: Completion::METHOD.into(),
+            params: serde_json::Value::Null,
+        })
+    }
+
+    fn cancel(id: i32) -> Message {
+        Message::Notification(lsp_server::Notification {
+            method: Cancel::METHOD.into(),
+            params: serde_json::to_value(lsp_types::CancelParams {
+                id: lsp_types::NumberOrString::Number(id),
+            })
+            .unwrap(),
+        })
+    }
+
+    fn id(msg: Option<Message>) -> Option<lsp_server::RequestId> {
+        match msg {
+            Some(Message::Request(req)) => Some(req.id),
+            Some(Message::Response(resp)) => Some(resp.id),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_cancel_pending_request() {
+        let (server, client) = Connection::memory();
+        let mut queue = std::collections::VecDeque::new();
+
+        for msg in [request(1), request(2), cancel(1), cancel(5), request(3)] {
+            client.sender.send(msg).unwrap();
+        }
+
+        // Request 1 is answered without being handled, and the unknown cancel is ignored.
+        assert_eq!(
+            id(next_message(&server, &mut queue).unwrap()),
+            Some(2.into())
+        );
+        let Message::Response(resp) = client.receiver.try_recv().unwrap() else {
+            panic!("Expected a response");
+        };
+        assert_eq!(resp.id, 1.into());
+        assert_eq!(
+            resp.error.unwrap().code,
+            lsp_server::ErrorCode::RequestCanceled as i32
+        );
+        assert!(client.receiver.try_recv().is_err());
+
+        // Cancelling a request that was already handled does nothing.
+        client.sender.send(cancel(2)).unwrap();
+        assert_eq!(
+            id(next_message(&server, &mut queue).unwrap()),
+            Some(3.into())
+        );
+        assert!(client.receiver.try_recv().is_err());
+
+        // A malformed cancel is dropped, rather than stopping the server.
+        let malformed = Message::Notification(lsp_server::Notification {
+            method: Cancel::METHOD.into(),
+            params: serde_json::json!({"id": [1]}),
+        });
+        client.sender.send(malformed).unwrap();
+        client.sender.send(request(4)).unwrap();
+        assert_eq!(
+            id(next_message(&server, &mut queue).unwrap()),
+            Some(4.into())
+        );
+        assert!(client.receiver.try_recv().is_err());
+
+        drop(client);
+        assert!(next_message(&server, &mut queue).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_shutdown_cancels_pending_requests() {
+        let (server, client) = Connection::memory();
+        let mut queue = std::collections::VecDeque::new();
+
+        let shutdown = Message::Request(lsp_server::Request {
+            id: 3.into(),
+            method: Shutdown::METHOD.into(),
+            params: serde_json::Value::Null,
+        });
+        for msg in [request(1), request(2), shutdown] {
+            client.sender.send(msg).unwrap();
+        }
+
+        // Shutdown is handled next, and the requests before it are answered without waiting.
+        assert_eq!(
+            id(next_message(&server, &mut queue).unwrap()),
+            Some(3.into())
+        );
+        for expected in [1, 2] {
+            let Message::Response(resp) = client.receiver.try_recv().unwrap() else {
+                panic!("Expected a response");
+            };
+            assert_eq!(resp.id, expected.into());
+            assert_eq!(
+                resp.error.unwrap().code,
+                lsp_server::ErrorCode::RequestCanceled as i32
+            );
+        }
+        assert!(client.receiver.try_recv().is_err());
+    }
+}
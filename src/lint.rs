@@ -0,0 +1,320 @@
+use crate::file::{queries, File};
+use lsp_types::{Diagnostic, DiagnosticSeverity, NumberOrString};
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default)]
+pub struct Config {
+    // Lints are opt-in, as they are only conventions.
+    pub enabled: bool,
+    pub message_names: bool,
+    pub field_names: bool,
+    pub enum_value_names: bool,
+    pub enum_value_prefix: bool,
+    // Proto2 groups are deprecated in favor of nested messages.
+    pub groups: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            enabled: false,
+            message_names: true,
+            field_names: true,
+            enum_value_names: true,
+            enum_value_prefix: true,
+            groups: true,
+        }
+    }
+}
+
+// Produce style diagnostics for the given file.
+pub fn diags(file: &File, config: &Config, source: &str) -> Vec<Diagnostic> {
+    if !config.enabled {
+        return vec![];
+    }
+
+    let mut res = vec![];
+    if config.message_names {
+        res.extend(message_names(file));
+    }
+    if config.field_names {
+        res.extend(field_names(file));
+    }
+    if config.enum_value_names {
+        res.extend(enum_value_names(file));
+    }
+    if config.enum_value_prefix {
+        res.extend(enum_value_prefix(file));
+    }
+    if config.groups {
+        res.extend(groups(file));
+    }
+    for diag in res.iter_mut() {
+        diag.source = Some(source.into());
+    }
+    res
+}
+
+fn diag(node: tree_sitter::Node, code: &str, message: String) -> Diagnostic {
+    Diagnostic {
+        range: crate::workspace::to_lsp_range(node.range()),
+        severity: Some(DiagnosticSeverity::INFORMATION),
+        code: Some(NumberOrString::String(code.into())),
+        message,
+        ..Default::default()
+    }
+}
+
+// Return all nodes captured by the first capture of the query.
+fn captures<'a>(file: &'a File, query: &tree_sitter::Query) -> Vec<tree_sitter::Node<'a>> {
+    let mut qc = tree_sitter::QueryCursor::new();
+    qc.matches(query, file.tree().root_node(), file.text().as_bytes())
+        .map(|m| m.captures[0].node)
+        .collect()
+}
+
+fn message_names(file: &File) -> Vec<Diagnostic> {
+    let query = &queries().message_names;
+
+    captures(file, query)
+        .into_iter()
+        .filter(|n| !is_upper_camel(file.get_text(*n)))
+        .map(|n| {
+            let name = file.get_text(n);
+            diag(
+                n,
+                "message-name",
+                format!("Message name {name} should be UpperCamelCase"),
+            )
+        })
+        .collect()
+}
+
+fn field_names(file: &File) -> Vec<Diagnostic> {
+    let query = &queries().field_names;
+
+    captures(file, query)
+        .into_iter()
+        .filter(|n| !is_lower_snake(file.get_text(*n)))
+        .map(|n| {
+            let name = file.get_text(n);
+            diag(
+                n,
+                "field-name",
+                format!("Field name {name} should be lower_snake_case"),
+            )
+        })
+        .collect()
+}
+
+fn enum_value_names(file: &File) -> Vec<Diagnostic> {
+    let query = &queries().enum_values;
+
+    captures(file, query)
+        .into_iter()
+        .filter(|n| !is_upper_snake(file.get_text(*n)))
+        .map(|n| {
+            let name = file.get_text(n);
+            diag(
+                n,
+                "enum-value-name",
+                format!("Enum value {name} should be UPPER_SNAKE_CASE"),
+            )
+        })
+        .collect()
+}
+
+fn enum_value_prefix(file: &File) -> Vec<Diagnostic> {
+    let query = &queries().enum_value_prefixes;
+
+    let mut qc = tree_sitter::QueryCursor::new();
+    qc.matches(query, file.tree().root_node(), file.text().as_bytes())
+        .filter_map(|m| {
+            let prefix = to_upper_snake(file.get_text(m.captures[0].node)) + "_";
+            let node = m.captures[1].node;
+            let name = file.get_text(node);
+            if name.starts_with(&prefix) {
+                None
+            } else {
+                Some(diag(
+                    node,
+                    "enum-value-prefix",
+                    format!("Enum value {name} should be prefixed with {prefix}"),
+                ))
+            }
+        })
+        .collect()
+}
+
+fn groups(file: &File) -> Vec<Diagnostic> {
+    let query = &queries().groups;
+
+    captures(file, query)
+        .into_iter()
+        .map(|n| {
+            let mut diag = diag(
+                n,
+                "group",
+                String::from("Groups are deprecated, use a nested message instead"),
+            );
+            // Only mark the declaration, not the whole body.
+            let mut cursor = n.walk();
+            if let Some(body) = n.children(&mut cursor).find(|c| c.kind() == "messageBody") {
+                diag.range.end = crate::workspace::to_lsp_pos(body.start_position());
+            }
+            diag
+        })
+        .collect()
+}
+
+// A name longer than one letter needs a lowercase letter, so FOO is not UpperCamelCase.
+fn is_upper_camel(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_uppercase())
+        && !name.contains('_')
+        && (name.len() == 1 || name.chars().any(|c| c.is_ascii_lowercase()))
+}
+
+fn is_lower_snake(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_lowercase())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+fn is_upper_snake(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_uppercase())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_')
+}
+
+// to_upper_snake("FooBar") -> "FOO_BAR"
+fn to_upper_snake(name: &str) -> String {
+    let mut res = String::new();
+    let mut prev_lower = false;
+    for c in name.chars() {
+        if c.is_ascii_uppercase() && prev_lower {
+            res.push('_');
+        }
+        prev_lower = c.is_ascii_lowercase() || c.is_ascii_digit();
+        res.push(c.to_ascii_uppercase());
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn codes(text: &str, config: Config) -> Vec<(u32, String)> {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let file = File::new(text.into()).unwrap();
+        diags(&file, &config, "pbls")
+            .into_iter()
+            .map(|d| match d.code {
+                Some(NumberOrString::String(code)) => (d.range.start.line, code),
+                code => panic!("Unexpected code {code:?}"),
+            })
+            .collect()
+    }
+
+    fn only(f: impl Fn(&mut Config)) -> Config {
+        let mut config = Config {
+            enabled: true,
+            message_names: false,
+            field_names: false,
+            enum_value_names: false,
+            enum_value_prefix: false,
+            groups: false,
+        };
+        f(&mut config);
+        config
+    }
+
+    const TEXT: &str = r#"syntax = "proto3";
+message FooBar {
+  uint32 good_name = 1;
+  uint32 badName = 2;
+}
+message foo_bar {}
+enum FooBar {
+  FOO_BAR_UNSPECIFIED = 0;
+  FooBarOne = 1;
+  BAZ = 2;
+}
+"#;
+
+    #[test]
+    fn test_disabled() {
+        assert_eq!(codes(TEXT, Config::default()), vec![]);
+    }
+
+    #[test]
+    fn test_message_names() {
+        assert_eq!(
+            codes(TEXT, only(|c| c.message_names = true)),
+            vec![(5, "message-name".into())]
+        );
+    }
+
+    #[test]
+    fn test_field_names() {
+        assert_eq!(
+            codes(TEXT, only(|c| c.field_names = true)),
+            vec![(3, "field-name".into())]
+        );
+    }
+
+    #[test]
+    fn test_enum_value_names() {
+        assert_eq!(
+            codes(TEXT, only(|c| c.enum_value_names = true)),
+            vec![(8, "enum-value-name".into())]
+        );
+    }
+
+    #[test]
+    fn test_enum_value_prefix() {
+        assert_eq!(
+            codes(TEXT, only(|c| c.enum_value_prefix = true)),
+            vec![
+                (8, "enum-value-prefix".into()),
+                (9, "enum-value-prefix".into())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_groups() {
+        let text = r#"syntax = "proto2";
+message Foo {
+  optional group Bar = 1 {
+    optional int32 baz = 2;
+  }
+}
+"#;
+        assert_eq!(
+            codes(text, only(|c| c.groups = true)),
+            vec![(2, "group".into())]
+        );
+    }
+
+    #[test]
+    fn test_is_upper_camel() {
+        assert!(is_upper_camel("Foo"));
+        assert!(is_upper_camel("FooBar2"));
+        assert!(is_upper_camel("X"));
+        assert!(!is_upper_camel("FOO"));
+        assert!(!is_upper_camel("FOO2"));
+        assert!(!is_upper_camel("Foo_Bar"));
+        assert!(!is_upper_camel("fooBar"));
+    }
+
+    #[test]
+    fn test_to_upper_snake() {
+        assert_eq!(to_upper_snake("Foo"), "FOO");
+        assert_eq!(to_upper_snake("FooBar"), "FOO_BAR");
+        assert_eq!(to_upper_snake("Foo2Bar"), "FOO2_BAR");
+    }
+}
@@ -1,11 +1,142 @@
 use anyhow::{bail, Context, Result};
-use lsp_types::{Diagnostic, DiagnosticSeverity, Range, Url};
+use lsp_types::{Diagnostic, DiagnosticSeverity, NumberOrString, Range, Url};
+
+// The code of the warning protoc reports for an import that nothing refers to.
+pub const UNUSED_IMPORT: &str = "unused-import";
 
 pub fn diags(
+    protoc: &std::path::Path,
+    uri: &Url,
+    text: &str,
+    proto_paths: &Vec<std::path::PathBuf>,
+    source: &str,
+) -> Result<Vec<Diagnostic>> {
+    if uri.scheme() == "untitled" {
+        return untitled_diags(protoc, uri, text, proto_paths, source);
+    }
+    let path = file_path(uri)?;
+    let stderr = run(protoc, &path, proto_paths)?;
+    Ok(parse(&stderr, text, source))
+}
+
+// How long a cached output may go without being rewritten before it is removed,
+// e.g. because its file was deleted.
+const CACHE_MAX_AGE: std::time::Duration = std::time::Duration::from_secs(30 * 24 * 60 * 60);
+
+// Like diags, but reuse protoc's output from the cache directory if neither the file,
+// the files it depends on, nor the include paths have changed since the last run.
+// Each file has one entry, named by a hash of its path, which starts with a hash of
+// everything protoc read. Hashes are SHA-256, so entries stay valid across pbls builds.
+pub fn cached_diags(
+    protoc: &std::path::Path,
+    cache_dir: &std::path::Path,
+    deps: &[std::path::PathBuf],
     uri: &Url,
     text: &str,
     proto_paths: &Vec<std::path::PathBuf>,
+    source: &str,
 ) -> Result<Vec<Diagnostic>> {
+    // An unsaved buffer has nothing on disk to compare against.
+    if uri.scheme() == "untitled" {
+        return untitled_diags(protoc, uri, text, proto_paths, source);
+    }
+    let path = file_path(uri)?;
+    let cached = cache_dir.join(hex(hmac_sha256::Hash::hash(
+        path.to_string_lossy().as_bytes(),
+    )));
+
+    // Protoc reads files from disk, so hash what is on disk rather than the buffer.
+    let mut hasher = hmac_sha256::Hash::new();
+    let mut add = |bytes: &[u8]| {
+        hasher.update((bytes.len() as u64).to_le_bytes());
+        hasher.update(bytes);
+    };
+    for p in proto_paths {
+        add(p.to_string_lossy().as_bytes());
+    }
+    for p in std::iter::once(&path).chain(deps) {
+        add(p.to_string_lossy().as_bytes());
+        add(&std::fs::read(p).unwrap_or_default());
+    }
+    let key = hex(hasher.finalize());
+
+    let hit = std::fs::read_to_string(&cached).ok().and_then(|s| {
+        let (k, stderr) = s.split_once('\n')?;
+        (k == key).then(|| stderr.to_string())
+    });
+    let stderr = match hit {
+        Some(stderr) => {
+            log::debug!("Using cached protoc output {cached:?}");
+            stderr
+        }
+        None => {
+            let stderr = run(protoc, &path, proto_paths)?;
+            if let Err(err) = std::fs::create_dir_all(cache_dir)
+                .and_then(|_| std::fs::write(&cached, format!("{key}\n{stderr}")))
+            {
+                log::warn!("Failed to cache protoc output at {cached:?}: {err}");
+            }
+            prune(cache_dir);
+            stderr
+        }
+    };
+    Ok(parse(&stderr, text, source))
+}
+
+fn hex(hash: [u8; 32]) -> String {
+    hash.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+// Remove cached outputs that have not been written in CACHE_MAX_AGE.
+// Directories, such as the bundled well-known files, are left alone.
+fn prune(cache_dir: &std::path::Path) {
+    let Ok(entries) = std::fs::read_dir(cache_dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let stale = entry.file_type().is_ok_and(|t| t.is_file())
+            && entry
+                .metadata()
+                .and_then(|m| m.modified())
+                .ok()
+                .and_then(|t| t.elapsed().ok())
+                .is_some_and(|age| age > CACHE_MAX_AGE);
+        if stale {
+            log::debug!("Removing stale protoc output {:?}", entry.path());
+            if let Err(err) = std::fs::remove_file(entry.path()) {
+                log::warn!("Failed to remove {:?}: {err}", entry.path());
+            }
+        }
+    }
+}
+
+// Protoc only reads files from disk, so write an unsaved buffer to a temporary file.
+// Its directory is added as the last include path, as protoc requires the file to be in one.
+// The directory is removed once protoc is done.
+fn untitled_diags(
+    protoc: &std::path::Path,
+    uri: &Url,
+    text: &str,
+    proto_paths: &Vec<std::path::PathBuf>,
+    source: &str,
+) -> Result<Vec<Diagnostic>> {
+    let dir = tempfile::Builder::new().prefix("pbls").tempdir()?;
+
+    let name: String = uri
+        .path()
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '_' })
+        .collect();
+    let path = dir.path().join(name + ".proto");
+    std::fs::write(&path, text)?;
+
+    let mut proto_paths = proto_paths.clone();
+    proto_paths.push(dir.path().to_path_buf());
+    let stderr = run(protoc, &path, &proto_paths)?;
+    Ok(parse(&stderr, text, source))
+}
+
+fn file_path(uri: &Url) -> Result<std::path::PathBuf> {
     if uri.scheme() != "file" {
         bail!("Unsupported URI scheme {uri}");
     }
@@ -13,8 +144,16 @@ pub fn diags(
     let Ok(path) = uri.to_file_path() else {
         bail!("Failed to normalize URI path: {uri}");
     };
+    Ok(path)
+}
 
-    let mut cmd = std::process::Command::new("protoc");
+// Run protoc on the file, returning its stderr.
+fn run(
+    protoc: &std::path::Path,
+    path: &std::path::Path,
+    proto_paths: &Vec<std::path::PathBuf>,
+) -> Result<String> {
+    let mut cmd = std::process::Command::new(protoc);
     cmd
         // Protoc requires some output
         // Tell it to generate a descriptor, but discard it
@@ -38,15 +177,19 @@ pub fn diags(
         );
 
     log::debug!("Running protoc: {cmd:?}");
-    let output = cmd.output()?;
+    let output = cmd
+        .output()
+        .with_context(|| format!("Failed to run {protoc:?}"))?;
 
     log::debug!("Protoc exited: {output:?}");
-    let stderr = std::str::from_utf8(output.stderr.as_slice())?;
+    Ok(String::from_utf8(output.stderr)?)
+}
 
-    Ok(stderr
+fn parse(stderr: &str, text: &str, source: &str) -> Vec<Diagnostic> {
+    stderr
         .lines()
-        .filter_map(|l| parse_diag(l, &text))
-        .collect())
+        .filter_map(|l| parse_diag(l, &text, source))
+        .collect()
 }
 
 // Parse a single error line from the protoc parser into a diagnostic.
@@ -54,7 +197,7 @@ pub fn diags(
 // foo.proto:4:13: "int" is not defined
 // Other lines do not contain location info.
 // We'll return None to skip these, as usually another line contains the location.
-fn parse_diag(diag: &str, file_contents: &str) -> Option<lsp_types::Diagnostic> {
+fn parse_diag(diag: &str, file_contents: &str, source: &str) -> Option<lsp_types::Diagnostic> {
     log::debug!("Parsing diagnostic {diag}");
     let (_, rest) = diag.split_once(".proto:")?;
     let (linestr, rest) = rest.split_once(':')?;
@@ -66,6 +209,7 @@ fn parse_diag(diag: &str, file_contents: &str) -> Option<lsp_types::Diagnostic>
         Some(msg) => (msg, DiagnosticSeverity::WARNING),
         None => (msg, DiagnosticSeverity::ERROR),
     };
+    let code = unused_import(msg).map(|_| NumberOrString::String(UNUSED_IMPORT.into()));
 
     // Lines from protoc stderr are 1-indexed.
     let lineno = linestr.parse::<u32>().unwrap() - 1;
@@ -88,12 +232,19 @@ fn parse_diag(diag: &str, file_contents: &str) -> Option<lsp_types::Diagnostic>
             },
         },
         severity: Some(severity),
-        source: Some(String::from("pbls")),
+        code,
+        source: Some(source.into()),
         message: msg.trim().into(),
         ..Default::default()
     })
 }
 
+// Return the import path named by an unused import warning, like:
+// Import bar.proto is unused
+pub fn unused_import(msg: &str) -> Option<&str> {
+    msg.strip_prefix("Import ")?.strip_suffix(" is unused")
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -123,7 +274,14 @@ mod tests {
             ],
         );
 
-        let diags = diags(&uri, &text, &vec![tmp.path().to_path_buf()]).unwrap();
+        let diags = diags(
+            "protoc".as_ref(),
+            &uri,
+            &text,
+            &vec![tmp.path().to_path_buf()],
+            "pbls",
+        )
+        .unwrap();
 
         assert_eq!(
             diags,
@@ -178,7 +336,14 @@ mod tests {
             &["syntax = \"proto3\";", "import \"bar.proto\";"],
         );
 
-        let diags = diags(&uri, &text, &vec![tmp.path().to_path_buf()]).unwrap();
+        let diags = diags(
+            "protoc".as_ref(),
+            &uri,
+            &text,
+            &vec![tmp.path().to_path_buf()],
+            "pbls",
+        )
+        .unwrap();
 
         assert_eq!(
             diags,
@@ -194,10 +359,103 @@ mod tests {
                     },
                 },
                 severity: Some(DiagnosticSeverity::WARNING),
+                code: Some(NumberOrString::String(UNUSED_IMPORT.into())),
                 source: Some("pbls".into()),
                 message: "Import bar.proto is unused".into(),
                 ..Default::default()
             },]
         );
     }
+
+    #[test]
+    fn test_cache() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let tmp = tempfile::tempdir().unwrap();
+        let cache = tmp.path().join("cache");
+        let proto_paths = vec![tmp.path().join("protos")];
+        std::fs::create_dir(&proto_paths[0]).unwrap();
+
+        let (uri, text) = proto(
+            &tmp,
+            "protos/foo.proto",
+            &["syntax = \"proto3\";", "message Foo {", "int i = 1;", "}"],
+        );
+        let messages = |text: &str, proto_paths: &Vec<std::path::PathBuf>| {
+            cached_diags(
+                "protoc".as_ref(),
+                &cache,
+                &[],
+                &uri,
+                text,
+                proto_paths,
+                "pbls",
+            )
+            .unwrap()
+            .into_iter()
+            .map(|d| d.message)
+            .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            messages(&text, &proto_paths),
+            vec!["\"int\" is not defined"]
+        );
+
+        // Replace the cached output to prove the next run reads it instead of running protoc.
+        let entries = std::fs::read_dir(&cache)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .collect::<Vec<_>>();
+        assert_eq!(entries.len(), 1);
+        let replace = || {
+            let cached = std::fs::read_to_string(&entries[0]).unwrap();
+            let (key, _) = cached.split_once('\n').unwrap();
+            std::fs::write(&entries[0], format!("{key}\nfoo.proto:3:1: Cached")).unwrap();
+        };
+        replace();
+        assert_eq!(messages(&text, &proto_paths), vec!["Cached"]);
+
+        // Changing the file invalidates the cache.
+        let (_, text) = proto(
+            &tmp,
+            "protos/foo.proto",
+            &["syntax = \"proto3\";", "message Foo {", "int32 i = 1;", "}"],
+        );
+        assert_eq!(messages(&text, &proto_paths), Vec::<String>::new());
+
+        // As does changing the include paths.
+        let (_, text) = proto(
+            &tmp,
+            "protos/foo.proto",
+            &["syntax = \"proto3\";", "message Foo {", "int i = 1;", "}"],
+        );
+        assert_eq!(
+            messages(&text, &proto_paths),
+            vec!["\"int\" is not defined"]
+        );
+        replace();
+        assert_eq!(messages(&text, &proto_paths), vec!["Cached"]);
+        let proto_paths = vec![tmp.path().join("protos"), tmp.path().to_path_buf()];
+        assert_eq!(
+            messages(&text, &proto_paths),
+            vec!["\"int\" is not defined"]
+        );
+
+        // Writing an entry removes those that have not been written for too long.
+        let stale = cache.join("stale");
+        std::fs::write(&stale, "").unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&stale)
+            .unwrap()
+            .set_modified(std::time::SystemTime::now() - 2 * CACHE_MAX_AGE)
+            .unwrap();
+        let proto_paths = vec![tmp.path().join("protos")];
+        assert_eq!(
+            messages(&text, &proto_paths),
+            vec!["\"int\" is not defined"]
+        );
+        assert!(!stale.exists());
+        assert_eq!(std::fs::read_dir(&cache).unwrap().count(), 1);
+    }
 }
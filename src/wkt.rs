@@ -0,0 +1,123 @@
+// Well-known protobuf types bundled with pbls.
+// These are used when an import of a well-known file is not found on the proto paths.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+const FILES: &[(&str, &str)] = &[
+    ("google/protobuf/any.proto", include_str!("wkt/any.proto")),
+    (
+        "google/protobuf/duration.proto",
+        include_str!("wkt/duration.proto"),
+    ),
+    (
+        "google/protobuf/empty.proto",
+        include_str!("wkt/empty.proto"),
+    ),
+    (
+        "google/protobuf/field_mask.proto",
+        include_str!("wkt/field_mask.proto"),
+    ),
+    (
+        "google/protobuf/struct.proto",
+        include_str!("wkt/struct.proto"),
+    ),
+    (
+        "google/protobuf/timestamp.proto",
+        include_str!("wkt/timestamp.proto"),
+    ),
+    (
+        "google/protobuf/wrappers.proto",
+        include_str!("wkt/wrappers.proto"),
+    ),
+];
+
+// Return the text of the bundled file with the given import path.
+pub fn find(name: &str) -> Option<&'static str> {
+    FILES
+        .iter()
+        .find(|(path, _)| *path == name)
+        .map(|(_, text)| *text)
+}
+
+// The directory pbls caches files in if the client does not set one.
+// This is under the user's cache directory, falling back to the temp dir if it cannot be determined.
+pub fn default_cache_dir() -> PathBuf {
+    let home = || std::env::var_os("HOME").map(PathBuf::from);
+    let dir = if cfg!(windows) {
+        std::env::var_os("LOCALAPPDATA").map(PathBuf::from)
+    } else if cfg!(target_os = "macos") {
+        home().map(|h| h.join("Library").join("Caches"))
+    } else {
+        std::env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| home().map(|h| h.join(".cache")))
+    };
+    dir.unwrap_or_else(std::env::temp_dir).join("pbls")
+}
+
+// Return the path of a bundled file, so clients can open it (e.g. after goto definition).
+// Files are written under the cache directory the first time any is needed, and reused after,
+// so their paths are the same across sessions.
+pub fn path(cache_dir: &Path, name: &str) -> Option<PathBuf> {
+    static DIRS: Mutex<BTreeMap<PathBuf, Option<PathBuf>>> = Mutex::new(BTreeMap::new());
+    find(name)?;
+    let dir = cache_dir.join("wkt");
+    let mut dirs = DIRS.lock().unwrap_or_else(|err| err.into_inner());
+    let dir = dirs.entry(dir).or_insert_with_key(|dir| materialize(dir));
+    Some(dir.as_ref()?.join(name))
+}
+
+// Write the bundled files under dir, unless they are already there.
+fn materialize(dir: &Path) -> Option<PathBuf> {
+    for (name, text) in FILES {
+        let path = dir.join(name);
+        if std::fs::read_to_string(&path).ok().as_deref() == Some(*text) {
+            continue;
+        }
+        let res = std::fs::create_dir_all(path.parent()?).and_then(|_| std::fs::write(&path, text));
+        if let Err(err) = res {
+            log::warn!("Failed to write {name} to {path:?}: {err}");
+            return None;
+        }
+    }
+    Some(dir.to_path_buf())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find() {
+        assert!(find("google/protobuf/timestamp.proto").is_some());
+        assert!(find("google/protobuf/nope.proto").is_none());
+
+        let tmp = tempfile::tempdir().unwrap();
+        assert_eq!(path(tmp.path(), "foo.proto"), None);
+        assert_eq!(
+            path(tmp.path(), "google/protobuf/any.proto"),
+            Some(tmp.path().join("wkt/google/protobuf/any.proto"))
+        );
+    }
+
+    #[test]
+    fn test_materialize() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = materialize(tmp.path()).unwrap();
+        let path = dir.join("google/protobuf/any.proto");
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            find("google/protobuf/any.proto").unwrap()
+        );
+
+        // Files that are already up to date are left alone, and stale ones are replaced.
+        std::fs::write(&path, "stale").unwrap();
+        assert_eq!(materialize(tmp.path()), Some(dir));
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            find("google/protobuf/any.proto").unwrap()
+        );
+    }
+}
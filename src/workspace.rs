@@ -1,6 +1,10 @@
 use std::collections::hash_map;
 
 use crate::file::{self};
+use crate::format;
+use crate::generate;
+use crate::lint;
+use crate::wkt;
 
 use super::protoc;
 use anyhow::{anyhow, Context, Result};
@@ -31,65 +35,176 @@ const OPTIONS: &[&str] = &[
     "swift_prefix",
 ];
 
-pub struct Workspace {
-    proto_paths: Vec<std::path::PathBuf>,
-    files: std::collections::HashMap<Url, file::File>,
+// The most symbols to return for a workspace symbol query.
+const MAX_WORKSPACE_SYMBOLS: usize = 500;
+
+// The number of recently used types to rank first in completion.
+const MAX_RECENT_TYPES: usize = 20;
+
+// The workspace/executeCommand command that records the use of a type, run by the client
+// when a type is accepted from completion. The argument is the fully qualified type name.
+pub const USE_TYPE_COMMAND: &str = "pbls/useType";
+
+const BUILTINS: &[&str] = &[
+    "bool", "bytes", "double", "fixed32", "fixed64", "float", "int32", "int64", "sfixed32",
+    "sfixed64", "sint32", "sint64", "string", "uint32", "uint64",
+];
+
+const KEYWORDS: &[&str] = &[
+    "enum", "extend", "import", "message", "oneof", "option", "package", "repeated", "reserved",
+    "returns", "rpc", "service", "stream", "map",
+];
+
+// The types that may be used as the key of a map field.
+const MAP_KEY_TYPES: &[&str] = &[
+    "bool", "fixed32", "fixed64", "int32", "int64", "sfixed32", "sfixed64", "sint32", "sint64",
+    "string", "uint32", "uint64",
+];
+
+// Field labels that are only keywords in some syntaxes.
+const LABELS: &[&str] = &["optional", "required", "group"];
+
+// Options provided by the client in initializationOptions.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default)]
+pub struct Options {
+    pub lint: lint::Config,
+    // Format the document with willSaveWaitUntil.
+    pub format_on_save: bool,
+    // The source of all diagnostics, also advertised as the diagnostic identifier.
+    pub diagnostic_source: String,
+    // If set, reuse protoc output from this directory for unchanged files.
+    // The bundled well-known files are also written here, instead of the user's cache directory.
+    pub cache_dir: Option<std::path::PathBuf>,
+    // The protoc executable, looked up on PATH unless it is a path.
+    pub protoc: std::path::PathBuf,
+    // Label type completions with their fully qualified name, still inserting the shortest name.
+    pub qualified_completion_labels: bool,
+    pub completion: CompletionOptions,
+    // Report warnings, such as lints and unused imports, as errors.
+    pub strict: bool,
+    // Also resolve imports relative to the directory of the importing file.
+    pub import_relative_to_file: bool,
 }
 
-// Return the possible package qualifiers to_pkg could use for a type imported from from_pkg
-fn possible_qualifiers<'a>(from_pkg: &'a str, to_pkg: &'a str) -> Vec<&'a str> {
-    log::trace!("possible_qualifiers({from_pkg}, {to_pkg})");
-    if to_pkg == "" {
-        return vec![from_pkg];
-    }
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default)]
+pub struct CompletionOptions {
+    // Suggest scalar types and keywords along with message and enum types.
+    pub include_builtins: bool,
+    // Offer a documentation template when starting a comment above a definition.
+    pub doc_templates: bool,
+    // Complete the type right after inserting a modifier, with VS Code's triggerSuggest command.
+    // Other editors do not have the command, so this is off by default.
+    pub trigger_suggest: bool,
+}
 
-    let mut res = vec![];
-    if let Some(pkg) = from_pkg.strip_prefix(to_pkg) {
-        let pkg = pkg.strip_prefix(".").unwrap_or(pkg);
-        res.push(pkg);
+impl Default for CompletionOptions {
+    fn default() -> Self {
+        CompletionOptions {
+            include_builtins: true,
+            doc_templates: false,
+            trigger_suggest: false,
+        }
     }
+}
 
-    if let Some((to_pkg, _)) = to_pkg.rsplit_once(".") {
-        res.append(&mut possible_qualifiers(from_pkg, to_pkg));
-    } else {
-        res.push(from_pkg);
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            lint: lint::Config::default(),
+            format_on_save: false,
+            diagnostic_source: String::from("pbls"),
+            cache_dir: None,
+            protoc: std::path::PathBuf::from("protoc"),
+            qualified_completion_labels: false,
+            completion: CompletionOptions::default(),
+            strict: false,
+            import_relative_to_file: false,
+        }
     }
-    return res;
 }
 
-#[test]
-fn test_possible_qualifiers() {
-    let _ = env_logger::builder().is_test(true).try_init();
-    assert_eq!(possible_qualifiers("", ""), vec![""]);
-    assert_eq!(possible_qualifiers("foo", ""), vec!["foo"]);
-    assert_eq!(possible_qualifiers("foo.bar", ""), vec!["foo.bar"]);
-    assert_eq!(possible_qualifiers("foo", "bar"), vec!["foo"]);
-    assert_eq!(possible_qualifiers("foo.bar", "bar"), vec!["foo.bar"]);
-    assert_eq!(possible_qualifiers("foo", "foo"), vec!["", "foo"]);
-    assert_eq!(
-        possible_qualifiers("foo.bar", "foo"),
-        vec!["bar", "foo.bar"]
-    );
-    assert_eq!(
-        possible_qualifiers("foo.bar.baz", "foo.bar"),
-        vec!["baz", "bar.baz", "foo.bar.baz",]
-    );
-    assert_eq!(
-        possible_qualifiers("foo.bar.baz", "foo.bar.baz"),
-        vec!["", "baz", "bar.baz", "foo.bar.baz",]
-    );
-    assert_eq!(
-        possible_qualifiers("folder.stuff", "folder.what"),
-        vec!["stuff", "folder.stuff"]
-    );
+// The imports reachable from a file, as returned by the pbls/importGraph request.
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ImportGraph {
+    // Every file reachable from the requested file, including itself.
+    pub nodes: Vec<Url>,
+    pub edges: Vec<ImportEdge>,
+    // Imports that could not be found on the proto paths.
+    pub unresolved: Vec<String>,
+}
+
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ImportEdge {
+    pub from: Url,
+    pub to: Url,
+}
+
+// Metrics about a loaded file, as returned by the pbls/status request.
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct FileStatus {
+    pub uri: Url,
+    // Time spent parsing the file when it was last loaded or edited.
+    pub parse_micros: u64,
+    pub symbols: usize,
+    pub imports: usize,
+    // Whether the file has unsaved edits, which protoc does not see as it reads from disk.
+    pub stale_diagnostics: bool,
+}
+
+pub struct Workspace {
+    proto_paths: Vec<std::path::PathBuf>,
+    files: std::collections::HashMap<Url, file::File>,
+    // The imports of each open file, to only refresh diagnostics when an edit changes them.
+    imports: std::collections::HashMap<Url, Vec<String>>,
+    // How long each file took to parse, for the pbls/status request.
+    parse_times: std::collections::HashMap<Url, std::time::Duration>,
+    // Files edited since they were opened or saved.
+    unsaved: std::collections::HashSet<Url>,
+    // The fully qualified types visible through each set of loaded imports, so diagnostics
+    // need not resolve every type reference against the imported files on each pass.
+    imported_types: std::cell::RefCell<
+        std::collections::HashMap<Vec<Url>, std::rc::Rc<std::collections::HashSet<String>>>,
+    >,
+    // Fully qualified names of types used this session, most recent first.
+    // Updated by goto, which only borrows the workspace.
+    recent_types: std::cell::RefCell<std::collections::VecDeque<String>>,
+    // Whether the client asked to shut down, checked to abandon long operations.
+    shutdown_requested: Box<dyn Fn() -> bool>,
+    options: Options,
 }
 
 impl Workspace {
+    #[cfg(test)]
     pub fn new(proto_paths: Vec<std::path::PathBuf>) -> Workspace {
+        Self::with_options(proto_paths, Options::default())
+    }
+
+    pub fn with_options(proto_paths: Vec<std::path::PathBuf>, options: Options) -> Workspace {
         Workspace {
             proto_paths: proto_paths.clone(),
             files: hash_map::HashMap::new(),
+            imports: hash_map::HashMap::new(),
+            parse_times: hash_map::HashMap::new(),
+            unsaved: std::collections::HashSet::new(),
+            imported_types: Default::default(),
+            recent_types: Default::default(),
+            shutdown_requested: Box::new(|| false),
+            options,
+        }
+    }
+
+    pub fn set_shutdown_check(&mut self, check: impl Fn() -> bool + 'static) {
+        self.shutdown_requested = Box::new(check);
+    }
+
+    // Fail if the client asked to shut down, so it is not kept waiting on a long operation.
+    fn check_shutdown(&self) -> Result<()> {
+        if (self.shutdown_requested)() {
+            return Err(anyhow!("Shutdown requested"));
         }
+        Ok(())
     }
 
     fn get(self: &Self, uri: &Url) -> Result<&file::File> {
@@ -99,87 +214,484 @@ impl Workspace {
             .with_context(|| format!("File not loaded: {uri}"))?)
     }
 
-    fn find_import(&self, name: &str) -> Option<std::path::PathBuf> {
-        self.proto_paths
-            .iter()
-            .map(|dir| dir.join(name))
+    // Return the include paths for a file, which are the proto paths, followed by the
+    // directory of the file itself if imports may be relative to it.
+    fn include_paths(&self, uri: &Url) -> Vec<std::path::PathBuf> {
+        let mut paths = self.proto_paths.clone();
+        if self.options.import_relative_to_file {
+            let dir = uri.to_file_path().ok();
+            paths.extend(
+                dir.as_deref()
+                    .and_then(std::path::Path::parent)
+                    .map(std::path::Path::to_path_buf),
+            );
+        }
+        paths
+    }
+
+    // Resolve an import of the file `from` against its include paths, falling back to the
+    // bundled well-known files.
+    // Absolute imports are never resolved, as protoc requires imports to be relative to an include path.
+    // Nor are those with "." or ".." segments, which protoc also rejects.
+    fn find_import(&self, from: &Url, name: &str) -> Option<std::path::PathBuf> {
+        let Some(name) = normalize_import(name) else {
+            log::warn!("Not resolving invalid import {name:?}");
+            return None;
+        };
+        self.include_paths(from)
+            .into_iter()
+            .map(|dir| dir.join(&name))
             .find(|path| path.exists())
+            .or_else(|| wkt::path(&self.cache_dir(), &name))
+    }
+
+    // The directory for pbls to cache files in, such as the bundled well-known files.
+    fn cache_dir(&self) -> std::path::PathBuf {
+        self.options
+            .cache_dir
+            .clone()
+            .unwrap_or_else(wkt::default_cache_dir)
+    }
+
+    fn import_uri(&self, from: &Url, name: &str) -> Option<Url> {
+        Url::from_file_path(self.find_import(from, name)?).ok()
+    }
+
+    // Return the loaded files imported by the given file.
+    // Imports that were not loaded (e.g. created after the file was opened) are skipped.
+    fn imported_files(&self, uri: &Url, file: &file::File) -> Vec<(Url, &file::File)> {
+        let mut qc = tree_sitter::QueryCursor::new();
+        file.imports(&mut qc)
+            .filter_map(|name| self.import_uri(uri, name))
+            .filter_map(|uri| match self.files.get(&uri) {
+                Some(file) => Some((uri, file)),
+                None => {
+                    log::warn!("Import not loaded: {uri}");
+                    None
+                }
+            })
+            .collect()
+    }
+
+    // Return the paths of all files the file imports, directly or indirectly.
+    fn import_closure(&self, uri: &Url, file: &file::File) -> Vec<std::path::PathBuf> {
+        let mut seen = std::collections::BTreeSet::new();
+        let mut stack = vec![(uri.clone(), file)];
+        while let Some((uri, file)) = stack.pop() {
+            for (uri, imported) in self.imported_files(&uri, file) {
+                if seen.insert(uri.clone()) {
+                    stack.push((uri, imported));
+                }
+            }
+        }
+        seen.into_iter()
+            .filter_map(|uri| uri.to_file_path().ok())
+            .collect()
+    }
+
+    // Return every file reachable from the given file through imports.
+    // Imports are loaded when a file is opened, so this only walks files we have already parsed.
+    pub fn import_graph(&self, uri: &Url) -> Result<ImportGraph> {
+        let mut graph = ImportGraph {
+            nodes: vec![uri.clone()],
+            edges: vec![],
+            unresolved: vec![],
+        };
+        let mut queue = std::collections::VecDeque::from([(uri.clone(), self.get(uri)?)]);
+        while let Some((from, file)) = queue.pop_front() {
+            let mut qc = tree_sitter::QueryCursor::new();
+            for name in file.imports(&mut qc) {
+                let Some(to) = self.import_uri(&from, name) else {
+                    if !graph.unresolved.iter().any(|n| n == name) {
+                        graph.unresolved.push(name.to_string());
+                    }
+                    continue;
+                };
+                graph.edges.push(ImportEdge {
+                    from: from.clone(),
+                    to: to.clone(),
+                });
+                if graph.nodes.contains(&to) {
+                    continue; // already visited, imports may form a cycle
+                }
+                graph.nodes.push(to.clone());
+                match self.files.get(&to) {
+                    Some(imported) => queue.push_back((to, imported)),
+                    None => log::warn!("Import not loaded: {to}"),
+                }
+            }
+        }
+        Ok(graph)
+    }
+
+    // Report metrics for every loaded file, to help diagnose a slow workspace.
+    pub fn status(&self) -> Vec<FileStatus> {
+        let mut res: Vec<_> = self
+            .files
+            .iter()
+            .map(|(uri, file)| {
+                let mut qc = tree_sitter::QueryCursor::new();
+                let symbols = file.symbols(&mut qc).count();
+                let imports = file.imports(&mut qc).count();
+                FileStatus {
+                    uri: uri.clone(),
+                    parse_micros: self
+                        .parse_times
+                        .get(uri)
+                        .map_or(0, |t| t.as_micros() as u64),
+                    symbols,
+                    imports,
+                    stale_diagnostics: self.unsaved.contains(uri),
+                }
+            })
+            .collect();
+        res.sort_by(|a, b| a.uri.cmp(&b.uri));
+        res
     }
 
     // Open and parse an imported file if we haven't already
-    fn open_import(&mut self, name: &str) -> Result<()> {
-        let Some(path) = self.find_import(name) else {
+    fn open_import(&mut self, from: &Url, name: &str) -> Result<()> {
+        let Some(uri) = self.import_uri(from, name) else {
             // TODO: Could generate not-found import diagnostic here, if we stop using protoc
             return Ok(());
         };
 
-        let uri = Url::from_file_path(path.clone()).or(Err(anyhow!("Invalid path {path:?}")))?;
         if self.files.contains_key(&uri) {
             return Ok(()); // already parsed
         };
 
+        let path = uri.to_file_path().or(Err(anyhow!("Invalid path: {uri}")))?;
         let text = std::fs::read_to_string(path)?;
+        let start = std::time::Instant::now();
         let file = file::File::new(text)?;
+        self.parse_times.insert(uri.clone(), start.elapsed());
         let mut qc = tree_sitter::QueryCursor::new();
         let imports = Vec::from_iter(file.imports(&mut qc).map(str::to_string));
-        self.files.insert(uri, file);
+        self.files.insert(uri.clone(), file);
         for import in imports {
-            self.open_import(import.as_str())?;
+            self.open_import(&uri, import.as_str())?;
         }
         Ok(())
     }
 
     pub fn open(&mut self, uri: Url, text: String) -> Result<Vec<lsp_types::Diagnostic>> {
-        let diags = protoc::diags(&uri, &text, &self.proto_paths);
+        let start = std::time::Instant::now();
         let file = file::File::new(text)?;
+        self.parse_times.insert(uri.clone(), start.elapsed());
+        self.unsaved.remove(&uri);
+        self.forget_imported_types(&uri);
 
         let mut qc = tree_sitter::QueryCursor::new();
         let imports = Vec::from_iter(file.imports(&mut qc).map(str::to_string));
 
         self.files.insert(uri.clone(), file);
 
-        for import in imports {
-            self.open_import(import.as_str())?;
+        for import in imports.iter() {
+            self.open_import(&uri, import.as_str())?;
         }
+        self.imports.insert(uri.clone(), imports);
 
-        diags
+        self.diagnostics(&uri)
     }
 
     pub fn save(&mut self, uri: Url) -> Result<Vec<lsp_types::Diagnostic>> {
-        let file = self.get(&uri)?;
-        protoc::diags(&uri, &file.text(), &self.proto_paths)
+        self.unsaved.remove(&uri);
+        self.diagnostics(&uri)
+    }
+
+    // Compute all diagnostics for a loaded file.
+    pub fn diagnostics(&self, uri: &Url) -> Result<Vec<lsp_types::Diagnostic>> {
+        let file = self.get(uri)?;
+        let source = self.options.diagnostic_source.as_str();
+        let proto_paths = self.include_paths(uri);
+        let protoc = self.options.protoc.as_path();
+        let protoc_diags = match &self.options.cache_dir {
+            Some(dir) => protoc::cached_diags(
+                protoc,
+                dir,
+                &self.import_closure(uri, file),
+                uri,
+                file.text(),
+                &proto_paths,
+                source,
+            ),
+            None => protoc::diags(protoc, uri, file.text(), &proto_paths, source),
+        };
+        // The checks below do not need protoc, so still report them if it fails, e.g. if it
+        // is not installed.
+        let mut diags = protoc_diags.unwrap_or_else(|err| {
+            log::error!("Failed to get protoc diagnostics for {uri}: {err:?}");
+            vec![]
+        });
+        // Protoc reports undefined types and other errors too, so only add those on lines it
+        // has not reported, e.g. in unsaved changes.
+        let reported: Vec<_> = diags.iter().map(|d| d.range.start.line).collect();
+        let unreported = |d: &lsp_types::Diagnostic| !reported.contains(&d.range.start.line);
+        diags.extend(
+            self.undefined_types(uri, file, source)?
+                .into_iter()
+                .filter(unreported),
+        );
+        diags.extend(lint::diags(file, &self.options.lint, source));
+        diags.extend(duplicate_imports(file, source));
+        diags.extend(invalid_imports(file, source));
+        diags.extend(duplicate_packages(file, source));
+        diags.extend(proto3_required(file, source).into_iter().filter(unreported));
+        diags.extend(
+            invalid_map_keys(file, source)
+                .into_iter()
+                .filter(unreported),
+        );
+        diags.extend(
+            extension_overlaps(file, source)
+                .into_iter()
+                .filter(unreported),
+        );
+        diags.extend(self.deprecated_usages(uri, file, source)?);
+        if self.options.strict {
+            for diag in diags.iter_mut() {
+                diag.severity = diag.severity.map(escalate);
+            }
+        }
+        Ok(diags)
+    }
+
+    // Report field and rpc types that do not resolve to any visible symbol, without waiting
+    // for protoc, which only sees the file once it is saved.
+    // Types are only resolved through direct imports, so to avoid false positives this reports
+    // nothing if an import is not loaded or re-exports others with `import public`.
+    fn undefined_types(
+        &self,
+        uri: &Url,
+        file: &file::File,
+        source: &str,
+    ) -> Result<Vec<lsp_types::Diagnostic>> {
+        let Some(imported) = self.imported_types(uri, file) else {
+            return Ok(vec![]);
+        };
+        let local = file.type_names();
+
+        let mut res = vec![];
+        for (name, range) in file.type_refs() {
+            if BUILTINS.contains(&name) {
+                continue;
+            }
+            let pos = range.start_point;
+            let Some(file::GotoContext::Type(typ)) = file.type_at(pos.row, pos.column) else {
+                continue;
+            };
+            let defined = file
+                .resolve_local(&local, typ.name, typ.parent.as_deref())
+                .is_some()
+                || resolves_to(&imported, file.package(), &typ);
+            if !defined {
+                res.push(lsp_types::Diagnostic {
+                    range: to_lsp_range(range),
+                    severity: Some(lsp_types::DiagnosticSeverity::ERROR),
+                    source: Some(source.into()),
+                    message: format!("\"{name}\" is not defined"),
+                    ..Default::default()
+                });
+            }
+        }
+        Ok(res)
+    }
+
+    // Drop the cached types of every set of imports including a file that changed.
+    fn forget_imported_types(&mut self, uri: &Url) {
+        self.imported_types
+            .get_mut()
+            .retain(|uris, _| !uris.contains(uri));
+    }
+
+    // Return the fully qualified names of the types visible through the imports of a file,
+    // including those its imports publicly import, or None if an import is not loaded.
+    fn imported_types(
+        &self,
+        uri: &Url,
+        file: &file::File,
+    ) -> Option<std::rc::Rc<std::collections::HashSet<String>>> {
+        let mut qc = tree_sitter::QueryCursor::new();
+        let mut uris = vec![];
+        for name in file.imports(&mut qc) {
+            uris.push(
+                self.import_uri(uri, name)
+                    .filter(|u| self.files.contains_key(u))?,
+            );
+        }
+        let mut i = 0;
+        while let Some(uri) = uris.get(i).cloned() {
+            for name in self.files[&uri].public_imports() {
+                let public = self
+                    .import_uri(&uri, name)
+                    .filter(|u| self.files.contains_key(u))?;
+                if !uris.contains(&public) {
+                    uris.push(public);
+                }
+            }
+            i += 1;
+        }
+
+        let mut cache = self.imported_types.borrow_mut();
+        let types = cache.entry(uris).or_insert_with_key(|uris| {
+            let mut types = std::collections::HashSet::new();
+            for file in uris.iter().map(|u| &self.files[u]) {
+                let mut qc = tree_sitter::QueryCursor::new();
+                types.extend(
+                    file.symbols(&mut qc)
+                        .filter(is_type)
+                        .map(|s| qualify(file.package(), &s.name)),
+                );
+            }
+            std::rc::Rc::new(types)
+        });
+        Some(types.clone())
+    }
+
+    // Hint at every use of a deprecated type, so editors can render it struck-through.
+    fn deprecated_usages(
+        &self,
+        uri: &Url,
+        file: &file::File,
+        source: &str,
+    ) -> Result<Vec<lsp_types::Diagnostic>> {
+        let mut res = vec![];
+        for (name, range) in file.type_refs() {
+            if BUILTINS.contains(&name) {
+                continue;
+            }
+            let pos = range.start_point;
+            let Some(file::GotoContext::Type(typ)) = file.type_at(pos.row, pos.column) else {
+                continue;
+            };
+            let Some(loc) = self.find_symbol(uri.clone(), file, &typ)? else {
+                continue;
+            };
+            let mut qc = tree_sitter::QueryCursor::new();
+            let deprecated = self
+                .get(&loc.uri)?
+                .symbols(&mut qc)
+                .any(|s| s.deprecated && to_lsp_range(s.range) == loc.range);
+            if deprecated {
+                res.push(lsp_types::Diagnostic {
+                    range: to_lsp_range(range),
+                    severity: Some(lsp_types::DiagnosticSeverity::HINT),
+                    source: Some(source.into()),
+                    message: format!("{name} is deprecated"),
+                    tags: Some(vec![lsp_types::DiagnosticTag::DEPRECATED]),
+                    ..Default::default()
+                });
+            }
+        }
+        Ok(res)
     }
 
+    // Apply changes to a file, returning whether they changed its imports, in which case
+    // its diagnostics should be refreshed.
     pub fn edit(
         &mut self,
         uri: &Url,
         changes: Vec<lsp_types::TextDocumentContentChangeEvent>,
-    ) -> Result<()> {
+    ) -> Result<bool> {
         log::trace!("Editing {uri:?}");
-        let file = self
-            .files
-            .get_mut(uri)
-            .with_context(|| format!("File not loaded: {uri}"))?;
+        let file = match self.files.entry(uri.clone()) {
+            hash_map::Entry::Occupied(entry) => entry.into_mut(),
+            hash_map::Entry::Vacant(entry) => {
+                // The client may send changes before opening the file.
+                // If the first change replaces the whole document, start from that,
+                // otherwise start from the contents on disk.
+                log::warn!("Editing unopened file {uri}");
+                let text = match changes.first() {
+                    Some(change) if change.range.is_none() => String::new(),
+                    _ => {
+                        let path = uri.to_file_path().or(Err(anyhow!("Invalid path: {uri}")))?;
+                        std::fs::read_to_string(path)?
+                    }
+                };
+                entry.insert(file::File::new(text)?)
+            }
+        };
+        let start = std::time::Instant::now();
         file.edit(changes)?;
+        let elapsed = start.elapsed();
 
         let mut qc = tree_sitter::QueryCursor::new();
         let imports = Vec::from_iter(file.imports(&mut qc).map(str::to_string));
 
-        for import in imports {
-            log::trace!("Loading new import {import:?}");
-            self.open_import(import.as_str())?;
+        for import in imports.iter() {
+            self.open_import(uri, import.as_str())?;
         }
+        self.forget_imported_types(uri);
+        let changed = self.imports.get(uri) != Some(&imports);
+        self.imports.insert(uri.clone(), imports);
+        self.parse_times.insert(uri.clone(), elapsed);
+        self.unsaved.insert(uri.clone());
 
-        Ok(())
+        Ok(changed)
+    }
+
+    pub fn format(&self, uri: &Url) -> Result<Vec<lsp_types::TextEdit>> {
+        let file = self.get(uri)?;
+        let formatted = format::format(file.text())?;
+        Ok(format::edits(file.text(), &formatted))
+    }
+
+    // Format the top-level definitions overlapping the given range.
+    pub fn format_range(
+        &self,
+        uri: &Url,
+        range: lsp_types::Range,
+    ) -> Result<Vec<lsp_types::TextEdit>> {
+        let file = self.get(uri)?;
+        let Some((start, end)) =
+            file.definition_rows(range.start.line.try_into()?, range.end.line.try_into()?)
+        else {
+            return Ok(vec![]);
+        };
+
+        let old = file
+            .text()
+            .lines()
+            .skip(start)
+            .take(end - start + 1)
+            .collect::<Vec<_>>()
+            .join("\n");
+        let formatted = format::format(&old)?;
+        Ok(format::range_edits(
+            &old,
+            formatted.trim_end_matches('\n'),
+            start.try_into()?,
+        ))
+    }
+
+    pub fn will_save(&self, uri: &Url) -> Result<Option<Vec<lsp_types::TextEdit>>> {
+        if !self.options.format_on_save {
+            return Ok(None);
+        }
+        Ok(Some(self.format(uri)?))
     }
 
     pub fn symbols(&self, uri: &Url) -> Result<Vec<SymbolInformation>> {
         let mut qc = tree_sitter::QueryCursor::new();
-        Ok(self
-            .get(uri)?
+        let file = self.get(uri)?;
+        let package = file.package();
+        let mut res: Vec<_> = file
             .symbols(&mut qc)
-            .map(|s| to_lsp_symbol(uri.clone(), s))
-            .collect())
+            .map(|s| to_lsp_symbol(uri.clone(), package, s))
+            .collect();
+
+        // Extend blocks are listed too, though they cannot be referenced by name.
+        // Their fields are contained by the message they extend.
+        for (block, fields) in file.extend_symbols() {
+            let extendee = block.name.clone();
+            res.push(to_lsp_symbol(uri.clone(), package, block));
+            res.extend(fields.into_iter().map(|f| SymbolInformation {
+                container_name: Some(extendee.clone()),
+                ..to_lsp_symbol(uri.clone(), package, f)
+            }));
+        }
+        res.sort_by_key(|s| s.location.range.start);
+        Ok(res)
     }
 
     fn load_all(&mut self) -> Result<()> {
@@ -201,16 +713,19 @@ impl Workspace {
             });
 
         for path in paths {
+            self.check_shutdown()?;
             log::debug!("Loading {path:?}");
             let uri = Url::from_file_path(&path).or(Err(anyhow!("Invalid path: {path:?}")))?;
-            if let Some(file) = self.files.get(&uri) {
-                file
-            } else {
-                let text = std::fs::read_to_string(uri.path())?;
-                let file = file::File::new(text)?;
-                self.files.insert(uri.clone(), file);
-                self.files.get(&uri).unwrap()
-            };
+            if self.files.contains_key(&uri) {
+                // Already loaded, and may contain unsaved edits.
+                // Don't clobber it with the contents on disk.
+                continue;
+            }
+            let text = std::fs::read_to_string(uri.path())?;
+            let start = std::time::Instant::now();
+            let file = file::File::new(text)?;
+            self.parse_times.insert(uri.clone(), start.elapsed());
+            self.files.insert(uri, file);
         }
 
         Ok(())
@@ -219,8 +734,10 @@ impl Workspace {
     pub fn all_symbols(&mut self, query: &str) -> Result<Vec<SymbolInformation>> {
         self.load_all()?;
 
-        let regexes: std::result::Result<Vec<_>, _> = query
-            .split_whitespace()
+        let ignore_case = query.chars().all(|c| !c.is_uppercase());
+        let words = query.split_whitespace().collect::<Vec<_>>();
+        let regexes: std::result::Result<Vec<_>, _> = words
+            .iter()
             .map(|s| {
                 RegexBuilder::new(
                     &s.chars()
@@ -228,7 +745,7 @@ impl Workspace {
                         .collect::<Vec<_>>()
                         .join(".*"),
                 )
-                .case_insensitive(query.chars().all(|c| !c.is_uppercase()))
+                .case_insensitive(ignore_case)
                 .build()
             })
             .collect();
@@ -238,13 +755,25 @@ impl Workspace {
         let mut res = vec![];
         let mut qc = tree_sitter::QueryCursor::new();
         for (uri, file) in &self.files {
+            self.check_shutdown()?;
+            let package = file.package();
             let symbols = file.symbols(&mut qc);
             let syms = symbols
                 .filter(|s| regexes.iter().all(|r| r.is_match(&s.name)))
-                .map(|s| to_lsp_symbol(uri.clone(), s));
+                .map(|s| {
+                    let rank: usize = words
+                        .iter()
+                        .map(|w| match_rank(&s.name, w, ignore_case))
+                        .sum();
+                    (rank, to_lsp_symbol(uri.clone(), package, s))
+                });
             res.extend(syms);
         }
-        Ok(res)
+
+        // Exact and prefix matches first, then substrings, then scattered fuzzy matches.
+        res.sort_by_key(|(rank, _)| *rank);
+        res.truncate(MAX_WORKSPACE_SYMBOLS);
+        Ok(res.into_iter().map(|(_, sym)| sym).collect())
     }
 
     pub fn complete(
@@ -258,10 +787,65 @@ impl Workspace {
             .get(uri)
             .with_context(|| format!("Completion requested on file with no tree for {uri}"))?;
         match file.completion_context(line, character)? {
-            Some(file::CompletionContext::Message(msg)) => self.complete_types(&msg, file),
+            Some(file::CompletionContext::Message(msg)) => self.complete_types(uri, &msg, file),
             Some(file::CompletionContext::Enum(_)) => Ok(None), // TODO
             Some(file::CompletionContext::Keyword) => Ok(complete_keywords()),
-            Some(file::CompletionContext::Import) => self.complete_imports(uri),
+            Some(file::CompletionContext::Import) => self.complete_imports(uri, line, character),
+            Some(file::CompletionContext::Package) => self.complete_package(uri),
+            Some(file::CompletionContext::Rpc) => Ok(complete_rpc_body()),
+            Some(file::CompletionContext::RpcType { stream }) => {
+                self.complete_rpc_types(uri, file, stream)
+            }
+            Some(file::CompletionContext::MapKey) => Ok(complete_map_keys()),
+            Some(file::CompletionContext::Extend) => self.complete_extend_types(uri, file),
+            Some(file::CompletionContext::EnumValueNumber(n)) => {
+                Ok(Some(lsp_types::CompletionResponse::Array(vec![
+                    lsp_types::CompletionItem {
+                        label: n.to_string(),
+                        kind: Some(lsp_types::CompletionItemKind::VALUE),
+                        ..Default::default()
+                    },
+                ])))
+            }
+            Some(file::CompletionContext::DocComment { kind, name, indent }) => {
+                Ok(self.options.completion.doc_templates.then(|| {
+                    lsp_types::CompletionResponse::Array(vec![doc_template(kind, name, &indent)])
+                }))
+            }
+            Some(file::CompletionContext::FieldNumber(n)) => {
+                Ok(Some(lsp_types::CompletionResponse::Array(vec![
+                    lsp_types::CompletionItem {
+                        label: n.to_string(),
+                        kind: Some(lsp_types::CompletionItemKind::VALUE),
+                        ..Default::default()
+                    },
+                ])))
+            }
+            Some(file::CompletionContext::FieldOption) => Ok(complete_field_options(file)),
+            Some(file::CompletionContext::EnumValueName { name, number }) => {
+                Ok(Some(lsp_types::CompletionResponse::Array(vec![
+                    lsp_types::CompletionItem {
+                        label: format!("{name} = {number};"),
+                        kind: Some(lsp_types::CompletionItemKind::ENUM_MEMBER),
+                        filter_text: Some(name.clone()),
+                        insert_text: Some(format!("{name} = ${{1:{number}}};")),
+                        insert_text_format: Some(lsp_types::InsertTextFormat::SNIPPET),
+                        ..Default::default()
+                    },
+                ])))
+            }
+            // Deleted values are not tracked, so just help quote a new name.
+            Some(file::CompletionContext::ReservedName) => {
+                Ok(Some(lsp_types::CompletionResponse::Array(vec![
+                    lsp_types::CompletionItem {
+                        label: "\"NAME\"".into(),
+                        kind: Some(lsp_types::CompletionItemKind::SNIPPET),
+                        insert_text: Some("\"${1:NAME}\"".into()),
+                        insert_text_format: Some(lsp_types::InsertTextFormat::SNIPPET),
+                        ..Default::default()
+                    },
+                ])))
+            }
             Some(file::CompletionContext::Option) => {
                 Ok(Some(lsp_types::CompletionResponse::Array(
                     OPTIONS
@@ -274,19 +858,14 @@ impl Workspace {
                         .collect(),
                 )))
             }
-            Some(file::CompletionContext::Syntax) => {
-                Ok(Some(lsp_types::CompletionResponse::Array(
-                    [3, 2]
-                        .iter()
-                        .map(|n| lsp_types::CompletionItem {
-                            label: format!("syntax = \"proto{n}\";"),
-                            kind: Some(lsp_types::CompletionItemKind::TEXT),
-                            ..Default::default()
-                        })
-                        .collect(),
-                )))
-            }
-            None => Ok(None),
+            Some(file::CompletionContext::Syntax) => Ok(complete_syntax()),
+            // Explicitly tell the client there are no suggestions here.
+            None => Ok(Some(lsp_types::CompletionResponse::List(
+                lsp_types::CompletionList {
+                    is_incomplete: false,
+                    items: vec![],
+                },
+            ))),
         }
     }
 
@@ -297,17 +876,75 @@ impl Workspace {
         log::debug!("Finding definition for {ctx:?}");
         match ctx {
             None => Ok(None),
-            Some(file::GotoContext::Type(typ)) => self.find_symbol(uri, file, &typ),
+            Some(file::GotoContext::Type(typ)) => {
+                let loc = self.find_symbol(uri, file, &typ)?;
+                if let Some(name) = loc.as_ref().and_then(|l| self.type_name_at(l)) {
+                    self.use_type(name);
+                }
+                Ok(loc)
+            }
             Some(file::GotoContext::Import(name)) => {
                 log::debug!("Looking up import {name:?}");
-                Ok(self.find_import(name).map(|path| lsp_types::Location {
-                    uri: Url::from_file_path(path).unwrap(),
+                Ok(self.import_uri(&uri, name).map(|uri| lsp_types::Location {
+                    uri,
                     range: lsp_types::Range::default(),
                 }))
             }
+            // Reserved names have no definition, but may have references.
+            Some(file::GotoContext::Reserved { .. }) => Ok(None),
+            Some(file::GotoContext::OptionField { option, field }) => {
+                self.find_option_field(uri, file, option, field)
+            }
+            Some(file::GotoContext::EnumValue { typ, value }) => {
+                self.find_enum_value(uri, file, &typ, value)
+            }
         }
     }
 
+    // Return the fully qualified name of the message or enum defined at a location.
+    fn type_name_at(&self, loc: &lsp_types::Location) -> Option<String> {
+        let file = self.files.get(&loc.uri)?;
+        let mut qc = tree_sitter::QueryCursor::new();
+        let sym = file
+            .symbols(&mut qc)
+            .find(|s| is_type(s) && to_lsp_range(s.range) == loc.range)?;
+        Some(qualify(file.package(), &sym.name))
+    }
+
+    // Remember that a type was used, to suggest it first when completing.
+    pub fn use_type(&self, name: String) {
+        let mut recent = self.recent_types.borrow_mut();
+        recent.retain(|t| *t != name);
+        recent.push_front(name);
+        recent.truncate(MAX_RECENT_TYPES);
+    }
+
+    // Called when the client selects a completion item.
+    // Looking up the comment of every type would slow down large completion lists,
+    // so the details of a type are only attached once it is selected.
+    pub fn resolve_completion(
+        &self,
+        mut item: lsp_types::CompletionItem,
+    ) -> Result<lsp_types::CompletionItem> {
+        let Some(data) = item.data.clone() else {
+            return Ok(item);
+        };
+        let loc: lsp_types::Location = serde_json::from_value(data)?;
+        let file = self.get(&loc.uri)?;
+        let mut qc = tree_sitter::QueryCursor::new();
+        let Some(sym) = file
+            .symbols(&mut qc)
+            .find(|s| to_lsp_range(s.range) == loc.range)
+        else {
+            return Ok(item);
+        };
+        item.documentation = file
+            .leading_comment(&sym)
+            .map(lsp_types::Documentation::String);
+        item.detail = Some(qualify(file.package(), &sym.name));
+        Ok(item)
+    }
+
     pub fn references(
         &mut self,
         params: lsp_types::ReferenceParams,
@@ -328,16 +965,33 @@ impl Workspace {
         let mut res = Vec::new();
         match &item {
             file::GotoContext::Type(t) => {
-                let src = self
+                let decl = self
                     .find_symbol(uri.clone(), file, &t)?
                     .with_context(|| format!("Symbol not found: {t:?}"))?;
-                let src = self.get(&src.uri)?;
+                let src = self.get(&decl.uri)?;
                 let pkg = src.package();
+                let mut qc = tree_sitter::QueryCursor::new();
+                let name = src
+                    .symbols(&mut qc)
+                    .find(|s| to_lsp_range(s.range) == decl.range)
+                    .map(|s| s.name);
+                if params.context.include_declaration {
+                    res.push(decl);
+                }
+                // The name at the cursor may be relative to its scope, e.g. Inner in Outer,
+                // so match by the qualified name of the declaration where it is known.
+                let qualified = name.as_deref().map(|name| file::GotoTypeContext {
+                    name,
+                    parent: None,
+                    message_only: false,
+                });
+                let target = qualified.as_ref().unwrap_or(t);
                 for (uri, file) in self.files.iter() {
-                    res.extend(file.type_references(pkg, t).iter().map(|range| {
+                    self.check_shutdown()?;
+                    res.extend(file.type_references(pkg, target).into_iter().map(|range| {
                         lsp_types::Location {
                             uri: uri.clone(),
-                            range: to_lsp_range(*range),
+                            range: to_lsp_range(range),
                         }
                     }));
                 }
@@ -352,779 +1006,5921 @@ impl Workspace {
                     }));
                 }
             }
+            // A reserved name should not be reused by a field of the message reserving it.
+            file::GotoContext::Reserved { message, name } => {
+                res.extend(file.field_references(message, name).iter().map(|range| {
+                    lsp_types::Location {
+                        uri: uri.clone(),
+                        range: to_lsp_range(*range),
+                    }
+                }));
+            }
+            // Fields set in option values and enum values are not indexed.
+            file::GotoContext::OptionField { .. } | file::GotoContext::EnumValue { .. } => {}
         };
 
         Ok(Some(res))
     }
 
-    fn find_symbol(
+    // Return the message at the position, or the message referenced by the type at the
+    // position, as the root of a hierarchy of messages composed of each other.
+    pub fn prepare_call_hierarchy(
         &self,
-        uri: Url,
-        file: &file::File,
-        typ: &file::GotoTypeContext,
-    ) -> Result<Option<lsp_types::Location>> {
-        let mut qc = tree_sitter::QueryCursor::new();
-
-        // First look within the file, qualifying the name if it is nested.
-        if let Some(sym) = typ.parent.as_ref().and_then(|p| {
-            let qualified = format!("{p}.{}", typ.name);
-            log::trace!("Searching for {qualified} in {uri}");
-            file.symbols(&mut qc).find(|sym| sym.name == qualified)
-        }) {
-            return Ok(Some(lsp_types::Location {
-                uri,
-                range: to_lsp_range(sym.range),
-            }));
-        }
-
-        log::trace!("Searching for {} in {uri}", typ.name);
-        // Next look within the file for the unqualified name.
-        if let Some(sym) = file.symbols(&mut qc).find(|s| s.name == typ.name) {
-            return Ok(Some(lsp_types::Location {
-                uri,
-                range: to_lsp_range(sym.range),
-            }));
+        uri: &Url,
+        pos: lsp_types::Position,
+    ) -> Result<Option<Vec<lsp_types::CallHierarchyItem>>> {
+        let file = self.get(uri)?;
+        let (row, col) = (pos.line.try_into()?, pos.character.try_into()?);
+        let (uri, range) = match file.type_at(row, col) {
+            Some(file::GotoContext::Type(typ)) => {
+                match self.find_symbol(uri.clone(), file, &typ)? {
+                    Some(loc) => (loc.uri, loc.range),
+                    None => return Ok(None),
+                }
+            }
+            _ => (uri.clone(), lsp_types::Range::new(pos, pos)),
         };
+        let target = self.get(&uri)?;
+        let item = enclosing_message(target, range)
+            .map(|sym| to_call_hierarchy_item(uri, target.package(), &sym));
+        Ok(item.map(|item| vec![item]))
+    }
 
-        // If the type is nested, try the fully qualified name
-        log::trace!("Searching for {} in {uri}", typ.name);
+    // Return the messages with a field of the given message's type.
+    pub fn incoming_calls(
+        &mut self,
+        item: lsp_types::CallHierarchyItem,
+    ) -> Result<Option<Vec<lsp_types::CallHierarchyIncomingCall>>> {
+        self.load_all()?;
+
+        let target = self.get(&item.uri)?;
         let mut qc = tree_sitter::QueryCursor::new();
-        if let Some(sym) = file.symbols(&mut qc).find(|s| s.name == typ.name) {
-            return Ok(Some(lsp_types::Location {
-                uri,
-                range: to_lsp_range(sym.range),
-            }));
+        let Some(sym) = target
+            .symbols(&mut qc)
+            .filter(is_message)
+            .find(|s| to_lsp_range(s.range) == item.range)
+        else {
+            return Ok(None);
+        };
+        let typ = file::GotoTypeContext {
+            name: &sym.name,
+            parent: None,
+            message_only: false,
         };
 
-        // Next look within the file imports.
-        let mut qc = tree_sitter::QueryCursor::new();
-        let imports = file
-            .imports(&mut qc)
-            .filter_map(|name| self.find_import(name))
-            .map(|path| Url::from_file_path(path).unwrap())
-            .map(|uri| (uri.clone(), self.get(&uri).unwrap()));
-
-        let mut qc = tree_sitter::QueryCursor::new();
-        let local_package = file.package();
-        for (uri, file) in imports {
-            let package = file.package();
-            if let Some(sym) = if package == local_package {
-                log::trace!("Searching for {} in {uri} (same package)", typ.name);
-                // same package, match the name without the package prefix
-                file.symbols(&mut qc).find(|sym| sym.name == typ.name)
-            } else if let Some(package) = package {
-                log::trace!("Searching for {} in {uri} (different package)", typ.name);
-                // different package, fully qualify the name
-                let local_package = local_package.unwrap_or("");
-                file.symbols(&mut qc).find(|sym| {
-                    let quals = possible_qualifiers(package, local_package);
-                    log::trace!("Qualifiers: {quals:?}");
-                    quals
-                        .iter()
-                        .inspect(|q| log::trace!("Qual == {q}"))
-                        .filter_map(|qual| typ.name.strip_prefix(qual))
-                        .inspect(|q| log::trace!("stripped == {q}"))
-                        .filter_map(|name| name.strip_prefix("."))
-                        .inspect(|q| log::trace!("name == {q} == {}", sym.name))
-                        .any(|name| name == sym.name)
-                })
-            } else {
-                // target file has no package
-                log::trace!("Searching for {} in {uri}", typ.name);
-                file.symbols(&mut qc).find(|sym| sym.name == typ.name)
-            } {
-                return Ok(Some(lsp_types::Location {
-                    uri,
-                    range: to_lsp_range(sym.range),
-                }));
+        let mut res: Vec<lsp_types::CallHierarchyIncomingCall> = vec![];
+        for (uri, file) in self.files.iter() {
+            self.check_shutdown()?;
+            let refs = file.type_references(target.package(), &typ);
+            if refs.is_empty() {
+                continue;
+            }
+            let mut qc = tree_sitter::QueryCursor::new();
+            let messages: Vec<_> = file.symbols(&mut qc).filter(is_message).collect();
+            for range in refs {
+                // Fields of nested messages belong to the nested message, which comes last.
+                let Some(from) = messages.iter().rev().find(|s| {
+                    s.range.start_byte <= range.start_byte && range.end_byte <= s.range.end_byte
+                }) else {
+                    continue; // e.g. an rpc type
+                };
+                let range = to_lsp_range(range);
+                let from = to_call_hierarchy_item(uri.clone(), file.package(), from);
+                match res.iter_mut().find(|call| call.from == from) {
+                    Some(call) => call.from_ranges.push(range),
+                    None => res.push(lsp_types::CallHierarchyIncomingCall {
+                        from,
+                        from_ranges: vec![range],
+                    }),
+                }
             }
         }
-        Ok(None)
+        Ok(Some(res))
     }
 
-    fn complete_types(
+    // Return the messages used as the type of a field in the given message.
+    pub fn outgoing_calls(
         &self,
-        base_name: &str,
-        file: &file::File,
-    ) -> Result<Option<lsp_types::CompletionResponse>> {
-        let current_package = file.package();
-        let mut qc = QueryCursor::new();
-        let mut items: Vec<_> = file
-            .relative_symbols(base_name, &mut qc)
-            .map(to_lsp_completion)
-            .collect();
+        item: lsp_types::CallHierarchyItem,
+    ) -> Result<Option<Vec<lsp_types::CallHierarchyOutgoingCall>>> {
+        let file = self.get(&item.uri)?;
 
-        let imports = file
-            .imports(&mut qc)
-            .filter_map(|name| self.find_import(name))
-            .map(|path| Url::from_file_path(path).unwrap())
-            .map(|uri| self.get(&uri).unwrap());
-
-        for file in imports {
-            let package = file.package();
-            if package.is_none() || package == current_package {
-                let mut qc = tree_sitter::QueryCursor::new();
-                items.extend(file.symbols(&mut qc).map(to_lsp_completion));
-            } else if let Some(package) = package {
-                let mut qc = tree_sitter::QueryCursor::new();
-                items.extend(
-                    file.symbols(&mut qc)
-                        .map(|s| file::Symbol {
-                            name: package.to_owned() + "." + &s.name,
-                            ..s
-                        })
-                        .map(to_lsp_completion),
-                );
+        let mut res: Vec<lsp_types::CallHierarchyOutgoingCall> = vec![];
+        for (_, range) in file.type_refs() {
+            let range = to_lsp_range(range);
+            // Fields of nested messages belong to the nested message.
+            let from = enclosing_message(file, range).map(|sym| to_lsp_range(sym.range));
+            if from != Some(item.range) {
+                continue;
+            }
+            let Some(loc) = self.resolve_type_ref(&item.uri, file, range)? else {
+                continue;
+            };
+            let target = self.get(&loc.uri)?;
+            let mut qc = tree_sitter::QueryCursor::new();
+            let Some(sym) = target
+                .symbols(&mut qc)
+                .filter(is_message)
+                .find(|s| to_lsp_range(s.range) == loc.range)
+            else {
+                continue;
+            };
+            let to = to_call_hierarchy_item(loc.uri, target.package(), &sym);
+            match res.iter_mut().find(|call| call.to == to) {
+                Some(call) => call.from_ranges.push(range),
+                None => res.push(lsp_types::CallHierarchyOutgoingCall {
+                    to,
+                    from_ranges: vec![range],
+                }),
             }
         }
-
-        let builtins = [
-            "bool", "bytes", "double", "fixed32", "fixed64", "float", "int32", "int64", "sfixed32",
-            "sfixed64", "sint32", "sint64", "string", "uint32", "uint64",
-        ]
-        .map(|s| lsp_types::CompletionItem {
-            label: s.to_string(),
-            kind: Some(lsp_types::CompletionItemKind::STRUCT),
-            ..Default::default()
-        });
-        items.extend(builtins);
-
-        let keywords = [
-            "enum", "extend", "import", "message", "oneof", "option", "optional", "package",
-            "repeated", "reserved", "returns", "rpc", "service", "stream", "map",
-        ]
-        .map(|s| lsp_types::CompletionItem {
-            label: s.to_string(),
-            kind: Some(lsp_types::CompletionItemKind::KEYWORD),
-            ..Default::default()
-        });
-        items.extend(keywords);
-
-        Ok(Some(lsp_types::CompletionResponse::Array(items)))
+        Ok(Some(res))
     }
 
-    fn complete_imports(
+    // Return the location of the definition of the type referenced at the range.
+    fn resolve_type_ref(
         &self,
-        url: &lsp_types::Url,
-    ) -> Result<Option<lsp_types::CompletionResponse>> {
-        log::debug!("Completing imports for {url:?}");
+        uri: &Url,
+        file: &file::File,
+        range: lsp_types::Range,
+    ) -> Result<Option<lsp_types::Location>> {
+        let pos = range.start;
+        let (row, col) = (pos.line.try_into()?, pos.character.try_into()?);
+        match file.type_at(row, col) {
+            Some(file::GotoContext::Type(typ)) => self.find_symbol(uri.clone(), file, &typ),
+            _ => Ok(None),
+        }
+    }
 
-        let current = std::path::Path::new(url.path())
-            .file_name()
-            .with_context(|| format!("Invalid path: {url}"))?
-            .to_str()
-            .with_context(|| format!("Invalid path: {url}"))?;
+    // Show the JSON name of a field, to help debug JSON encoding.
+    pub fn hover(&self, uri: &Url, pos: lsp_types::Position) -> Result<Option<lsp_types::Hover>> {
+        let file = self.get(uri)?;
+        let Some(json) = file.json_name_at(pos.line.try_into()?, pos.character.try_into()?) else {
+            return Ok(None);
+        };
+        Ok(Some(lsp_types::Hover {
+            contents: lsp_types::HoverContents::Markup(lsp_types::MarkupContent {
+                kind: lsp_types::MarkupKind::Markdown,
+                value: format!("`{}`\n\nJSON name: `{}`", json.name, json.json_name),
+            }),
+            range: Some(to_lsp_range(json.name_range)),
+        }))
+    }
 
-        let file = self
-            .files
-            .get(url)
-            .with_context(|| format!("File not loaded: {url}"))?;
+    // Return a stable identifier for the symbol at the position, qualified by its package,
+    // e.g. main.Foo.Bar. A type reference is identified by the symbol it refers to.
+    pub fn moniker(
+        &self,
+        uri: &Url,
+        pos: lsp_types::Position,
+    ) -> Result<Option<lsp_types::Moniker>> {
+        let file = self.get(uri)?;
         let mut qc = tree_sitter::QueryCursor::new();
-        let existing = file
-            .imports(&mut qc)
-            .chain(std::iter::once(current))
-            .collect::<Vec<_>>();
-
-        log::trace!("Excluding existing imports: {existing:?}");
-
-        let items = self
-            .proto_paths
-            .iter()
-            .map(|p| find_protos(p.as_path()))
-            .flat_map(|p| {
-                p.iter()
-                    .filter(|s| !existing.contains(&s.as_str()))
-                    .map(|s| lsp_types::CompletionItem {
-                        insert_text: Some(format!("{}\";", s)),
-                        label: s.to_owned(),
-                        label_details: None,
-                        kind: Some(lsp_types::CompletionItemKind::FILE),
-                        ..Default::default()
+        let (row, col) = (pos.line.try_into()?, pos.character.try_into()?);
+        let (target, sym, kind) = match file.type_at(row, col) {
+            Some(file::GotoContext::Type(typ)) => {
+                let Some(loc) = self.find_symbol(uri.clone(), file, &typ)? else {
+                    return Ok(None);
+                };
+                let kind = match loc.uri == *uri {
+                    true => lsp_types::MonikerKind::Export,
+                    false => lsp_types::MonikerKind::Import,
+                };
+                let target = self.get(&loc.uri)?;
+                let sym = target
+                    .symbols(&mut qc)
+                    .find(|s| to_lsp_range(s.range) == loc.range);
+                (target, sym, kind)
+            }
+            // Symbols are ordered by position, so the last one containing pos is the innermost.
+            _ => {
+                let sym = file
+                    .symbols(&mut qc)
+                    .filter(|s| {
+                        let range = to_lsp_range(s.range);
+                        range.start <= pos && pos < range.end
                     })
-                    .collect::<Vec<_>>()
-            })
-            .collect();
-        Ok(Some(lsp_types::CompletionResponse::Array(items)))
+                    .last();
+                (file, sym, lsp_types::MonikerKind::Export)
+            }
+        };
+        let Some(sym) = sym else {
+            return Ok(None);
+        };
+        Ok(Some(lsp_types::Moniker {
+            scheme: String::from("protobuf"),
+            identifier: qualify(target.package(), &sym.name),
+            unique: lsp_types::UniquenessLevel::Scheme,
+            kind: Some(kind),
+        }))
     }
-}
 
-fn find_protos(dir: &std::path::Path) -> Vec<String> {
-    let mut res = vec![];
-    let entries = match std::fs::read_dir(dir) {
-        Ok(ok) => ok,
-        Err(err) => {
-            log::warn!("Failed to read dir {dir:?}: {err:?}");
-            return res;
+    // Fold each multi-line message, enum, service, and oneof, and the block of imports.
+    pub fn folding_ranges(&self, uri: &Url) -> Result<Vec<lsp_types::FoldingRange>> {
+        let file = self.get(uri)?;
+        let mut res = vec![];
+        if let Some((start, end)) = file.import_lines().filter(|(start, end)| start < end) {
+            res.push(lsp_types::FoldingRange {
+                start_line: start.try_into()?,
+                end_line: end.try_into()?,
+                kind: Some(lsp_types::FoldingRangeKind::Imports),
+                ..Default::default()
+            });
         }
-    };
-    log::trace!("Finding imports under {dir:?}");
-    for path in entries {
-        let path = match path {
-            Ok(ok) => ok,
-            Err(err) => {
-                log::warn!("Failed to read dir {dir:?}: {err:?}");
-                continue;
-            }
-        };
 
-        let meta = match path.metadata() {
-            Ok(ok) => ok,
-            Err(err) => {
-                log::warn!("Failed to read dir {dir:?}: {err:?}");
-                continue;
+        let mut qc = tree_sitter::QueryCursor::new();
+        for sym in file.symbols(&mut qc) {
+            let (start, end) = (sym.range.start_point.row, sym.range.end_point.row);
+            if start < end && sym.kind != file::SymbolKind::Field {
+                res.push(lsp_types::FoldingRange {
+                    start_line: start.try_into()?,
+                    end_line: end.try_into()?,
+                    ..Default::default()
+                });
             }
-        };
-
-        if meta.is_dir() {
-            let dir = dir.join(path.path());
-            let protos = find_protos(dir.as_path());
-            let root = &path.file_name();
-            let root = std::path::PathBuf::from(root);
-            res.extend(
-                protos
-                    .iter()
-                    .filter_map(|p| root.join(p).to_str().map(str::to_string)),
-            );
-            continue;
         }
+        Ok(res)
+    }
 
-        if !meta.is_file() {
-            continue;
+    // Return the ranges of the type under the cursor and its other uses in the same file,
+    // so they can be edited together. Only the last part of qualified names is included.
+    // Types defined in another file are left alone, as editing their uses would not rename them.
+    pub fn linked_editing_ranges(
+        &self,
+        uri: &Url,
+        pos: lsp_types::Position,
+    ) -> Result<Option<lsp_types::LinkedEditingRanges>> {
+        let file = self.get(uri)?;
+        let Some(file::GotoContext::Type(typ)) =
+            file.type_at(pos.line.try_into()?, pos.character.try_into()?)
+        else {
+            return Ok(None);
+        };
+        let Some(decl) = self.find_symbol(uri.clone(), file, &typ)? else {
+            return Ok(None);
+        };
+        if &decl.uri != uri {
+            return Ok(None);
         }
 
-        let name = &path.file_name();
-        let Some(name) = name.to_str() else {
-            continue;
+        let point = |p: lsp_types::Position| tree_sitter::Point {
+            row: p.line as usize,
+            column: p.character as usize,
         };
+        let mut ranges = vec![];
+        ranges.extend(
+            file.definition_name(point(decl.range.start), point(decl.range.end))
+                .map(to_lsp_range),
+        );
 
-        if !name.ends_with(".proto") {
-            continue;
+        let pkg = file.package();
+        for range in file.type_references(pkg, &typ) {
+            let text = &file.text()[range.start_byte..range.end_byte];
+            let mut range = to_lsp_range(range);
+            if let Some(i) = text.rfind('.') {
+                range.start.character += u32::try_from(i + 1)?;
+            }
+            ranges.push(range);
         }
 
-        log::trace!("Found import {name:?}");
-        res.push(name.to_string())
+        Ok(Some(lsp_types::LinkedEditingRanges {
+            ranges,
+            word_pattern: Some(String::from("[A-Za-z_][A-Za-z0-9_]*")),
+        }))
     }
-    res
-}
 
-fn complete_keywords() -> Option<lsp_types::CompletionResponse> {
-    let items = ["message", "enum", "import", "option"]
-        .iter()
-        .map(|s| lsp_types::CompletionItem {
-            label: s.to_string(),
-            kind: Some(lsp_types::CompletionItemKind::KEYWORD),
+    // Insert a message generated from a JSON sample at the given position.
+    pub fn message_from_json(
+        &self,
+        uri: &Url,
+        pos: lsp_types::Position,
+        name: &str,
+        json: &serde_json::Value,
+    ) -> Result<lsp_types::WorkspaceEdit> {
+        self.get(uri)?;
+        let edit = lsp_types::TextEdit {
+            range: lsp_types::Range::new(pos, pos),
+            new_text: generate::message(name, json)?,
+        };
+        Ok(lsp_types::WorkspaceEdit {
+            changes: Some(std::collections::HashMap::from([(uri.clone(), vec![edit])])),
             ..Default::default()
-        });
-    Some(lsp_types::CompletionResponse::Array(items.collect()))
-}
-
-fn to_lsp_pos(p: tree_sitter::Point) -> lsp_types::Position {
-    lsp_types::Position {
-        line: p.row.try_into().unwrap(),
-        character: p.column.try_into().unwrap(),
+        })
     }
-}
 
-fn to_lsp_range(r: tree_sitter::Range) -> lsp_types::Range {
-    lsp_types::Range {
-        start: to_lsp_pos(r.start_point),
-        end: to_lsp_pos(r.end_point),
-    }
-}
+    // Return the range of the package name under the cursor, the only thing that can be renamed.
+    pub fn prepare_rename(
+        &self,
+        params: lsp_types::TextDocumentPositionParams,
+    ) -> Result<Option<lsp_types::PrepareRenameResponse>> {
+        let file = self.get(&params.text_document.uri)?;
+        check_renamable(file, params.position)?;
 
-fn to_lsp_symbol(uri: Url, sym: file::Symbol) -> lsp_types::SymbolInformation {
-    // deprecated field is deprecated, but cannot be omitted
-    #[allow(deprecated)]
-    lsp_types::SymbolInformation {
-        name: sym.name,
-        kind: match sym.kind {
-            file::SymbolKind::Enum => lsp_types::SymbolKind::ENUM,
-            file::SymbolKind::Message => lsp_types::SymbolKind::STRUCT,
-        },
-        tags: None,
-        deprecated: None,
-        location: lsp_types::Location {
-            uri,
-            range: lsp_types::Range {
-                start: to_lsp_pos(sym.range.start_point),
-                end: to_lsp_pos(sym.range.end_point),
-            },
-        },
-        container_name: None,
+        // Only packages can be renamed.
+        let Some(range) = file.package_range().map(to_lsp_range) else {
+            return Ok(None);
+        };
+        if params.position < range.start || params.position > range.end {
+            return Ok(None);
+        }
+        Ok(Some(lsp_types::PrepareRenameResponse::Range(range)))
     }
-}
 
-fn to_lsp_completion(sym: file::Symbol) -> lsp_types::CompletionItem {
-    lsp_types::CompletionItem {
-        label: sym.name,
-        kind: Some(match sym.kind {
-            file::SymbolKind::Enum => lsp_types::CompletionItemKind::ENUM,
-            file::SymbolKind::Message => lsp_types::CompletionItemKind::STRUCT,
-        }),
-        ..Default::default()
-    }
-}
+    // Rename the package declared in the file, updating all references to it.
+    pub fn rename(
+        &mut self,
+        params: lsp_types::RenameParams,
+    ) -> Result<Option<lsp_types::WorkspaceEdit>> {
+        let doc = params.text_document_position;
+        check_renamable(self.get(&doc.text_document.uri)?, doc.position)?;
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use pretty_assertions::assert_eq;
+        self.load_all()?;
 
-    fn setup() -> (Workspace, tempfile::TempDir) {
-        let _ = env_logger::builder().is_test(true).try_init();
-        let tmp = tempfile::tempdir().unwrap();
-        (Workspace::new(vec![tmp.path().into()]), tmp)
-    }
+        let file = self.get(&doc.text_document.uri)?;
+        let (Some(old), Some(range)) = (file.package(), file.package_range()) else {
+            return Ok(None);
+        };
 
-    fn proto(dir: impl AsRef<std::path::Path>, path: &str, lines: &[&str]) -> (Url, String) {
-        let path = dir.as_ref().join(path);
-        let text = lines.join("\n") + "\n";
-        std::fs::write(&path, &text).unwrap();
-        (Url::from_file_path(path).unwrap(), text)
+        // Only packages can be renamed.
+        let range = to_lsp_range(range);
+        if doc.position < range.start || doc.position > range.end {
+            return Ok(None);
+        }
+
+        let new = params.new_name.as_str();
+        if !is_full_ident(new) {
+            return Err(anyhow!("Invalid package name: {new}"));
+        }
+
+        Ok(Some(lsp_types::WorkspaceEdit {
+            changes: Some(self.rename_package(old, new)),
+            ..Default::default()
+        }))
     }
 
-    #[test]
-    fn test_open_loop() {
-        let (mut ws, tmp) = setup();
-        let (uri, text) = proto(
-            &tmp,
-            "foo.proto",
-            &["syntax = \"proto3\";", "import \"bar.proto\";"],
-        );
+    // Compute the edits to rename package old to new across all loaded files.
+    fn rename_package(
+        &self,
+        old: &str,
+        new: &str,
+    ) -> std::collections::HashMap<Url, Vec<lsp_types::TextEdit>> {
+        let types = self.package_types(old);
+        let mut res = std::collections::HashMap::new();
+        for (uri, file) in &self.files {
+            let pkg = file.package().unwrap_or("");
+            let new_pkg = if pkg == old { new } else { pkg };
+            // Types in the file's own package shadow those in the renamed package.
+            let local = if pkg == old {
+                Default::default()
+            } else {
+                self.package_types(pkg)
+            };
+
+            let mut edits = vec![];
+            if pkg == old {
+                edits.extend(file.package_range().map(|range| lsp_types::TextEdit {
+                    range: to_lsp_range(range),
+                    new_text: new.into(),
+                }));
+            }
+
+            // Extend blocks name a message just like a field type does.
+            for (text, range) in file.type_refs().into_iter().chain(file.extendee_refs()) {
+                if local.contains(text) {
+                    continue;
+                }
+                let Some(new_text) = requalify(text, &types, old, pkg, new, new_pkg) else {
+                    continue;
+                };
+                if new_text != text {
+                    edits.push(lsp_types::TextEdit {
+                        range: to_lsp_range(range),
+                        new_text,
+                    });
+                }
+            }
+
+            if !edits.is_empty() {
+                res.insert(uri.clone(), edits);
+            }
+        }
+        res
+    }
+
+    // Return the names of all types defined in the given package.
+    fn package_types(&self, pkg: &str) -> std::collections::HashSet<String> {
+        let mut qc = tree_sitter::QueryCursor::new();
+        let mut res = std::collections::HashSet::new();
+        for file in self.files.values() {
+            if file.package().unwrap_or("") == pkg {
+                res.extend(file.symbols(&mut qc).filter(is_type).map(|s| s.name));
+            }
+        }
+        res
+    }
+
+    pub fn code_actions(
+        &self,
+        params: lsp_types::CodeActionParams,
+    ) -> Result<Vec<lsp_types::CodeActionOrCommand>> {
+        let uri = params.text_document.uri;
+        let file = self.get(&uri)?;
+        let pos = params.range.start;
+        let mut res = vec![];
+
+        if let Some(action) =
+            toggle_optional(&uri, file, pos.line.try_into()?, pos.character.try_into()?)
+        {
+            res.push(lsp_types::CodeActionOrCommand::CodeAction(action));
+        }
+
+        for diag in params.context.diagnostics {
+            if let Some(action) = remove_unused_import(&uri, file, diag) {
+                res.push(lsp_types::CodeActionOrCommand::CodeAction(action));
+            }
+        }
+
+        if let Some(action) = sort_imports(&uri, file) {
+            res.push(lsp_types::CodeActionOrCommand::CodeAction(action));
+        }
+
+        if let Some(action) = extract_nested(&uri, file, pos)? {
+            res.push(lsp_types::CodeActionOrCommand::CodeAction(action));
+        }
+
+        Ok(res)
+    }
+
+    // Find the field set by a key in an aggregate option value, by resolving the type
+    // of the extension the option sets. Only fields of a message type are resolved.
+    fn find_option_field(
+        &self,
+        uri: Url,
+        file: &file::File,
+        option: &str,
+        field: &str,
+    ) -> Result<Option<lsp_types::Location>> {
+        // The option names an extension field, resolved like a type reference.
+        let ext = file::GotoTypeContext {
+            name: option,
+            parent: None,
+            message_only: false,
+        };
+        let Some(loc) = self.find_in(uri, file, &ext, &|file, _| {
+            Box::new(file.extensions().into_iter().map(|(sym, _)| sym))
+        })?
+        else {
+            return Ok(None);
+        };
+        let ext_file = self.get(&loc.uri)?;
+        let Some((sym, typ)) = ext_file
+            .extensions()
+            .into_iter()
+            .find(|(sym, _)| to_lsp_range(sym.range) == loc.range)
+        else {
+            return Ok(None);
+        };
+
+        // The extension's type is relative to the scope of the extend block.
+        let typ = file::GotoTypeContext {
+            name: typ,
+            parent: sym.name.rsplit_once('.').map(|(p, _)| p.to_string()),
+            message_only: true,
+        };
+        let Some(loc) = self.find_symbol(loc.uri, ext_file, &typ)? else {
+            return Ok(None);
+        };
+        let target = self.get(&loc.uri)?;
+        let mut qc = tree_sitter::QueryCursor::new();
+        let Some(message) = target
+            .symbols(&mut qc)
+            .find(|s| to_lsp_range(s.range) == loc.range)
+            .filter(is_message)
+        else {
+            return Ok(None);
+        };
+        Ok(target
+            .field_range(&message.name, field)
+            .map(|range| lsp_types::Location {
+                uri: loc.uri,
+                range: to_lsp_range(range),
+            }))
+    }
+
+    // Compute the edit of a code action that was deferred until the client chose it.
+    // Only extracting a nested type is deferred, as it must resolve every type reference.
+    pub fn resolve_code_action(
+        &self,
+        mut action: lsp_types::CodeAction,
+    ) -> Result<lsp_types::CodeAction> {
+        let Some(data) = action.data.take() else {
+            return Ok(action);
+        };
+        let params: lsp_types::TextDocumentPositionParams = serde_json::from_value(data)?;
+        let uri = params.text_document.uri;
+        let file = self.get(&uri)?;
+        let Some(edits) = self.extract_nested_edits(&uri, file, params.position)? else {
+            return Ok(action);
+        };
+        action.edit = Some(lsp_types::WorkspaceEdit {
+            changes: Some(std::collections::HashMap::from([(uri, edits)])),
+            ..Default::default()
+        });
+        Ok(action)
+    }
+
+    // Move the nested type to the end of the file, along with its doc comment, and update the
+    // references to it in the file.
+    fn extract_nested_edits(
+        &self,
+        uri: &Url,
+        file: &file::File,
+        pos: lsp_types::Position,
+    ) -> Result<Option<Vec<lsp_types::TextEdit>>> {
+        let Some(sym) = nested_type_at(file, pos) else {
+            return Ok(None);
+        };
+        let Some((parent, _)) = sym.name.rsplit_once('.') else {
+            return Ok(None);
+        };
+
+        // Qualify a new name the way the reference was qualified by package, if at all.
+        let pkg = file.package();
+        let qualify = |text: &str, name: &str| match pkg {
+            Some(pkg) if text.starts_with('.') => format!(".{pkg}.{name}"),
+            Some(pkg) if text.starts_with(&format!("{pkg}.")) => format!("{pkg}.{name}"),
+            _ if text.starts_with('.') => format!(".{name}"),
+            _ => name.to_string(),
+        };
+
+        let node = sym.range;
+        let text = file.text();
+        // The moved text starts at the doc comment, if any.
+        let row = file.leading_comment_row(&sym);
+        let start = match row < node.start_point.row {
+            true => {
+                let line = text
+                    .split_inclusive('\n')
+                    .take(row)
+                    .map(str::len)
+                    .sum::<usize>();
+                let rest = &text[line..];
+                line + rest.len() - rest.trim_start().len()
+            }
+            false => node.start_byte,
+        };
+        let mut moved = vec![];
+        let mut edits = vec![];
+        for (ref_text, range) in file.type_refs() {
+            let Some(loc) = self.resolve_type_ref(uri, file, to_lsp_range(range))? else {
+                continue;
+            };
+            if loc.uri != *uri {
+                continue;
+            }
+            let mut qc = tree_sitter::QueryCursor::new();
+            let Some(target) = file
+                .symbols(&mut qc)
+                .find(|s| to_lsp_range(s.range) == loc.range)
+                .map(|s| s.name)
+            else {
+                continue;
+            };
+            let inside = node.start_byte <= range.start_byte && range.end_byte <= node.end_byte;
+            let new_text = if target == sym.name || target.starts_with(&(sym.name.clone() + ".")) {
+                // e.g. Outer.Inner.Deep -> Inner.Deep
+                qualify(ref_text, &target[parent.len() + 1..])
+            } else if inside && ref_text != target && !ref_text.contains(&target) {
+                // The moved type can no longer refer to its old siblings by a relative name.
+                target
+            } else {
+                continue;
+            };
+            if new_text == ref_text {
+                continue;
+            }
+            if inside {
+                let (ref_start, ref_end) = (range.start_byte, range.end_byte);
+                moved.push((ref_start - start, ref_end - start, new_text));
+            } else {
+                edits.push(lsp_types::TextEdit {
+                    range: to_lsp_range(range),
+                    new_text,
+                });
+            }
+        }
+
+        let mut body = text[start..node.end_byte].to_string();
+        for (ref_start, ref_end, new_text) in moved.into_iter().rev() {
+            body.replace_range(ref_start..ref_end, &new_text);
+        }
+        // Remove the indentation of the nested type from each of its lines.
+        let indent = node.start_point.column;
+        let body = body
+            .lines()
+            .map(|l| &l[(l.len() - l.trim_start().len()).min(indent)..])
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        // Delete the nested type, including the rest of its lines if nothing else is on them.
+        let (mut start, mut end) = (start, node.end_byte);
+        let line_start = text[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = text[end..].find('\n').map_or(text.len(), |i| end + i + 1);
+        if text[line_start..start].trim().is_empty() && text[end..line_end].trim().is_empty() {
+            (start, end) = (line_start, line_end);
+        }
+        edits.push(lsp_types::TextEdit {
+            range: lsp_types::Range::new(byte_position(text, start)?, byte_position(text, end)?),
+            new_text: "".into(),
+        });
+        let eof = byte_position(text, text.len())?;
+        let sep = if text.ends_with('\n') { "\n" } else { "\n\n" };
+        edits.push(lsp_types::TextEdit {
+            range: lsp_types::Range::new(eof, eof),
+            new_text: format!("{sep}{body}\n"),
+        });
+        Ok(Some(edits))
+    }
+
+    // Find the declaration of a value of the enum with the given type.
+    fn find_enum_value(
+        &self,
+        uri: Url,
+        file: &file::File,
+        typ: &file::GotoTypeContext,
+        value: &str,
+    ) -> Result<Option<lsp_types::Location>> {
+        let Some(loc) = self.find_symbol(uri, file, typ)? else {
+            return Ok(None);
+        };
+        let target = self.get(&loc.uri)?;
+        let mut qc = tree_sitter::QueryCursor::new();
+        let Some(sym) = target
+            .symbols(&mut qc)
+            .find(|s| to_lsp_range(s.range) == loc.range)
+            .filter(|s| s.kind == file::SymbolKind::Enum)
+        else {
+            return Ok(None);
+        };
+        Ok(target
+            .enum_value_range(&sym.name, value)
+            .map(|range| lsp_types::Location {
+                uri: loc.uri,
+                range: to_lsp_range(range),
+            }))
+    }
+
+    fn find_symbol(
+        &self,
+        uri: Url,
+        file: &file::File,
+        typ: &file::GotoTypeContext,
+    ) -> Result<Option<lsp_types::Location>> {
+        self.find_in(uri, file, typ, &|file, qc| {
+            Box::new(file.symbols(qc).filter(is_definition))
+        })
+    }
+
+    // Resolve a name against the candidates yielded by `symbols` for the file and its imports,
+    // following the same scoping and package rules as a type reference.
+    fn find_in(
+        &self,
+        uri: Url,
+        file: &file::File,
+        typ: &file::GotoTypeContext,
+        symbols: &Candidates,
+    ) -> Result<Option<lsp_types::Location>> {
+        // A message and enum may share a name across imports, but an rpc type must be a message.
+        let keep = |sym: &file::Symbol| !typ.message_only || is_message(sym);
+        // Prefer a definition with a body over a declaration without one that shares its name,
+        // e.g. `message One {}` over an incomplete `message One`.
+        let find = |file: &file::File, pred: &dyn Fn(&file::Symbol) -> bool| {
+            let mut qc = tree_sitter::QueryCursor::new();
+            let mut found = symbols(file, &mut qc).filter(|sym| keep(sym) && pred(sym));
+            let first = found.next()?;
+            if file.has_body(&first) {
+                return Some(first);
+            }
+            found.find(|sym| file.has_body(sym)).or(Some(first))
+        };
+
+        // First look within the file, qualifying the name by each enclosing scope,
+        // innermost first. This also resolves a nested message referring to itself.
+        let mut scope = typ.parent.as_deref();
+        while let Some(p) = scope {
+            let qualified = format!("{p}.{}", typ.name);
+            log::trace!("Searching for {qualified} in {uri}");
+            if let Some(sym) = find(file, &|sym| sym.name == qualified) {
+                return Ok(Some(lsp_types::Location {
+                    uri,
+                    range: to_lsp_range(sym.range),
+                }));
+            }
+            scope = p.rsplit_once('.').map(|(parent, _)| parent);
+        }
+
+        log::trace!("Searching for {} in {uri}", typ.name);
+        // Next look within the file for the unqualified name.
+        if let Some(sym) = find(file, &|s| s.name == typ.name) {
+            return Ok(Some(lsp_types::Location {
+                uri,
+                range: to_lsp_range(sym.range),
+            }));
+        };
+
+        // Finally, the name may be qualified by the file's own package, e.g. .main.Outer.Inner
+        let local = file.package().and_then(|pkg| {
+            typ.name
+                .trim_start_matches('.')
+                .strip_prefix(pkg)
+                .and_then(|name| name.strip_prefix('.'))
+        });
+        if let Some(local) = local {
+            log::trace!("Searching for {local} in {uri}");
+            if let Some(sym) = find(file, &|s| s.name == local) {
+                return Ok(Some(lsp_types::Location {
+                    uri,
+                    range: to_lsp_range(sym.range),
+                }));
+            };
+        }
+
+        // Next look within the file imports.
+        let imports = self.imported_files(&uri, file);
+
+        let local_package = file.package();
+        for (uri, file) in imports {
+            let package = file.package();
+            // A leading dot fully qualifies the name, e.g. .foo.Bar
+            let absolute = typ.name.strip_prefix('.').map(|name| match package {
+                Some(package) => name.strip_prefix(package)?.strip_prefix('.'),
+                None => Some(name),
+            });
+            if let Some(sym) = if package == local_package {
+                log::trace!("Searching for {} in {uri} (same package)", typ.name);
+                // same package, match the name with or without the package prefix
+                find(file, &|sym| {
+                    sym.name == typ.name || Some(sym.name.as_str()) == local
+                })
+            } else if let Some(package) = package {
+                log::trace!("Searching for {} in {uri} (different package)", typ.name);
+                // different package, fully qualify the name
+                let local_package = local_package.unwrap_or("");
+                find(file, &|sym| {
+                    let quals = file::possible_qualifiers(package, local_package);
+                    log::trace!("Qualifiers: {quals:?}");
+                    absolute == Some(Some(sym.name.as_str()))
+                        || quals
+                            .iter()
+                            .inspect(|q| log::trace!("Qual == {q}"))
+                            .filter_map(|qual| typ.name.strip_prefix(qual))
+                            .inspect(|q| log::trace!("stripped == {q}"))
+                            .filter_map(|name| name.strip_prefix("."))
+                            .inspect(|q| log::trace!("name == {q} == {}", sym.name))
+                            .any(|name| name == sym.name)
+                })
+            } else {
+                // target file has no package
+                log::trace!("Searching for {} in {uri}", typ.name);
+                find(file, &|sym| {
+                    sym.name == typ.name || absolute == Some(Some(sym.name.as_str()))
+                })
+            } {
+                return Ok(Some(lsp_types::Location {
+                    uri,
+                    range: to_lsp_range(sym.range),
+                }));
+            }
+        }
+        Ok(None)
+    }
+
+    fn complete_types(
+        &self,
+        uri: &Url,
+        base_name: &str,
+        file: &file::File,
+    ) -> Result<Option<lsp_types::CompletionResponse>> {
+        let mut items = self.complete_symbols(uri, base_name, file, is_type);
+
+        if !self.options.completion.include_builtins {
+            return Ok(Some(lsp_types::CompletionResponse::Array(items)));
+        }
+
+        let builtins = BUILTINS.iter().map(|s| lsp_types::CompletionItem {
+            label: s.to_string(),
+            kind: Some(lsp_types::CompletionItemKind::STRUCT),
+            ..Default::default()
+        });
+        items.extend(builtins);
+
+        // Only proto2 supports required fields and groups.
+        // Editions have no presence labels, presence is set with a feature instead.
+        let syntax_keywords: &[&str] = match file.syntax() {
+            file::Syntax::Proto2 => &["optional", "required", "group"],
+            file::Syntax::Proto3 => &["optional"],
+            file::Syntax::Editions => &[],
+        };
+        // Modifiers are followed by a type, so complete the type right after inserting one.
+        let modifier = |s: &str| match s {
+            "optional" | "repeated" | "required" => Some(format!("{s} ")),
+            "map" => Some(String::from("map<")),
+            _ => None,
+        };
+        items.extend(KEYWORDS.iter().chain(syntax_keywords).map(|s| {
+            let insert_text = modifier(s);
+            lsp_types::CompletionItem {
+                label: s.to_string(),
+                kind: Some(lsp_types::CompletionItemKind::KEYWORD),
+                command: insert_text.as_ref().and_then(|_| self.trigger_suggest()),
+                insert_text,
+                ..Default::default()
+            }
+        }));
+        Ok(Some(lsp_types::CompletionResponse::Array(items)))
+    }
+
+    // The triggerSuggest command is specific to VS Code, so only send it if the client opted in.
+    fn trigger_suggest(&self) -> Option<lsp_types::Command> {
+        self.options
+            .completion
+            .trigger_suggest
+            .then(trigger_suggest)
+    }
+
+    // Only messages may be used as the request or response of an rpc.
+    fn complete_rpc_types(
+        &self,
+        uri: &Url,
+        file: &file::File,
+        stream: bool,
+    ) -> Result<Option<lsp_types::CompletionResponse>> {
+        let mut items = vec![];
+        if stream {
+            // A type still follows the keyword, so suggest again after inserting it.
+            items.push(lsp_types::CompletionItem {
+                label: "stream".into(),
+                kind: Some(lsp_types::CompletionItemKind::KEYWORD),
+                insert_text: Some("stream ".into()),
+                command: self.trigger_suggest(),
+                ..Default::default()
+            });
+        }
+        items.extend(self.complete_symbols(uri, "", file, is_message));
+        Ok(Some(lsp_types::CompletionResponse::Array(items)))
+    }
+
+    // Only messages may be extended. Suggest the descriptor options first, as custom options
+    // are the most common extensions.
+    fn complete_extend_types(
+        &self,
+        uri: &Url,
+        file: &file::File,
+    ) -> Result<Option<lsp_types::CompletionResponse>> {
+        let mut items = self.complete_symbols(uri, "", file, is_message);
+        for item in items.iter_mut() {
+            let name = item.insert_text.as_ref().unwrap_or(&item.label);
+            let rank = if name.ends_with("Options") { 0 } else { 1 };
+            item.sort_text = Some(format!("{rank}{name}"));
+        }
+        Ok(Some(lsp_types::CompletionResponse::Array(items)))
+    }
+
+    // Complete symbols in the file relative to base_name, and those from imported files,
+    // qualified as needed from the current package.
+    fn complete_symbols(
+        &self,
+        uri: &Url,
+        base_name: &str,
+        file: &file::File,
+        keep: fn(&file::Symbol) -> bool,
+    ) -> Vec<lsp_types::CompletionItem> {
+        let current_package = file.package();
+        let qualified_labels = self.options.qualified_completion_labels;
+        // Complete sym as name, labeled with the fully qualified name if configured.
+        let item = |uri: &Url, sym: file::Symbol, package: Option<&str>, name: String| {
+            let qualified = qualify(package, &sym.name);
+            // Identify the definition, to look up its details when resolved.
+            let loc = lsp_types::Location {
+                uri: uri.clone(),
+                range: to_lsp_range(sym.range),
+            };
+            let mut item = to_lsp_completion(file::Symbol { name, ..sym });
+            // Recently used types come first, most recent first.
+            let recent = self.recent_types.borrow();
+            if let Some(i) = recent.iter().position(|t| *t == qualified) {
+                item.sort_text = Some(format!("0{i:02}{}", item.label));
+            }
+            item.data = serde_json::to_value(loc).ok();
+            // Record the use of the type once accepted.
+            item.command = Some(lsp_types::Command {
+                title: String::from("Use type"),
+                command: String::from(USE_TYPE_COMMAND),
+                arguments: Some(vec![serde_json::Value::String(qualified.clone())]),
+            });
+            // Filter on the inserted name, as that is what is typed.
+            if qualified_labels {
+                item.filter_text = Some(item.label.clone());
+                item.insert_text = Some(std::mem::replace(&mut item.label, qualified));
+            }
+            item
+        };
+
+        let mut qc = QueryCursor::new();
+        let mut items: Vec<_> = file
+            .relative_symbols(base_name, &mut qc)
+            .filter(|(sym, _)| keep(sym))
+            .map(|(sym, name)| item(uri, sym, current_package, name))
+            .collect();
+
+        for (uri, file) in self.imported_files(uri, file) {
+            let package = file.package();
+            if package.is_none() || package == current_package {
+                let mut qc = tree_sitter::QueryCursor::new();
+                items.extend(file.symbols(&mut qc).filter(keep).map(|s| {
+                    let name = s.name.clone();
+                    item(&uri, s, package, name)
+                }));
+            } else if let Some(package) = package {
+                // Use the shortest qualifier that resolves from the current package.
+                // Symbols include their parents, so nested types are fully qualified.
+                let qualifier = file::possible_qualifiers(package, current_package.unwrap_or(""))
+                    .first()
+                    .copied()
+                    .unwrap_or(package);
+                let mut qc = tree_sitter::QueryCursor::new();
+                items.extend(file.symbols(&mut qc).filter(keep).map(|s| {
+                    let name = qualifier.to_owned() + "." + &s.name;
+                    item(&uri, s, Some(package), name)
+                }));
+            }
+        }
+        items
+    }
+
+    // Suggest a package matching the file's directory relative to the nearest proto path.
+    fn complete_package(&self, url: &Url) -> Result<Option<lsp_types::CompletionResponse>> {
+        let path = url.to_file_path().or(Err(anyhow!("Invalid path: {url}")))?;
+        let Some(dir) = path.parent() else {
+            return Ok(None);
+        };
+
+        let package = self
+            .proto_paths
+            .iter()
+            .filter_map(|root| dir.strip_prefix(root).ok())
+            .min_by_key(|rel| rel.components().count())
+            .map(|rel| {
+                rel.components()
+                    .filter_map(|c| c.as_os_str().to_str())
+                    .collect::<Vec<_>>()
+                    .join(".")
+            });
+
+        Ok(match package {
+            Some(package) if !package.is_empty() => {
+                Some(lsp_types::CompletionResponse::Array(vec![
+                    lsp_types::CompletionItem {
+                        insert_text: Some(format!("{package};")),
+                        label: package,
+                        kind: Some(lsp_types::CompletionItemKind::MODULE),
+                        ..Default::default()
+                    },
+                ]))
+            }
+            _ => None,
+        })
+    }
+
+    fn complete_imports(
+        &self,
+        url: &lsp_types::Url,
+        line: usize,
+        character: usize,
+    ) -> Result<Option<lsp_types::CompletionResponse>> {
+        log::debug!("Completing imports for {url:?}");
+
+        let path = std::path::Path::new(url.path());
+        let current = path
+            .file_name()
+            .with_context(|| format!("Invalid path: {url}"))?
+            .to_str()
+            .with_context(|| format!("Invalid path: {url}"))?;
+
+        // The current file may be reachable by a different relative path under each root.
+        let current = self
+            .proto_paths
+            .iter()
+            .filter_map(|root| path.strip_prefix(root).ok())
+            .filter_map(|p| p.to_str())
+            .chain(std::iter::once(current));
+
+        let file = self
+            .files
+            .get(url)
+            .with_context(|| format!("File not loaded: {url}"))?;
+        let mut qc = tree_sitter::QueryCursor::new();
+        let existing = file.imports(&mut qc).chain(current).collect::<Vec<_>>();
+
+        log::trace!("Excluding existing imports: {existing:?}");
+
+        // If the path is already quoted, replace everything between the quotes.
+        let replace = import_path_range(file.text(), line, character);
+        log::trace!("Replacing import path range: {replace:?}");
+
+        // The same relative path may exist under multiple roots.
+        // Keep only the first, as that is the one an import would resolve to.
+        let mut seen = std::collections::HashSet::new();
+        let items = self
+            .proto_paths
+            .iter()
+            .flat_map(|p| find_protos(p.as_path()))
+            .filter(|s| !existing.contains(&s.as_str()))
+            .filter(|s| seen.insert(s.clone()))
+            .map(|s| {
+                // Show the directory beside the label to tell apart files with the same name
+                let label_details =
+                    s.rsplit_once('/')
+                        .map(|(dir, _)| lsp_types::CompletionItemLabelDetails {
+                            detail: None,
+                            description: Some(dir.to_string()),
+                        });
+                match replace {
+                    Some(range) => lsp_types::CompletionItem {
+                        text_edit: Some(lsp_types::CompletionTextEdit::Edit(lsp_types::TextEdit {
+                            range,
+                            new_text: s.clone(),
+                        })),
+                        label: s,
+                        label_details,
+                        kind: Some(lsp_types::CompletionItemKind::FILE),
+                        ..Default::default()
+                    },
+                    None => lsp_types::CompletionItem {
+                        insert_text: Some(format!("{}\";", s)),
+                        label: s,
+                        label_details,
+                        kind: Some(lsp_types::CompletionItemKind::FILE),
+                        ..Default::default()
+                    },
+                }
+            })
+            .collect();
+        Ok(Some(lsp_types::CompletionResponse::Array(items)))
+    }
+}
+
+// Find the range between the quotes surrounding the cursor on an import line.
+// Returns None if there is no closing quote after the cursor.
+fn import_path_range(text: &str, line: usize, character: usize) -> Option<lsp_types::Range> {
+    let text = text.lines().nth(line)?;
+    let character = character.min(text.len());
+    if !text.is_char_boundary(character) {
+        return None;
+    }
+    let start = text[..character].rfind('"')? + 1;
+    let end = character + text[character..].find('"')?;
+    Some(lsp_types::Range {
+        start: lsp_types::Position {
+            line: line.try_into().ok()?,
+            character: start.try_into().ok()?,
+        },
+        end: lsp_types::Position {
+            line: line.try_into().ok()?,
+            character: end.try_into().ok()?,
+        },
+    })
+}
+
+// In proto3, offer to add or remove the optional label on a singular scalar field.
+fn toggle_optional(
+    uri: &Url,
+    file: &file::File,
+    row: usize,
+    col: usize,
+) -> Option<lsp_types::CodeAction> {
+    if file.syntax() != file::Syntax::Proto3 {
+        return None;
+    }
+
+    let field = file.field_at(row, col)?;
+    if !BUILTINS.contains(&field.typ) {
+        return None;
+    }
+
+    let type_start = to_lsp_pos(field.type_range.start_point);
+    let (title, edit) = match (field.label, field.label_range) {
+        (None, _) => (
+            "Add optional",
+            lsp_types::TextEdit {
+                range: lsp_types::Range {
+                    start: type_start,
+                    end: type_start,
+                },
+                new_text: "optional ".into(),
+            },
+        ),
+        (Some("optional"), Some(range)) => (
+            "Remove optional",
+            lsp_types::TextEdit {
+                range: lsp_types::Range {
+                    start: to_lsp_pos(range.start_point),
+                    end: type_start,
+                },
+                new_text: "".into(),
+            },
+        ),
+        _ => return None,
+    };
+
+    Some(lsp_types::CodeAction {
+        title: title.into(),
+        kind: Some(lsp_types::CodeActionKind::REFACTOR_REWRITE),
+        edit: Some(lsp_types::WorkspaceEdit {
+            changes: Some(std::collections::HashMap::from([(uri.clone(), vec![edit])])),
+            ..Default::default()
+        }),
+        ..Default::default()
+    })
+}
+
+// A documentation comment for a definition, inserted after the // that starts it.
+// Types get a summary and details, while their members get a single line.
+fn doc_template(kind: &str, name: &str, indent: &str) -> lsp_types::CompletionItem {
+    let text = match kind {
+        "message" | "enum" | "service" => {
+            format!(" ${{1:{name} summary.}}\n{indent}//\n{indent}// ${{2:Details.}}")
+        }
+        _ => format!(" ${{1:{name} description.}}"),
+    };
+    lsp_types::CompletionItem {
+        label: format!("Document {name}"),
+        kind: Some(lsp_types::CompletionItemKind::SNIPPET),
+        insert_text: Some(text),
+        insert_text_format: Some(lsp_types::InsertTextFormat::SNIPPET),
+        // The template is already indented to match the comment.
+        insert_text_mode: Some(lsp_types::InsertTextMode::AS_IS),
+        ..Default::default()
+    }
+}
+
+// Offer to delete the line of an import that protoc reported as unused.
+fn remove_unused_import(
+    uri: &Url,
+    file: &file::File,
+    diag: lsp_types::Diagnostic,
+) -> Option<lsp_types::CodeAction> {
+    let code = lsp_types::NumberOrString::String(protoc::UNUSED_IMPORT.into());
+    if diag.code.as_ref() != Some(&code) {
+        return None;
+    }
+
+    let name = protoc::unused_import(&diag.message)?;
+    let line = diag.range.start.line;
+    let import = file
+        .import_references(name)
+        .into_iter()
+        .find(|r| r.start_point.row == line as usize)?;
+
+    // Remove the whole line, including the trailing newline.
+    let row = import.start_point.row.try_into().ok()?;
+    let edit = lsp_types::TextEdit {
+        range: lsp_types::Range {
+            start: lsp_types::Position::new(row, 0),
+            end: lsp_types::Position::new(row + 1, 0),
+        },
+        new_text: "".into(),
+    };
+
+    Some(lsp_types::CodeAction {
+        title: "Remove unused import".into(),
+        kind: Some(lsp_types::CodeActionKind::QUICKFIX),
+        diagnostics: Some(vec![diag]),
+        edit: Some(lsp_types::WorkspaceEdit {
+            changes: Some(std::collections::HashMap::from([(uri.clone(), vec![edit])])),
+            ..Default::default()
+        }),
+        is_preferred: Some(true),
+        ..Default::default()
+    })
+}
+
+// Offer to sort the imports by path, if they are not sorted already.
+fn sort_imports(uri: &Url, file: &file::File) -> Option<lsp_types::CodeAction> {
+    let (rows, text) = file.sorted_imports()?;
+    let current = file
+        .text()
+        .lines()
+        .skip(rows.start)
+        .take(rows.len())
+        .map(|l| l.to_string() + "\n")
+        .collect::<String>();
+    if current == text {
+        return None;
+    }
+
+    let edit = lsp_types::TextEdit {
+        range: lsp_types::Range {
+            start: lsp_types::Position::new(rows.start.try_into().ok()?, 0),
+            end: lsp_types::Position::new(rows.end.try_into().ok()?, 0),
+        },
+        new_text: text,
+    };
+
+    Some(lsp_types::CodeAction {
+        title: "Sort imports".into(),
+        kind: Some(lsp_types::CodeActionKind::SOURCE_ORGANIZE_IMPORTS),
+        edit: Some(lsp_types::WorkspaceEdit {
+            changes: Some(std::collections::HashMap::from([(uri.clone(), vec![edit])])),
+            ..Default::default()
+        }),
+        ..Default::default()
+    })
+}
+
+// Offer to move the message or enum nested innermost around the position to the top
+// level of the file. The edit is computed when the action is resolved.
+fn extract_nested(
+    uri: &Url,
+    file: &file::File,
+    pos: lsp_types::Position,
+) -> Result<Option<lsp_types::CodeAction>> {
+    let Some(sym) = nested_type_at(file, pos) else {
+        return Ok(None);
+    };
+    let Some((_, name)) = sym.name.rsplit_once('.') else {
+        return Ok(None); // already top-level
+    };
+    let title = match sym.kind {
+        file::SymbolKind::Enum => "Extract nested enum to top level",
+        _ => "Extract nested message to top level",
+    };
+
+    let mut qc = tree_sitter::QueryCursor::new();
+    let mut top_level = file.symbols(&mut qc).filter(is_definition);
+    if top_level.any(|s| s.name == name) {
+        return Ok(Some(lsp_types::CodeAction {
+            title: title.into(),
+            kind: Some(lsp_types::CodeActionKind::REFACTOR_EXTRACT),
+            disabled: Some(lsp_types::CodeActionDisabled {
+                reason: format!("A top-level type named {name} already exists"),
+            }),
+            ..Default::default()
+        }));
+    }
+
+    let data = lsp_types::TextDocumentPositionParams {
+        text_document: lsp_types::TextDocumentIdentifier { uri: uri.clone() },
+        position: pos,
+    };
+    Ok(Some(lsp_types::CodeAction {
+        title: title.into(),
+        kind: Some(lsp_types::CodeActionKind::REFACTOR_EXTRACT),
+        data: Some(serde_json::to_value(data)?),
+        ..Default::default()
+    }))
+}
+
+// Return the message or enum nested innermost around the position.
+fn nested_type_at(file: &file::File, pos: lsp_types::Position) -> Option<file::Symbol> {
+    let mut qc = tree_sitter::QueryCursor::new();
+    file.symbols(&mut qc)
+        .filter(is_type)
+        .filter(|s| {
+            let r = to_lsp_range(s.range);
+            r.start <= pos && pos <= r.end
+        })
+        .last()
+}
+
+// Return the position of a byte offset in the text.
+fn byte_position(text: &str, byte: usize) -> Result<lsp_types::Position> {
+    let line = text[..byte].matches('\n').count();
+    let character = byte - text[..byte].rfind('\n').map_or(0, |i| i + 1);
+    Ok(lsp_types::Position::new(
+        line.try_into()?,
+        character.try_into()?,
+    ))
+}
+
+// In strict mode, warnings and lints are errors. Hints, such as deprecation, are left as-is.
+fn escalate(severity: lsp_types::DiagnosticSeverity) -> lsp_types::DiagnosticSeverity {
+    if severity == lsp_types::DiagnosticSeverity::WARNING
+        || severity == lsp_types::DiagnosticSeverity::INFORMATION
+    {
+        lsp_types::DiagnosticSeverity::ERROR
+    } else {
+        severity
+    }
+}
+
+// Warn about every import after the first of the same file.
+fn duplicate_imports(file: &file::File, source: &str) -> Vec<lsp_types::Diagnostic> {
+    let mut qc = tree_sitter::QueryCursor::new();
+    let mut seen = std::collections::HashSet::new();
+    file.imports(&mut qc)
+        .filter(|name| !seen.insert(*name))
+        .collect::<std::collections::BTreeSet<_>>()
+        .into_iter()
+        .flat_map(|name| {
+            file.import_references(name)
+                .into_iter()
+                .skip(1)
+                .map(move |range| lsp_types::Diagnostic {
+                    range: to_lsp_range(range),
+                    severity: Some(lsp_types::DiagnosticSeverity::WARNING),
+                    source: Some(source.into()),
+                    message: format!("Duplicate import {name}"),
+                    ..Default::default()
+                })
+        })
+        .collect()
+}
+
+// Imports must be relative to an include path, so flag any that are absolute, or that
+// protoc would otherwise reject, e.g. "../foo.proto".
+fn invalid_imports(file: &file::File, source: &str) -> Vec<lsp_types::Diagnostic> {
+    let mut qc = tree_sitter::QueryCursor::new();
+    file.imports(&mut qc)
+        .filter(|name| normalize_import(name).is_none())
+        .collect::<std::collections::BTreeSet<_>>()
+        .into_iter()
+        .flat_map(|name| {
+            file.import_references(name)
+                .into_iter()
+                .map(move |range| lsp_types::Diagnostic {
+                    range: to_lsp_range(range),
+                    severity: Some(lsp_types::DiagnosticSeverity::ERROR),
+                    source: Some(source.into()),
+                    message: format!(
+                        "Import {name} not found: imports must be relative to a proto path, \
+                         without empty, \".\" or \"..\" segments"
+                    ),
+                    ..Default::default()
+                })
+        })
+        .collect()
+}
+
+// Convert backslashes (possibly escaped) to forward slashes.
+// Returns None for a unix-style absolute path, or one starting with a windows drive letter.
+// Also returns None for a path with empty, "." or ".." segments, which protoc rejects.
+fn normalize_import(name: &str) -> Option<String> {
+    let name = name.replace("\\\\", "/").replace('\\', "/");
+    if name.starts_with('/') || name.chars().nth(1) == Some(':') {
+        return None;
+    }
+    if name.split('/').any(|part| matches!(part, "" | "." | "..")) {
+        return None;
+    }
+    Some(name)
+}
+
+// A file may only declare one package, so flag every package statement after the first.
+fn duplicate_packages(file: &file::File, source: &str) -> Vec<lsp_types::Diagnostic> {
+    file.package_statements()
+        .into_iter()
+        .skip(1)
+        .map(|range| lsp_types::Diagnostic {
+            range: to_lsp_range(range),
+            severity: Some(lsp_types::DiagnosticSeverity::ERROR),
+            source: Some(source.into()),
+            message: String::from("Multiple package declarations"),
+            ..Default::default()
+        })
+        .collect()
+}
+
+// Required fields are not allowed in proto3, so flag the label without waiting on protoc.
+fn proto3_required(file: &file::File, source: &str) -> Vec<lsp_types::Diagnostic> {
+    if file.syntax() != file::Syntax::Proto3 {
+        return vec![];
+    }
+    file.required_labels()
+        .into_iter()
+        .map(|range| lsp_types::Diagnostic {
+            range: to_lsp_range(range),
+            severity: Some(lsp_types::DiagnosticSeverity::ERROR),
+            source: Some(source.into()),
+            message: String::from("Required fields are not allowed in proto3"),
+            ..Default::default()
+        })
+        .collect()
+}
+
+// Map keys must be an integral or string type, so flag others (e.g. messages) without waiting on protoc.
+fn invalid_map_keys(file: &file::File, source: &str) -> Vec<lsp_types::Diagnostic> {
+    file.map_key_types()
+        .into_iter()
+        .filter(|(name, _)| {
+            !name.is_empty()
+                && !MAP_KEY_TYPES.contains(name)
+                && is_full_ident(name.trim_start_matches('.'))
+        })
+        .map(|(name, range)| lsp_types::Diagnostic {
+            range: to_lsp_range(range),
+            severity: Some(lsp_types::DiagnosticSeverity::ERROR),
+            source: Some(source.into()),
+            message: format!("Invalid map key type: {name}"),
+            ..Default::default()
+        })
+        .collect()
+}
+
+// Flag extension ranges that overlap each other, or the number of a field in the same message.
+fn extension_overlaps(file: &file::File, source: &str) -> Vec<lsp_types::Diagnostic> {
+    let diag = |range, message| lsp_types::Diagnostic {
+        range: to_lsp_range(range),
+        severity: Some(lsp_types::DiagnosticSeverity::ERROR),
+        source: Some(source.into()),
+        message,
+        ..Default::default()
+    };
+    let mut res = vec![];
+    for numbers in file.message_numbers() {
+        for (i, (start, end, range)) in numbers.extensions.iter().enumerate() {
+            let overlap = numbers.extensions[..i]
+                .iter()
+                .find(|(s, e, _)| s <= end && start <= e);
+            if let Some((s, e, _)) = overlap {
+                res.push(diag(
+                    *range,
+                    format!("Extension range {start} to {end} overlaps {s} to {e}"),
+                ));
+            }
+        }
+        for (n, range) in numbers.fields.iter() {
+            let overlap = numbers
+                .extensions
+                .iter()
+                .find(|(start, end, _)| start <= n && n <= end);
+            if let Some((start, end, _)) = overlap {
+                res.push(diag(
+                    *range,
+                    format!("Field number {n} is in extension range {start} to {end}"),
+                ));
+            }
+        }
+    }
+    res
+}
+
+fn find_protos(dir: &std::path::Path) -> Vec<String> {
+    let mut res = vec![];
+    let entries = match std::fs::read_dir(dir) {
+        Ok(ok) => ok,
+        Err(err) => {
+            log::warn!("Failed to read dir {dir:?}: {err:?}");
+            return res;
+        }
+    };
+    log::trace!("Finding imports under {dir:?}");
+    for path in entries {
+        let path = match path {
+            Ok(ok) => ok,
+            Err(err) => {
+                log::warn!("Failed to read dir {dir:?}: {err:?}");
+                continue;
+            }
+        };
+
+        let meta = match path.metadata() {
+            Ok(ok) => ok,
+            Err(err) => {
+                log::warn!("Failed to read dir {dir:?}: {err:?}");
+                continue;
+            }
+        };
+
+        if meta.is_dir() {
+            let dir = dir.join(path.path());
+            let protos = find_protos(dir.as_path());
+            let root = &path.file_name();
+            let root = std::path::PathBuf::from(root);
+            res.extend(
+                protos
+                    .iter()
+                    .filter_map(|p| root.join(p).to_str().map(str::to_string)),
+            );
+            continue;
+        }
+
+        if !meta.is_file() {
+            continue;
+        }
+
+        let name = &path.file_name();
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+
+        if !name.ends_with(".proto") {
+            continue;
+        }
+
+        log::trace!("Found import {name:?}");
+        res.push(name.to_string())
+    }
+    res
+}
+
+// If text in package pkg refers to one of the types in package old,
+// return the text needed to refer to the same type once old is renamed to new.
+// The file's own package may change from pkg to new_pkg in the same rename.
+fn requalify(
+    text: &str,
+    types: &std::collections::HashSet<String>,
+    old: &str,
+    pkg: &str,
+    new: &str,
+    new_pkg: &str,
+) -> Option<String> {
+    let quals = file::possible_qualifiers(old, pkg);
+    let (qual, name) = quals.iter().find_map(|q| {
+        let name = if q.is_empty() {
+            text
+        } else {
+            text.strip_prefix(q)?.strip_prefix(".")?
+        };
+        types.contains(name).then_some((*q, name))
+    })?;
+
+    // Keep fully qualified references fully qualified.
+    let new_qual = if qual == old {
+        new
+    } else {
+        file::possible_qualifiers(new, new_pkg)
+            .first()
+            .copied()
+            .unwrap_or(new)
+    };
+    Some(if new_qual.is_empty() {
+        name.into()
+    } else {
+        format!("{new_qual}.{name}")
+    })
+}
+
+// Return true if the name is a valid dotted identifier, like foo.bar.
+fn is_full_ident(name: &str) -> bool {
+    name.split('.').all(|part| {
+        part.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && part.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    })
+}
+
+// Reject renaming a builtin type or keyword, so the user sees why nothing happened.
+fn check_renamable(file: &file::File, pos: lsp_types::Position) -> Result<()> {
+    match file.keyword_at(pos.line.try_into()?, pos.character.try_into()?) {
+        Some(kw) if BUILTINS.contains(&kw) => Err(anyhow!("Cannot rename built-in type")),
+        Some(kw) if KEYWORDS.contains(&kw) || LABELS.contains(&kw) => {
+            Err(anyhow!("Cannot rename keyword {kw}"))
+        }
+        _ => Ok(()),
+    }
+}
+
+// Suggest proto3 first, as the most common choice for new files.
+fn complete_syntax() -> Option<lsp_types::CompletionResponse> {
+    let items = [
+        (
+            "syntax = \"proto3\";",
+            "Fields are implicitly present with a zero default, unless marked optional. \
+             Required fields, groups, and custom defaults are not allowed.",
+        ),
+        (
+            "syntax = \"proto2\";",
+            "Fields are labeled optional, required, or repeated. \
+             Optional fields track presence and may declare a custom default.",
+        ),
+        (
+            "edition = \"2023\";",
+            "Behavior is configured with features instead of a syntax version. \
+             Fields track presence by default, as in proto2.",
+        ),
+    ];
+    let items = items
+        .iter()
+        .enumerate()
+        .map(|(i, (label, doc))| lsp_types::CompletionItem {
+            label: label.to_string(),
+            kind: Some(lsp_types::CompletionItemKind::TEXT),
+            documentation: Some(lsp_types::Documentation::String(doc.to_string())),
+            sort_text: Some(i.to_string()),
+            ..Default::default()
+        });
+    Some(lsp_types::CompletionResponse::Array(items.collect()))
+}
+
+fn complete_keywords() -> Option<lsp_types::CompletionResponse> {
+    let items = ["message", "enum", "import", "option"]
+        .iter()
+        .map(|s| lsp_types::CompletionItem {
+            label: s.to_string(),
+            kind: Some(lsp_types::CompletionItemKind::KEYWORD),
+            ..Default::default()
+        });
+    Some(lsp_types::CompletionResponse::Array(items.collect()))
+}
+
+// Ask the editor to show completions again, e.g. for the type following a modifier.
+fn trigger_suggest() -> lsp_types::Command {
+    lsp_types::Command {
+        title: String::from("Suggest"),
+        command: String::from("editor.action.triggerSuggest"),
+        arguments: None,
+    }
+}
+
+// Only integral and string scalars can be map keys.
+fn complete_map_keys() -> Option<lsp_types::CompletionResponse> {
+    let items = MAP_KEY_TYPES.iter().map(|s| lsp_types::CompletionItem {
+        label: s.to_string(),
+        kind: Some(lsp_types::CompletionItemKind::STRUCT),
+        ..Default::default()
+    });
+    Some(lsp_types::CompletionResponse::Array(items.collect()))
+}
+
+// Editions have no presence labels, presence is set with a feature instead.
+fn complete_field_options(file: &file::File) -> Option<lsp_types::CompletionResponse> {
+    let mut items = vec![];
+    if file.syntax() == file::Syntax::Editions {
+        items.push(lsp_types::CompletionItem {
+            label: "features.field_presence".into(),
+            kind: Some(lsp_types::CompletionItemKind::PROPERTY),
+            insert_text: Some(
+                "features.field_presence = ${1|EXPLICIT,IMPLICIT,LEGACY_REQUIRED|}".into(),
+            ),
+            insert_text_format: Some(lsp_types::InsertTextFormat::SNIPPET),
+            ..Default::default()
+        });
+    }
+    Some(lsp_types::CompletionResponse::Array(items))
+}
+
+fn complete_rpc_body() -> Option<lsp_types::CompletionResponse> {
+    let items = [
+        ("{}", "{\n\t$0\n}"),
+        ("{ option }", "{\n\toption (${1:name}) = ${0:value};\n}"),
+    ]
+    .iter()
+    .map(|(label, snippet)| lsp_types::CompletionItem {
+        label: label.to_string(),
+        kind: Some(lsp_types::CompletionItemKind::SNIPPET),
+        insert_text: Some(snippet.to_string()),
+        insert_text_format: Some(lsp_types::InsertTextFormat::SNIPPET),
+        ..Default::default()
+    });
+    Some(lsp_types::CompletionResponse::Array(items.collect()))
+}
+
+// Services and rpcs are symbols, but cannot be used as a field type.
+fn is_type(sym: &file::Symbol) -> bool {
+    sym.kind == file::SymbolKind::Message || sym.kind == file::SymbolKind::Enum
+}
+
+// Whether a type name used in the given package and scope is one of the given fully qualified
+// types, searching the scope and then each enclosing scope, e.g. Bar in foo.Outer may be
+// foo.Outer.Bar, foo.Bar, or Bar.
+fn resolves_to(
+    types: &std::collections::HashSet<String>,
+    package: Option<&str>,
+    typ: &file::GotoTypeContext,
+) -> bool {
+    if let Some(name) = typ.name.strip_prefix('.') {
+        return types.contains(name);
+    }
+    let full = [package, typ.parent.as_deref()]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>()
+        .join(".");
+    let mut scope = Some(full.as_str()).filter(|s| !s.is_empty());
+    loop {
+        if types.contains(&qualify(scope, typ.name)) {
+            return true;
+        }
+        let Some(s) = scope else {
+            return false;
+        };
+        scope = s.rsplit_once('.').map(|(parent, _)| parent);
+    }
+}
+
+// qualify(Some("foo"), "Bar") -> "foo.Bar"
+fn qualify(package: Option<&str>, name: &str) -> String {
+    match package {
+        Some(package) => format!("{package}.{name}"),
+        None => name.to_string(),
+    }
+}
+
+// Yields the symbols a name may resolve to within a file.
+type Candidates = dyn for<'a> Fn(
+    &'a file::File,
+    &'a mut tree_sitter::QueryCursor,
+) -> Box<dyn Iterator<Item = file::Symbol> + 'a>;
+
+fn is_message(sym: &file::Symbol) -> bool {
+    sym.kind == file::SymbolKind::Message
+}
+
+// Rank how well a symbol name matches a word of a workspace symbol query, lower is better:
+// an exact match, a prefix, a substring, or only the characters in order.
+fn match_rank(name: &str, word: &str, ignore_case: bool) -> usize {
+    let (name, word) = match ignore_case {
+        true => (name.to_lowercase(), word.to_lowercase()),
+        false => (name.to_string(), word.to_string()),
+    };
+    // Nested symbols are qualified by their parents, e.g. Outer.Inner
+    let last = name.rsplit('.').next().unwrap_or(&name);
+    if name == word || last == word {
+        0
+    } else if name.starts_with(&word) || last.starts_with(&word) {
+        1
+    } else if name.contains(&word) {
+        2
+    } else {
+        3
+    }
+}
+
+// Fields and oneofs are symbols, but cannot be referenced by name.
+fn is_definition(sym: &file::Symbol) -> bool {
+    sym.kind != file::SymbolKind::Field && sym.kind != file::SymbolKind::Oneof
+}
+
+// Positions past what the protocol can represent are clamped, rather than panicking.
+pub(crate) fn to_lsp_pos(p: tree_sitter::Point) -> lsp_types::Position {
+    lsp_types::Position {
+        line: p.row.try_into().unwrap_or(u32::MAX),
+        character: p.column.try_into().unwrap_or(u32::MAX),
+    }
+}
+
+pub(crate) fn to_lsp_range(r: tree_sitter::Range) -> lsp_types::Range {
+    lsp_types::Range {
+        start: to_lsp_pos(r.start_point),
+        end: to_lsp_pos(r.end_point),
+    }
+}
+
+fn to_lsp_symbol(
+    uri: Url,
+    package: Option<&str>,
+    sym: file::Symbol,
+) -> lsp_types::SymbolInformation {
+    // The container is the fully qualified parent, or just the package for top-level symbols.
+    // Extend blocks are top-level, but named by a (possibly qualified) extendee.
+    let parent = match sym.kind {
+        file::SymbolKind::Extend => None,
+        _ => sym.name.rsplit_once('.'),
+    };
+    let container_name = match parent {
+        Some((parent, _)) => Some(qualify(package, parent)),
+        None => package.map(str::to_string),
+    };
+    // deprecated field is deprecated, but cannot be omitted
+    #[allow(deprecated)]
+    lsp_types::SymbolInformation {
+        name: sym.name,
+        kind: match sym.kind {
+            file::SymbolKind::Enum => lsp_types::SymbolKind::ENUM,
+            file::SymbolKind::Message => lsp_types::SymbolKind::STRUCT,
+            file::SymbolKind::Service => lsp_types::SymbolKind::INTERFACE,
+            file::SymbolKind::Rpc => lsp_types::SymbolKind::METHOD,
+            file::SymbolKind::Oneof => lsp_types::SymbolKind::ENUM_MEMBER,
+            file::SymbolKind::Field => lsp_types::SymbolKind::FIELD,
+            file::SymbolKind::Extend => lsp_types::SymbolKind::NAMESPACE,
+        },
+        tags: sym
+            .deprecated
+            .then(|| vec![lsp_types::SymbolTag::DEPRECATED]),
+        deprecated: None,
+        location: lsp_types::Location {
+            uri,
+            range: lsp_types::Range {
+                start: to_lsp_pos(sym.range.start_point),
+                end: to_lsp_pos(sym.range.end_point),
+            },
+        },
+        container_name,
+    }
+}
+
+// Return the innermost message containing the range.
+// Symbols are ordered by position, so this is the last message that contains it.
+fn enclosing_message(file: &file::File, range: lsp_types::Range) -> Option<file::Symbol> {
+    let mut qc = tree_sitter::QueryCursor::new();
+    let res = file
+        .symbols(&mut qc)
+        .filter(is_message)
+        .filter(|s| {
+            let r = to_lsp_range(s.range);
+            r.start <= range.start && range.end <= r.end
+        })
+        .last();
+    res
+}
+
+fn to_call_hierarchy_item(
+    uri: Url,
+    package: Option<&str>,
+    sym: &file::Symbol,
+) -> lsp_types::CallHierarchyItem {
+    let range = to_lsp_range(sym.range);
+    lsp_types::CallHierarchyItem {
+        name: sym.name.clone(),
+        kind: lsp_types::SymbolKind::STRUCT,
+        tags: sym
+            .deprecated
+            .then(|| vec![lsp_types::SymbolTag::DEPRECATED]),
+        detail: package.map(str::to_string),
+        uri,
+        range,
+        selection_range: range,
+        data: None,
+    }
+}
+
+fn to_lsp_completion(sym: file::Symbol) -> lsp_types::CompletionItem {
+    lsp_types::CompletionItem {
+        tags: sym
+            .deprecated
+            .then(|| vec![lsp_types::CompletionItemTag::DEPRECATED]),
+        label: sym.name,
+        kind: Some(match sym.kind {
+            file::SymbolKind::Enum => lsp_types::CompletionItemKind::ENUM,
+            file::SymbolKind::Message => lsp_types::CompletionItemKind::STRUCT,
+            file::SymbolKind::Service => lsp_types::CompletionItemKind::INTERFACE,
+            file::SymbolKind::Rpc => lsp_types::CompletionItemKind::METHOD,
+            file::SymbolKind::Oneof => lsp_types::CompletionItemKind::ENUM_MEMBER,
+            file::SymbolKind::Field => lsp_types::CompletionItemKind::FIELD,
+            file::SymbolKind::Extend => lsp_types::CompletionItemKind::MODULE,
+        }),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn setup() -> (Workspace, tempfile::TempDir) {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let tmp = tempfile::tempdir().unwrap();
+        (Workspace::new(vec![tmp.path().into()]), tmp)
+    }
+
+    // Write the bundled well-known files to a temporary directory rather than the user's cache.
+    fn use_cache_dir(ws: &mut Workspace) -> tempfile::TempDir {
+        let cache = tempfile::tempdir().unwrap();
+        ws.options.cache_dir = Some(cache.path().into());
+        cache
+    }
+
+    fn proto(dir: impl AsRef<std::path::Path>, path: &str, lines: &[&str]) -> (Url, String) {
+        let path = dir.as_ref().join(path);
+        let text = lines.join("\n") + "\n";
+        std::fs::write(&path, &text).unwrap();
+        (Url::from_file_path(path).unwrap(), text)
+    }
+
+    fn references(uri: &Url, line: u32, character: u32) -> lsp_types::ReferenceParams {
+        lsp_types::ReferenceParams {
+            text_document_position: lsp_types::TextDocumentPositionParams {
+                text_document: lsp_types::TextDocumentIdentifier { uri: uri.clone() },
+                position: lsp_types::Position { line, character },
+            },
+            work_done_progress_params: lsp_types::WorkDoneProgressParams {
+                work_done_token: None,
+            },
+            partial_result_params: lsp_types::PartialResultParams {
+                partial_result_token: None,
+            },
+            context: lsp_types::ReferenceContext {
+                include_declaration: false,
+            },
+        }
+    }
+
+    fn complete_items(
+        ws: &Workspace,
+        uri: &Url,
+        line: usize,
+        character: usize,
+    ) -> Vec<lsp_types::CompletionItem> {
+        let Some(lsp_types::CompletionResponse::Array(items)) =
+            ws.complete(uri, line, character).unwrap()
+        else {
+            panic!("Expected completion array");
+        };
+        items
+    }
+
+    fn range(line: u32, start: u32, end: u32) -> lsp_types::Range {
+        lsp_types::Range {
+            start: lsp_types::Position {
+                line,
+                character: start,
+            },
+            end: lsp_types::Position {
+                line,
+                character: end,
+            },
+        }
+    }
+
+    #[test]
+    fn test_open_loop() {
+        let (mut ws, tmp) = setup();
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &["syntax = \"proto3\";", "import \"bar.proto\";"],
+        );
+        proto(
+            &tmp,
+            "bar.proto",
+            &["syntax = \"proto3\";", "import \"bar.proto\";"],
+        );
+
+        ws.open(uri.clone(), text).unwrap();
+    }
+
+    #[test]
+    fn test_to_lsp_pos() {
+        let pos = |row, column| to_lsp_pos(tree_sitter::Point { row, column });
+        assert_eq!(pos(1, 2), lsp_types::Position::new(1, 2));
+        assert_eq!(pos(usize::MAX, 2), lsp_types::Position::new(u32::MAX, 2));
+        assert_eq!(pos(1, usize::MAX), lsp_types::Position::new(1, u32::MAX));
+    }
+
+    #[test]
+    fn test_complete_syntax() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let mut ws = Workspace::new(vec![]);
+        let uri = Url::from_file_path(std::env::temp_dir().join("foo.proto")).unwrap();
+        ws.open(uri.clone(), "".into()).unwrap();
+        let mut items = complete_items(&ws, &uri, 0, 0);
+        items.sort_by_key(|i| i.sort_text.clone());
+        assert_eq!(
+            items.iter().map(|i| i.label.as_str()).collect::<Vec<_>>(),
+            vec![
+                "syntax = \"proto3\";",
+                "syntax = \"proto2\";",
+                "edition = \"2023\";"
+            ]
+        );
+        assert!(items.iter().all(|i| i.documentation.is_some()), "{items:?}");
+    }
+
+    #[test]
+    fn test_complete_import() {
+        let (mut ws, tmp) = setup();
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &["syntax = \"proto3\";", "import \"bar.proto\";", "import \""],
+        );
+        proto(&tmp, "bar.proto", &["syntax = \"proto3\";"]);
+        proto(&tmp, "baz.proto", &["syntax = \"proto3\";"]);
+
+        ws.open(uri.clone(), text).unwrap();
+        assert_eq!(
+            ws.complete(&uri, 2, "import \"".len()).unwrap().unwrap(),
+            lsp_types::CompletionResponse::Array(vec![lsp_types::CompletionItem {
+                label: "baz.proto".into(),
+                kind: Some(lsp_types::CompletionItemKind::FILE),
+                insert_text: Some("baz.proto\";".into()),
+                ..Default::default()
+            },])
+        );
+    }
+
+    #[test]
+    fn test_complete_nested_import() {
+        let (mut ws, tmp) = setup();
+        let (uri, text) = proto(&tmp, "foo.proto", &["syntax = \"proto3\";", "import \""]);
+        proto(&tmp, "bar.proto", &["syntax = \"proto3\";"]);
+
+        let subdir = tmp.path().join("subdir");
+        let subdir = subdir.as_path();
+        std::fs::create_dir(subdir).unwrap();
+        proto(subdir, "baz.proto", &["syntax = \"proto3\";"]);
+
+        ws.open(uri.clone(), text).unwrap();
+        assert_eq!(
+            ws.complete(&uri, 1, "import \"".len()).unwrap().unwrap(),
+            lsp_types::CompletionResponse::Array(vec![
+                lsp_types::CompletionItem {
+                    label: "bar.proto".into(),
+                    kind: Some(lsp_types::CompletionItemKind::FILE),
+                    insert_text: Some("bar.proto\";".into()),
+                    ..Default::default()
+                },
+                lsp_types::CompletionItem {
+                    label: "subdir/baz.proto".into(),
+                    label_details: Some(lsp_types::CompletionItemLabelDetails {
+                        detail: None,
+                        description: Some("subdir".into()),
+                    }),
+                    kind: Some(lsp_types::CompletionItemKind::FILE),
+                    insert_text: Some("subdir/baz.proto\";".into()),
+                    ..Default::default()
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn test_complete_import_same_name() {
+        let (mut ws, tmp) = setup();
+        let (uri, text) = proto(&tmp, "foo.proto", &["syntax = \"proto3\";", "import \""]);
+        for dir in ["a", "b/c"] {
+            let dir = tmp.path().join(dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            proto(dir.as_path(), "bar.proto", &["syntax = \"proto3\";"]);
+        }
+
+        ws.open(uri.clone(), text).unwrap();
+        let mut actual = complete_items(&ws, &uri, 1, "import \"".len());
+        actual.sort_by_key(|c| c.label.clone());
+        assert_eq!(
+            actual
+                .into_iter()
+                .map(|c| (c.label, c.label_details.and_then(|d| d.description)))
+                .collect::<Vec<_>>(),
+            vec![
+                (String::from("a/bar.proto"), Some(String::from("a"))),
+                (String::from("b/c/bar.proto"), Some(String::from("b/c"))),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_complete_import_overlapping_roots() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let first = tempfile::tempdir().unwrap();
+        let second = tempfile::tempdir().unwrap();
+        let mut ws = Workspace::new(vec![first.path().into(), second.path().into()]);
+
+        let (uri, text) = proto(
+            &first,
+            "foo.proto",
+            &["syntax = \"proto3\";", "import \"bar.proto\";", "import \""],
+        );
+        proto(&first, "bar.proto", &["syntax = \"proto3\";"]);
+        proto(&first, "baz.proto", &["syntax = \"proto3\";"]);
+        proto(&second, "foo.proto", &["syntax = \"proto3\";"]);
+        proto(&second, "bar.proto", &["syntax = \"proto3\";"]);
+        proto(&second, "baz.proto", &["syntax = \"proto3\";"]);
+        proto(&second, "biz.proto", &["syntax = \"proto3\";"]);
+
+        ws.open(uri.clone(), text).unwrap();
+        let mut actual = complete_items(&ws, &uri, 2, "import \"".len());
+        actual.sort_by_key(|c| c.label.clone());
+        assert_eq!(
+            actual,
+            vec![
+                lsp_types::CompletionItem {
+                    label: "baz.proto".into(),
+                    kind: Some(lsp_types::CompletionItemKind::FILE),
+                    insert_text: Some("baz.proto\";".into()),
+                    ..Default::default()
+                },
+                lsp_types::CompletionItem {
+                    label: "biz.proto".into(),
+                    kind: Some(lsp_types::CompletionItemKind::FILE),
+                    insert_text: Some("biz.proto\";".into()),
+                    ..Default::default()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_complete_options() {
+        let (mut ws, tmp) = setup();
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &["syntax = \"proto3\";", "import \"bar.proto\";", "option j"],
+        );
+        proto(&tmp, "bar.proto", &["syntax = \"proto3\";"]);
+        proto(&tmp, "baz.proto", &["syntax = \"proto3\";"]);
+
+        ws.open(uri.clone(), text).unwrap();
+        assert_eq!(
+            ws.complete(&uri, 2, "option j".len()).unwrap().unwrap(),
+            lsp_types::CompletionResponse::Array(
+                OPTIONS
+                    .iter()
+                    .map(|name| {
+                        lsp_types::CompletionItem {
+                            label: name.to_string(),
+                            kind: Some(lsp_types::CompletionItemKind::TEXT),
+                            ..Default::default()
+                        }
+                    })
+                    .collect()
+            )
+        );
+    }
+
+    #[test]
+    fn test_goto_import() {
+        let (mut ws, tmp) = setup();
+        let (foo_uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &[
+                "syntax = \"proto3\";",
+                "import \"bar.proto\";",
+                "import \"baz.proto\";",
+                "import \"biz.proto\";",
+            ],
+        );
+        let (bar_uri, _) = proto(&tmp, "bar.proto", &["syntax = \"proto3\";"]);
+        let (baz_uri, _) = proto(&tmp, "baz.proto", &["syntax = \"proto3\";"]);
+
+        ws.open(foo_uri.clone(), text).unwrap();
+
+        assert_eq!(
+            ws.goto(
+                foo_uri.clone(),
+                lsp_types::Position {
+                    line: 1,
+                    character: "import \"bar.".len().try_into().unwrap(),
+                }
+            )
+            .unwrap(),
+            Some(lsp_types::Location {
+                uri: bar_uri,
+                range: lsp_types::Range::default(),
+            })
+        );
+
+        assert_eq!(
+            ws.goto(
+                foo_uri.clone(),
+                lsp_types::Position {
+                    line: 2,
+                    character: "import \"baz".len().try_into().unwrap(),
+                }
+            )
+            .unwrap(),
+            Some(lsp_types::Location {
+                uri: baz_uri,
+                range: lsp_types::Range::default(),
+            })
+        );
+
+        assert_eq!(
+            ws.goto(
+                foo_uri,
+                lsp_types::Position {
+                    line: 3,
+                    character: "import \"biz".len().try_into().unwrap(),
+                }
+            )
+            .unwrap(),
+            None,
+        );
+    }
+
+    #[test]
+    fn test_goto_type() {
+        let (mut ws, tmp) = setup();
+        let (foo_uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &[
+                "syntax = \"proto3\";",        // 0
+                "package main;",               // 1
+                "import \"bar.proto\";",       // 2
+                "import \"baz.proto\";",       // 3
+                "message One {",               // 4
+                "message Two {",               // 5
+                "enum Three {}",               // 6
+                "}",                           // 7
+                "Two.Three tt = 1;",           // 8
+                "}",                           // 9
+                "message Stuff {",             // 10
+                "One one = 1;",                // 11
+                "One.Two two = 2;",            // 12
+                "One.Two.Three three = 3;",    // 13
+                "Two nope = 4;",               // 14
+                "bar.One bar_one = 5;",        // 15
+                "bar.One.Two b1 = 6;",         // 16
+                "bar.One.Two.Three b123 = 7;", // 17
+                "baz.buz.Baz bazbuz = 8;",     // 18
+                "}",                           // 19
+            ],
+        );
+        let (bar_uri, _) = proto(
+            &tmp,
+            "bar.proto",
+            &[
+                "syntax = \"proto3\";", // 0
+                "package bar;",         // 1
+                "message One",          // 2
+                "message One {",        // 3
+                "message Two {",        // 4
+                "enum Three {",         // 5
+                "}",                    // 6
+                "}",                    // 7
+                "}",                    // 8
+            ],
+        );
+        let (baz_uri, _) = proto(
+            &tmp,
+            "baz.proto",
+            &[
+                "syntax = \"proto3\";", // 0
+                "package baz.buz;",     // 1
+                "message Baz{}",        // 2
+            ],
+        );
+
+        ws.open(foo_uri.clone(), text).unwrap();
+
+        assert_eq!(
+            ws.goto(
+                foo_uri.clone(),
+                lsp_types::Position {
+                    line: 8,
+                    character: "Two.Th".len().try_into().unwrap(),
+                }
+            )
+            .unwrap(),
+            Some(lsp_types::Location {
+                uri: foo_uri.clone(),
+                range: lsp_types::Range {
+                    start: lsp_types::Position {
+                        line: 6,
+                        character: 0,
+                    },
+                    end: lsp_types::Position {
+                        line: 6,
+                        character: 13,
+                    },
+                },
+            })
+        );
+
+        assert_eq!(
+            ws.goto(
+                foo_uri.clone(),
+                lsp_types::Position {
+                    line: 11,
+                    character: 0,
+                }
+            )
+            .unwrap(),
+            Some(lsp_types::Location {
+                uri: foo_uri.clone(),
+                range: lsp_types::Range {
+                    start: lsp_types::Position {
+                        line: 4,
+                        character: 0,
+                    },
+                    end: lsp_types::Position {
+                        line: 9,
+                        character: 1,
+                    },
+                },
+            })
+        );
+
+        assert_eq!(
+            ws.goto(
+                foo_uri.clone(),
+                lsp_types::Position {
+                    line: 12,
+                    character: "One.".len().try_into().unwrap(),
+                }
+            )
+            .unwrap(),
+            Some(lsp_types::Location {
+                uri: foo_uri.clone(),
+                range: lsp_types::Range {
+                    start: lsp_types::Position {
+                        line: 5,
+                        character: 0,
+                    },
+                    end: lsp_types::Position {
+                        line: 7,
+                        character: 1,
+                    },
+                },
+            })
+        );
+
+        assert_eq!(
+            ws.goto(
+                foo_uri.clone(),
+                lsp_types::Position {
+                    line: 13,
+                    character: "One.Two.T".len().try_into().unwrap(),
+                }
+            )
+            .unwrap(),
+            Some(lsp_types::Location {
+                uri: foo_uri.clone(),
+                range: lsp_types::Range {
+                    start: lsp_types::Position {
+                        line: 6,
+                        character: 0,
+                    },
+                    end: lsp_types::Position {
+                        line: 6,
+                        character: 13,
+                    },
+                },
+            })
+        );
+
+        assert_eq!(
+            ws.goto(
+                foo_uri.clone(),
+                lsp_types::Position {
+                    line: 15,
+                    character: 0,
+                }
+            )
+            .unwrap(),
+            Some(lsp_types::Location {
+                uri: bar_uri.clone(),
+                range: lsp_types::Range {
+                    start: lsp_types::Position {
+                        line: 3,
+                        character: 0,
+                    },
+                    end: lsp_types::Position {
+                        line: 8,
+                        character: 1,
+                    },
+                },
+            })
+        );
+
+        assert_eq!(
+            ws.goto(
+                foo_uri.clone(),
+                lsp_types::Position {
+                    line: 16,
+                    character: 0,
+                }
+            )
+            .unwrap(),
+            Some(lsp_types::Location {
+                uri: bar_uri.clone(),
+                range: lsp_types::Range {
+                    start: lsp_types::Position {
+                        line: 4,
+                        character: 0,
+                    },
+                    end: lsp_types::Position {
+                        line: 7,
+                        character: 1,
+                    },
+                },
+            })
+        );
+
+        assert_eq!(
+            ws.goto(
+                foo_uri.clone(),
+                lsp_types::Position {
+                    line: 17,
+                    character: 0,
+                }
+            )
+            .unwrap(),
+            Some(lsp_types::Location {
+                uri: bar_uri.clone(),
+                range: lsp_types::Range {
+                    start: lsp_types::Position {
+                        line: 5,
+                        character: 0,
+                    },
+                    end: lsp_types::Position {
+                        line: 6,
+                        character: 1,
+                    },
+                },
+            })
+        );
+
+        assert_eq!(
+            ws.goto(
+                foo_uri.clone(),
+                lsp_types::Position {
+                    line: 18,
+                    character: 2,
+                }
+            )
+            .unwrap(),
+            Some(lsp_types::Location {
+                uri: baz_uri.clone(),
+                range: lsp_types::Range {
+                    start: lsp_types::Position {
+                        line: 2,
+                        character: 0,
+                    },
+                    end: lsp_types::Position {
+                        line: 2,
+                        character: 13,
+                    },
+                },
+            })
+        );
+
+        assert_eq!(
+            ws.goto(
+                foo_uri.clone(),
+                lsp_types::Position {
+                    line: 14,
+                    character: 0,
+                }
+            )
+            .unwrap(),
+            None,
+        );
+    }
+
+    #[test]
+    fn test_references_reserved_name() {
+        let (mut ws, tmp) = setup();
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &[
+                "syntax = \"proto3\";",         // 0
+                "message Foo {",                // 1
+                "  reserved \"bar\", \"baz\";", // 2
+                "  int32 bar = 1;",             // 3
+                "  int32 biz = 2;",             // 4
+                "  message Inner {",            // 5
+                "    int32 bar = 1;",           // 6
+                "  }",                          // 7
+                "}",                            // 8
+                "message Other {",              // 9
+                "  int32 bar = 1;",             // 10
+                "}",                            // 11
+            ],
+        );
+        ws.open(uri.clone(), text).unwrap();
+
+        // Fields of other messages, including nested ones, may use the name.
+        assert_eq!(
+            ws.references(references(&uri, 2, 13)).unwrap(),
+            Some(vec![lsp_types::Location {
+                uri: uri.clone(),
+                range: range(3, 8, 11),
+            }])
+        );
+        assert_eq!(
+            ws.references(references(&uri, 2, 20)).unwrap(),
+            Some(vec![])
+        );
+        assert_eq!(
+            ws.goto(uri.clone(), lsp_types::Position::new(2, 13))
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_edit_unopened() {
+        let (mut ws, tmp) = setup();
+        let (uri, _) = proto(
+            &tmp,
+            "foo.proto",
+            &["syntax = \"proto3\";", "message Foo {}"],
+        );
+        let pos = lsp_types::Position {
+            line: 2,
+            character: 0,
+        };
+
+        // An edit to an unopened file applies to the contents on disk.
+        ws.edit(
+            &uri,
+            vec![lsp_types::TextDocumentContentChangeEvent {
+                range: Some(lsp_types::Range {
+                    start: pos,
+                    end: pos,
+                }),
+                range_length: None,
+                text: "message Bar {}\n".into(),
+            }],
+        )
+        .unwrap();
+        assert_eq!(
+            ws.get(&uri).unwrap().text(),
+            "syntax = \"proto3\";\nmessage Foo {}\nmessage Bar {}\n"
+        );
+
+        // A full replacement does not need the file on disk.
+        let uri = Url::from_file_path(tmp.path().join("missing.proto")).unwrap();
+        ws.edit(
+            &uri,
+            vec![lsp_types::TextDocumentContentChangeEvent {
+                range: None,
+                range_length: None,
+                text: "syntax = \"proto3\";\n".into(),
+            }],
+        )
+        .unwrap();
+        assert_eq!(ws.get(&uri).unwrap().text(), "syntax = \"proto3\";\n");
+    }
+
+    #[test]
+    fn test_edit_reports_changed_imports() {
+        let (mut ws, tmp) = setup();
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &["syntax = \"proto3\";", "import \"bar.proto\";"],
+        );
+        ws.open(uri.clone(), text).unwrap();
+
+        let insert = |line: u32, text: &str| {
+            let pos = lsp_types::Position { line, character: 0 };
+            vec![lsp_types::TextDocumentContentChangeEvent {
+                range: Some(lsp_types::Range {
+                    start: pos,
+                    end: pos,
+                }),
+                range_length: None,
+                text: text.into(),
+            }]
+        };
+
+        // bar.proto did not exist when it was first imported, so it is loaded by the next edit,
+        // though the imports are unchanged.
+        let (bar_uri, _) = proto(&tmp, "bar.proto", &["syntax = \"proto3\";"]);
+        assert!(!ws.edit(&uri, insert(2, "message Foo {}\n")).unwrap());
+        assert!(ws.files.contains_key(&bar_uri));
+
+        // A new import is loaded, and the imports have changed.
+        let (baz_uri, _) = proto(&tmp, "baz.proto", &["syntax = \"proto3\";"]);
+        assert!(ws.edit(&uri, insert(2, "import \"baz.proto\";\n")).unwrap());
+        assert!(ws.files.contains_key(&baz_uri));
+
+        // Removing an import changes them too.
+        let remove = vec![lsp_types::TextDocumentContentChangeEvent {
+            range: Some(lsp_types::Range {
+                start: lsp_types::Position::new(2, 0),
+                end: lsp_types::Position::new(3, 0),
+            }),
+            range_length: None,
+            text: String::new(),
+        }];
+        assert!(ws.edit(&uri, remove).unwrap());
+    }
+
+    #[test]
+    fn test_references_unsaved_edit() {
+        let (mut ws, tmp) = setup();
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &[
+                "syntax = \"proto3\";", // 0
+                "message Foo {}",       // 1
+                "message Bar {",        // 2
+                "  Foo f = 1;",         // 3
+                "}",                    // 4
+            ],
+        );
+        ws.open(uri.clone(), text).unwrap();
+
+        let pos = lsp_types::Position {
+            line: 4,
+            character: 0,
+        };
+        ws.edit(
+            &uri,
+            vec![lsp_types::TextDocumentContentChangeEvent {
+                range: Some(lsp_types::Range {
+                    start: pos,
+                    end: pos,
+                }),
+                range_length: None,
+                text: "  Foo g = 2;\n".into(),
+            }],
+        )
+        .unwrap();
+
+        // The edit is not saved, so only the buffer contains the second reference.
+        assert_eq!(
+            ws.references(references(&uri, 1, 9)).unwrap(),
+            Some(vec![
+                lsp_types::Location {
+                    uri: uri.clone(),
+                    range: range(3, 2, 5),
+                },
+                lsp_types::Location {
+                    uri: uri.clone(),
+                    range: range(4, 2, 5),
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn test_references_equivalent_qualifiers() {
+        let (mut ws, tmp) = setup();
+        let (foo_uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &["syntax = \"proto3\";", "package main;", "message Foo {}"],
+        );
+        ws.open(foo_uri, text).unwrap();
+        let (bar_uri, text) = proto(
+            &tmp,
+            "bar.proto",
+            &[
+                "syntax = \"proto3\";",  // 0
+                "package main;",         // 1
+                "import \"foo.proto\";", // 2
+                "message Bar {",         // 3
+                "  Foo f = 1;",          // 4
+                "}",                     // 5
+            ],
+        );
+        ws.open(bar_uri.clone(), text).unwrap();
+        let (baz_uri, text) = proto(
+            &tmp,
+            "baz.proto",
+            &[
+                "syntax = \"proto3\";",  // 0
+                "package main;",         // 1
+                "import \"foo.proto\";", // 2
+                "message Baz {",         // 3
+                "  main.Foo f = 1;",     // 4
+                "}",                     // 5
+            ],
+        );
+        ws.open(baz_uri.clone(), text).unwrap();
+
+        let expected = vec![
+            lsp_types::Location {
+                uri: bar_uri.clone(),
+                range: range(4, 2, 5),
+            },
+            lsp_types::Location {
+                uri: baz_uri.clone(),
+                range: range(4, 2, 10),
+            },
+        ];
+        for (uri, character) in [(&bar_uri, 3), (&baz_uri, 8)] {
+            let mut actual = ws
+                .references(references(uri, 4, character))
+                .unwrap()
+                .unwrap();
+            actual.sort_by_key(|loc| loc.uri.to_string());
+            assert_eq!(actual, expected);
+        }
+    }
+
+    #[test]
+    fn test_references_nested_scope() {
+        let (mut ws, tmp) = setup();
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &[
+                "syntax = \"proto3\";",
+                "package main;",
+                "message Outer {",
+                "  message Inner {}",
+                "  Inner a = 1;",
+                "  message Deeper {",
+                "    Inner b = 1;",
+                "  }",
+                "}",
+                "message Other {",
+                "  message Inner {}",
+                "  Inner c = 1;",
+                "  Outer.Inner d = 2;",
+                "}",
+            ],
+        );
+        ws.open(uri.clone(), text).unwrap();
+
+        let expected = vec![
+            lsp_types::Location {
+                uri: uri.clone(),
+                range: range(4, 2, 7),
+            },
+            lsp_types::Location {
+                uri: uri.clone(),
+                range: range(6, 4, 9),
+            },
+            lsp_types::Location {
+                uri: uri.clone(),
+                range: range(12, 2, 13),
+            },
+        ];
+        for (line, character) in [(4, 3), (6, 5), (12, 9)] {
+            let mut actual = ws
+                .references(references(&uri, line, character))
+                .unwrap()
+                .unwrap();
+            actual.sort_by_key(|loc| loc.range.start);
+            assert_eq!(actual, expected, "references from {line}:{character}");
+        }
+    }
+
+    #[test]
+    fn test_rpc_symbols() {
+        let (mut ws, tmp) = setup();
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &[
+                "syntax = \"proto3\";",                                  // 0
+                "message HelloRequest {}",                               // 1
+                "message HelloReply {}",                                 // 2
+                "service Greeter {",                                     // 3
+                "  rpc SayHello (HelloRequest) returns (HelloReply) {}", // 4
+                "}",                                                     // 5
+            ],
+        );
+        ws.open(uri.clone(), text).unwrap();
+
+        let rpc = lsp_types::Location {
+            uri: uri.clone(),
+            range: range(4, 2, 53),
+        };
+
+        let actual = ws.all_symbols("SayHello").unwrap();
+        assert_eq!(
+            actual.iter().map(|s| &s.location).collect::<Vec<_>>(),
+            vec![&rpc]
+        );
+        assert_eq!(actual[0].name, "Greeter.SayHello");
+        assert_eq!(actual[0].kind, lsp_types::SymbolKind::METHOD);
+
+        assert_eq!(
+            ws.goto(
+                uri.clone(),
+                lsp_types::Position {
+                    line: 4,
+                    character: "  rpc Say".len().try_into().unwrap(),
+                }
+            )
+            .unwrap(),
+            Some(rpc)
+        );
+    }
+
+    #[test]
+    fn test_rpc_type_references() {
+        let (mut ws, tmp) = setup();
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &[
+                "syntax = \"proto3\";",                                         // 0
+                "message HelloRequest {}",                                      // 1
+                "message HelloReply {}",                                        // 2
+                "message Wrapper {",                                            // 3
+                "  HelloReply reply = 1;",                                      // 4
+                "}",                                                            // 5
+                "service Greeter {",                                            // 6
+                "  rpc SayHello (HelloRequest) returns (stream HelloReply) {}", // 7
+                "}",                                                            // 8
+            ],
+        );
+        ws.open(uri.clone(), text).unwrap();
+
+        assert_eq!(
+            ws.references(references(&uri, 2, 10)).unwrap(),
+            Some(vec![
+                lsp_types::Location {
+                    uri: uri.clone(),
+                    range: range(4, 2, 12),
+                },
+                lsp_types::Location {
+                    uri: uri.clone(),
+                    range: range(7, 46, 56),
+                },
+            ])
+        );
+
+        assert_eq!(
+            ws.goto(
+                uri.clone(),
+                lsp_types::Position {
+                    line: 7,
+                    character: "  rpc SayHello (Hello".len().try_into().unwrap(),
+                }
+            )
+            .unwrap(),
+            Some(lsp_types::Location {
+                uri: uri.clone(),
+                range: range(1, 0, 23),
+            })
+        );
+    }
+
+    #[test]
+    fn test_complete_keywords_by_syntax() {
+        let (mut ws, tmp) = setup();
+
+        let mut keywords = |syntax: &str| {
+            let (uri, text) = proto(
+                &tmp,
+                "foo.proto",
+                &[
+                    format!("syntax = \"{syntax}\";").as_str(),
+                    "message Foo {",
+                    "r",
+                    "}",
+                ],
+            );
+            ws.open(uri.clone(), text).unwrap();
+            complete_items(&ws, &uri, 2, 1)
+                .into_iter()
+                .filter(|i| i.kind == Some(lsp_types::CompletionItemKind::KEYWORD))
+                .map(|i| i.label)
+                .collect::<Vec<_>>()
+        };
+
+        let proto2 = keywords("proto2");
+        assert!(proto2.contains(&"required".to_string()), "{proto2:?}");
+        assert!(proto2.contains(&"group".to_string()), "{proto2:?}");
+        assert!(proto2.contains(&"optional".to_string()), "{proto2:?}");
+
+        let proto3 = keywords("proto3");
+        assert!(!proto3.contains(&"required".to_string()), "{proto3:?}");
+        assert!(!proto3.contains(&"group".to_string()), "{proto3:?}");
+        assert!(proto3.contains(&"optional".to_string()), "{proto3:?}");
+    }
+
+    #[test]
+    fn test_complete_modifiers() {
+        let (mut ws, tmp) = setup();
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &["syntax = \"proto2\";", "message Foo {", "r", "}"],
+        );
+        ws.open(uri.clone(), text).unwrap();
+        let complete = |ws: &Workspace| complete_items(ws, &uri, 2, 1);
+
+        // Without the option, the modifier is inserted but completion is not re-triggered.
+        let items = complete(&ws);
+        let optional = items.iter().find(|i| i.label == "optional").unwrap();
+        assert_eq!(optional.insert_text.as_deref(), Some("optional "));
+        assert_eq!(optional.command, None);
+
+        ws.options.completion.trigger_suggest = true;
+        let items = complete(&ws);
+        let find = |label: &str| items.iter().find(|i| i.label == label).unwrap();
+        for (label, insert) in [
+            ("optional", "optional "),
+            ("repeated", "repeated "),
+            ("required", "required "),
+            ("map", "map<"),
+        ] {
+            let item = find(label);
+            assert_eq!(item.insert_text.as_deref(), Some(insert));
+            assert_eq!(item.command, Some(trigger_suggest()));
+        }
+        assert_eq!(find("message").insert_text, None);
+        assert_eq!(find("message").command, None);
+    }
+
+    #[test]
+    fn test_complete_keywords_in_edition() {
+        let (mut ws, tmp) = setup();
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &[
+                "edition = \"2023\";",                       // 0
+                "message Foo {",                             // 1
+                "r",                                         // 2
+                "  int32 i = 1 [features.field_pr = true];", // 3
+                "}",                                         // 4
+            ],
+        );
+        ws.open(uri.clone(), text).unwrap();
+        let items = complete_items(&ws, &uri, 2, 1);
+        let labels = items.iter().map(|i| i.label.as_str()).collect::<Vec<_>>();
+        assert!(labels.contains(&"repeated"), "{labels:?}");
+        assert!(!labels.contains(&"optional"), "{labels:?}");
+        assert!(!labels.contains(&"required"), "{labels:?}");
+        assert!(!labels.contains(&"group"), "{labels:?}");
+        // Presence is a field option, so it is not suggested where a type goes.
+        assert!(!labels.contains(&"features.field_presence"), "{labels:?}");
+
+        let items = complete_items(&ws, &uri, 3, "  int32 i = 1 [features.field_pr".len());
+        assert_eq!(
+            items,
+            vec![lsp_types::CompletionItem {
+                label: "features.field_presence".into(),
+                kind: Some(lsp_types::CompletionItemKind::PROPERTY),
+                insert_text: Some(
+                    "features.field_presence = ${1|EXPLICIT,IMPLICIT,LEGACY_REQUIRED|}".into()
+                ),
+                insert_text_format: Some(lsp_types::InsertTextFormat::SNIPPET),
+                ..Default::default()
+            }]
+        );
+    }
+
+    #[test]
+    fn test_toggle_optional() {
+        let (mut ws, tmp) = setup();
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &[
+                "syntax = \"proto3\";",     // 0
+                "message Foo {",            // 1
+                "  uint32 i = 1;",          // 2
+                "  optional string s = 2;", // 3
+                "  repeated int32 r = 3;",  // 4
+                "  Foo f = 4;",             // 5
+                "}",                        // 6
+            ],
+        );
+        ws.open(uri.clone(), text).unwrap();
+
+        let actions = |line| {
+            ws.code_actions(lsp_types::CodeActionParams {
+                text_document: lsp_types::TextDocumentIdentifier { uri: uri.clone() },
+                range: range(line, 4, 4),
+                context: Default::default(),
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+            })
+            .unwrap()
+        };
+
+        let edit = |title: &str, range, new_text: &str| {
+            vec![lsp_types::CodeActionOrCommand::CodeAction(
+                lsp_types::CodeAction {
+                    title: title.into(),
+                    kind: Some(lsp_types::CodeActionKind::REFACTOR_REWRITE),
+                    edit: Some(lsp_types::WorkspaceEdit {
+                        changes: Some(std::collections::HashMap::from([(
+                            uri.clone(),
+                            vec![lsp_types::TextEdit {
+                                range,
+                                new_text: new_text.into(),
+                            }],
+                        )])),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+            )]
+        };
+
+        assert_eq!(
+            actions(2),
+            edit("Add optional", range(2, 2, 2), "optional ")
+        );
+        assert_eq!(actions(3), edit("Remove optional", range(3, 2, 11), ""));
+        assert_eq!(actions(4), vec![]);
+        assert_eq!(actions(5), vec![]);
+    }
+
+    #[test]
+    fn test_sort_imports() {
+        let (mut ws, tmp) = setup();
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &[
+                "syntax = \"proto3\";",  // 0
+                "import \"foo.proto\";", // 1
+                "",                      // 2
+                "import \"bar.proto\";", // 3
+                "message Foo {}",        // 4
+            ],
+        );
+        ws.open(uri.clone(), text).unwrap();
+
+        let actions = ws
+            .code_actions(lsp_types::CodeActionParams {
+                text_document: lsp_types::TextDocumentIdentifier { uri: uri.clone() },
+                range: range(4, 0, 0),
+                context: Default::default(),
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+            })
+            .unwrap();
+        assert_eq!(
+            actions,
+            vec![lsp_types::CodeActionOrCommand::CodeAction(
+                lsp_types::CodeAction {
+                    title: "Sort imports".into(),
+                    kind: Some(lsp_types::CodeActionKind::SOURCE_ORGANIZE_IMPORTS),
+                    edit: Some(lsp_types::WorkspaceEdit {
+                        changes: Some(std::collections::HashMap::from([(
+                            uri.clone(),
+                            vec![lsp_types::TextEdit {
+                                range: lsp_types::Range {
+                                    start: lsp_types::Position::new(1, 0),
+                                    end: lsp_types::Position::new(4, 0),
+                                },
+                                new_text: "import \"bar.proto\";\n\nimport \"foo.proto\";\n".into(),
+                            }],
+                        )])),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+            )]
+        );
+    }
+
+    #[test]
+    fn test_extract_nested_message() {
+        let (mut ws, tmp) = setup();
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &[
+                "syntax = \"proto3\";",               // 0
+                "package main;",                      // 1
+                "message Outer {",                    // 2
+                "  // The inner message.",            // 3
+                "  message Inner {",                  // 4
+                "    Other o = 1;",                   // 5
+                "    Inner next = 2;",                // 6
+                "  }",                                // 7
+                "  message Other {}",                 // 8
+                "  Inner a = 1;",                     // 9
+                "  Outer.Inner b = 2;",               // 10
+                "}",                                  // 11
+                "message User {",                     // 12
+                "  Outer.Inner c = 1;",               // 13
+                "  main.Outer.Inner d = 2;",          // 14
+                "}",                                  // 15
+                "message Single { message Tiny {} }", // 16
+            ],
+        );
+        ws.open(uri.clone(), text).unwrap();
+
+        let actions = |line, character| {
+            ws.code_actions(lsp_types::CodeActionParams {
+                text_document: lsp_types::TextDocumentIdentifier { uri: uri.clone() },
+                range: range(line, character, character),
+                context: Default::default(),
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+            })
+            .unwrap()
+        };
+        let edit = |range, new_text: &str| lsp_types::TextEdit {
+            range,
+            new_text: new_text.into(),
+        };
+
+        // Top-level types cannot be extracted.
+        assert_eq!(actions(12, 4), vec![]);
+
+        // The edit is only computed when the action is resolved.
+        let action = lsp_types::CodeAction {
+            title: "Extract nested message to top level".into(),
+            kind: Some(lsp_types::CodeActionKind::REFACTOR_EXTRACT),
+            data: Some(serde_json::json!({
+                "textDocument": {"uri": uri},
+                "position": {"line": 4, "character": 12},
+            })),
+            ..Default::default()
+        };
+        assert_eq!(
+            actions(4, 12),
+            vec![lsp_types::CodeActionOrCommand::CodeAction(action.clone())]
+        );
+
+        assert_eq!(
+            ws.resolve_code_action(action).unwrap(),
+            lsp_types::CodeAction {
+                title: "Extract nested message to top level".into(),
+                kind: Some(lsp_types::CodeActionKind::REFACTOR_EXTRACT),
+                edit: Some(lsp_types::WorkspaceEdit {
+                    changes: Some(std::collections::HashMap::from([(
+                        uri.clone(),
+                        vec![
+                            edit(range(10, 2, 13), "Inner"),
+                            edit(range(13, 2, 13), "Inner"),
+                            edit(range(14, 2, 18), "main.Inner"),
+                            edit(
+                                lsp_types::Range::new(
+                                    lsp_types::Position::new(3, 0),
+                                    lsp_types::Position::new(8, 0),
+                                ),
+                                "",
+                            ),
+                            edit(
+                                range(17, 0, 0),
+                                [
+                                    "",
+                                    "// The inner message.",
+                                    "message Inner {",
+                                    "  Outer.Other o = 1;",
+                                    "  Inner next = 2;",
+                                    "}",
+                                    "",
+                                ]
+                                .join("\n")
+                                .as_str(),
+                            ),
+                        ],
+                    )])),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }
+        );
+
+        // A type sharing its line with other text is removed without the rest of the line.
+        let [lsp_types::CodeActionOrCommand::CodeAction(action)] = &actions(16, 26)[..] else {
+            panic!("Expected a single code action");
+        };
+        assert_eq!(
+            ws.resolve_code_action(action.clone()).unwrap().edit,
+            Some(lsp_types::WorkspaceEdit {
+                changes: Some(std::collections::HashMap::from([(
+                    uri.clone(),
+                    vec![
+                        edit(range(16, 17, 32), ""),
+                        edit(range(17, 0, 0), "\nmessage Tiny {}\n"),
+                    ],
+                )])),
+                ..Default::default()
+            })
+        );
+    }
+
+    #[test]
+    fn test_extract_nested_message_name_collision() {
+        let (mut ws, tmp) = setup();
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &[
+                "syntax = \"proto3\";", // 0
+                "message Outer {",      // 1
+                "  message Inner {}",   // 2
+                "}",                    // 3
+                "message Inner {}",     // 4
+            ],
+        );
+        ws.open(uri.clone(), text).unwrap();
+
+        let actions = ws
+            .code_actions(lsp_types::CodeActionParams {
+                text_document: lsp_types::TextDocumentIdentifier { uri: uri.clone() },
+                range: range(2, 12, 12),
+                context: Default::default(),
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+            })
+            .unwrap();
+        assert_eq!(
+            actions,
+            vec![lsp_types::CodeActionOrCommand::CodeAction(
+                lsp_types::CodeAction {
+                    title: "Extract nested message to top level".into(),
+                    kind: Some(lsp_types::CodeActionKind::REFACTOR_EXTRACT),
+                    disabled: Some(lsp_types::CodeActionDisabled {
+                        reason: "A top-level type named Inner already exists".into(),
+                    }),
+                    ..Default::default()
+                },
+            )]
+        );
+    }
+
+    #[test]
+    fn test_remove_unused_import() {
+        let (mut ws, tmp) = setup();
+        proto(&tmp, "bar.proto", &["syntax = \"proto3\";"]);
+        proto(&tmp, "baz.proto", &["syntax = \"proto3\";"]);
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &[
+                "syntax = \"proto3\";",  // 0
+                "import \"bar.proto\";", // 1
+                "import \"baz.proto\";", // 2
+                "message Foo {}",        // 3
+            ],
+        );
+        ws.open(uri.clone(), text.clone()).unwrap();
+
+        let diag = |line, code: Option<&str>| lsp_types::Diagnostic {
+            range: range(line, 0, 19),
+            severity: Some(lsp_types::DiagnosticSeverity::WARNING),
+            code: code.map(|c| lsp_types::NumberOrString::String(c.into())),
+            message: "Import bar.proto is unused".into(),
+            ..Default::default()
+        };
+        let actions = |diag: lsp_types::Diagnostic| {
+            ws.code_actions(lsp_types::CodeActionParams {
+                text_document: lsp_types::TextDocumentIdentifier { uri: uri.clone() },
+                range: diag.range,
+                context: lsp_types::CodeActionContext {
+                    diagnostics: vec![diag],
+                    ..Default::default()
+                },
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+            })
+            .unwrap()
+        };
+
+        // Only diagnostics with the unused import code get a fix.
+        assert_eq!(actions(diag(1, None)), vec![]);
+        assert_eq!(actions(diag(1, Some("field-name"))), vec![]);
+
+        let diag = diag(1, Some(protoc::UNUSED_IMPORT));
+        let edit = lsp_types::TextEdit {
+            range: lsp_types::Range {
+                start: lsp_types::Position::new(1, 0),
+                end: lsp_types::Position::new(2, 0),
+            },
+            new_text: "".into(),
+        };
+        assert_eq!(
+            actions(diag.clone()),
+            vec![lsp_types::CodeActionOrCommand::CodeAction(
+                lsp_types::CodeAction {
+                    title: "Remove unused import".into(),
+                    kind: Some(lsp_types::CodeActionKind::QUICKFIX),
+                    diagnostics: Some(vec![diag]),
+                    edit: Some(lsp_types::WorkspaceEdit {
+                        changes: Some(std::collections::HashMap::from([(
+                            uri.clone(),
+                            vec![edit.clone()],
+                        )])),
+                        ..Default::default()
+                    }),
+                    is_preferred: Some(true),
+                    ..Default::default()
+                },
+            )]
+        );
+
+        // Applying the edit leaves no blank line behind.
+        ws.edit(
+            &uri,
+            vec![lsp_types::TextDocumentContentChangeEvent {
+                range: Some(edit.range),
+                range_length: None,
+                text: edit.new_text,
+            }],
+        )
+        .unwrap();
+        assert_eq!(
+            ws.get(&uri).unwrap().text(),
+            [
+                "syntax = \"proto3\";",
+                "import \"baz.proto\";",
+                "message Foo {}",
+                "",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn test_complete_package() {
+        let (mut ws, tmp) = setup();
+        let dir = tmp.path().join("foo").join("bar");
+        std::fs::create_dir_all(&dir).unwrap();
+        let (uri, text) = proto(&dir, "baz.proto", &["syntax = \"proto3\";", "package "]);
+
+        ws.open(uri.clone(), text).unwrap();
+        assert_eq!(
+            ws.complete(&uri, 1, "package ".len()).unwrap().unwrap(),
+            lsp_types::CompletionResponse::Array(vec![lsp_types::CompletionItem {
+                label: "foo.bar".into(),
+                kind: Some(lsp_types::CompletionItemKind::MODULE),
+                insert_text: Some("foo.bar;".into()),
+                ..Default::default()
+            }])
+        );
+    }
+
+    #[test]
+    fn test_complete_import_replace_existing() {
+        let (mut ws, tmp) = setup();
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &["syntax = \"proto3\";", "import \"bar.proto\";"],
+        );
+        proto(&tmp, "bar.proto", &["syntax = \"proto3\";"]);
+        proto(&tmp, "baz.proto", &["syntax = \"proto3\";"]);
+
+        ws.open(uri.clone(), text).unwrap();
+        assert_eq!(
+            ws.complete(&uri, 1, "import \"".len()).unwrap().unwrap(),
+            lsp_types::CompletionResponse::Array(vec![lsp_types::CompletionItem {
+                label: "baz.proto".into(),
+                kind: Some(lsp_types::CompletionItemKind::FILE),
+                text_edit: Some(lsp_types::CompletionTextEdit::Edit(lsp_types::TextEdit {
+                    range: range(1, 8, 17),
+                    new_text: "baz.proto".into(),
+                })),
+                ..Default::default()
+            },])
+        );
+
+        // Positions are byte offsets, so non-ASCII paths shift the range by their length.
+        let text = "import \"é/bar.proto\";";
+        assert_eq!(import_path_range(text, 0, 10), Some(range(0, 8, 20)));
+        assert_eq!(import_path_range(text, 0, 9), None);
+    }
+
+    #[test]
+    fn test_import_created_after_open() {
+        let (mut ws, tmp) = setup();
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &[
+                "syntax = \"proto3\";",
+                "import \"bar.proto\";",
+                "message Foo {",
+                "  Bar b = 1;",
+                "}",
+            ],
+        );
+        ws.open(uri.clone(), text).unwrap();
+
+        // The import exists now, but was not loaded when foo.proto was opened.
+        proto(
+            &tmp,
+            "bar.proto",
+            &["syntax = \"proto3\";", "message Bar {}"],
+        );
+
+        let pos = lsp_types::Position {
+            line: 3,
+            character: 2,
+        };
+        assert_eq!(ws.goto(uri.clone(), pos).unwrap(), None);
+        assert!(ws.complete(&uri, 3, 3).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_complete_well_known_types() {
+        let (mut ws, tmp) = setup();
+        let _cache = use_cache_dir(&mut ws);
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &[
+                "syntax = \"proto3\";",
+                "package foo;",
+                "import \"google/protobuf/timestamp.proto\";",
+                "message Foo {",
+                "  T",
+                "}",
+            ],
+        );
+        ws.open(uri.clone(), text).unwrap();
+
+        let items = complete_items(&ws, &uri, 4, 3);
+        let labels = items
+            .into_iter()
+            .filter(|i| i.kind == Some(lsp_types::CompletionItemKind::STRUCT))
+            .filter(|i| i.label.starts_with("google"))
+            .map(|i| i.label)
+            .collect::<Vec<_>>();
+        // Only the imported well-known file is offered.
+        assert_eq!(labels, vec!["google.protobuf.Timestamp"]);
+    }
+
+    #[test]
+    fn test_goto_well_known_type() {
+        let (mut ws, tmp) = setup();
+        let cache = use_cache_dir(&mut ws);
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &[
+                "syntax = \"proto3\";",
+                "import \"google/protobuf/timestamp.proto\";",
+                "message Foo {",
+                "  google.protobuf.Timestamp t = 1;",
+                "}",
+            ],
+        );
+        ws.open(uri.clone(), text).unwrap();
+
+        let loc = ws
+            .goto(uri.clone(), lsp_types::Position::new(3, 20))
+            .unwrap()
+            .unwrap();
+        let path = loc.uri.to_file_path().unwrap();
+        assert!(
+            path.ends_with("google/protobuf/timestamp.proto"),
+            "{path:?}"
+        );
+
+        let text = std::fs::read_to_string(path).unwrap();
+        let line = text.lines().nth(loc.range.start.line as usize).unwrap();
+        assert_eq!(line, "message Timestamp {");
+
+        // The import itself also resolves to the bundled file.
+        let bundled = wkt::path(cache.path(), "google/protobuf/timestamp.proto").unwrap();
+        assert!(bundled.starts_with(cache.path()), "{bundled:?}");
+        let bundled = Url::from_file_path(bundled).unwrap();
+        assert_eq!(loc.uri, bundled);
+        assert_eq!(
+            ws.goto(uri.clone(), lsp_types::Position::new(1, 10))
+                .unwrap()
+                .map(|loc| loc.uri),
+            Some(bundled.clone())
+        );
+
+        // The bundled file is reused, so its URI is stable.
+        assert_eq!(
+            ws.goto(uri, lsp_types::Position::new(3, 20))
+                .unwrap()
+                .map(|loc| loc.uri),
+            Some(bundled)
+        );
+    }
+
+    #[test]
+    fn test_complete_qualified_labels() {
+        let (mut ws, tmp) = setup();
+        proto(
+            &tmp,
+            "bar.proto",
+            &["syntax = \"proto3\";", "package bar;", "message Bar {}"],
+        );
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &[
+                "syntax = \"proto3\";",  // 0
+                "package foo;",          // 1
+                "import \"bar.proto\";", // 2
+                "message Foo {",         // 3
+                "  message Inner {}",    // 4
+                "  B",                   // 5
+                "}",                     // 6
+            ],
+        );
+        ws.open(uri.clone(), text).unwrap();
+
+        let mut labels = |qualified: bool| {
+            ws.options.qualified_completion_labels = qualified;
+            complete_items(&ws, &uri, 5, 3)
+                .into_iter()
+                .filter(|i| i.kind == Some(lsp_types::CompletionItemKind::STRUCT))
+                .filter(|i| !BUILTINS.contains(&i.label.as_str()))
+                .map(|i| {
+                    // Typing filters on the inserted name, not the label.
+                    assert_eq!(i.filter_text, i.insert_text, "{i:?}");
+                    (i.label, i.insert_text)
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            labels(false),
+            vec![
+                (String::from("Foo"), None),
+                (String::from("Inner"), None),
+                (String::from("bar.Bar"), None),
+            ]
+        );
+        assert_eq!(
+            labels(true),
+            vec![
+                (String::from("foo.Foo"), Some(String::from("Foo"))),
+                (String::from("foo.Foo.Inner"), Some(String::from("Inner"))),
+                (String::from("bar.Bar"), Some(String::from("bar.Bar"))),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_complete_recent_types() {
+        let (mut ws, tmp) = setup();
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &[
+                "syntax = \"proto3\";", // 0
+                "package foo;",         // 1
+                "message Apple {}",     // 2
+                "message Avocado {}",   // 3
+                "message Foo {",        // 4
+                "  Avocado a = 1;",     // 5
+                "  A",                  // 6
+                "}",                    // 7
+            ],
+        );
+        ws.open(uri.clone(), text).unwrap();
+
+        let sorted = |ws: &Workspace| {
+            let mut items = complete_items(ws, &uri, 6, 3);
+            items.retain(|i| i.label.starts_with('A'));
+            items.sort_by_key(|i| i.sort_text.clone().unwrap_or(i.label.clone()));
+            items.into_iter().map(|i| i.label).collect::<Vec<_>>()
+        };
+        assert_eq!(sorted(&ws), vec!["Apple", "Avocado"]);
+
+        // Going to a type ranks it first.
+        ws.goto(uri.clone(), lsp_types::Position::new(5, 4))
+            .unwrap()
+            .unwrap();
+        assert_eq!(sorted(&ws), vec!["Avocado", "Apple"]);
+
+        // Highlighting a completion resolves it, but is not a use.
+        let items = complete_items(&ws, &uri, 6, 3);
+        let apple = items.into_iter().find(|i| i.label == "Apple").unwrap();
+        let apple = ws.resolve_completion(apple).unwrap();
+        assert_eq!(sorted(&ws), vec!["Avocado", "Apple"]);
+
+        // Accepting it runs its command, which ranks it first.
+        let command = apple.command.unwrap();
+        assert_eq!(command.command, USE_TYPE_COMMAND);
+        assert_eq!(
+            command.arguments,
+            Some(vec![serde_json::json!("foo.Apple")])
+        );
+        ws.use_type("foo.Apple".into());
+        assert_eq!(sorted(&ws), vec!["Apple", "Avocado"]);
+    }
+
+    #[test]
+    fn test_complete_resolve() {
+        let (mut ws, tmp) = setup();
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &[
+                "syntax = \"proto3\";", // 0
+                "package foo;",         // 1
+                "// A tasty fruit.",    // 2
+                "//",                   // 3
+                "// Grows on trees.",   // 4
+                "message Apple {}",     // 5
+                "message Foo {",        // 6
+                "  A",                  // 7
+                "}",                    // 8
+            ],
+        );
+        ws.open(uri.clone(), text).unwrap();
+
+        let items = complete_items(&ws, &uri, 7, 3);
+        let apple = items.into_iter().find(|i| i.label == "Apple").unwrap();
+        assert_eq!(apple.documentation, None);
+        assert_eq!(apple.detail, None);
+        // The item locates the definition, so resolving it need not search for it.
+        let loc = lsp_types::Location {
+            uri: uri.clone(),
+            range: range(5, 0, 16),
+        };
+        assert_eq!(apple.data, Some(serde_json::to_value(loc).unwrap()));
+
+        let apple = ws.resolve_completion(apple).unwrap();
+        assert_eq!(
+            apple.documentation,
+            Some(lsp_types::Documentation::String(
+                "A tasty fruit.\n\nGrows on trees.".into()
+            ))
+        );
+        assert_eq!(apple.detail, Some("foo.Apple".into()));
+    }
+
+    #[test]
+    fn test_complete_without_builtins() {
+        let (mut ws, tmp) = setup();
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &[
+                "syntax = \"proto3\";",
+                "message Foo {",
+                "  enum Bar {}",
+                "  B",
+                "}",
+            ],
+        );
+        ws.open(uri.clone(), text).unwrap();
+
+        let mut labels = |include_builtins: bool| {
+            ws.options.completion.include_builtins = include_builtins;
+            let items = complete_items(&ws, &uri, 3, 3);
+            items.into_iter().map(|i| i.label).collect::<Vec<_>>()
+        };
+
+        let labels_with_builtins = labels(true);
+        assert!(labels_with_builtins.contains(&"int32".into()));
+        assert!(labels_with_builtins.contains(&"repeated".into()));
+        assert_eq!(labels(false), vec!["Foo", "Bar"]);
+    }
+
+    #[test]
+    fn test_complete_nested_enum_from_import() {
+        let (mut ws, tmp) = setup();
+        proto(
+            &tmp,
+            "baz.proto",
+            &[
+                "syntax = \"proto3\";",
+                "package baz.buz;",
+                "message Baz {",
+                "  enum Color {}",
+                "}",
+            ],
+        );
+
+        let mut labels = |package: &str| {
+            let (uri, text) = proto(
+                &tmp,
+                "foo.proto",
+                &[
+                    "syntax = \"proto3\";",
+                    format!("package {package};").as_str(),
+                    "import \"baz.proto\";",
+                    "message Foo {",
+                    "  C",
+                    "}",
+                ],
+            );
+            ws.open(uri.clone(), text).unwrap();
+            complete_items(&ws, &uri, 4, 3)
+                .into_iter()
+                .filter(|i| i.kind == Some(lsp_types::CompletionItemKind::ENUM))
+                .map(|i| i.label)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(labels("main"), vec!["baz.buz.Baz.Color"]);
+        assert_eq!(labels("baz.other"), vec!["buz.Baz.Color"]);
+    }
+
+    #[test]
+    fn test_duplicate_imports() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let file = file::File::new(
+            [
+                "syntax = \"proto3\";",
+                "import \"foo.proto\";",
+                "import \"bar.proto\";",
+                "import \"foo.proto\";",
+                "",
+            ]
+            .join("\n"),
+        )
+        .unwrap();
+
+        assert_eq!(
+            duplicate_imports(&file, "pbls"),
+            vec![lsp_types::Diagnostic {
+                range: range(3, 7, 18),
+                severity: Some(lsp_types::DiagnosticSeverity::WARNING),
+                source: Some("pbls".into()),
+                message: "Duplicate import foo.proto".into(),
+                ..Default::default()
+            }]
+        );
+    }
+
+    #[test]
+    fn test_goto_option_field() {
+        let (mut ws, tmp) = setup();
+        proto(
+            &tmp,
+            "rule.proto",
+            &[
+                "syntax = \"proto3\";",                         // 0
+                "package rules;",                               // 1
+                "import \"google/protobuf/descriptor.proto\";", // 2
+                "message Rule {",                               // 3
+                "  int32 min = 1;",                             // 4
+                "  int32 max = 2;",                             // 5
+                "}",                                            // 6
+                "extend google.protobuf.MessageOptions {",      // 7
+                "  Rule rule = 50000;",                         // 8
+                "}",                                            // 9
+                "message Scope {",                              // 10
+                "  message Rule {",                             // 11
+                "    int32 max = 1;",                           // 12
+                "  }",                                          // 13
+                "  extend google.protobuf.MessageOptions {",    // 14
+                "    Rule nested = 50001;",                     // 15
+                "  }",                                          // 16
+                "}",                                            // 17
+            ],
+        );
+        proto(
+            &tmp,
+            "other.proto",
+            &[
+                "syntax = \"proto3\";",                         // 0
+                "package other;",                               // 1
+                "import \"google/protobuf/descriptor.proto\";", // 2
+                "message Other {",                              // 3
+                "  int32 max = 1;",                             // 4
+                "}",                                            // 5
+                "extend google.protobuf.MessageOptions {",      // 6
+                "  Other rule = 50002;",                        // 7
+                "}",                                            // 8
+            ],
+        );
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &[
+                "syntax = \"proto3\";",                        // 0
+                "import \"rule.proto\";",                      // 1
+                "import \"other.proto\";",                     // 2
+                "message Foo {",                               // 3
+                "  option (rules.rule) = { max: 1 };",         // 4
+                "  option (other.rule) = { max: 1 };",         // 5
+                "  option (rules.Scope.nested) = { max: 1 };", // 6
+                "  option (rule) = { max: 1 };",               // 7
+                "}",                                           // 8
+            ],
+        );
+        ws.open(uri.clone(), text).unwrap();
+
+        let goto = |line, character| {
+            ws.goto(uri.clone(), lsp_types::Position::new(line, character))
+                .unwrap()
+                .map(|loc| {
+                    let name = loc.uri.path().rsplit('/').next().unwrap().to_string();
+                    (name, loc.range.start)
+                })
+        };
+        assert_eq!(
+            goto(4, 28),
+            Some(("rule.proto".into(), lsp_types::Position::new(5, 2)))
+        );
+        assert_eq!(
+            goto(5, 28),
+            Some(("other.proto".into(), lsp_types::Position::new(4, 2)))
+        );
+        assert_eq!(
+            goto(6, 36),
+            Some(("rule.proto".into(), lsp_types::Position::new(12, 4)))
+        );
+        // The extension must be qualified by its package.
+        assert_eq!(goto(7, 22), None);
+    }
+
+    #[test]
+    fn test_goto_default_enum_value() {
+        let (mut ws, tmp) = setup();
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &[
+                "syntax = \"proto2\";",                         // 0
+                "message Foo {",                                // 1
+                "  enum Color {",                               // 2
+                "    BLUE = 0;",                                // 3
+                "    RED = 1;",                                 // 4
+                "  }",                                          // 5
+                "  optional Color c = 1 [default = RED];",      // 6
+                "}",                                            // 7
+                "enum Color {",                                 // 8
+                "  RED = 0;",                                   // 9
+                "}",                                            // 10
+                "message Bar {",                                // 11
+                "  optional Color c = 1 [default = RED];",      // 12
+                "  optional Foo.Color d = 2 [default = BLUE];", // 13
+                "}",                                            // 14
+            ],
+        );
+        ws.open(uri.clone(), text).unwrap();
+
+        let goto = |line, character| {
+            ws.goto(uri.clone(), lsp_types::Position::new(line, character))
+                .unwrap()
+                .map(|loc| loc.range)
+        };
+        assert_eq!(goto(6, 35), Some(range(4, 4, 12)));
+        assert_eq!(goto(12, 35), Some(range(9, 2, 10)));
+        assert_eq!(goto(13, 40), Some(range(3, 4, 13)));
+    }
+
+    #[test]
+    fn test_goto_message_and_enum_with_same_name() {
+        let (mut ws, tmp) = setup();
+        let (enum_uri, _) = proto(
+            &tmp,
+            "bar.proto",
+            &["syntax = \"proto3\";", "enum Status { OK = 0; }"],
+        );
+        let (message_uri, _) = proto(
+            &tmp,
+            "foo.proto",
+            &["syntax = \"proto3\";", "message Status {}"],
+        );
+        let (uri, text) = proto(
+            &tmp,
+            "main.proto",
+            &[
+                "syntax = \"proto3\";",                // 0
+                "import \"bar.proto\";",               // 1
+                "import \"foo.proto\";",               // 2
+                "message Req {",                       // 3
+                "  Status status = 1;",                // 4
+                "}",                                   // 5
+                "service Svc {",                       // 6
+                "  rpc Get (Req) returns (Status) {}", // 7
+                "}",                                   // 8
+            ],
+        );
+        ws.open(uri.clone(), text).unwrap();
+
+        // A field may be either, so the first import wins.
+        assert_eq!(
+            ws.goto(uri.clone(), lsp_types::Position::new(4, 4))
+                .unwrap()
+                .map(|loc| loc.uri),
+            Some(enum_uri)
+        );
+        // An rpc type must be a message.
+        assert_eq!(
+            ws.goto(uri.clone(), lsp_types::Position::new(7, 28))
+                .unwrap()
+                .map(|loc| loc.uri),
+            Some(message_uri)
+        );
+    }
+
+    #[test]
+    fn test_goto_nested_shadows_import() {
+        let (mut ws, tmp) = setup();
+        let (bar_uri, _) = proto(
+            &tmp,
+            "bar.proto",
+            &["syntax = \"proto3\";", "message Inner {}"],
+        );
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &[
+                "syntax = \"proto3\";",  // 0
+                "import \"bar.proto\";", // 1
+                "message Outer {",       // 2
+                "  message Inner {}",    // 3
+                "  Inner i = 1;",        // 4
+                "}",                     // 5
+                "message Other {",       // 6
+                "  Inner i = 1;",        // 7
+                "}",                     // 8
+            ],
+        );
+        ws.open(uri.clone(), text).unwrap();
+
+        // The nested type is in the closest scope.
+        assert_eq!(
+            ws.goto(uri.clone(), lsp_types::Position::new(4, 3))
+                .unwrap(),
+            Some(lsp_types::Location {
+                uri: uri.clone(),
+                range: range(3, 2, 18),
+            })
+        );
+        // Outside of Outer, only the imported type is visible.
+        assert_eq!(
+            ws.goto(uri.clone(), lsp_types::Position::new(7, 3))
+                .unwrap()
+                .map(|loc| loc.uri),
+            Some(bar_uri)
+        );
+    }
+
+    #[test]
+    fn test_goto_partial_package() {
+        let (mut ws, tmp) = setup();
+        proto(
+            &tmp,
+            "mainsub.proto",
+            &[
+                "syntax = \"proto3\";",
+                "package mainsub;",
+                "message Type {}",
+            ],
+        );
+        let (sub_uri, _) = proto(
+            &tmp,
+            "sub.proto",
+            &[
+                "syntax = \"proto3\";",
+                "package main.sub;",
+                "message Type {}",
+            ],
+        );
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &[
+                "syntax = \"proto3\";",      // 0
+                "package main;",             // 1
+                "import \"mainsub.proto\";", // 2
+                "import \"sub.proto\";",     // 3
+                "message Foo {",             // 4
+                "  sub.Type t = 1;",         // 5
+                "}",                         // 6
+            ],
+        );
+        ws.open(uri.clone(), text).unwrap();
+
+        // sub.Type is relative to the current package, so refers to main.sub.Type.
+        assert_eq!(
+            ws.goto(uri.clone(), lsp_types::Position::new(5, 7))
+                .unwrap(),
+            Some(lsp_types::Location {
+                uri: sub_uri,
+                range: range(2, 0, 15),
+            })
+        );
+    }
+
+    #[test]
+    fn test_goto_skips_declaration_without_body() {
+        let (mut ws, tmp) = setup();
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &[
+                "syntax = \"proto2\";",    // 0
+                "message One",             // 1
+                "message One {}",          // 2
+                "message Two {",           // 3
+                "  optional One one = 1;", // 4
+                "}",                       // 5
+            ],
+        );
+        ws.open(uri.clone(), text).unwrap();
+
+        assert_eq!(
+            ws.goto(uri.clone(), lsp_types::Position::new(4, 12))
+                .unwrap(),
+            Some(lsp_types::Location {
+                uri: uri.clone(),
+                range: range(2, 0, 14),
+            })
+        );
+    }
+
+    #[test]
+    fn test_goto_qualified_nested() {
+        let (mut ws, tmp) = setup();
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &[
+                "syntax = \"proto3\";",       // 0
+                "package main;",              // 1
+                "message Outer {",            // 2
+                "  message Inner {}",         // 3
+                "}",                          // 4
+                "message Foo {",              // 5
+                "  Outer.Inner a = 1;",       // 6
+                "  main.Outer.Inner b = 2;",  // 7
+                "  .main.Outer.Inner c = 3;", // 8
+                "  Inner d = 4;",             // 9
+                "}",                          // 10
+            ],
+        );
+        ws.open(uri.clone(), text).unwrap();
+
+        let goto = |line: u32, character: u32| {
+            ws.goto(uri.clone(), lsp_types::Position::new(line, character))
+                .unwrap()
+                .map(|loc| loc.range.start)
+        };
+        let inner = Some(lsp_types::Position::new(3, 2));
+        assert_eq!(goto(6, 10), inner);
+        assert_eq!(goto(7, 15), inner);
+        assert_eq!(goto(8, 16), inner);
+        // Inner is not in scope here without qualification.
+        assert_eq!(goto(9, 3), None);
+    }
+
+    #[test]
+    fn test_goto_qualified_builtin_name() {
+        let (mut ws, tmp) = setup();
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &[
+                "syntax = \"proto3\";",  // 0
+                "package main;",         // 1
+                "message String {}",     // 2
+                "message Foo {",         // 3
+                "  main.String a = 1;",  // 4
+                "  .main.String b = 2;", // 5
+                "  string c = 3;",       // 6
+                "}",                     // 7
+            ],
+        );
+        ws.open(uri.clone(), text).unwrap();
+
+        let goto = |line: u32, character: u32| {
+            ws.goto(uri.clone(), lsp_types::Position::new(line, character))
+                .unwrap()
+                .map(|loc| loc.range.start)
+        };
+        let string = Some(lsp_types::Position::new(2, 0));
+        // On the package segment, and on the type name itself.
+        assert_eq!(goto(4, 3), string);
+        assert_eq!(goto(4, 9), string);
+        assert_eq!(goto(5, 4), string);
+        assert_eq!(goto(5, 10), string);
+        // The scalar is not shadowed by the message.
+        assert_eq!(goto(6, 4), None);
+    }
+
+    #[test]
+    fn test_goto_import_backslash() {
+        let (mut ws, tmp) = setup();
+        std::fs::create_dir(tmp.path().join("sub")).unwrap();
+        let (bar_uri, _) = proto(&tmp, "sub/bar.proto", &["syntax = \"proto3\";"]);
+        let (foo_uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &["syntax = \"proto3\";", "import \"sub\\\\bar.proto\";"],
+        );
+        ws.open(foo_uri.clone(), text).unwrap();
+
+        assert_eq!(
+            ws.goto(foo_uri, lsp_types::Position::new(1, 10)).unwrap(),
+            Some(lsp_types::Location {
+                uri: bar_uri,
+                range: lsp_types::Range::default(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_import_relative_to_file() {
+        let (mut ws, tmp) = setup();
+        std::fs::create_dir(tmp.path().join("sub")).unwrap();
+        let (bar_uri, _) = proto(
+            &tmp,
+            "sub/bar.proto",
+            &["syntax = \"proto3\";", "message Bar {}"],
+        );
+        let (foo_uri, text) = proto(
+            &tmp,
+            "sub/foo.proto",
+            &[
+                "syntax = \"proto3\";",  // 0
+                "import \"bar.proto\";", // 1
+                "message Foo {",         // 2
+                "  Bar bar = 1;",        // 3
+                "}",                     // 4
+            ],
+        );
+
+        // bar.proto is not on the proto paths, only next to foo.proto.
+        ws.open(foo_uri.clone(), text.clone()).unwrap();
+        assert_eq!(
+            ws.goto(foo_uri.clone(), lsp_types::Position::new(1, 10))
+                .unwrap(),
+            None
+        );
+
+        ws.options.import_relative_to_file = true;
+        ws.open(foo_uri.clone(), text).unwrap();
+        assert_eq!(
+            ws.goto(foo_uri.clone(), lsp_types::Position::new(1, 10))
+                .unwrap(),
+            Some(lsp_types::Location {
+                uri: bar_uri.clone(),
+                range: lsp_types::Range::default(),
+            })
+        );
+        assert_eq!(
+            ws.goto(foo_uri, lsp_types::Position::new(3, 3)).unwrap(),
+            Some(lsp_types::Location {
+                uri: bar_uri,
+                range: range(1, 0, 14),
+            })
+        );
+    }
+
+    #[test]
+    fn test_invalid_imports() {
+        let (mut ws, tmp) = setup();
+        let (bar_uri, _) = proto(&tmp, "bar.proto", &["syntax = \"proto3\";"]);
+        let bar_path = bar_uri.to_file_path().unwrap();
+        let bar_path = bar_path.to_str().unwrap();
+        let (foo_uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &[
+                "syntax = \"proto3\";",
+                format!("import \"{bar_path}\";").as_str(),
+                "import \"C:\\\\protos\\\\baz.proto\";",
+                "import \"bar.proto\";",
+                "import \"./bar.proto\";",
+            ],
+        );
+        ws.open(foo_uri.clone(), text).unwrap();
+
+        // The absolute import is not resolved, even though the file exists.
+        assert_eq!(
+            ws.goto(foo_uri.clone(), lsp_types::Position::new(1, 10))
+                .unwrap(),
+            None
+        );
+        // Nor is one that protoc rejects for its "." segment.
+        assert_eq!(
+            ws.goto(foo_uri.clone(), lsp_types::Position::new(4, 10))
+                .unwrap(),
+            None
+        );
+
+        let file = ws.get(&foo_uri).unwrap();
+        let end = u32::try_from(bar_path.len()).unwrap() + 9;
+        assert_eq!(
+            invalid_imports(file, "pbls"),
+            vec![
+                lsp_types::Diagnostic {
+                    range: range(4, 7, 20),
+                    severity: Some(lsp_types::DiagnosticSeverity::ERROR),
+                    source: Some("pbls".into()),
+                    message: "Import ./bar.proto not found: imports must be relative to a proto path, \
+                              without empty, \".\" or \"..\" segments"
+                        .into(),
+                    ..Default::default()
+                },
+                lsp_types::Diagnostic {
+                    range: range(1, 7, end),
+                    severity: Some(lsp_types::DiagnosticSeverity::ERROR),
+                    source: Some("pbls".into()),
+                    message: format!(
+                        "Import {bar_path} not found: imports must be relative to a proto path, \
+                         without empty, \".\" or \"..\" segments"
+                    ),
+                    ..Default::default()
+                },
+                lsp_types::Diagnostic {
+                    range: range(2, 7, 30),
+                    severity: Some(lsp_types::DiagnosticSeverity::ERROR),
+                    source: Some("pbls".into()),
+                    message: "Import C:\\\\protos\\\\baz.proto not found: imports must be relative to a proto path, \
+                              without empty, \".\" or \"..\" segments".into(),
+                    ..Default::default()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_normalize_import() {
+        assert_eq!(
+            normalize_import("foo/bar.proto"),
+            Some("foo/bar.proto".into())
+        );
+        assert_eq!(
+            normalize_import("foo\\bar.proto"),
+            Some("foo/bar.proto".into())
+        );
+        assert_eq!(
+            normalize_import("foo\\\\bar.proto"),
+            Some("foo/bar.proto".into())
+        );
+        assert_eq!(normalize_import("/foo/bar.proto"), None);
+        assert_eq!(normalize_import("C:\\\\foo\\\\bar.proto"), None);
+        // Protoc rejects these, so they are not resolved either.
+        assert_eq!(normalize_import("foo//bar.proto"), None);
+        assert_eq!(normalize_import("./foo/bar.proto"), None);
+        assert_eq!(normalize_import("foo/./bar.proto"), None);
+        assert_eq!(normalize_import("../foo/bar.proto"), None);
+        assert_eq!(normalize_import("foo/../bar.proto"), None);
+        assert_eq!(normalize_import("foo/"), None);
+    }
+
+    #[test]
+    fn test_extension_overlaps() {
+        let file = file::File::new(
+            [
+                "syntax = \"proto2\";",
+                "message Foo {",
+                "  extensions 100 to 200;",
+                "  extensions 150 to max;",
+                "  optional int32 a = 1;",
+                "  optional int32 b = 120;",
+                "}",
+                "message Bar {",
+                "  extensions 1 to 10;",
+                "  optional int32 c = 11;",
+                "}",
+                "",
+            ]
+            .join("\n"),
+        )
+        .unwrap();
+
+        // The extensions do not hide the messages.
+        let mut qc = tree_sitter::QueryCursor::new();
+        assert_eq!(
+            file.symbols(&mut qc)
+                .filter(is_message)
+                .map(|s| s.name)
+                .collect::<Vec<_>>(),
+            vec!["Foo", "Bar"]
+        );
+
+        let diag = |range, message: &str| lsp_types::Diagnostic {
+            range,
+            severity: Some(lsp_types::DiagnosticSeverity::ERROR),
+            source: Some("pbls".into()),
+            message: message.into(),
+            ..Default::default()
+        };
+        assert_eq!(
+            extension_overlaps(&file, "pbls"),
+            vec![
+                diag(
+                    range(3, 2, 24),
+                    "Extension range 150 to 536870911 overlaps 100 to 200"
+                ),
+                diag(
+                    range(5, 2, 25),
+                    "Field number 120 is in extension range 100 to 200"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_duplicate_packages() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let file = file::File::new(
+            [
+                "syntax = \"proto3\";",
+                "package foo;",
+                "package bar;",
+                "package baz.buz;",
+                "",
+            ]
+            .join("\n"),
+        )
+        .unwrap();
+
+        let diag = |range| lsp_types::Diagnostic {
+            range,
+            severity: Some(lsp_types::DiagnosticSeverity::ERROR),
+            source: Some("pbls".into()),
+            message: "Multiple package declarations".into(),
+            ..Default::default()
+        };
+        assert_eq!(
+            duplicate_packages(&file, "pbls"),
+            vec![diag(range(2, 0, 12)), diag(range(3, 0, 16))]
+        );
+
+        let file = file::File::new("syntax = \"proto3\";\npackage foo;\n".into()).unwrap();
+        assert_eq!(duplicate_packages(&file, "pbls"), vec![]);
+    }
+
+    #[test]
+    fn test_proto3_required() {
+        let lines = [
+            "syntax = \"proto3\";",
+            "message Foo {",
+            "  required string name = 1;",
+            "  optional int32 id = 2;",
+            "  message Bar {",
+            "    required  Foo foo = 1;",
+            "  }",
+            "}",
+            "",
+        ];
+        let file = file::File::new(lines.join("\n")).unwrap();
+
+        let diag = |range| lsp_types::Diagnostic {
+            range,
+            severity: Some(lsp_types::DiagnosticSeverity::ERROR),
+            source: Some("pbls".into()),
+            message: "Required fields are not allowed in proto3".into(),
+            ..Default::default()
+        };
+        assert_eq!(
+            proto3_required(&file, "pbls"),
+            vec![diag(range(2, 2, 10)), diag(range(5, 4, 12))]
+        );
+
+        let text = lines.join("\n").replace("proto3", "proto2");
+        let file = file::File::new(text).unwrap();
+        assert_eq!(proto3_required(&file, "pbls"), vec![]);
+    }
+
+    #[test]
+    fn test_invalid_map_keys() {
+        let lines = [
+            "syntax = \"proto3\";",
+            "message Foo {}",
+            "message Bar {",
+            "  map<Foo, Bar> a = 1;",
+            "  map<string, Foo> b = 2;",
+            "  map< foo.Foo , int32> c = 3;",
+            "  map<double, int32> d = 4; // map<Foo, Bar>",
+            "  /* map<Foo, Bar> */",
+            "  string e = 5 [json_name = \"map<Foo, Bar>\"];",
+            "  map<Fo",
+            "}",
+            "",
+        ];
+        let file = file::File::new(lines.join("\n")).unwrap();
+
+        let diag = |range, name| lsp_types::Diagnostic {
+            range,
+            severity: Some(lsp_types::DiagnosticSeverity::ERROR),
+            source: Some("pbls".into()),
+            message: format!("Invalid map key type: {name}"),
+            ..Default::default()
+        };
+        assert_eq!(
+            invalid_map_keys(&file, "pbls"),
+            vec![
+                diag(range(3, 6, 9), "Foo"),
+                diag(range(5, 7, 14), "foo.Foo"),
+                diag(range(6, 6, 12), "double"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_deprecated_symbols() {
+        let (mut ws, tmp) = setup();
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &[
+                "syntax = \"proto3\";",
+                "message Old {",
+                "  option deprecated = true;",
+                "}",
+                "message New {}",
+            ],
+        );
+        ws.open(uri.clone(), text).unwrap();
+
+        let tags = |syms: Vec<SymbolInformation>| {
+            syms.into_iter()
+                .map(|s| (s.name, s.tags))
+                .collect::<Vec<_>>()
+        };
+        let expected = vec![
+            (
+                "Old".to_string(),
+                Some(vec![lsp_types::SymbolTag::DEPRECATED]),
+            ),
+            ("New".to_string(), None),
+        ];
+        assert_eq!(tags(ws.symbols(&uri).unwrap()), expected);
+        assert_eq!(tags(ws.all_symbols("").unwrap()), expected);
+    }
+
+    #[test]
+    fn test_symbol_container_name() {
+        let (mut ws, tmp) = setup();
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &[
+                "syntax = \"proto3\";",
+                "package main;",
+                "message Baz {",
+                "  message Biz {}",
+                "}",
+            ],
+        );
+        ws.open(uri.clone(), text).unwrap();
+
+        assert_eq!(
+            ws.symbols(&uri)
+                .unwrap()
+                .into_iter()
+                .map(|s| (s.name, s.container_name))
+                .collect::<Vec<_>>(),
+            vec![
+                ("Baz".to_string(), Some("main".to_string())),
+                ("Baz.Biz".to_string(), Some("main.Baz".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_extend_symbols() {
+        let (mut ws, tmp) = setup();
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &[
+                "syntax = \"proto2\";",
+                "package main;",
+                "message Foo {",
+                "  extensions 100 to 200;",
+                "}",
+                "extend Foo {",
+                "  optional int32 bar = 100;",
+                "}",
+                "message Baz {}",
+            ],
+        );
+        ws.open(uri.clone(), text).unwrap();
+
+        // Fields of an extend block are contained by the message it extends.
+        assert_eq!(
+            ws.symbols(&uri)
+                .unwrap()
+                .into_iter()
+                .map(|s| (s.name, s.kind, s.container_name))
+                .collect::<Vec<_>>(),
+            vec![
+                (
+                    "Foo".to_string(),
+                    lsp_types::SymbolKind::STRUCT,
+                    Some("main".to_string())
+                ),
+                (
+                    "Foo".to_string(),
+                    lsp_types::SymbolKind::NAMESPACE,
+                    Some("main".to_string())
+                ),
+                (
+                    "bar".to_string(),
+                    lsp_types::SymbolKind::FIELD,
+                    Some("Foo".to_string())
+                ),
+                (
+                    "Baz".to_string(),
+                    lsp_types::SymbolKind::STRUCT,
+                    Some("main".to_string())
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_symbols_without_protoc() {
+        let (mut ws, tmp) = setup();
+        let uri = Url::from_file_path(tmp.path().join("foo.proto")).unwrap();
+
+        // The outline comes from the parse tree, so it needs neither protoc nor a valid file.
+        // Editing a file before opening it never runs protoc.
+        ws.edit(
+            &uri,
+            vec![lsp_types::TextDocumentContentChangeEvent {
+                range: None,
+                range_length: None,
+                text: [
+                    "syntax = \"proto3\";",
+                    "import \"nope.proto\"",
+                    "option = ;",
+                    "message Foo {}",
+                    "enum Baz {}",
+                ]
+                .join("\n"),
+            }],
+        )
+        .unwrap();
+
+        assert_eq!(
+            ws.symbols(&uri)
+                .unwrap()
+                .into_iter()
+                .map(|s| s.name)
+                .collect::<Vec<_>>(),
+            vec!["Foo", "Baz"]
+        );
+    }
+
+    #[test]
+    fn test_complete_nested_message() {
+        let (mut ws, tmp) = setup();
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &[
+                "syntax = \"proto3\";", // 0
+                "message Outer {",      // 1
+                "  message Middle {",   // 2
+                "    message Inner {",  // 3
+                "      S",              // 4
+                "    }",                // 5
+                "  }",                  // 6
+                "  message Sibling {}", // 7
+                "}",                    // 8
+            ],
+        );
+        ws.open(uri.clone(), text).unwrap();
+
+        let items = complete_items(&ws, &uri, 4, 7);
+        assert_eq!(
+            items
+                .into_iter()
+                .filter(|i| i.kind == Some(lsp_types::CompletionItemKind::STRUCT))
+                .filter(|i| !BUILTINS.contains(&i.label.as_str()))
+                .map(|i| i.label)
+                .collect::<Vec<_>>(),
+            vec!["Outer", "Middle", "Inner", "Sibling"],
+        );
+    }
+
+    #[test]
+    fn test_complete_nested_message_qualified() {
+        let (mut ws, tmp) = setup();
+        proto(
+            &tmp,
+            "dep.proto",
+            &["syntax = \"proto3\";", "message D {", "  message E {}", "}"],
+        );
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &[
+                "syntax = \"proto3\";",  // 0
+                "import \"dep.proto\";", // 1
+                "message A {",           // 2
+                "  message B {}",        // 3
+                "}",                     // 4
+                "message C {",           // 5
+                "  ",                    // 6
+                "}",                     // 7
+            ],
+        );
+        ws.open(uri.clone(), text).unwrap();
+
+        // B is only reachable as A.B outside of A.
+        let items = complete_items(&ws, &uri, 6, 2);
+        assert_eq!(
+            items
+                .into_iter()
+                .filter(|i| i.kind == Some(lsp_types::CompletionItemKind::STRUCT))
+                .filter(|i| !BUILTINS.contains(&i.label.as_str()))
+                .map(|i| i.label)
+                .collect::<Vec<_>>(),
+            vec!["A", "A.B", "C", "D", "D.E"],
+        );
+    }
+
+    #[test]
+    fn test_complete_oneof_field() {
+        let (mut ws, tmp) = setup();
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &[
+                "syntax = \"proto3\";", // 0
+                "message Foo {",        // 1
+                "  message Bar {}",     // 2
+                "  oneof choice {",     // 3
+                "    B",                // 4
+                "  }",                  // 5
+                "}",                    // 6
+            ],
+        );
+        ws.open(uri.clone(), text).unwrap();
+
+        let items = complete_items(&ws, &uri, 4, 5);
+        assert_eq!(
+            items
+                .into_iter()
+                .filter(|i| i.kind == Some(lsp_types::CompletionItemKind::STRUCT))
+                .filter(|i| !BUILTINS.contains(&i.label.as_str()))
+                .map(|i| i.label)
+                .collect::<Vec<_>>(),
+            vec!["Foo", "Bar"],
+        );
+    }
+
+    #[test]
+    fn test_goto_multiline_field() {
+        let (mut ws, tmp) = setup();
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &[
+                "syntax = \"proto3\";", // 0
+                "package main;",        // 1
+                "message Foo {}",       // 2
+                "message Bar {",        // 3
+                "  repeated",           // 4
+                "    Foo",              // 5
+                "    foo = 1;",         // 6
+                "}",                    // 7
+            ],
+        );
+        ws.open(uri.clone(), text).unwrap();
+
+        assert_eq!(
+            ws.goto(uri.clone(), lsp_types::Position::new(5, 5))
+                .unwrap()
+                .map(|loc| loc.range.start),
+            Some(lsp_types::Position::new(2, 0))
+        );
+        assert_eq!(
+            ws.references(references(&uri, 5, 5)).unwrap().unwrap(),
+            vec![lsp_types::Location {
+                uri: uri.clone(),
+                range: range(5, 4, 7),
+            }]
+        );
+
+        // The label and field name are not part of the type.
+        assert_eq!(
+            ws.goto(uri.clone(), lsp_types::Position::new(4, 4))
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            ws.goto(uri.clone(), lsp_types::Position::new(6, 5))
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_complete_recursive_message() {
+        let (mut ws, tmp) = setup();
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &[
+                "syntax = \"proto3\";", // 0
+                "package foo;",         // 1
+                "message Tree {",       // 2
+                "  message Node {",     // 3
+                "    Node next = 1;",   // 4
+                "    N",                // 5
+                "  }",                  // 6
+                "}",                    // 7
+            ],
+        );
+        ws.open(uri.clone(), text).unwrap();
+
+        let items = complete_items(&ws, &uri, 5, 5);
+        assert_eq!(
+            items
+                .into_iter()
+                .filter(|i| i.kind == Some(lsp_types::CompletionItemKind::STRUCT))
+                .filter(|i| !BUILTINS.contains(&i.label.as_str()))
+                .map(|i| i.label)
+                .collect::<Vec<_>>(),
+            vec!["Tree", "Node"],
+        );
+
+        // The suggested name refers back to the message being defined.
+        assert_eq!(
+            ws.goto(uri.clone(), lsp_types::Position::new(4, 5))
+                .unwrap()
+                .map(|loc| loc.range.start),
+            Some(lsp_types::Position::new(3, 2))
+        );
+    }
+
+    #[test]
+    fn test_complete_no_context() {
+        let (mut ws, tmp) = setup();
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &[
+                "syntax = \"proto3\";",
+                "message Foo {",
+                "  int32 foo = 1;",
+                "}",
+            ],
+        );
+        ws.open(uri.clone(), text).unwrap();
+
+        assert_eq!(
+            ws.complete(&uri, 2, "  int32 fo".len()).unwrap(),
+            Some(lsp_types::CompletionResponse::List(
+                lsp_types::CompletionList {
+                    is_incomplete: false,
+                    items: vec![],
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn test_complete_rpc_body() {
+        let (mut ws, tmp) = setup();
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &[
+                "syntax = \"proto3\";",
+                "service Foo {",
+                "  rpc Bar (Req) returns (Resp) ",
+                "}",
+            ],
+        );
+        ws.open(uri.clone(), text).unwrap();
+
+        let items = complete_items(&ws, &uri, 2, "  rpc Bar (Req) returns (Resp) ".len());
+        assert_eq!(
+            items
+                .iter()
+                .map(|i| i.insert_text.as_deref().unwrap())
+                .collect::<Vec<_>>(),
+            vec!["{\n\t$0\n}", "{\n\toption (${1:name}) = ${0:value};\n}"],
+        );
+        assert!(items
+            .iter()
+            .all(|i| i.insert_text_format == Some(lsp_types::InsertTextFormat::SNIPPET)));
+    }
+
+    #[test]
+    fn test_complete_rpc_types() {
+        let (mut ws, tmp) = setup();
+        proto(
+            &tmp,
+            "bar.proto",
+            &[
+                "syntax = \"proto3\";",
+                "package bar;",
+                "message BarRequest {}",
+                "enum BarEnum {}",
+            ],
+        );
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &[
+                "syntax = \"proto3\";",                  // 0
+                "import \"bar.proto\";",                 // 1
+                "message Resp {",                        // 2
+                "  enum Status {}",                      // 3
+                "}",                                     // 4
+                "enum Color {}",                         // 5
+                "service Foo {",                         // 6
+                "  rpc Bar (bar.BarRequest) returns (R", // 7
+                "}",                                     // 8
+            ],
+        );
+        ws.open(uri.clone(), text).unwrap();
+
+        let items = complete_items(&ws, &uri, 7, "  rpc Bar (bar.BarRequest) returns (R".len());
+        assert_eq!(
+            items.iter().map(|i| i.label.as_str()).collect::<Vec<_>>(),
+            vec!["Resp", "bar.BarRequest"],
+        );
+        assert!(items
+            .iter()
+            .all(|i| i.kind == Some(lsp_types::CompletionItemKind::STRUCT)));
+    }
+
+    #[test]
+    fn test_complete_rpc_stream() {
+        let (mut ws, tmp) = setup();
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &[
+                "syntax = \"proto3\";",                      // 0
+                "message Req {}",                            // 1
+                "message Resp {}",                           // 2
+                "service Foo {",                             // 3
+                "  rpc Bar (stream Req) returns (",          // 4
+                "  rpc Baz (stream Req) returns (stream Re", // 5
+                "}",                                         // 6
+            ],
+        );
+        ws.open(uri.clone(), text).unwrap();
+
+        let labels = |line, character| {
+            let items = complete_items(&ws, &uri, line, character);
+            items.into_iter().map(|i| i.label).collect::<Vec<_>>()
+        };
+        assert_eq!(
+            labels(4, "  rpc Bar (stream Req) returns (".len()),
+            vec!["stream", "Req", "Resp"],
+        );
+        assert_eq!(
+            labels(5, "  rpc Baz (stream Req) returns (stream Re".len()),
+            vec!["Req", "Resp"],
+        );
+
+        let stream = |ws: &Workspace| {
+            complete_items(ws, &uri, 4, "  rpc Bar (stream Req) returns (".len())[0].clone()
+        };
+        assert_eq!(stream(&ws).insert_text.as_deref(), Some("stream "));
+        assert_eq!(stream(&ws).command, None);
+
+        // Completion is only re-triggered for the type if the client opted in.
+        ws.options.completion.trigger_suggest = true;
+        assert_eq!(stream(&ws).command, Some(trigger_suggest()));
+    }
+
+    #[test]
+    fn test_complete_extend() {
+        let (mut ws, tmp) = setup();
+        let dir = tmp.path().join("google").join("protobuf");
+        std::fs::create_dir_all(&dir).unwrap();
+        proto(
+            &dir,
+            "descriptor.proto",
+            &[
+                "syntax = \"proto2\";",
+                "package google.protobuf;",
+                "message FieldOptions {}",
+                "message MessageOptions {}",
+                "enum Edition {}",
+            ],
+        );
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &[
+                "syntax = \"proto3\";",
+                "import \"google/protobuf/descriptor.proto\";",
+                "message Foo {}",
+                "extend ",
+            ],
+        );
+        ws.open(uri.clone(), text).unwrap();
+
+        let mut items = complete_items(&ws, &uri, 3, "extend ".len());
+        items.sort_by_key(|i| i.sort_text.clone());
+        assert_eq!(
+            items.iter().map(|i| i.label.as_str()).collect::<Vec<_>>(),
+            vec![
+                "google.protobuf.FieldOptions",
+                "google.protobuf.MessageOptions",
+                "Foo",
+            ],
+        );
+    }
+
+    #[test]
+    fn test_rename_package() {
+        let (mut ws, tmp) = setup();
+        let (bar, text) = proto(
+            &tmp,
+            "bar.proto",
+            &[
+                "syntax = \"proto3\";",
+                "package foo.bar;",
+                "message X {}",
+                "message Y {",
+                "  X a = 1;",
+                "  foo.bar.X b = 2;",
+                "}",
+            ],
+        );
+        let (qux, _) = proto(
+            &tmp,
+            "qux.proto",
+            &[
+                "syntax = \"proto3\";",
+                "package foo.qux;",
+                "import \"bar.proto\";",
+                "message X {}",
+                "message Z {",
+                "  bar.X a = 1;",
+                "  foo.bar.X b = 2;",
+                "  X c = 3;",
+                "}",
+                "extend bar.X {",
+                "  int32 ext = 100;",
+                "}",
+            ],
+        );
+        let (sub, _) = proto(
+            &tmp,
+            "sub.proto",
+            &[
+                "syntax = \"proto3\";",
+                "package foo.bar.sub;",
+                "import \"bar.proto\";",
+                "message S {",
+                "  X a = 1;",
+                "}",
+            ],
+        );
+        let (other, _) = proto(
+            &tmp,
+            "other.proto",
+            &[
+                "syntax = \"proto3\";",
+                "import \"bar.proto\";",
+                "message W {",
+                "  foo.bar.X a = 1;",
+                "  extend foo.bar.X {",
+                "    int32 ext = 100;",
+                "  }",
+                "}",
+            ],
+        );
+        ws.open(bar.clone(), text).unwrap();
+
+        let rename = |line, character, name: &str| lsp_types::RenameParams {
+            text_document_position: lsp_types::TextDocumentPositionParams {
+                text_document: lsp_types::TextDocumentIdentifier { uri: bar.clone() },
+                position: lsp_types::Position { line, character },
+            },
+            new_name: name.into(),
+            work_done_progress_params: lsp_types::WorkDoneProgressParams {
+                work_done_token: None,
+            },
+        };
+        let edit = |range, text: &str| lsp_types::TextEdit {
+            range,
+            new_text: text.into(),
+        };
+
+        // Only the package can be renamed.
+        assert_eq!(ws.rename(rename(2, 9, "foo.baz")).unwrap(), None);
+        assert!(ws.rename(rename(1, 10, "foo..baz")).is_err());
+
+        let changes = ws
+            .rename(rename(1, 10, "foo.baz"))
+            .unwrap()
+            .unwrap()
+            .changes
+            .unwrap();
+        assert_eq!(
+            changes,
+            std::collections::HashMap::from([
+                (
+                    bar,
+                    vec![
+                        edit(range(1, 8, 15), "foo.baz"),
+                        edit(range(5, 2, 11), "foo.baz.X"),
+                    ]
+                ),
+                (
+                    qux,
+                    vec![
+                        edit(range(5, 2, 7), "baz.X"),
+                        edit(range(6, 2, 11), "foo.baz.X"),
+                        edit(range(9, 7, 12), "baz.X"),
+                    ]
+                ),
+                (sub, vec![edit(range(4, 2, 3), "baz.X")]),
+                (
+                    other,
+                    vec![
+                        edit(range(3, 2, 11), "foo.baz.X"),
+                        edit(range(4, 9, 18), "foo.baz.X"),
+                    ]
+                ),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_rename_builtin() {
+        let (mut ws, tmp) = setup();
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &[
+                "syntax = \"proto3\";",
+                "package foo;",
+                "message string {}",
+                "message Foo {",
+                "  string s = 1;",
+                "  .foo.string t = 2;",
+                "}",
+            ],
+        );
+        ws.open(uri.clone(), text).unwrap();
+
+        let pos = |line, character| lsp_types::TextDocumentPositionParams {
+            text_document: lsp_types::TextDocumentIdentifier { uri: uri.clone() },
+            position: lsp_types::Position { line, character },
+        };
+        let rename = |line, character| lsp_types::RenameParams {
+            text_document_position: pos(line, character),
+            new_name: "bar".into(),
+            work_done_progress_params: lsp_types::WorkDoneProgressParams {
+                work_done_token: None,
+            },
+        };
+
+        let err = ws.rename(rename(4, 4)).unwrap_err();
+        assert_eq!(err.to_string(), "Cannot rename built-in type");
+        let err = ws.prepare_rename(pos(4, 2)).unwrap_err();
+        assert_eq!(err.to_string(), "Cannot rename built-in type");
+
+        let err = ws.rename(rename(3, 3)).unwrap_err();
+        assert_eq!(err.to_string(), "Cannot rename keyword message");
+        let err = ws.prepare_rename(pos(1, 0)).unwrap_err();
+        assert_eq!(err.to_string(), "Cannot rename keyword package");
+
+        // A qualified name is not a builtin, but can't be renamed either.
+        assert_eq!(ws.prepare_rename(pos(5, 8)).unwrap(), None);
+        assert_eq!(ws.rename(rename(5, 8)).unwrap(), None);
+
+        assert_eq!(
+            ws.prepare_rename(pos(1, 9)).unwrap(),
+            Some(lsp_types::PrepareRenameResponse::Range(range(1, 8, 11)))
+        );
+
+        // Keywords are valid identifiers, so a package may be named like one.
+        let (uri, text) = proto(
+            &tmp,
+            "service.proto",
+            &["syntax = \"proto3\";", "package service;"],
+        );
+        ws.open(uri.clone(), text).unwrap();
+        assert_eq!(
+            ws.prepare_rename(lsp_types::TextDocumentPositionParams {
+                text_document: lsp_types::TextDocumentIdentifier { uri },
+                position: lsp_types::Position::new(1, 9),
+            })
+            .unwrap(),
+            Some(lsp_types::PrepareRenameResponse::Range(range(1, 8, 15)))
+        );
+    }
+
+    #[test]
+    fn test_many_files() {
+        const COUNT: usize = 200;
+        let (mut ws, tmp) = setup();
+        let (uri, text) = proto(
+            &tmp,
+            "common.proto",
+            &["syntax = \"proto3\";", "package foo;", "message Common {}"],
+        );
+        for i in 0..COUNT {
+            proto(
+                &tmp,
+                &format!("file{i}.proto"),
+                &[
+                    "syntax = \"proto3\";",
+                    "package foo;",
+                    "import \"common.proto\";",
+                    format!("message Msg{i} {{").as_str(),
+                    "  Common c = 1;",
+                    "}",
+                ],
+            );
+        }
+        ws.open(uri.clone(), text).unwrap();
+
+        let start = std::time::Instant::now();
+        let refs = ws.references(references(&uri, 2, 9)).unwrap().unwrap();
+        log::info!("Found {} references in {:?}", refs.len(), start.elapsed());
+        assert_eq!(refs.len(), COUNT);
+
+        let start = std::time::Instant::now();
+        let syms = ws.all_symbols("Msg").unwrap();
+        log::info!("Found {} symbols in {:?}", syms.len(), start.elapsed());
+        assert_eq!(syms.len(), COUNT);
+    }
+
+    #[test]
+    fn test_map_enum_value() {
+        let (mut ws, tmp) = setup();
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &[
+                "syntax = \"proto3\";",
+                "package foo;",
+                "import \"color.proto\";",
+                "message Foo {",
+                "  map<string, paint.Color> m = 1;",
+                "  map<string, Co> n = 2;",
+                "}",
+            ],
+        );
+        let (color_uri, _) = proto(
+            &tmp,
+            "color.proto",
+            &[
+                "syntax = \"proto3\";",
+                "package paint;",
+                "enum Color {",
+                "  RED = 0;",
+                "}",
+            ],
+        );
+        ws.open(uri.clone(), text).unwrap();
+
+        assert_eq!(
+            ws.goto(
+                uri.clone(),
+                lsp_types::Position {
+                    line: 4,
+                    character: "  map<string, paint.Co".len().try_into().unwrap(),
+                }
+            )
+            .unwrap(),
+            Some(lsp_types::Location {
+                uri: color_uri,
+                range: lsp_types::Range {
+                    start: lsp_types::Position {
+                        line: 2,
+                        character: 0,
+                    },
+                    end: lsp_types::Position {
+                        line: 4,
+                        character: 1,
+                    },
+                },
+            })
+        );
+
+        let items = complete_items(&ws, &uri, 5, "  map<string, Co".len());
+        assert!(
+            items
+                .iter()
+                .any(|i| i.label == "paint.Color"
+                    && i.kind == Some(lsp_types::CompletionItemKind::ENUM)),
+            "{items:?}"
+        );
+    }
+
+    #[test]
+    fn test_complete_map() {
+        let (mut ws, tmp) = setup();
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &[
+                "syntax = \"proto3\";",
+                "message Foo {",
+                "  map<s, F> m = 1;",
+                "}",
+            ],
+        );
+        ws.open(uri.clone(), text).unwrap();
+
+        let labels = |character| {
+            complete_items(&ws, &uri, 2, character)
+                .into_iter()
+                .map(|i| i.label)
+                .collect::<Vec<_>>()
+        };
+
+        // Keys may only be integral or string scalars.
+        assert_eq!(
+            labels("  map<s".len()),
+            vec![
+                "bool", "fixed32", "fixed64", "int32", "int64", "sfixed32", "sfixed64", "sint32",
+                "sint64", "string", "uint32", "uint64",
+            ]
+        );
+
+        // Values may be any type.
+        let values = labels("  map<s, F".len());
+        assert!(values.contains(&"Foo".to_string()), "{values:?}");
+        assert!(values.contains(&"bytes".to_string()), "{values:?}");
+    }
+
+    #[test]
+    fn test_deprecated_usages() {
+        let (mut ws, tmp) = setup();
+        proto(
+            &tmp,
+            "old.proto",
+            &[
+                "syntax = \"proto3\";",
+                "package old;",
+                "message Old {",
+                "  option deprecated = true;",
+                "}",
+                "message New {}",
+            ],
+        );
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &[
+                "syntax = \"proto3\";",  // 0
+                "import \"old.proto\";", // 1
+                "message Foo {",         // 2
+                "  old.Old a = 1;",      // 3
+                "  old.New b = 2;",      // 4
+                "  int32 c = 3;",        // 5
+                "}",                     // 6
+            ],
+        );
+        ws.open(uri.clone(), text).unwrap();
+
+        let file = ws.get(&uri).unwrap();
+        assert_eq!(
+            ws.deprecated_usages(&uri, file, "pbls").unwrap(),
+            vec![lsp_types::Diagnostic {
+                range: range(3, 2, 9),
+                severity: Some(lsp_types::DiagnosticSeverity::HINT),
+                source: Some("pbls".into()),
+                message: "old.Old is deprecated".into(),
+                tags: Some(vec![lsp_types::DiagnosticTag::DEPRECATED]),
+                ..Default::default()
+            }]
+        );
+    }
+
+    #[test]
+    fn test_undefined_types() {
+        let (mut ws, tmp) = setup();
+        let _cache = use_cache_dir(&mut ws);
+        let (dep_uri, _) = proto(
+            &tmp,
+            "dep.proto",
+            &[
+                "syntax = \"proto3\";",
+                "package dep;",
+                "import public \"pub.proto\";",
+                "message Dep {}",
+            ],
+        );
         proto(
             &tmp,
-            "bar.proto",
-            &["syntax = \"proto3\";", "import \"bar.proto\";"],
+            "pub.proto",
+            &["syntax = \"proto3\";", "package pub;", "message Pub {}"],
+        );
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &[
+                "syntax = \"proto3\";",                        // 0
+                "package main;",                               // 1
+                "import \"dep.proto\";",                       // 2
+                "import \"google/protobuf/timestamp.proto\";", // 3
+                "message Foo {",                               // 4
+                "  message Inner {}",                          // 5
+                "  dep.Dep a = 1;",                            // 6
+                "  .dep.Dep b = 2;",                           // 7
+                "  Inner c = 3;",                              // 8
+                "  main.Foo d = 4;",                           // 9
+                "  google.protobuf.Timestamp e = 5;",          // 10
+                "  int32 f = 6;",                              // 11
+                "  Nope g = 7;",                               // 12
+                "  dep.Nope h = 8;",                           // 13
+                "  pub.Pub i = 9;",                            // 14
+                "}",                                           // 15
+            ],
+        );
+        ws.open(uri.clone(), text).unwrap();
+
+        let diag = |range, name: &str| lsp_types::Diagnostic {
+            range,
+            severity: Some(lsp_types::DiagnosticSeverity::ERROR),
+            source: Some("pbls".into()),
+            message: format!("\"{name}\" is not defined"),
+            ..Default::default()
+        };
+        let undefined = |ws: &Workspace| {
+            ws.undefined_types(&uri, ws.get(&uri).unwrap(), "pbls")
+                .unwrap()
+        };
+        assert_eq!(
+            undefined(&ws),
+            vec![
+                diag(range(12, 2, 6), "Nope"),
+                diag(range(13, 2, 10), "dep.Nope"),
+            ]
         );
 
+        // Types are looked up again once an import changes.
+        let pos = lsp_types::Position::new(4, 0);
+        ws.edit(
+            &dep_uri,
+            vec![lsp_types::TextDocumentContentChangeEvent {
+                range: Some(lsp_types::Range::new(pos, pos)),
+                range_length: None,
+                text: "message Nope {}\n".into(),
+            }],
+        )
+        .unwrap();
+        assert_eq!(undefined(&ws), vec![diag(range(12, 2, 6), "Nope")]);
+    }
+
+    #[test]
+    fn test_complete_deprecated() {
+        let (mut ws, tmp) = setup();
+        proto(
+            &tmp,
+            "old.proto",
+            &[
+                "syntax = \"proto3\";",
+                "message Old {",
+                "  option deprecated = true;",
+                "}",
+                "message New {}",
+            ],
+        );
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &[
+                "syntax = \"proto3\";",
+                "import \"old.proto\";",
+                "message Foo {",
+                "  O",
+                "}",
+            ],
+        );
         ws.open(uri.clone(), text).unwrap();
+
+        let items = complete_items(&ws, &uri, 3, "  O".len());
+        let tags = |label: &str| {
+            items
+                .iter()
+                .find(|i| i.label == label)
+                .unwrap_or_else(|| panic!("{label} not in {items:?}"))
+                .tags
+                .clone()
+        };
+        assert_eq!(
+            tags("Old"),
+            Some(vec![lsp_types::CompletionItemTag::DEPRECATED])
+        );
+        assert_eq!(tags("New"), None);
+        assert_eq!(tags("Foo"), None);
     }
 
     #[test]
-    fn test_complete_syntax() {
-        let _ = env_logger::builder().is_test(true).try_init();
-        let mut ws = Workspace::new(vec![]);
-        let uri = Url::from_file_path(std::env::temp_dir().join("foo.proto")).unwrap();
-        ws.open(uri.clone(), "".into()).unwrap();
+    fn test_complete_enum_value_number() {
+        let (mut ws, tmp) = setup();
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &[
+                "syntax = \"proto3\";",
+                "enum Color {",
+                "  RED = 0;",
+                "  GREEN = 4;",
+                "  BLUE = ",
+                "}",
+            ],
+        );
+        ws.open(uri.clone(), text).unwrap();
+
         assert_eq!(
-            ws.complete(&uri, 0, 0).unwrap().unwrap(),
-            lsp_types::CompletionResponse::Array(vec![
-                lsp_types::CompletionItem {
-                    label: "syntax = \"proto3\";".into(),
-                    kind: Some(lsp_types::CompletionItemKind::TEXT),
-                    ..Default::default()
-                },
+            ws.complete(&uri, 4, "  BLUE = ".len()).unwrap(),
+            Some(lsp_types::CompletionResponse::Array(vec![
                 lsp_types::CompletionItem {
-                    label: "syntax = \"proto2\";".into(),
-                    kind: Some(lsp_types::CompletionItemKind::TEXT),
+                    label: "5".into(),
+                    kind: Some(lsp_types::CompletionItemKind::VALUE),
                     ..Default::default()
                 }
-            ])
+            ]))
         );
     }
 
     #[test]
-    fn test_complete_import() {
+    fn test_complete_doc_template() {
         let (mut ws, tmp) = setup();
         let (uri, text) = proto(
             &tmp,
             "foo.proto",
-            &["syntax = \"proto3\";", "import \"bar.proto\";", "import \""],
+            &[
+                "syntax = \"proto3\";", // 0
+                "//",                   // 1
+                "message Foo {",        // 2
+                "  //",                 // 3
+                "  int32 bar = 1;",     // 4
+                "  // not a template",  // 5
+                "",                     // 6
+                "  //",                 // 7
+                "}",                    // 8
+            ],
         );
-        proto(&tmp, "bar.proto", &["syntax = \"proto3\";"]);
-        proto(&tmp, "baz.proto", &["syntax = \"proto3\";"]);
-
         ws.open(uri.clone(), text).unwrap();
+
+        // Templates are opt-in.
+        assert_eq!(ws.complete(&uri, 1, 2).unwrap(), None);
+        ws.options.completion.doc_templates = true;
+
+        let template = |name: &str, text: &str| {
+            Some(lsp_types::CompletionResponse::Array(vec![
+                lsp_types::CompletionItem {
+                    label: format!("Document {name}"),
+                    kind: Some(lsp_types::CompletionItemKind::SNIPPET),
+                    insert_text: Some(text.into()),
+                    insert_text_format: Some(lsp_types::InsertTextFormat::SNIPPET),
+                    insert_text_mode: Some(lsp_types::InsertTextMode::AS_IS),
+                    ..Default::default()
+                },
+            ]))
+        };
         assert_eq!(
-            ws.complete(&uri, 2, "import \"".len()).unwrap().unwrap(),
-            lsp_types::CompletionResponse::Array(vec![lsp_types::CompletionItem {
-                label: "baz.proto".into(),
-                kind: Some(lsp_types::CompletionItemKind::FILE),
-                insert_text: Some("baz.proto\";".into()),
-                ..Default::default()
-            },])
+            ws.complete(&uri, 1, 2).unwrap(),
+            template("Foo", " ${1:Foo summary.}\n//\n// ${2:Details.}")
+        );
+        assert_eq!(
+            ws.complete(&uri, 3, 4).unwrap(),
+            template("bar", " ${1:bar description.}")
         );
+
+        // Only an empty comment directly above a definition gets a template.
+        let is_template = |line, character| {
+            matches!(
+                ws.complete(&uri, line, character).unwrap(),
+                Some(lsp_types::CompletionResponse::Array(items))
+                    if items.iter().any(|i| i.label.starts_with("Document "))
+            )
+        };
+        assert!(!is_template(5, 19));
+        assert!(!is_template(7, 4));
     }
 
     #[test]
-    fn test_complete_nested_import() {
+    fn test_complete_field_number() {
         let (mut ws, tmp) = setup();
-        let (uri, text) = proto(&tmp, "foo.proto", &["syntax = \"proto3\";", "import \""]);
-        proto(&tmp, "bar.proto", &["syntax = \"proto3\";"]);
-
-        let subdir = tmp.path().join("subdir");
-        let subdir = subdir.as_path();
-        std::fs::create_dir(subdir).unwrap();
-        proto(subdir, "baz.proto", &["syntax = \"proto3\";"]);
-
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &[
+                "syntax = \"proto3\";",
+                "message Foo {",
+                "  reserved 2, 4 to 6;",
+                "  int32 a = 1;",
+                "  int32 b = 3;",
+                "  string c = ",
+                "}",
+            ],
+        );
         ws.open(uri.clone(), text).unwrap();
+
         assert_eq!(
-            ws.complete(&uri, 1, "import \"".len()).unwrap().unwrap(),
-            lsp_types::CompletionResponse::Array(vec![
-                lsp_types::CompletionItem {
-                    label: "bar.proto".into(),
-                    kind: Some(lsp_types::CompletionItemKind::FILE),
-                    insert_text: Some("bar.proto\";".into()),
-                    ..Default::default()
-                },
+            ws.complete(&uri, 5, "  string c = ".len()).unwrap(),
+            Some(lsp_types::CompletionResponse::Array(vec![
                 lsp_types::CompletionItem {
-                    label: "subdir/baz.proto".into(),
-                    kind: Some(lsp_types::CompletionItemKind::FILE),
-                    insert_text: Some("subdir/baz.proto\";".into()),
+                    label: "7".into(),
+                    kind: Some(lsp_types::CompletionItemKind::VALUE),
                     ..Default::default()
-                },
-            ])
+                }
+            ]))
         );
     }
 
     #[test]
-    fn test_complete_options() {
+    fn test_folding_ranges() {
         let (mut ws, tmp) = setup();
         let (uri, text) = proto(
             &tmp,
             "foo.proto",
-            &["syntax = \"proto3\";", "import \"bar.proto\";", "option j"],
+            &[
+                "syntax = \"proto3\";", // 0
+                "import \"a.proto\";",  // 1
+                "import \"b.proto\";",  // 2
+                "// c",                 // 3
+                "import \"c.proto\";",  // 4
+                "message Foo {",        // 5
+                "  message Bar {}",     // 6
+                "  oneof o {",          // 7
+                "    int32 i = 1;",     // 8
+                "  }",                  // 9
+                "}",                    // 10
+            ],
         );
-        proto(&tmp, "bar.proto", &["syntax = \"proto3\";"]);
-        proto(&tmp, "baz.proto", &["syntax = \"proto3\";"]);
-
         ws.open(uri.clone(), text).unwrap();
+
+        let fold = |start, end, kind| lsp_types::FoldingRange {
+            start_line: start,
+            end_line: end,
+            kind,
+            ..Default::default()
+        };
         assert_eq!(
-            ws.complete(&uri, 2, "option j".len()).unwrap().unwrap(),
-            lsp_types::CompletionResponse::Array(
-                OPTIONS
-                    .iter()
-                    .map(|name| {
-                        lsp_types::CompletionItem {
-                            label: name.to_string(),
-                            kind: Some(lsp_types::CompletionItemKind::TEXT),
-                            ..Default::default()
-                        }
-                    })
-                    .collect()
-            )
+            ws.folding_ranges(&uri).unwrap(),
+            vec![
+                fold(1, 4, Some(lsp_types::FoldingRangeKind::Imports)),
+                fold(5, 10, None),
+                fold(7, 9, None),
+            ]
         );
     }
 
     #[test]
-    fn test_goto_import() {
+    fn test_import_graph() {
         let (mut ws, tmp) = setup();
-        let (foo_uri, text) = proto(
+        let (a_uri, _) = proto(
+            &tmp,
+            "a.proto",
+            &["syntax = \"proto3\";", "import \"b.proto\";"],
+        );
+        let (b_uri, _) = proto(
+            &tmp,
+            "b.proto",
+            &["syntax = \"proto3\";", "import \"a.proto\";"],
+        );
+        let (c_uri, _) = proto(&tmp, "c.proto", &["syntax = \"proto3\";"]);
+        let (uri, text) = proto(
             &tmp,
             "foo.proto",
             &[
                 "syntax = \"proto3\";",
-                "import \"bar.proto\";",
-                "import \"baz.proto\";",
-                "import \"biz.proto\";",
+                "import \"a.proto\";",
+                "import \"c.proto\";",
+                "import \"nope.proto\";",
             ],
         );
-        let (bar_uri, _) = proto(&tmp, "bar.proto", &["syntax = \"proto3\";"]);
-        let (baz_uri, _) = proto(&tmp, "baz.proto", &["syntax = \"proto3\";"]);
-
-        ws.open(foo_uri.clone(), text).unwrap();
+        ws.open(uri.clone(), text).unwrap();
 
+        let edge = |from: &Url, to: &Url| ImportEdge {
+            from: from.clone(),
+            to: to.clone(),
+        };
         assert_eq!(
-            ws.goto(
-                foo_uri.clone(),
-                lsp_types::Position {
-                    line: 1,
-                    character: "import \"bar.".len().try_into().unwrap(),
-                }
-            )
-            .unwrap(),
-            Some(lsp_types::Location {
-                uri: bar_uri,
-                range: lsp_types::Range::default(),
-            })
+            ws.import_graph(&uri).unwrap(),
+            ImportGraph {
+                nodes: vec![uri.clone(), a_uri.clone(), c_uri.clone(), b_uri.clone()],
+                edges: vec![
+                    edge(&uri, &a_uri),
+                    edge(&uri, &c_uri),
+                    edge(&a_uri, &b_uri),
+                    edge(&b_uri, &a_uri),
+                ],
+                unresolved: vec!["nope.proto".into()],
+            }
+        );
+    }
+
+    #[test]
+    fn test_status() {
+        let (mut ws, tmp) = setup();
+        let (a_uri, _) = proto(&tmp, "a.proto", &["syntax = \"proto3\";"]);
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &[
+                "syntax = \"proto3\";",
+                "import \"a.proto\";",
+                "message Foo {}",
+                "message Bar {}",
+            ],
         );
+        ws.open(uri.clone(), text).unwrap();
 
+        let status = ws.status();
         assert_eq!(
-            ws.goto(
-                foo_uri.clone(),
-                lsp_types::Position {
-                    line: 2,
-                    character: "import \"baz".len().try_into().unwrap(),
-                }
-            )
-            .unwrap(),
-            Some(lsp_types::Location {
-                uri: baz_uri,
-                range: lsp_types::Range::default(),
-            })
+            status.iter().map(|s| &s.uri).collect::<Vec<_>>(),
+            vec![&a_uri, &uri]
+        );
+        let foo = &status[1];
+        assert!(foo.symbols > 0);
+        assert_eq!(foo.imports, 1);
+        assert!(!foo.stale_diagnostics);
+
+        ws.edit(
+            &uri,
+            vec![lsp_types::TextDocumentContentChangeEvent {
+                range: None,
+                range_length: None,
+                text: "syntax = \"proto3\";\nmessage Foo {}\n".into(),
+            }],
+        )
+        .unwrap();
+        let status = ws.status();
+        assert_eq!(status[1].imports, 0);
+        assert!(status[1].stale_diagnostics);
+
+        ws.save(uri.clone()).unwrap();
+        assert!(!ws.status()[1].stale_diagnostics);
+    }
+
+    #[test]
+    fn test_shutdown_interrupts_search() {
+        let (mut ws, tmp) = setup();
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &["syntax = \"proto3\";", "message Foo {}"],
+        );
+        ws.open(uri.clone(), text).unwrap();
+        assert_eq!(ws.all_symbols("Foo").unwrap().len(), 1);
+
+        // Searching every file is abandoned once the client asks to shut down.
+        ws.set_shutdown_check(|| true);
+        assert!(ws.all_symbols("Foo").is_err());
+        assert!(ws.references(references(&uri, 1, 9)).is_err());
+    }
+
+    #[test]
+    fn test_workspace_symbols_rank() {
+        let (mut ws, tmp) = setup();
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &[
+                "syntax = \"proto3\";",
+                "message FlowLoop {}",
+                "message MyFoo {}",
+                "message FooBar {}",
+                "message Outer {",
+                "  message Foo {}",
+                "}",
+            ],
         );
+        ws.open(uri.clone(), text).unwrap();
 
         assert_eq!(
-            ws.goto(
-                foo_uri,
-                lsp_types::Position {
-                    line: 3,
-                    character: "import \"biz".len().try_into().unwrap(),
-                }
-            )
-            .unwrap(),
-            None,
+            ws.all_symbols("Foo")
+                .unwrap()
+                .into_iter()
+                .map(|s| s.name)
+                .collect::<Vec<_>>(),
+            vec!["Outer.Foo", "FooBar", "MyFoo", "FlowLoop"],
         );
     }
 
     #[test]
-    fn test_goto_type() {
+    fn test_complete_enum_value_name() {
         let (mut ws, tmp) = setup();
-        let (foo_uri, text) = proto(
+        let (uri, text) = proto(
             &tmp,
             "foo.proto",
             &[
-                "syntax = \"proto3\";",        // 0
-                "package main;",               // 1
-                "import \"bar.proto\";",       // 2
-                "import \"baz.proto\";",       // 3
-                "message One {",               // 4
-                "message Two {",               // 5
-                "enum Three {}",               // 6
-                "}",                           // 7
-                "Two.Three tt = 1;",           // 8
-                "}",                           // 9
-                "message Stuff {",             // 10
-                "One one = 1;",                // 11
-                "One.Two two = 2;",            // 12
-                "One.Two.Three three = 3;",    // 13
-                "Two nope = 4;",               // 14
-                "bar.One bar_one = 5;",        // 15
-                "bar.One.Two b1 = 6;",         // 16
-                "bar.One.Two.Three b123 = 7;", // 17
-                "baz.buz.Baz bazbuz = 8;",     // 18
-                "}",                           // 19
+                "syntax = \"proto3\";",
+                "enum Color {",
+                "  RED = 0;",
+                "  GREEN = 4;",
+                "  BLU",
+                "}",
             ],
         );
-        let (bar_uri, _) = proto(
+        ws.open(uri.clone(), text).unwrap();
+
+        assert_eq!(
+            ws.complete(&uri, 4, "  BLU".len()).unwrap(),
+            Some(lsp_types::CompletionResponse::Array(vec![
+                lsp_types::CompletionItem {
+                    label: "BLU = 5;".into(),
+                    kind: Some(lsp_types::CompletionItemKind::ENUM_MEMBER),
+                    filter_text: Some("BLU".into()),
+                    insert_text: Some("BLU = ${1:5};".into()),
+                    insert_text_format: Some(lsp_types::InsertTextFormat::SNIPPET),
+                    ..Default::default()
+                }
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_moniker() {
+        let (mut ws, tmp) = setup();
+        let (dep, text) = proto(
             &tmp,
-            "bar.proto",
+            "dep.proto",
             &[
                 "syntax = \"proto3\";", // 0
-                "package bar;",         // 1
-                "message One",          // 2
-                "message One {",        // 3
-                "message Two {",        // 4
-                "enum Three {",         // 5
-                "}",                    // 6
-                "}",                    // 7
-                "}",                    // 8
+                "package dep;",         // 1
+                "message Dep {}",       // 2
             ],
         );
-        let (baz_uri, _) = proto(
+        ws.open(dep, text).unwrap();
+        let (uri, text) = proto(
             &tmp,
-            "baz.proto",
+            "foo.proto",
             &[
-                "syntax = \"proto3\";", // 0
-                "package baz.buz;",     // 1
-                "message Baz{}",        // 2
+                "syntax = \"proto3\";",  // 0
+                "package main;",         // 1
+                "import \"dep.proto\";", // 2
+                "message Foo {",         // 3
+                "  message Bar {",       // 4
+                "    dep.Dep dep = 1;",  // 5
+                "  }",                   // 6
+                "  Bar bar = 1;",        // 7
+                "}",                     // 8
             ],
         );
+        ws.open(uri.clone(), text).unwrap();
 
-        ws.open(foo_uri.clone(), text).unwrap();
+        let moniker = |line, character| {
+            ws.moniker(&uri, lsp_types::Position { line, character })
+                .unwrap()
+                .map(|m| (m.identifier, m.kind.unwrap()))
+        };
+        let export = |id: &str| Some((id.to_string(), lsp_types::MonikerKind::Export));
+        let import = |id: &str| Some((id.to_string(), lsp_types::MonikerKind::Import));
+        assert_eq!(moniker(3, 9), export("main.Foo"));
+        assert_eq!(moniker(4, 12), export("main.Foo.Bar"));
+        assert_eq!(moniker(5, 6), import("dep.Dep"));
+        assert_eq!(moniker(7, 3), export("main.Foo.Bar"));
+        assert_eq!(moniker(1, 3), None);
+    }
 
-        assert_eq!(
-            ws.goto(
-                foo_uri.clone(),
-                lsp_types::Position {
-                    line: 8,
-                    character: "Two.Th".len().try_into().unwrap(),
-                }
-            )
-            .unwrap(),
-            Some(lsp_types::Location {
-                uri: foo_uri.clone(),
-                range: lsp_types::Range {
-                    start: lsp_types::Position {
-                        line: 6,
-                        character: 0,
-                    },
-                    end: lsp_types::Position {
-                        line: 6,
-                        character: 13,
-                    },
-                },
-            })
+    #[test]
+    fn test_call_hierarchy() {
+        let (mut ws, tmp) = setup();
+        let (dep_uri, _) = proto(
+            &tmp,
+            "dep.proto",
+            &[
+                "syntax = \"proto3\";",     // 0
+                "package dep;",             // 1
+                "import \"foo.proto\";",    // 2
+                "message User {",           // 3
+                "  main.Address home = 1;", // 4
+                "}",                        // 5
+            ],
         );
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &[
+                "syntax = \"proto3\";",    // 0
+                "package main;",           // 1
+                "message Address {",       // 2
+                "  string street = 1;",    // 3
+                "}",                       // 4
+                "message Company {",       // 5
+                "  Address office = 1;",   // 6
+                "  Address shipping = 2;", // 7
+                "  message Inner {",       // 8
+                "    Company c = 1;",      // 9
+                "  }",                     // 10
+                "}",                       // 11
+            ],
+        );
+        ws.open(uri.clone(), text).unwrap();
 
+        // Prepare from the definition, or from a reference.
+        let items = ws
+            .prepare_call_hierarchy(&uri, lsp_types::Position::new(2, 10))
+            .unwrap()
+            .unwrap();
         assert_eq!(
-            ws.goto(
-                foo_uri.clone(),
-                lsp_types::Position {
-                    line: 11,
-                    character: 0,
-                }
-            )
-            .unwrap(),
-            Some(lsp_types::Location {
-                uri: foo_uri.clone(),
-                range: lsp_types::Range {
-                    start: lsp_types::Position {
-                        line: 4,
-                        character: 0,
-                    },
-                    end: lsp_types::Position {
-                        line: 9,
-                        character: 1,
-                    },
-                },
-            })
+            ws.prepare_call_hierarchy(&uri, lsp_types::Position::new(6, 4))
+                .unwrap()
+                .unwrap(),
+            items
         );
-
+        let address = items.into_iter().next().unwrap();
+        assert_eq!(address.name, "Address");
         assert_eq!(
-            ws.goto(
-                foo_uri.clone(),
-                lsp_types::Position {
-                    line: 12,
-                    character: "One.".len().try_into().unwrap(),
-                }
+            address.range,
+            lsp_types::Range::new(
+                lsp_types::Position::new(2, 0),
+                lsp_types::Position::new(4, 1)
             )
-            .unwrap(),
-            Some(lsp_types::Location {
-                uri: foo_uri.clone(),
-                range: lsp_types::Range {
-                    start: lsp_types::Position {
-                        line: 5,
-                        character: 0,
-                    },
-                    end: lsp_types::Position {
-                        line: 7,
-                        character: 1,
-                    },
-                },
-            })
         );
+        assert_eq!(address.detail, Some("main".into()));
 
+        let incoming = ws.incoming_calls(address.clone()).unwrap().unwrap();
+        let mut incoming = incoming
+            .into_iter()
+            .map(|call| (call.from.uri, call.from.name, call.from_ranges))
+            .collect::<Vec<_>>();
+        incoming.sort_by_key(|(_, name, _)| name.clone());
         assert_eq!(
-            ws.goto(
-                foo_uri.clone(),
-                lsp_types::Position {
-                    line: 13,
-                    character: "One.Two.T".len().try_into().unwrap(),
-                }
-            )
-            .unwrap(),
-            Some(lsp_types::Location {
-                uri: foo_uri.clone(),
-                range: lsp_types::Range {
-                    start: lsp_types::Position {
-                        line: 6,
-                        character: 0,
-                    },
-                    end: lsp_types::Position {
-                        line: 6,
-                        character: 13,
-                    },
-                },
-            })
+            incoming,
+            vec![
+                (
+                    uri.clone(),
+                    "Company".into(),
+                    vec![range(6, 2, 9), range(7, 2, 9)]
+                ),
+                (dep_uri, "User".into(), vec![range(4, 2, 14)]),
+            ]
         );
 
+        let company = ws
+            .prepare_call_hierarchy(&uri, lsp_types::Position::new(5, 10))
+            .unwrap()
+            .unwrap()
+            .pop()
+            .unwrap();
+        let outgoing = ws.outgoing_calls(company).unwrap().unwrap();
         assert_eq!(
-            ws.goto(
-                foo_uri.clone(),
-                lsp_types::Position {
-                    line: 15,
-                    character: 0,
-                }
-            )
-            .unwrap(),
-            Some(lsp_types::Location {
-                uri: bar_uri.clone(),
-                range: lsp_types::Range {
-                    start: lsp_types::Position {
-                        line: 3,
-                        character: 0,
-                    },
-                    end: lsp_types::Position {
-                        line: 8,
-                        character: 1,
-                    },
-                },
-            })
+            outgoing
+                .into_iter()
+                .map(|call| (call.to, call.from_ranges))
+                .collect::<Vec<_>>(),
+            vec![(address, vec![range(6, 2, 9), range(7, 2, 9)])]
+        );
+    }
+
+    #[test]
+    fn test_hover_json_name() {
+        let (mut ws, tmp) = setup();
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &[
+                "syntax = \"proto3\";",
+                "message Foo {",
+                "  string user_id = 1;",
+                "  string name = 2 [json_name = \"displayName\"];",
+                "}",
+            ],
         );
+        ws.open(uri.clone(), text).unwrap();
 
+        let hover = |line, character| {
+            ws.hover(&uri, lsp_types::Position { line, character })
+                .unwrap()
+        };
         assert_eq!(
-            ws.goto(
-                foo_uri.clone(),
-                lsp_types::Position {
-                    line: 16,
-                    character: 0,
-                }
-            )
-            .unwrap(),
-            Some(lsp_types::Location {
-                uri: bar_uri.clone(),
-                range: lsp_types::Range {
-                    start: lsp_types::Position {
-                        line: 4,
-                        character: 0,
-                    },
-                    end: lsp_types::Position {
-                        line: 7,
-                        character: 1,
-                    },
-                },
+            hover(2, 10),
+            Some(lsp_types::Hover {
+                contents: lsp_types::HoverContents::Markup(lsp_types::MarkupContent {
+                    kind: lsp_types::MarkupKind::Markdown,
+                    value: "`user_id`\n\nJSON name: `userId`".into(),
+                }),
+                range: Some(range(2, 9, 16)),
             })
         );
-
         assert_eq!(
-            ws.goto(
-                foo_uri.clone(),
-                lsp_types::Position {
-                    line: 17,
-                    character: 0,
-                }
-            )
-            .unwrap(),
-            Some(lsp_types::Location {
-                uri: bar_uri.clone(),
-                range: lsp_types::Range {
-                    start: lsp_types::Position {
-                        line: 5,
-                        character: 0,
-                    },
-                    end: lsp_types::Position {
-                        line: 6,
-                        character: 1,
-                    },
-                },
+            hover(3, 10),
+            Some(lsp_types::Hover {
+                contents: lsp_types::HoverContents::Markup(lsp_types::MarkupContent {
+                    kind: lsp_types::MarkupKind::Markdown,
+                    value: "`name`\n\nJSON name: `displayName`".into(),
+                }),
+                range: Some(range(3, 9, 13)),
             })
         );
+        assert_eq!(hover(2, 4), None);
+    }
+
+    #[test]
+    fn test_linked_editing_ranges() {
+        let (mut ws, tmp) = setup();
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &[
+                "syntax = \"proto3\";", // 0
+                "package foo;",         // 1
+                "message Bar {}",       // 2
+                "message Baz {",        // 3
+                "  Bar a = 1;",         // 4
+                "  foo.Bar b = 2;",     // 5
+                "  int32 c = 3;",       // 6
+                "}",                    // 7
+            ],
+        );
+        ws.open(uri.clone(), text).unwrap();
 
+        let expected = Some(lsp_types::LinkedEditingRanges {
+            ranges: vec![range(2, 8, 11), range(4, 2, 5), range(5, 6, 9)],
+            word_pattern: Some("[A-Za-z_][A-Za-z0-9_]*".into()),
+        });
         assert_eq!(
-            ws.goto(
-                foo_uri.clone(),
-                lsp_types::Position {
-                    line: 18,
-                    character: 2,
-                }
-            )
-            .unwrap(),
-            Some(lsp_types::Location {
-                uri: baz_uri.clone(),
-                range: lsp_types::Range {
-                    start: lsp_types::Position {
-                        line: 2,
-                        character: 0,
-                    },
-                    end: lsp_types::Position {
-                        line: 2,
-                        character: 13,
-                    },
-                },
-            })
+            ws.linked_editing_ranges(&uri, lsp_types::Position::new(2, 9))
+                .unwrap(),
+            expected
+        );
+        assert_eq!(
+            ws.linked_editing_ranges(&uri, lsp_types::Position::new(4, 3))
+                .unwrap(),
+            expected
+        );
+        assert_eq!(
+            ws.linked_editing_ranges(&uri, lsp_types::Position::new(6, 3))
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_linked_editing_ranges_imported() {
+        let (mut ws, tmp) = setup();
+        proto(
+            &tmp,
+            "dep.proto",
+            &["syntax = \"proto3\";", "package dep;", "message Dep {}"],
         );
+        let (uri, text) = proto(
+            &tmp,
+            "foo.proto",
+            &[
+                "syntax = \"proto3\";",  // 0
+                "package foo;",          // 1
+                "import \"dep.proto\";", // 2
+                "message Foo {",         // 3
+                "  dep.Dep a = 1;",      // 4
+                "  dep.Dep b = 2;",      // 5
+                "}",                     // 6
+            ],
+        );
+        ws.open(uri.clone(), text).unwrap();
 
+        // Renaming only the uses would leave the definition in dep.proto unchanged.
         assert_eq!(
-            ws.goto(
-                foo_uri.clone(),
-                lsp_types::Position {
-                    line: 14,
-                    character: 0,
-                }
-            )
-            .unwrap(),
-            None,
+            ws.linked_editing_ranges(&uri, lsp_types::Position::new(4, 7))
+                .unwrap(),
+            None
         );
     }
 }
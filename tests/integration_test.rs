@@ -5,7 +5,8 @@ use lsp_types::notification::{
     DidChangeTextDocument, DidOpenTextDocument, DidSaveTextDocument, PublishDiagnostics,
 };
 use lsp_types::request::{
-    Completion, DocumentSymbolRequest, GotoDefinition, Shutdown, WorkspaceSymbolRequest,
+    ApplyWorkspaceEdit, Completion, DocumentSymbolRequest, ExecuteCommand, GotoDefinition, Request,
+    Shutdown, WorkspaceSymbolRequest,
 };
 use lsp_types::{notification::Initialized, request::Initialize, InitializedParams};
 use lsp_types::{
@@ -53,7 +54,7 @@ fn diag(uri: Url, target: &str, message: &str) -> Diagnostic {
     }
 }
 
-fn sym(uri: Url, name: &str, text: &str) -> SymbolInformation {
+fn sym(uri: Url, name: &str, text: &str, container: &str) -> SymbolInformation {
     let kind = text
         .split_once(" ")
         .unwrap_or_else(|| panic!("Invalid symbol {text}"))
@@ -70,11 +71,10 @@ fn sym(uri: Url, name: &str, text: &str) -> SymbolInformation {
         tags: None,
         deprecated: None,
         location: locate_sym(uri, text),
-        container_name: None,
+        container_name: Some(container.into()),
     }
 }
 
-// Generate TextDocumentPositionParams for the given string and offset.
 fn position(uri: Url, text: &str, column: u32) -> TextDocumentPositionParams {
     let filetext = std::fs::read_to_string(uri.to_file_path().unwrap()).unwrap();
     let (lineno, line) = filetext
@@ -197,6 +197,25 @@ fn completion_params(uri: Url, position: Position) -> CompletionParams {
     }
 }
 
+// The command run when a type is accepted from completion, to rank it first next time.
+fn use_type(qualified: &str) -> lsp_types::Command {
+    lsp_types::Command {
+        title: "Use type".into(),
+        command: "pbls/useType".into(),
+        arguments: Some(vec![qualified.into()]),
+    }
+}
+
+// Modifiers are inserted along with the space or bracket that follows them.
+fn modifier(name: &str, insert: &str) -> CompletionItem {
+    CompletionItem {
+        label: name.into(),
+        kind: Some(CompletionItemKind::KEYWORD),
+        insert_text: Some(insert.into()),
+        ..Default::default()
+    }
+}
+
 fn assert_elements_equal<T, K, F>(mut a: Vec<T>, mut b: Vec<T>, key: F)
 where
     T: Clone + std::fmt::Debug + std::cmp::PartialEq,
@@ -213,6 +232,7 @@ struct TestClient {
     conn: Connection,
     thread: Option<std::thread::JoinHandle<()>>,
     id: i32,
+    capabilities: lsp_types::ServerCapabilities,
 }
 
 impl TestClient {
@@ -222,6 +242,21 @@ impl TestClient {
     }
 
     fn new_with_root(path: impl AsRef<std::path::Path>) -> Result<TestClient> {
+        Self::new_with_options(path, None)
+    }
+
+    fn new_with_options(
+        path: impl AsRef<std::path::Path>,
+        options: Option<serde_json::Value>,
+    ) -> Result<TestClient> {
+        Self::new_with_params(InitializeParams {
+            root_uri: Some(Url::from_file_path(std::fs::canonicalize(path).unwrap()).unwrap()),
+            initialization_options: options,
+            ..Default::default()
+        })
+    }
+
+    fn new_with_params(params: InitializeParams) -> Result<TestClient> {
         let (client, server) = Connection::memory();
         let thread = std::thread::spawn(|| {
             pbls::run(server).unwrap();
@@ -230,12 +265,10 @@ impl TestClient {
             conn: client,
             thread: Some(thread),
             id: 0,
+            capabilities: Default::default(),
         };
 
-        client.request::<Initialize>(InitializeParams {
-            root_uri: Some(Url::from_file_path(std::fs::canonicalize(path).unwrap()).unwrap()),
-            ..Default::default()
-        })?;
+        client.capabilities = client.request::<Initialize>(params)?.capabilities;
         client.notify::<Initialized>(InitializedParams {})?;
 
         Ok(client)
@@ -331,6 +364,62 @@ fn test_start_stop() -> pbls::Result<()> {
     Ok(())
 }
 
+#[test]
+fn test_invalid_params() -> pbls::Result<()> {
+    let client = TestClient::new()?;
+    client
+        .conn
+        .sender
+        .send(Message::Request(lsp_server::Request {
+            id: 100.into(),
+            method: <GotoDefinition as lsp_types::request::Request>::METHOD.to_string(),
+            params: serde_json::json!({"bogus": 1}),
+        }))?;
+
+    match client
+        .conn
+        .receiver
+        .recv_timeout(std::time::Duration::from_secs(5))?
+    {
+        Message::Response(resp) => {
+            assert_eq!(resp.id, 100.into());
+            assert_eq!(
+                resp.error.map(|e| e.code),
+                Some(lsp_server::ErrorCode::InvalidParams as i32)
+            );
+        }
+        msg => panic!("Expected response, got {msg:?}"),
+    }
+
+    // The server should still be alive, which is checked on drop.
+    Ok(())
+}
+
+#[test]
+fn test_no_root() -> pbls::Result<()> {
+    let _ = env_logger::builder().is_test(true).try_init();
+    // Falls back to the current directory, which contains testdata.
+    let client = TestClient::new_with_params(InitializeParams::default())?;
+    assert_eq!(client.open(base_uri())?.diagnostics, vec![]);
+    Ok(())
+}
+
+#[test]
+fn test_non_file_root() -> pbls::Result<()> {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let testdata = Url::from_file_path(std::fs::canonicalize("testdata")?).unwrap();
+    let client = TestClient::new_with_params(InitializeParams {
+        root_uri: Some(Url::parse("https://example.com/protos")?),
+        workspace_folders: Some(vec![lsp_types::WorkspaceFolder {
+            uri: testdata,
+            name: "testdata".into(),
+        }]),
+        ..Default::default()
+    })?;
+    assert_eq!(client.open(base_uri())?.diagnostics, vec![]);
+    Ok(())
+}
+
 #[test]
 fn test_open() -> pbls::Result<()> {
     let client = TestClient::new()?;
@@ -346,6 +435,71 @@ fn test_open() -> pbls::Result<()> {
     Ok(())
 }
 
+#[test]
+fn test_open_untitled() -> pbls::Result<()> {
+    let mut client = TestClient::new()?;
+    let uri = Url::parse("untitled:Untitled-1")?;
+
+    // The buffer was never saved, so only the client has its text.
+    client.notify::<DidOpenTextDocument>(DidOpenTextDocumentParams {
+        text_document: TextDocumentItem {
+            uri: uri.clone(),
+            language_id: "protobuf".into(),
+            version: 0,
+            text: [
+                "syntax = \"proto3\";",
+                "message Foo {",
+                "  Unknown u = 1;",
+                "}",
+                "",
+            ]
+            .join("\n"),
+        },
+    })?;
+    assert_eq!(
+        client.recv::<PublishDiagnostics>()?,
+        PublishDiagnosticsParams {
+            uri: uri.clone(),
+            diagnostics: vec![Diagnostic {
+                range: Range {
+                    start: Position {
+                        line: 2,
+                        character: 2,
+                    },
+                    end: Position {
+                        line: 2,
+                        character: 16,
+                    },
+                },
+                severity: Some(DiagnosticSeverity::ERROR),
+                source: Some("pbls".into()),
+                message: "\"Unknown\" is not defined".into(),
+                ..Default::default()
+            }],
+            version: None,
+        }
+    );
+
+    let Some(DocumentSymbolResponse::Flat(actual)) =
+        client.request::<DocumentSymbolRequest>(DocumentSymbolParams {
+            text_document: TextDocumentIdentifier { uri },
+            work_done_progress_params: lsp_types::WorkDoneProgressParams {
+                work_done_token: None,
+            },
+            partial_result_params: lsp_types::PartialResultParams {
+                partial_result_token: None,
+            },
+        })?
+    else {
+        panic!("Expected DocumentSymbolResponse::Flat")
+    };
+    assert_eq!(
+        actual.into_iter().map(|s| s.name).collect::<Vec<_>>(),
+        vec!["Foo"]
+    );
+    Ok(())
+}
+
 #[test]
 fn test_diagnostics_on_open() -> pbls::Result<()> {
     let client = TestClient::new()?;
@@ -367,6 +521,177 @@ fn test_diagnostics_on_open() -> pbls::Result<()> {
     Ok(())
 }
 
+#[test]
+fn test_document_diagnostic() -> pbls::Result<()> {
+    let mut client = TestClient::new()?;
+    client.open(error_uri())?;
+
+    let resp = client.request::<lsp_types::request::DocumentDiagnosticRequest>(
+        lsp_types::DocumentDiagnosticParams {
+            text_document: TextDocumentIdentifier { uri: error_uri() },
+            identifier: None,
+            previous_result_id: None,
+            work_done_progress_params: WorkDoneProgressParams {
+                work_done_token: None,
+            },
+            partial_result_params: PartialResultParams {
+                partial_result_token: None,
+            },
+        },
+    )?;
+    let report = match resp {
+        lsp_types::DocumentDiagnosticReportResult::Report(
+            lsp_types::DocumentDiagnosticReport::Full(report),
+        ) => report,
+        resp => panic!("Expected full report, got {resp:?}"),
+    };
+    assert_elements_equal(
+        report.full_document_diagnostic_report.items,
+        vec![
+            diag(error_uri(), "Unknown t =", "\"Unknown\" is not defined"),
+            diag(
+                error_uri(),
+                "int32 bar =",
+                "Field number 1 has already been used in \"main.Noo\" by field \"foo\"",
+            ),
+        ],
+        |s| s.message.clone(),
+    );
+    Ok(())
+}
+
+#[test]
+fn test_diagnostic_source() -> pbls::Result<()> {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let init = serde_json::json!({"diagnostic_source": "foo"});
+    let client = TestClient::new_with_options("testdata", Some(init))?;
+
+    let Some(lsp_types::DiagnosticServerCapabilities::Options(options)) =
+        &client.capabilities.diagnostic_provider
+    else {
+        panic!("Missing diagnostic options");
+    };
+    assert_eq!(options.identifier, Some("foo".into()));
+
+    let diags = client.open(error_uri())?;
+    assert!(!diags.diagnostics.is_empty());
+    for diag in diags.diagnostics {
+        assert_eq!(diag.source, options.identifier);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_strict_diagnostics() -> pbls::Result<()> {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let tmp = tempfile::tempdir()?;
+    let path = tmp.path().join("example.proto");
+    let uri = Url::from_file_path(&path).unwrap();
+    std::fs::write(&path, "syntax = \"proto3\";\nmessage foo_bar {}\n")?;
+
+    let severities = |strict: bool| -> pbls::Result<Vec<_>> {
+        let init = serde_json::json!({"lint": {"enabled": true}, "strict": strict});
+        let client = TestClient::new_with_options(&tmp, Some(init))?;
+        let diags = client.open(uri.clone())?;
+        Ok(diags.diagnostics.into_iter().map(|d| d.severity).collect())
+    };
+    assert_eq!(
+        severities(false)?,
+        vec![Some(lsp_types::DiagnosticSeverity::INFORMATION)]
+    );
+    assert_eq!(
+        severities(true)?,
+        vec![Some(lsp_types::DiagnosticSeverity::ERROR)]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_no_duplicate_protoc_errors() -> pbls::Result<()> {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let tmp = tempfile::tempdir()?;
+    let client = TestClient::new_with_options(&tmp, None)?;
+
+    // Protoc and pbls both flag these, but each line is only reported once.
+    let lines = |name: &str, lines: &[&str]| -> pbls::Result<Vec<u32>> {
+        let path = tmp.path().join(name);
+        std::fs::write(&path, lines.join("\n") + "\n")?;
+        let diags = client.open(Url::from_file_path(&path).unwrap())?;
+        let mut lines: Vec<_> = diags
+            .diagnostics
+            .iter()
+            .map(|d| d.range.start.line)
+            .collect();
+        lines.sort();
+        Ok(lines)
+    };
+    assert_eq!(
+        lines(
+            "required.proto",
+            &[
+                "syntax = \"proto3\";",
+                "message Foo {",
+                "  required int32 a = 1;",
+                "}",
+            ]
+        )?,
+        vec![2]
+    );
+    assert_eq!(
+        lines(
+            "extensions.proto",
+            &[
+                "syntax = \"proto2\";",
+                "message Foo {",
+                "  extensions 1 to 5, 3 to 10;",
+                "}",
+            ]
+        )?,
+        vec![2]
+    );
+    assert_eq!(
+        lines(
+            "map.proto",
+            &[
+                "syntax = \"proto3\";",
+                "message Foo {}",
+                "message Bar {",
+                "  map<Foo, int32> m = 1;",
+                "}",
+            ]
+        )?,
+        vec![3]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_diagnostics_without_protoc() -> pbls::Result<()> {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let tmp = tempfile::tempdir()?;
+    let path = tmp.path().join("example.proto");
+    let uri = Url::from_file_path(&path).unwrap();
+    std::fs::write(
+        &path,
+        "syntax = \"proto3\";\nmessage Foo {\n  Nope a = 1;\n}\n",
+    )?;
+
+    // Checks that do not need protoc are still reported if it cannot be run.
+    let protoc = tmp.path().join("nonexistent").join("protoc");
+    let init = serde_json::json!({"protoc": protoc});
+    let client = TestClient::new_with_options(&tmp, Some(init))?;
+    let diags = client.open(uri)?;
+    assert_eq!(
+        diags
+            .diagnostics
+            .iter()
+            .map(|d| (d.range.start.line, d.message.as_str()))
+            .collect::<Vec<_>>(),
+        vec![(2, "\"Nope\" is not defined")]
+    );
+    Ok(())
+}
+
 #[test]
 fn test_diagnostics_on_save() -> pbls::Result<()> {
     let tmp = tempfile::tempdir()?;
@@ -436,6 +761,63 @@ message Foo{Flob flob = 1;}
     Ok(())
 }
 
+#[test]
+fn test_diagnostics_on_import_change() -> pbls::Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let path = tmp.path().join("example.proto");
+    let uri = Url::from_file_path(&path).unwrap();
+    let client = TestClient::new_with_root(&tmp)?;
+
+    std::fs::write(
+        tmp.path().join("bar.proto"),
+        "syntax = \"proto3\";\npackage main;\nmessage Bar{}\n",
+    )?;
+    let text = r#"
+syntax = "proto3";
+package main;
+message Foo{}
+"#;
+    std::fs::write(&path, text)?;
+    client.open(uri.clone())?;
+
+    // An edit that leaves the imports alone does not refresh diagnostics.
+    let change = |line, character, text: &str| TextDocumentContentChangeEvent {
+        text: text.into(),
+        range: Some(lsp_types::Range {
+            start: Position { line, character },
+            end: Position { line, character },
+        }),
+        range_length: None,
+    };
+    client.notify::<DidChangeTextDocument>(DidChangeTextDocumentParams {
+        text_document: lsp_types::VersionedTextDocumentIdentifier {
+            uri: uri.clone(),
+            version: 1,
+        },
+        content_changes: vec![change(3, "message Foo{".len() as u32, "Bar bar = 1;")],
+    })?;
+
+    // Importing the type it uses does.
+    client.notify::<DidChangeTextDocument>(DidChangeTextDocumentParams {
+        text_document: lsp_types::VersionedTextDocumentIdentifier {
+            uri: uri.clone(),
+            version: 2,
+        },
+        content_changes: vec![change(3, 0, "import \"bar.proto\";\n")],
+    })?;
+    let diags = client.recv::<PublishDiagnostics>()?;
+    assert_eq!(
+        diags,
+        PublishDiagnosticsParams {
+            uri: uri.clone(),
+            diagnostics: vec![],
+            version: None,
+        }
+    );
+
+    Ok(())
+}
+
 #[test]
 fn test_no_diagnostics_on_open() -> pbls::Result<()> {
     let client = TestClient::new()?;
@@ -473,11 +855,11 @@ fn test_document_symbols() -> pbls::Result<()> {
     assert_elements_equal(
         actual,
         vec![
-            sym(base_uri(), "Thing", "enum Thing"),
-            sym(base_uri(), "Foo", "message Foo"),
-            sym(base_uri(), "Foo.Buz", "message Buz"),
-            sym(base_uri(), "Bar", "message Bar"),
-            sym(base_uri(), "Empty", "message Empty"),
+            sym(base_uri(), "Thing", "enum Thing", "main"),
+            sym(base_uri(), "Foo", "message Foo", "main"),
+            sym(base_uri(), "Foo.Buz", "message Buz", "main.Foo"),
+            sym(base_uri(), "Bar", "message Bar", "main"),
+            sym(base_uri(), "Empty", "message Empty", "main"),
         ],
         |s| s.name.clone(),
     );
@@ -511,44 +893,49 @@ fn test_workspace_symbols() -> pbls::Result<()> {
     test(
         "",
         vec![
-            sym(other_uri(), "Other", "message Other"),
-            sym(dep_uri(), "Dep", "message Dep"),
-            sym(dep_uri(), "Dep2", "enum Dep2"),
-            sym(base_uri(), "Thing", "enum Thing"),
-            sym(base_uri(), "Foo", "message Foo"),
-            sym(base_uri(), "Foo.Buz", "message Buz"),
-            sym(base_uri(), "Bar", "message Bar"),
-            sym(base_uri(), "Empty", "message Empty"),
-            sym(other_uri(), "Other.Nested", "message Nested"),
-            sym(error_uri(), "Nope", "enum Nope"),
-            sym(error_uri(), "Nah", "message Nah"),
-            sym(error_uri(), "Noo", "message Noo"),
-            sym(stuff_uri(), "Stuff", "message Stuff"),
+            sym(other_uri(), "Other", "message Other", "other"),
+            sym(dep_uri(), "Dep", "message Dep", "main"),
+            sym(dep_uri(), "Dep2", "enum Dep2", "main"),
+            sym(base_uri(), "Thing", "enum Thing", "main"),
+            sym(base_uri(), "Foo", "message Foo", "main"),
+            sym(base_uri(), "Foo.Buz", "message Buz", "main.Foo"),
+            sym(base_uri(), "Bar", "message Bar", "main"),
+            sym(base_uri(), "Empty", "message Empty", "main"),
+            sym(other_uri(), "Other.Nested", "message Nested", "other.Other"),
+            sym(error_uri(), "Nope", "enum Nope", "main"),
+            sym(error_uri(), "Nah", "message Nah", "main"),
+            sym(error_uri(), "Noo", "message Noo", "main"),
+            sym(stuff_uri(), "Stuff", "message Stuff", "folder.stuff"),
         ],
     );
 
     test(
         "oo",
         vec![
-            sym(base_uri(), "Foo", "message Foo"),
-            sym(base_uri(), "Foo.Buz", "message Buz"),
-            sym(error_uri(), "Noo", "message Noo"),
+            sym(base_uri(), "Foo", "message Foo", "main"),
+            sym(base_uri(), "Foo.Buz", "message Buz", "main.Foo"),
+            sym(error_uri(), "Noo", "message Noo", "main"),
         ],
     );
 
     test(
         "fo",
         vec![
-            sym(base_uri(), "Foo", "message Foo"),
-            sym(base_uri(), "Foo.Buz", "message Buz"),
+            sym(base_uri(), "Foo", "message Foo", "main"),
+            sym(base_uri(), "Foo.Buz", "message Buz", "main.Foo"),
         ],
     );
 
-    test("np", vec![sym(error_uri(), "Nope", "enum Nope")]);
+    test("np", vec![sym(error_uri(), "Nope", "enum Nope", "main")]);
 
     test(
         "nst oth",
-        vec![sym(other_uri(), "Other.Nested", "message Nested")],
+        vec![sym(
+            other_uri(),
+            "Other.Nested",
+            "message Nested",
+            "other.Other",
+        )],
     );
 
     Ok(())
@@ -706,6 +1093,33 @@ fn test_message_references() -> pbls::Result<()> {
     Ok(())
 }
 
+#[test]
+fn test_message_references_include_declaration() -> pbls::Result<()> {
+    let mut client = TestClient::new()?;
+    client.open(base_uri())?;
+
+    assert_eq!(
+        client.request::<lsp_types::request::References>(lsp_types::ReferenceParams {
+            text_document_position: position(base_uri(), "message Foo", 9),
+            work_done_progress_params: lsp_types::WorkDoneProgressParams {
+                work_done_token: None,
+            },
+            partial_result_params: lsp_types::PartialResultParams {
+                partial_result_token: None
+            },
+            context: lsp_types::ReferenceContext {
+                include_declaration: true,
+            },
+        })?,
+        Some(vec![
+            locate_sym(base_uri(), "message Foo"),
+            locate(base_uri(), "|Foo| f = 1")
+        ])
+    );
+
+    Ok(())
+}
+
 #[test]
 fn test_message_references_cross_package() -> pbls::Result<()> {
     let mut client = TestClient::new()?;
@@ -780,6 +1194,10 @@ fn test_complete_import() -> pbls::Result<()> {
             },
             CompletionItem {
                 label: "folder/what.proto".into(),
+                label_details: Some(lsp_types::CompletionItemLabelDetails {
+                    detail: None,
+                    description: Some("folder".into()),
+                }),
                 kind: Some(CompletionItemKind::FILE),
                 insert_text: Some("folder/what.proto\";".into()),
                 ..Default::default()
@@ -880,6 +1298,11 @@ fn test_complete_type_after_adding_import() -> pbls::Result<()> {
     let Some(lsp_types::CompletionResponse::Array(actual)) = resp else {
         panic!("Unexpected completion response {resp:?}");
     };
+    // The data of each type locates its definition, to resolve its details.
+    let actual = actual
+        .into_iter()
+        .map(|item| CompletionItem { data: None, ..item })
+        .collect();
 
     let keyword = |name: &str| CompletionItem {
         label: name.into(),
@@ -893,9 +1316,18 @@ fn test_complete_type_after_adding_import() -> pbls::Result<()> {
         ..Default::default()
     };
 
-    let _enum = |name: &str| CompletionItem {
+    // Accepting a type records its use by its fully qualified name.
+    let message = |name: &str, qualified: &str| CompletionItem {
+        label: name.into(),
+        kind: Some(CompletionItemKind::STRUCT),
+        command: Some(use_type(qualified)),
+        ..Default::default()
+    };
+
+    let _enum = |name: &str, qualified: &str| CompletionItem {
         label: name.into(),
         kind: Some(CompletionItemKind::ENUM),
+        command: Some(use_type(qualified)),
         ..Default::default()
     };
 
@@ -905,13 +1337,13 @@ fn test_complete_type_after_adding_import() -> pbls::Result<()> {
             keyword("enum"),
             keyword("extend"),
             keyword("import"),
-            keyword("map"),
+            modifier("map", "map<"),
             keyword("message"),
             keyword("oneof"),
             keyword("option"),
-            keyword("optional"),
+            modifier("optional", "optional "),
             keyword("package"),
-            keyword("repeated"),
+            modifier("repeated", "repeated "),
             keyword("reserved"),
             keyword("returns"),
             keyword("rpc"),
@@ -932,9 +1364,9 @@ fn test_complete_type_after_adding_import() -> pbls::Result<()> {
             _struct("string"),
             _struct("uint32"),
             _struct("uint64"),
-            _struct("Other"),
-            _struct("Nested"),
-            _struct("folder.stuff.Stuff"),
+            message("Other", "other.Other"),
+            message("Nested", "other.Other.Nested"),
+            message("folder.stuff.Stuff", "folder.stuff.Stuff"),
         ],
         |s| s.label.clone(),
     );
@@ -961,6 +1393,11 @@ fn test_complete_type() -> pbls::Result<()> {
     let Some(lsp_types::CompletionResponse::Array(actual)) = resp else {
         panic!("Unexpected completion response {resp:?}");
     };
+    // The data of each type locates its definition, to resolve its details.
+    let actual = actual
+        .into_iter()
+        .map(|item| CompletionItem { data: None, ..item })
+        .collect();
 
     let keyword = |name: &str| CompletionItem {
         label: name.into(),
@@ -974,9 +1411,18 @@ fn test_complete_type() -> pbls::Result<()> {
         ..Default::default()
     };
 
-    let _enum = |name: &str| CompletionItem {
+    // Accepting a type records its use by its fully qualified name.
+    let message = |name: &str, qualified: &str| CompletionItem {
+        label: name.into(),
+        kind: Some(CompletionItemKind::STRUCT),
+        command: Some(use_type(qualified)),
+        ..Default::default()
+    };
+
+    let _enum = |name: &str, qualified: &str| CompletionItem {
         label: name.into(),
         kind: Some(CompletionItemKind::ENUM),
+        command: Some(use_type(qualified)),
         ..Default::default()
     };
 
@@ -986,13 +1432,13 @@ fn test_complete_type() -> pbls::Result<()> {
             keyword("enum"),
             keyword("extend"),
             keyword("import"),
-            keyword("map"),
+            modifier("map", "map<"),
             keyword("message"),
             keyword("oneof"),
             keyword("option"),
-            keyword("optional"),
+            modifier("optional", "optional "),
             keyword("package"),
-            keyword("repeated"),
+            modifier("repeated", "repeated "),
             keyword("reserved"),
             keyword("returns"),
             keyword("rpc"),
@@ -1013,16 +1459,16 @@ fn test_complete_type() -> pbls::Result<()> {
             _struct("string"),
             _struct("uint32"),
             _struct("uint64"),
-            _struct("Bar"),
-            _struct("Buz"),
-            _struct("Empty"),
-            _struct("Foo"),
-            _enum("Thing"),
-            _struct("Dep"),
-            _enum("Dep2"),
-            _struct("other.Other"),
-            _struct("other.Other.Nested"),
-            _struct("folder.stuff.Stuff"),
+            message("Bar", "main.Bar"),
+            message("Buz", "main.Foo.Buz"),
+            message("Empty", "main.Empty"),
+            message("Foo", "main.Foo"),
+            _enum("Thing", "main.Thing"),
+            message("Dep", "main.Dep"),
+            _enum("Dep2", "main.Dep2"),
+            message("other.Other", "other.Other"),
+            message("other.Other.Nested", "other.Other.Nested"),
+            message("folder.stuff.Stuff", "folder.stuff.Stuff"),
         ],
         |s| s.label.clone(),
     );
@@ -1120,3 +1566,53 @@ fn test_import_discovery() -> pbls::Result<()> {
     );
     Ok(())
 }
+
+#[test]
+fn test_from_json_command() -> pbls::Result<()> {
+    let client = TestClient::new()?;
+    let uri = base_uri();
+    client.open(uri.clone())?;
+
+    client
+        .conn
+        .sender
+        .send(Message::Request(lsp_server::Request {
+            id: 100.into(),
+            method: ExecuteCommand::METHOD.into(),
+            params: serde_json::to_value(lsp_types::ExecuteCommandParams {
+                command: "pbls/fromJson".into(),
+                arguments: vec![
+                    serde_json::to_value(&uri)?,
+                    serde_json::to_value(Position::new(1, 0))?,
+                    serde_json::json!({"id": 1}),
+                    serde_json::json!("Sample"),
+                ],
+                work_done_progress_params: WorkDoneProgressParams::default(),
+            })?,
+        }))?;
+
+    // The edit is sent for the client to apply, before the command completes.
+    let timeout = std::time::Duration::from_secs(5);
+    let Message::Request(req) = client.conn.receiver.recv_timeout(timeout)? else {
+        bail!("Expected an applyEdit request");
+    };
+    assert_eq!(req.method, ApplyWorkspaceEdit::METHOD);
+    let params: lsp_types::ApplyWorkspaceEditParams = serde_json::from_value(req.params)?;
+    assert_eq!(
+        params.edit.changes,
+        Some(std::collections::HashMap::from([(
+            uri,
+            vec![lsp_types::TextEdit {
+                range: Range::new(Position::new(1, 0), Position::new(1, 0)),
+                new_text: "message Sample {\n  int64 id = 1;\n}\n".into(),
+            }]
+        )]))
+    );
+
+    let Message::Response(resp) = client.conn.receiver.recv_timeout(timeout)? else {
+        bail!("Expected a response");
+    };
+    assert_eq!(resp.id, 100.into());
+    assert_eq!(resp.result, Some(serde_json::Value::Null));
+    Ok(())
+}